@@ -0,0 +1,225 @@
+// Compares the block kernels against the loop AudioFilter::apply used to run.
+// Run with `cargo bench -p audioserver --bench kernels`.
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/dsp/mod.rs"]
+mod dsp;
+
+use biquad::{Coefficients, ToHertz, Type, Q_BUTTERWORTH_F32};
+use dsp::biquad::{BiquadBank, BiquadCoefficients};
+use dsp::convolver::PartitionedConvolver;
+use dsp::fir::FirFilter;
+use dsp::Kernel;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const SAMPLE_RATE: f32 = 48000.0;
+const BLOCK: usize = 512;
+const CHANNELS: usize = 8;
+const FIR_TAPS: usize = 256;
+// Room correction length
+const CORRECTION_TAPS: usize = 65536;
+const BLOCKS: usize = 200;
+
+fn noise(len: usize, seed: u32) -> Vec<f32> {
+    let mut state = seed.max(1);
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state as f32 / u32::MAX as f32) * 2.0 - 1.0
+        })
+        .collect()
+}
+
+// The original AudioFilter::apply
+fn apply(coefficients: &[f32], input: &[f32]) -> Vec<f32> {
+    let mut output = Vec::new();
+    for i in 0..input.len() {
+        let mut sum = 0.0;
+        for j in 0..coefficients.len() {
+            if i >= j {
+                sum += input[i - j] * coefficients[j];
+            }
+        }
+        output.push(sum);
+    }
+    output
+}
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    let started = Instant::now();
+    for _ in 0..BLOCKS {
+        f();
+    }
+    started.elapsed() / BLOCKS as u32
+}
+
+fn max_diff(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).fold(0.0, |max, (x, y)| max.max((x - y).abs()))
+}
+
+fn bench_fir() {
+    let coefficients: Vec<f32> = noise(FIR_TAPS, 7).iter().map(|c| c / FIR_TAPS as f32).collect();
+    let input = noise(BLOCK, 11);
+    let budget = Duration::from_secs_f32(BLOCK as f32 / SAMPLE_RATE);
+
+    let reference = apply(&coefficients, &input);
+    let elapsed = time(|| {
+        for _ in 0..CHANNELS {
+            black_box(apply(&coefficients, black_box(&input)));
+        }
+    });
+    println!(
+        "FIR {} taps x {} ch, AudioFilter::apply: {:>9.1?} per block ({:.1}% of {:?})",
+        FIR_TAPS,
+        CHANNELS,
+        elapsed,
+        elapsed.as_secs_f32() / budget.as_secs_f32() * 100.0,
+        budget
+    );
+
+    for kernel in Kernel::available() {
+        let mut output = vec![0.0; BLOCK];
+        // First block from a fresh filter matches the stateless reference
+        FirFilter::with_kernel(&coefficients, kernel).process(&input, &mut output);
+        let diff = max_diff(&output, &reference);
+
+        let mut filters = vec![FirFilter::with_kernel(&coefficients, kernel); CHANNELS];
+        let elapsed = time(|| {
+            for fir in filters.iter_mut() {
+                fir.process(black_box(&input), &mut output);
+                black_box(&output);
+            }
+        });
+        println!(
+            "FIR {} taps x {} ch, {:<7}: {:>9.1?} per block ({:.1}% of budget), max diff {:.2e}",
+            FIR_TAPS,
+            CHANNELS,
+            format!("{:?}", kernel),
+            elapsed,
+            elapsed.as_secs_f32() / budget.as_secs_f32() * 100.0,
+            diff
+        );
+    }
+}
+
+fn bench_correction() {
+    let coefficients: Vec<f32> = noise(CORRECTION_TAPS, 17).iter().map(|c| c / CORRECTION_TAPS as f32).collect();
+    let input = noise(BLOCK, 19);
+    let budget = Duration::from_secs_f32(BLOCK as f32 / SAMPLE_RATE);
+    let report = |name: &str, elapsed: Duration| {
+        println!(
+            "Correction {} taps x {} ch, {:<11}: {:>9.1?} per block ({:.1}% of budget)",
+            CORRECTION_TAPS,
+            CHANNELS,
+            name,
+            elapsed,
+            elapsed.as_secs_f32() / budget.as_secs_f32() * 100.0
+        );
+    };
+
+    let mut output = vec![0.0; BLOCK];
+    let mut fir = FirFilter::new(&coefficients);
+    let started = Instant::now();
+    fir.process(black_box(&input), &mut output);
+    report("time domain", started.elapsed() * CHANNELS as u32);
+
+    let mut convolvers: Vec<_> = (0..CHANNELS).map(|_| PartitionedConvolver::new(&coefficients)).collect();
+    let elapsed = time(|| {
+        for convolver in convolvers.iter_mut() {
+            convolver.process(black_box(&input), &mut output);
+            black_box(&output);
+        }
+    });
+    report("partitioned", elapsed);
+}
+
+// Four-way stereo crossover with Linkwitz-Riley 24 dB/oct slopes, as bands are
+// built for the pipeline: two sections per slope
+fn crossover_cascades() -> Vec<Vec<BiquadCoefficients>> {
+    let section = |kind, freq: f32| {
+        BiquadCoefficients::from(
+            Coefficients::<f32>::from_params(kind, SAMPLE_RATE.hz(), freq.hz(), Q_BUTTERWORTH_F32).unwrap(),
+        )
+    };
+    let slope = |kind, freq: f32| vec![section(kind, freq), section(kind, freq)];
+    let mut cascades = Vec::new();
+    for _ in 0..2 {
+        cascades.push(slope(Type::LowPass, 80.0));
+        cascades.push([slope(Type::HighPass, 80.0), slope(Type::LowPass, 500.0)].concat());
+        cascades.push([slope(Type::HighPass, 500.0), slope(Type::LowPass, 3000.0)].concat());
+        cascades.push(slope(Type::HighPass, 3000.0));
+    }
+    cascades
+}
+
+// BandPool gives band i to thread i % (workers + 1) and each thread runs its bands
+// as the lanes of one bank. Reports the slowest thread, which the callback waits for.
+fn bench_biquads() {
+    let cascades = crossover_cascades();
+    let bands = cascades.len();
+    let budget = Duration::from_secs_f32(BLOCK as f32 / SAMPLE_RATE);
+    let report = |layout: &str, kernel: Kernel, elapsed: Duration| {
+        println!(
+            "Crossover biquads {} bands, {:<22} {:<7}: {:>9.1?} per block ({:.1}% of budget)",
+            bands,
+            layout,
+            format!("{:?}", kernel),
+            elapsed,
+            elapsed.as_secs_f32() / budget.as_secs_f32() * 100.0
+        );
+    };
+
+    // The layout before bands were grouped: a one-lane bank per band
+    let mut single: Vec<BiquadBank> =
+        cascades.iter().map(|cascade| BiquadBank::new(std::slice::from_ref(cascade))).collect();
+    let input = noise(BLOCK, 13);
+    let mut data = input.clone();
+    let elapsed = time(|| {
+        for bank in single.iter_mut() {
+            data.copy_from_slice(&input);
+            bank.process_interleaved(black_box(&mut data));
+        }
+    });
+    report("one bank per band", Kernel::Scalar, elapsed);
+
+    for workers in [0, 1, 3] {
+        let groups: Vec<Vec<Vec<BiquadCoefficients>>> = (0..=workers)
+            .map(|thread| cascades.iter().skip(thread).step_by(workers + 1).cloned().collect())
+            .collect();
+        let mut reference: Vec<Vec<f32>> = Vec::new();
+        for kernel in Kernel::available() {
+            let mut slowest = Duration::ZERO;
+            let mut diff = 0.0f32;
+            for (thread, group) in groups.iter().enumerate() {
+                let input = noise(BLOCK * group.len(), 13);
+                let mut output = input.clone();
+                BiquadBank::with_kernel(group, kernel).process_interleaved(&mut output);
+                match reference.get(thread) {
+                    Some(expected) => diff = diff.max(max_diff(&output, expected)),
+                    None => reference.push(output),
+                }
+
+                let mut bank = BiquadBank::with_kernel(group, kernel);
+                let mut data = input.clone();
+                slowest = slowest.max(time(|| {
+                    data.copy_from_slice(&input);
+                    bank.process_interleaved(black_box(&mut data));
+                }));
+            }
+            report(&format!("{} worker(s), slowest", workers), kernel, slowest);
+            if diff != 0.0 {
+                println!("    max diff to scalar {:.2e}", diff);
+            }
+        }
+    }
+}
+
+fn main() {
+    println!("Detected kernel: {:?}", Kernel::detect());
+    bench_fir();
+    bench_correction();
+    bench_biquads();
+}
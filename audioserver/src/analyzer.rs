@@ -0,0 +1,370 @@
+use anyhow::{anyhow, bail, Result};
+use rtrb::{Consumer, Producer, RingBuffer};
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::meter::to_db;
+
+// Where in the pipeline a tap reads its samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TapPoint {
+    // Channel of the pipeline input, before any processing
+    Input(usize),
+    // Output of a processed band
+    Band(usize),
+}
+
+impl TapPoint {
+    // "input:<channel>" or "band:<index>"
+    pub fn parse(text: &str) -> Result<Self> {
+        let (kind, index) = text
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected input:<channel> or band:<index>, got {}", text))?;
+        let index = index.parse().map_err(|_| anyhow!("Bad tap index in {}", text))?;
+        match kind {
+            "input" => Ok(TapPoint::Input(index)),
+            "band" => Ok(TapPoint::Band(index)),
+            _ => bail!("Expected input:<channel> or band:<index>, got {}", text),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            TapPoint::Input(channel) => format!("input:{}", channel),
+            TapPoint::Band(index) => format!("band:{}", index),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Smoothing {
+    None,
+    Third,
+    Sixth,
+    Twelfth,
+}
+
+impl Smoothing {
+    // "none" or the octave fraction, "3", "6" or "12" (also written "1/3" ...)
+    pub fn parse(text: &str) -> Result<Self> {
+        let text = text.trim();
+        match text.strip_prefix("1/").unwrap_or(text) {
+            "none" => Ok(Smoothing::None),
+            "3" => Ok(Smoothing::Third),
+            "6" => Ok(Smoothing::Sixth),
+            "12" => Ok(Smoothing::Twelfth),
+            _ => bail!("Expected none, 3, 6 or 12 octave smoothing, got {}", text),
+        }
+    }
+
+    fn fraction(self) -> Option<f32> {
+        match self {
+            Smoothing::None => None,
+            Smoothing::Third => Some(3.0),
+            Smoothing::Sixth => Some(6.0),
+            Smoothing::Twelfth => Some(12.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Averaging {
+    None,
+    // Exponential averaging with the given time constant in seconds
+    Exponential(f32),
+    // Plain mean of the last N spectra
+    Linear(usize),
+    PeakHold,
+}
+
+impl Averaging {
+    // "none", "exp:<seconds>", "linear:<spectra>" or "peak"
+    pub fn parse(text: &str) -> Result<Self> {
+        let text = text.trim();
+        let (kind, value) = text.split_once(':').unwrap_or((text, ""));
+        match (kind, value) {
+            ("none", "") => Ok(Averaging::None),
+            ("peak", "") => Ok(Averaging::PeakHold),
+            ("exp", seconds) => seconds
+                .parse::<f32>()
+                .ok()
+                .filter(|seconds| seconds.is_finite() && *seconds > 0.0)
+                .map(Averaging::Exponential)
+                .ok_or_else(|| anyhow!("Bad averaging time in {}", text)),
+            ("linear", count) => count
+                .parse::<usize>()
+                .ok()
+                .filter(|count| *count > 0)
+                .map(Averaging::Linear)
+                .ok_or_else(|| anyhow!("Bad averaging count in {}", text)),
+            _ => bail!("Expected none, exp:<seconds>, linear:<spectra> or peak, got {}", text),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AnalyzerConfig {
+    pub fft_size: usize,
+    pub smoothing: Smoothing,
+    pub averaging: Averaging,
+    // Spectra published per second for every tap
+    pub update_rate_hz: f32,
+}
+
+impl Default for AnalyzerConfig {
+    fn default() -> Self {
+        AnalyzerConfig {
+            fft_size: 8192,
+            smoothing: Smoothing::Sixth,
+            averaging: Averaging::Exponential(1.0),
+            update_rate_hz: 10.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Spectrum {
+    pub tap: TapPoint,
+    // Bin center frequencies in Hz
+    pub frequencies: Vec<f32>,
+    // Magnitudes in dBFS, a full scale sine reads 0 dB
+    pub magnitudes_db: Vec<f32>,
+}
+
+// Audio thread side of a tap: copies one channel of an interleaved block into a ring buffer
+pub struct AnalyzerTap {
+    point: TapPoint,
+    producer: Producer<f32>,
+}
+
+impl AnalyzerTap {
+    pub fn point(&self) -> TapPoint {
+        self.point
+    }
+
+    // Drops samples when the analyzer falls behind instead of blocking
+    pub fn push(&mut self, data: &[f32], channels: usize, channel: usize) {
+        if channel >= channels {
+            return;
+        }
+        for frame in data.chunks(channels) {
+            if self.producer.push(frame[channel]).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+struct TapAnalysis {
+    point: TapPoint,
+    consumer: Consumer<f32>,
+    buffer: Vec<f32>,
+    averaged: Vec<f32>,
+    history: Vec<Vec<f32>>,
+    frames: usize,
+}
+
+// Precomputed per-bin ranges for fractional-octave smoothing
+fn smoothing_ranges(bins: usize, fft_size: usize, sample_rate: u32, fraction: f32) -> Vec<(usize, usize)> {
+    let bin_hz = sample_rate as f32 / fft_size as f32;
+    let half_width = 2f32.powf(1.0 / (2.0 * fraction));
+    (0..bins)
+        .map(|k| {
+            if k == 0 {
+                return (0, 1);
+            }
+            let f = k as f32 * bin_hz;
+            let lo = ((f / half_width / bin_hz).floor() as usize).max(1).min(k);
+            let hi = (((f * half_width / bin_hz).ceil() as usize) + 1).min(bins).max(k + 1);
+            (lo, hi)
+        })
+        .collect()
+}
+
+// Fan-out of spectra to subscribers plus the tap configuration for the next start
+#[derive(Clone, Default)]
+pub struct AnalyzerHub {
+    subscribers: Arc<Mutex<Vec<Sender<Spectrum>>>>,
+    taps: Arc<Mutex<Vec<TapPoint>>>,
+}
+
+impl AnalyzerHub {
+    pub fn subscribe(&self) -> Receiver<Spectrum> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn add_tap(&self, point: TapPoint) {
+        let mut taps = self.taps.lock().unwrap();
+        if !taps.contains(&point) {
+            taps.push(point);
+        }
+    }
+
+    // Create the audio side taps and spawn the analysis thread
+    pub fn start(
+        &self,
+        config: AnalyzerConfig,
+        sample_rate: u32,
+        running: Arc<AtomicBool>,
+    ) -> (Vec<AnalyzerTap>, Option<JoinHandle<()>>) {
+        let points = self.taps.lock().unwrap().clone();
+        if points.is_empty() {
+            return (Vec::new(), None);
+        }
+
+        let fft_size = config.fft_size.max(64).next_power_of_two();
+        let bins = fft_size / 2 + 1;
+
+        let mut taps = Vec::new();
+        let mut analyses = Vec::new();
+        for point in points {
+            // Room for a couple of FFT frames so short analyzer stalls don't drop audio
+            let (producer, consumer) = RingBuffer::new(fft_size * 4);
+            taps.push(AnalyzerTap { point, producer });
+            analyses.push(TapAnalysis {
+                point,
+                consumer,
+                buffer: vec![0.0; fft_size],
+                averaged: vec![0.0; bins],
+                history: Vec::new(),
+                frames: 0,
+            });
+        }
+
+        let subscribers = self.subscribers.clone();
+        let handle = std::thread::spawn(move || {
+            let fft = FftPlanner::<f32>::new().plan_fft_forward(fft_size);
+            let window: Vec<f32> = (0..fft_size)
+                .map(|n| 0.5 - 0.5 * (2.0 * PI * n as f32 / fft_size as f32).cos())
+                .collect();
+            let window_gain: f32 = window.iter().sum();
+            let frequencies: Vec<f32> = (0..bins)
+                .map(|k| k as f32 * sample_rate as f32 / fft_size as f32)
+                .collect();
+            let ranges = config
+                .smoothing
+                .fraction()
+                .map(|fraction| smoothing_ranges(bins, fft_size, sample_rate, fraction));
+            let period = Duration::from_secs_f32(1.0 / config.update_rate_hz.max(0.1));
+            let exp_coeff = match config.averaging {
+                Averaging::Exponential(tau) => 1.0 - (-period.as_secs_f32() / tau.max(1e-3)).exp(),
+                _ => 1.0,
+            };
+
+            let mut spectrum = vec![Complex::new(0.0, 0.0); fft_size];
+            let mut power = vec![0.0f32; bins];
+            let mut prefix = vec![0.0f32; bins + 1];
+
+            while running.load(Ordering::SeqCst) {
+                for analysis in analyses.iter_mut() {
+                    // Slide the newest samples into the analysis buffer
+                    let available = analysis.consumer.slots();
+                    if available == 0 {
+                        continue;
+                    }
+                    let take = available.min(fft_size);
+                    for _ in 0..available - take {
+                        let _ = analysis.consumer.pop();
+                    }
+                    analysis.buffer.copy_within(take.., 0);
+                    for sample in analysis.buffer[fft_size - take..].iter_mut() {
+                        *sample = analysis.consumer.pop().unwrap_or(0.0);
+                    }
+
+                    for (n, bin) in spectrum.iter_mut().enumerate() {
+                        *bin = Complex::new(analysis.buffer[n] * window[n], 0.0);
+                    }
+                    fft.process(&mut spectrum);
+
+                    let scale = 2.0 / window_gain;
+                    for (k, p) in power.iter_mut().enumerate() {
+                        *p = (spectrum[k].norm() * scale).powi(2);
+                    }
+
+                    if let Some(ranges) = ranges.as_ref() {
+                        for k in 0..bins {
+                            prefix[k + 1] = prefix[k] + power[k];
+                        }
+                        for (k, &(lo, hi)) in ranges.iter().enumerate() {
+                            power[k] = (prefix[hi] - prefix[lo]) / (hi - lo) as f32;
+                        }
+                    }
+
+                    match config.averaging {
+                        Averaging::None => analysis.averaged.copy_from_slice(&power),
+                        Averaging::Exponential(_) => {
+                            for (avg, p) in analysis.averaged.iter_mut().zip(&power) {
+                                *avg = if analysis.frames == 0 { *p } else { *avg + exp_coeff * (p - *avg) };
+                            }
+                        }
+                        Averaging::Linear(count) => {
+                            analysis.history.push(power.clone());
+                            if analysis.history.len() > count.max(1) {
+                                analysis.history.remove(0);
+                            }
+                            let n = analysis.history.len() as f32;
+                            for (k, avg) in analysis.averaged.iter_mut().enumerate() {
+                                *avg = analysis.history.iter().map(|h| h[k]).sum::<f32>() / n;
+                            }
+                        }
+                        Averaging::PeakHold => {
+                            for (avg, p) in analysis.averaged.iter_mut().zip(&power) {
+                                *avg = avg.max(*p);
+                            }
+                        }
+                    }
+                    analysis.frames += 1;
+
+                    let result = Spectrum {
+                        tap: analysis.point,
+                        frequencies: frequencies.clone(),
+                        magnitudes_db: analysis.averaged.iter().map(|p| to_db(p.sqrt())).collect(),
+                    };
+                    subscribers
+                        .lock()
+                        .unwrap()
+                        .retain(|tx| tx.send(result.clone()).is_ok());
+                }
+                std::thread::sleep(period);
+            }
+        });
+
+        (taps, Some(handle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tap_points_by_name() {
+        assert_eq!(TapPoint::parse("input:1").unwrap(), TapPoint::Input(1));
+        assert_eq!(TapPoint::parse(" band:12").unwrap(), TapPoint::Band(12));
+        assert_eq!(TapPoint::parse("band:3").unwrap().name(), "band:3");
+        assert!(TapPoint::parse("output:0").is_err());
+        assert!(TapPoint::parse("input").is_err());
+        assert!(TapPoint::parse("input:-1").is_err());
+    }
+
+    #[test]
+    fn smoothing_and_averaging_by_name() {
+        assert_eq!(Smoothing::parse("1/12").unwrap(), Smoothing::Twelfth);
+        assert_eq!(Smoothing::parse("none").unwrap(), Smoothing::None);
+        assert!(Smoothing::parse("1/24").is_err());
+        assert_eq!(Averaging::parse("exp:0.5").unwrap(), Averaging::Exponential(0.5));
+        assert_eq!(Averaging::parse("linear:8").unwrap(), Averaging::Linear(8));
+        assert_eq!(Averaging::parse("peak").unwrap(), Averaging::PeakHold);
+        assert!(Averaging::parse("linear:0").is_err());
+        assert!(Averaging::parse("none:1").is_err());
+    }
+}
@@ -0,0 +1,759 @@
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::io::{Cursor, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::analyzer::{AnalyzerHub, Spectrum, TapPoint};
+use crate::decoder::{read_metadata, TrackMetadata};
+use crate::library::{Library, LibraryTrack, TrackFilter};
+use crate::meter::{ChannelLevels, MeterHub, MeterSnapshot};
+use crate::player::{Player, PlayerStatus, QueueEntry};
+use crate::standby::{PowerState, StandbyHub};
+use crate::stats::CallbackReport;
+use crate::status::StatusReport;
+
+// How often the server thread checks whether it should stop
+const POLL: Duration = Duration::from_millis(100);
+// Library listings without an explicit limit
+const DEFAULT_LIMIT: usize = 500;
+// Event streams send a comment after this long without events, which also
+// notices clients that went away
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+// Engine status for GET status, called on the API thread
+pub type StatusProvider = Box<dyn Fn() -> StatusReport + Send>;
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name, value).expect("valid header")
+}
+
+// The UI is served from its own port, so every response allows any origin
+fn respond(request: Request, response: HttpResponse) {
+    let response = response.with_header(header("Access-Control-Allow-Origin", "*"));
+    if let Err(e) = request.respond(response) {
+        eprintln!("API response failed: {}", e);
+    }
+}
+
+fn json_response(status: u16, body: Value) -> HttpResponse {
+    Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn error(status: u16, message: &str) -> HttpResponse {
+    json_response(status, json!({ "error": message }))
+}
+
+fn seconds(duration: Option<Duration>) -> Value {
+    duration.map_or(Value::Null, |duration| json!(duration.as_secs_f64()))
+}
+
+fn entry_json(entry: &QueueEntry) -> Value {
+    json!({
+        "id": entry.id,
+        "path": entry.path.display().to_string(),
+        "title": entry.title,
+    })
+}
+
+// Everything but the picture, which has its own URL
+fn metadata_json(metadata: &TrackMetadata, id: u64) -> Value {
+    json!({
+        "title": metadata.title,
+        "artist": metadata.artist,
+        "album": metadata.album,
+        "album_artist": metadata.album_artist,
+        "track_number": metadata.track_number,
+        "track_total": metadata.track_total,
+        "disc_number": metadata.disc_number,
+        "date": metadata.date,
+        "genre": metadata.genre,
+        "duration": seconds(metadata.duration),
+        "cover": metadata.cover.as_ref().map(|_| format!("/api/v1/queue/{}/cover", id)),
+    })
+}
+
+// Tags of the playing entry are already known, anything else is read from its file or URL
+fn entry_metadata(player: &Player, entry: &QueueEntry) -> Result<Arc<TrackMetadata>> {
+    let status = player.status();
+    if status.entry.is_some_and(|playing| playing.id == entry.id)
+        && let Some(metadata) = status.metadata
+    {
+        return Ok(metadata);
+    }
+    read_metadata(&entry.path).map(Arc::new)
+}
+
+fn status_json(status: &PlayerStatus) -> Value {
+    json!({
+        "state": status.state.name(),
+        "entry": status.entry.as_ref().map(entry_json),
+        "metadata": status
+            .entry
+            .as_ref()
+            .zip(status.metadata.as_ref())
+            .map(|(entry, metadata)| metadata_json(metadata, entry.id)),
+        "position": status.position.as_secs_f64(),
+        "duration": seconds(status.duration),
+        "gain_db": status.gain_db,
+        "buffered": status.buffered.as_secs_f64(),
+        "buffering": status.buffering,
+    })
+}
+
+fn callback_json(report: &CallbackReport) -> Value {
+    json!({
+        "callbacks": report.callbacks,
+        "last_ms": report.last_ms,
+        "max_ms": report.max_ms,
+        "period_ms": report.period_ms,
+        "deadline_misses": report.deadline_misses,
+        "load_percent": report.load_percent,
+    })
+}
+
+fn engine_status_json(status: &StatusReport) -> Value {
+    json!({
+        "running": status.running,
+        "source": status.source,
+        "latency": status.latency.as_ref().map(|latency| json!({
+            "input_ms": latency.input_ms,
+            "transport_ms": latency.transport_ms,
+            "resampler_ms": latency.resampler_ms,
+            "output_ms": latency.output_ms,
+            "channels_ms": latency.channels_ms,
+        })),
+        "stats": status.stats.as_ref().map(|stats| json!({
+            "dsp_load_percent": stats.dsp_load_percent,
+            "input": callback_json(&stats.input),
+            "output": callback_json(&stats.output),
+            "underruns": stats.underruns,
+            "overruns": stats.overruns,
+        })),
+        "realtime": status.realtime.as_ref().map(|realtime| json!({
+            "memory_locked": realtime.memory_locked,
+            "threads": realtime
+                .threads
+                .iter()
+                .map(|thread| json!({ "name": thread.name, "scheduling": thread.scheduling, "cpu": thread.cpu }))
+                .collect::<Vec<_>>(),
+        })),
+        "volume_db": status.volume_db,
+        "loudness_shelves_db": status.loudness_shelves_db.map(|(low, high)| json!([low, high])),
+        "power": status.power.map(|power| power.name()),
+    })
+}
+
+// State is null while the engine is stopped or auto standby is off
+fn power_json(state: Option<PowerState>) -> Value {
+    json!({ "state": state.map(|state| state.name()) })
+}
+
+fn levels_json(levels: &[ChannelLevels]) -> Value {
+    Value::Array(
+        levels
+            .iter()
+            .map(|levels| {
+                json!({
+                    "peak_db": levels.peak_db,
+                    "rms_db": levels.rms_db,
+                    "true_peak_db": levels.true_peak_db,
+                    "clips": levels.clips,
+                    "overload": levels.overload,
+                })
+            })
+            .collect(),
+    )
+}
+
+fn meters_json(snapshot: &MeterSnapshot) -> Value {
+    json!({
+        "input": levels_json(&snapshot.input),
+        "output": levels_json(&snapshot.output),
+    })
+}
+
+fn spectrum_json(spectrum: &Spectrum) -> Value {
+    json!({
+        "tap": spectrum.tap.name(),
+        "frequencies": spectrum.frequencies,
+        "magnitudes_db": spectrum.magnitudes_db,
+    })
+}
+
+// One piece of a chunked body, sent right away. An empty one ends the body.
+fn write_chunk(writer: &mut dyn Write, data: &[u8]) -> std::io::Result<()> {
+    write!(writer, "{:x}\r\n", data.len())?;
+    writer.write_all(data)?;
+    writer.write_all(b"\r\n")?;
+    writer.flush()
+}
+
+// Server-sent events on a thread of their own, `first` before the messages.
+// `event` turns a message into the event name and its data, None skips it. Ends
+// when the client goes away or the server stops.
+fn stream_events<T, F>(
+    request: Request,
+    first: Option<T>,
+    messages: Receiver<T>,
+    running: Arc<AtomicBool>,
+    event: F,
+) -> JoinHandle<()>
+where
+    T: Send + 'static,
+    F: Fn(&T) -> Option<(&'static str, Value)> + Send + 'static,
+{
+    std::thread::spawn(move || {
+        // Written by hand: tiny_http's chunked responses hold back data until 8 KiB are buffered
+        let mut writer = request.into_writer();
+        let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+                    Access-Control-Allow-Origin: *\r\nTransfer-Encoding: chunked\r\n\r\n";
+        if writer.write_all(head.as_bytes()).and_then(|_| writer.flush()).is_err() {
+            return;
+        }
+        let mut quiet = Duration::ZERO;
+        let mut first = first;
+        while running.load(Ordering::SeqCst) {
+            let next = match first.take() {
+                Some(message) => Ok(message),
+                None => messages.recv_timeout(POLL),
+            };
+            let text = match next {
+                Ok(message) => match event(&message) {
+                    Some((name, data)) => format!("event: {}\ndata: {}\n\n", name, data),
+                    None => continue,
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    quiet += POLL;
+                    if quiet < KEEPALIVE {
+                        continue;
+                    }
+                    ": keepalive\n\n".to_string()
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            quiet = Duration::ZERO;
+            if write_chunk(&mut writer, text.as_bytes()).is_err() {
+                return;
+            }
+        }
+        let _ = write_chunk(&mut writer, b"");
+    })
+}
+
+// %XX escapes and + for space
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn query_param(query: &str, key: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| percent_decode(v))
+}
+
+fn library_track_json(track: &LibraryTrack) -> Value {
+    json!({
+        "id": track.id,
+        "path": track.path.display().to_string(),
+        "title": track.title,
+        "artist": track.artist,
+        "album": track.album,
+        "album_artist": track.album_artist,
+        "genre": track.genre,
+        "track_number": track.track_number,
+        "disc_number": track.disc_number,
+        "date": track.date,
+        "duration": seconds(track.duration),
+        "cover": track.has_cover.then(|| format!("/api/v1/library/tracks/{}/cover", track.id)),
+    })
+}
+
+// HTTP control API for the web UI, under /api/v1:
+//   GET  status                          source, latency, DSP load and xruns, realtime scheduling, volume and power
+//   GET  power                           auto standby state, active or standby
+//   GET  power/events                    server-sent events on every transition
+//   GET  player                          transport state, position and what is playing
+//   GET  player/events                   server-sent events with the player status on every change
+//   POST player/{play,pause,stop,next,previous}
+//   POST player/seek?position=<s>|by=<s>
+//   GET  queue                           queue entries in list order
+//   POST queue?path=<file or URL>[&at=<index>]  append, or insert at a position
+//   POST queue/<id>/move?to=<index>
+//   DELETE queue/<id>
+//   GET  queue/<id>/metadata             tags of an entry
+//   GET  queue/<id>/cover                embedded cover art of an entry
+//   GET  analyzer/spectrum[?tap=input:<channel>|band:<index>]
+//                                        server-sent events with the spectra of the analyzer taps
+//   GET  meters                          peak, RMS and true-peak levels, clip counts of every channel
+//   GET  meters/events                   server-sent events with the levels at the meter rate
+//   POST meters/reset                    clear clip counts and overload flags
+//   GET  library/artists|genres
+//   GET  library/albums?artist=<name>
+//   GET  library/tracks?artist=&album=&genre=&limit=
+//   GET  library/search?q=<words>&limit=  full-text search
+//   GET  library/tracks/<id>[/cover]
+//   POST library/tracks/<id>/enqueue     append to the play queue
+//   POST library/rescan
+pub struct ApiServer {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ApiServer {
+    // The library endpoints answer 404 without a library
+    pub fn start(
+        address: &str,
+        player: Player,
+        library: Option<Library>,
+        status: StatusProvider,
+        analyzer: AnalyzerHub,
+        power: StandbyHub,
+        meters: MeterHub,
+    ) -> Result<Self> {
+        let server = Server::http(address).map_err(|e| anyhow!("{}: {}", address, e))?;
+        let running = Arc::new(AtomicBool::new(true));
+        let running_flag = running.clone();
+        let thread = std::thread::spawn(move || {
+            let mut api = Api {
+                player,
+                library,
+                status,
+                analyzer,
+                power,
+                meters,
+                running: running_flag.clone(),
+                streams: Vec::new(),
+            };
+            while running_flag.load(Ordering::SeqCst) {
+                match server.recv_timeout(POLL) {
+                    Ok(Some(request)) => api.handle(request),
+                    Ok(None) => {}
+                    Err(e) => {
+                        eprintln!("API server stopped: {}", e);
+                        break;
+                    }
+                }
+            }
+            // They see the flag within POLL
+            running_flag.store(false, Ordering::SeqCst);
+            for stream in api.streams {
+                let _ = stream.join();
+            }
+        });
+        println!("Control API on http://{}/api/v1", address);
+        Ok(ApiServer {
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn stop(mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Api {
+    player: Player,
+    library: Option<Library>,
+    status: StatusProvider,
+    analyzer: AnalyzerHub,
+    power: StandbyHub,
+    meters: MeterHub,
+    running: Arc<AtomicBool>,
+    // Threads of the open event streams
+    streams: Vec<JoinHandle<()>>,
+}
+
+impl Api {
+    fn handle(&mut self, request: Request) {
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let Some(route) = path.strip_prefix("/api/v1/") else {
+            return respond(request, error(404, "Not found"));
+        };
+        let segments: Vec<&str> = route.trim_end_matches('/').split('/').collect();
+        let method = request.method().clone();
+        // Event streams keep the connection, so they take the request
+        match (&method, segments.as_slice()) {
+            (Method::Get, ["analyzer", "spectrum"]) => self.spectrum_stream(request, query),
+            (Method::Get, ["player", "events"]) => self.player_events(request),
+            (Method::Get, ["power", "events"]) => self.power_events(request),
+            (Method::Get, ["meters", "events"]) => self.meter_events(request),
+            (Method::Get, ["queue", id, item @ ("metadata" | "cover")]) => self.entry_tags(request, id, item),
+            _ => {
+                let response = self.route(&method, &segments, query);
+                respond(request, response);
+            }
+        }
+    }
+
+    fn spectrum_stream(&mut self, request: Request, query: &str) {
+        let tap = match query_param(query, "tap").map(|tap| TapPoint::parse(&tap)).transpose() {
+            Ok(tap) => tap,
+            Err(e) => return respond(request, error(400, &e.to_string())),
+        };
+        let spectra = self.analyzer.subscribe();
+        let stream = stream_events(request, None, spectra, self.running.clone(), move |spectrum: &Spectrum| {
+            tap.is_none_or(|tap| tap == spectrum.tap).then(|| ("spectrum", spectrum_json(spectrum)))
+        });
+        self.add_stream(stream);
+    }
+
+    // The current status, then every change and the position while playing
+    fn player_events(&mut self, request: Request) {
+        let events = self.player.subscribe();
+        let status = self.player.status();
+        let stream = stream_events(request, Some(status), events, self.running.clone(), |status: &PlayerStatus| {
+            Some(("status", status_json(status)))
+        });
+        self.add_stream(stream);
+    }
+
+    // The current state, then every transition between active and standby
+    fn power_events(&mut self, request: Request) {
+        let events = self.power.subscribe();
+        let state = self.power.state();
+        let stream = stream_events(request, state, events, self.running.clone(), |state: &PowerState| {
+            Some(("power", power_json(Some(*state))))
+        });
+        self.add_stream(stream);
+    }
+
+    // Reading the tags can mean opening a remote stream, so it answers from a thread of its own.
+    // Those are not joined at stop, a slow server is left to time out.
+    fn entry_tags(&self, request: Request, id: &str, item: &str) {
+        let Some(entry) = id.parse().ok().and_then(|id| self.entry(id)) else {
+            return respond(request, error(404, "No such queue entry"));
+        };
+        let player = self.player.clone();
+        let cover = item == "cover";
+        std::thread::spawn(move || {
+            let response = match entry_metadata(&player, &entry) {
+                Err(e) => error(500, &e.to_string()),
+                Ok(metadata) if !cover => json_response(200, metadata_json(&metadata, entry.id)),
+                Ok(metadata) => match &metadata.cover {
+                    Some(cover) => {
+                        Response::from_data(cover.data.clone()).with_header(header("Content-Type", &cover.media_type))
+                    }
+                    None => error(404, "No cover art"),
+                },
+            };
+            respond(request, response);
+        });
+    }
+
+    // Levels of every input and output channel at the meter rate while the engine runs
+    fn meter_events(&mut self, request: Request) {
+        let snapshots = self.meters.subscribe();
+        let stream = stream_events(request, None, snapshots, self.running.clone(), |snapshot: &MeterSnapshot| {
+            Some(("meters", meters_json(snapshot)))
+        });
+        self.add_stream(stream);
+    }
+
+    fn add_stream(&mut self, stream: JoinHandle<()>) {
+        self.streams.retain(|stream| !stream.is_finished());
+        self.streams.push(stream);
+    }
+
+    fn route(&self, method: &Method, segments: &[&str], query: &str) -> HttpResponse {
+        match (method, segments) {
+            (Method::Get, ["status"]) => json_response(200, engine_status_json(&(self.status)())),
+            (Method::Get, ["power"]) => json_response(200, power_json(self.power.state())),
+            (Method::Get, ["player"]) => self.player_status(),
+            (Method::Post, ["player", "seek"]) => self.seek(query),
+            (Method::Post, ["player", command]) => self.transport(command),
+            (Method::Get, ["queue"]) => {
+                json_response(200, Value::Array(self.player.queue().iter().map(entry_json).collect()))
+            }
+            (Method::Post, ["queue"]) => self.enqueue(query),
+            (Method::Post, ["queue", id, "move"]) => {
+                let to = query_param(query, "to").and_then(|to| to.parse().ok());
+                match (id.parse().ok(), to) {
+                    (Some(id), Some(to)) if self.player.move_entry(id, to) => json_response(200, json!({ "id": id })),
+                    (_, None) => error(400, "Expected to=<index>"),
+                    _ => error(404, "No such queue entry"),
+                }
+            }
+            (Method::Delete, ["queue", id]) => match id.parse().ok().and_then(|id| self.entry(id)) {
+                Some(entry) => {
+                    self.player.remove(entry.id);
+                    json_response(200, json!({ "id": entry.id }))
+                }
+                None => error(404, "No such queue entry"),
+            },
+            (Method::Get, ["meters"]) => match self.meters.snapshot() {
+                Some(snapshot) => json_response(200, meters_json(&snapshot)),
+                None => error(409, "The engine is not running"),
+            },
+            (Method::Post, ["meters", "reset"]) => {
+                self.meters.reset_clips();
+                json_response(200, json!({}))
+            }
+            (_, ["library", ..]) => match &self.library {
+                Some(library) => self.library_route(library, method, &segments[1..], query),
+                None => error(404, "No library configured"),
+            },
+            _ => error(404, "Not found"),
+        }
+    }
+
+    fn library_route(&self, library: &Library, method: &Method, segments: &[&str], query: &str) -> HttpResponse {
+        let limit = query_param(query, "limit")
+            .and_then(|limit| limit.parse().ok())
+            .unwrap_or(DEFAULT_LIMIT);
+        let result = match (method, segments) {
+            (Method::Get, ["artists"]) => library.artists().map(|artists| {
+                artists
+                    .iter()
+                    .map(|artist| json!({ "name": artist.name, "albums": artist.albums, "tracks": artist.tracks }))
+                    .collect()
+            }),
+            (Method::Get, ["albums"]) => library.albums(query_param(query, "artist").as_deref()).map(|albums| {
+                albums
+                    .iter()
+                    .map(|album| {
+                        json!({
+                            "title": album.title,
+                            "artist": album.artist,
+                            "date": album.date,
+                            "tracks": album.tracks,
+                            "cover": album.cover_track.map(|id| format!("/api/v1/library/tracks/{}/cover", id)),
+                        })
+                    })
+                    .collect()
+            }),
+            (Method::Get, ["genres"]) => library.genres().map(|genres| {
+                genres
+                    .iter()
+                    .map(|genre| json!({ "name": genre.name, "tracks": genre.tracks }))
+                    .collect()
+            }),
+            (Method::Get, ["tracks"]) => {
+                let filter = TrackFilter {
+                    artist: query_param(query, "artist"),
+                    album: query_param(query, "album"),
+                    genre: query_param(query, "genre"),
+                };
+                library
+                    .tracks(&filter, limit)
+                    .map(|tracks| tracks.iter().map(library_track_json).collect())
+            }
+            (Method::Get, ["search"]) => library
+                .search(&query_param(query, "q").unwrap_or_default(), limit)
+                .map(|tracks| tracks.iter().map(library_track_json).collect()),
+            (Method::Post, ["rescan"]) => {
+                library.rescan_in_background();
+                return json_response(202, json!({ "scanning": true }));
+            }
+            (_, ["tracks", id, rest @ ..]) => {
+                let track = match id.parse().map_err(anyhow::Error::from).and_then(|id| library.track(id)) {
+                    Ok(Some(track)) => track,
+                    Ok(None) | Err(_) => return error(404, "No such track"),
+                };
+                return match (method, rest) {
+                    (Method::Get, []) => json_response(200, library_track_json(&track)),
+                    (Method::Get, ["cover"]) => match read_metadata(&track.path).map(|metadata| metadata.cover) {
+                        Ok(Some(cover)) => {
+                            Response::from_data(cover.data).with_header(header("Content-Type", &cover.media_type))
+                        }
+                        Ok(None) => error(404, "No cover art"),
+                        Err(e) => error(500, &e.to_string()),
+                    },
+                    (Method::Post, ["enqueue"]) => {
+                        let id = self.player.enqueue(&track.path);
+                        json_response(200, json!({ "id": id }))
+                    }
+                    _ => error(404, "Not found"),
+                };
+            }
+            _ => return error(404, "Not found"),
+        };
+        match result {
+            Ok(items) => json_response(200, Value::Array(items)),
+            Err(e) => error(500, &e.to_string()),
+        }
+    }
+
+    fn entry(&self, id: u64) -> Option<QueueEntry> {
+        self.player.queue().into_iter().find(|entry| entry.id == id)
+    }
+
+    // The playing entry's tags were read when it opened, others are read from the file
+    fn player_status(&self) -> HttpResponse {
+        json_response(200, status_json(&self.player.status()))
+    }
+
+    fn transport(&self, command: &str) -> HttpResponse {
+        match command {
+            "play" => self.player.play(),
+            "pause" => self.player.pause(),
+            "stop" => self.player.stop(),
+            "next" => self.player.next(),
+            "previous" => self.player.previous(),
+            _ => return error(404, "Unknown command"),
+        }
+        self.player_status()
+    }
+
+    // A file or URL, appended or inserted before the entry at index `at`
+    fn enqueue(&self, query: &str) -> HttpResponse {
+        let Some(path) = query_param(query, "path").filter(|path| !path.is_empty()) else {
+            return error(400, "Expected path=<file or URL>");
+        };
+        let id = match query_param(query, "at").map(|at| at.parse::<usize>()) {
+            None => self.player.enqueue(Path::new(&path)),
+            Some(Ok(at)) => self.player.insert(at, Path::new(&path)),
+            Some(Err(_)) => return error(400, "Expected at=<index>"),
+        };
+        json_response(200, json!({ "id": id }))
+    }
+
+    fn seek(&self, query: &str) -> HttpResponse {
+        let number = |key| query_param(query, key).and_then(|v| v.parse::<f64>().ok()).filter(|v| v.is_finite());
+        let moved = if let Some(position) = number("position") {
+            self.player.seek(Duration::from_secs_f64(position.max(0.0)))
+        } else if let Some(by) = number("by") {
+            self.player.seek_by(by)
+        } else {
+            return error(400, "Expected position or by");
+        };
+        if !moved {
+            return error(409, "Nothing is playing");
+        }
+        self.player_status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc;
+
+    fn line(reader: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line.trim_end().to_string()
+    }
+
+    // Data of the next chunk of a chunked body
+    fn chunk(reader: &mut BufReader<TcpStream>) -> String {
+        let size = usize::from_str_radix(&line(reader), 16).unwrap();
+        let mut data = vec![0; size + 2];
+        reader.read_exact(&mut data).unwrap();
+        data.truncate(size);
+        String::from_utf8(data).unwrap()
+    }
+
+    #[test]
+    fn decodes_query_values() {
+        assert_eq!(percent_decode("Artist+-+Title%21"), "Artist - Title!");
+        assert_eq!(percent_decode("/music/%C3%A9t%C3%A9.flac"), "/music/été.flac");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(query_param("path=%2Fa%20b&at=3", "path").as_deref(), Some("/a b"));
+    }
+
+    #[test]
+    fn events_are_streamed_until_the_server_stops() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /api/v1/events HTTP/1.1\r\nHost: test\r\n\r\n").unwrap();
+        let request = server.recv().unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let running = Arc::new(AtomicBool::new(true));
+        let stream = stream_events(request, Some(0), rx, running.clone(), |n: &u32| {
+            n.is_multiple_of(2).then(|| ("number", json!({ "n": n })))
+        });
+        for n in 1..=4 {
+            tx.send(n).unwrap();
+        }
+
+        let mut reader = BufReader::new(client);
+        assert_eq!(line(&mut reader), "HTTP/1.1 200 OK");
+        let head: Vec<String> =
+            std::iter::from_fn(|| Some(line(&mut reader)).filter(|line| !line.is_empty())).collect();
+        assert!(head.iter().any(|line| line == "Content-Type: text/event-stream"));
+        assert!(head.iter().any(|line| line == "Transfer-Encoding: chunked"));
+        // Odd numbers are skipped
+        assert_eq!(chunk(&mut reader), "event: number\ndata: {\"n\":0}\n\n");
+        assert_eq!(chunk(&mut reader), "event: number\ndata: {\"n\":2}\n\n");
+        assert_eq!(chunk(&mut reader), "event: number\ndata: {\"n\":4}\n\n");
+        running.store(false, Ordering::SeqCst);
+        stream.join().unwrap();
+        assert_eq!(chunk(&mut reader), "");
+    }
+
+    #[test]
+    fn tags_of_a_stalled_url_do_not_hold_up_other_requests() {
+        // Accepts connections but never answers
+        let stalled = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/stream.mp3", stalled.local_addr().unwrap());
+        let player = Player::default();
+        let id = player.enqueue(Path::new(&url));
+        let mut api = Api {
+            player,
+            library: None,
+            status: Box::new(|| -> StatusReport { unreachable!() }),
+            analyzer: AnalyzerHub::default(),
+            power: StandbyHub::default(),
+            meters: MeterHub::default(),
+            running: Arc::new(AtomicBool::new(true)),
+            streams: Vec::new(),
+        };
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+
+        let mut tags = TcpStream::connect(address).unwrap();
+        write!(tags, "GET /api/v1/queue/{}/metadata HTTP/1.1\r\nHost: test\r\n\r\n", id).unwrap();
+        let started = std::time::Instant::now();
+        api.handle(server.recv().unwrap());
+        let mut queue = TcpStream::connect(address).unwrap();
+        queue.write_all(b"GET /api/v1/queue HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n").unwrap();
+        api.handle(server.recv().unwrap());
+        // Well within the read timeout of the stalled connection
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let mut body = String::new();
+        BufReader::new(queue).read_to_string(&mut body).unwrap();
+        assert!(body.starts_with("HTTP/1.1 200 OK"));
+        assert!(body.contains(&url));
+    }
+}
@@ -0,0 +1,311 @@
+use anyhow::{anyhow, Context, Result};
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+
+// Zero crossings on each side of the interpolation kernel used when resampling
+const RESAMPLE_HALF_WIDTH: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    // Use the file as is
+    None,
+    // Largest tap becomes 1.0
+    Peak,
+    // Gain at DC becomes 0 dB
+    DcGain,
+    // Highest point of the magnitude response becomes 0 dB, so the filter never boosts
+    MaxGain,
+}
+
+impl Normalization {
+    // "none", "peak", "dc" or "max", as in AUDIOSERVER_CORRECTION_NORMALIZATION
+    pub fn parse(text: &str) -> Result<Self> {
+        match text.trim() {
+            "none" => Ok(Normalization::None),
+            "peak" => Ok(Normalization::Peak),
+            "dc" => Ok(Normalization::DcGain),
+            "max" => Ok(Normalization::MaxGain),
+            other => Err(anyhow!("Unknown normalization {}, expected none, peak, dc or max", other)),
+        }
+    }
+}
+
+// Room correction impulse responses loaded from a WAV file (REW, DRC, rePhase ...)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Correction {
+    pub path: PathBuf,
+    pub sample_rate: u32,
+    // One kernel per file channel
+    pub kernels: Vec<Vec<f32>>,
+    pub normalization: Normalization,
+    // File channel used for each pipeline channel, None passes the channel through.
+    // Channels past the end use file channel `c % file_channels`.
+    pub assignment: Vec<Option<usize>>,
+}
+
+impl Correction {
+    // Mono files apply to every channel, multichannel files map channel to channel
+    pub fn from_wav(path: &Path) -> Result<Self> {
+        let mut reader = hound::WavReader::open(path)
+            .with_context(|| format!("Failed to open FIR file {}", path.display()))?;
+        let spec = reader.spec();
+        let channels = spec.channels as usize;
+        if channels == 0 {
+            return Err(anyhow!("{} has no channels", path.display()));
+        }
+
+        let samples: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => {
+                if spec.bits_per_sample != 32 {
+                    return Err(anyhow!("Unsupported float WAV with {} bits", spec.bits_per_sample));
+                }
+                reader.samples::<f32>().collect::<Result<_, _>>()?
+            }
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|sample| sample.map(|s| s as f32 * scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+
+        let mut kernels = vec![Vec::with_capacity(samples.len() / channels); channels];
+        for frame in samples.chunks_exact(channels) {
+            for (kernel, &sample) in kernels.iter_mut().zip(frame) {
+                kernel.push(sample);
+            }
+        }
+        if kernels[0].is_empty() {
+            return Err(anyhow!("{} contains no samples", path.display()));
+        }
+
+        Ok(Correction {
+            path: path.to_path_buf(),
+            sample_rate: spec.sample_rate,
+            kernels,
+            normalization: Normalization::None,
+            assignment: Vec::new(),
+        })
+    }
+
+    pub fn with_normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    // Use `file_channel` of the file for pipeline channel `channel`, None to bypass it
+    pub fn assign(mut self, channel: usize, file_channel: Option<usize>) -> Self {
+        if self.assignment.len() <= channel {
+            let file_channels = self.kernels.len();
+            let defaults = (self.assignment.len()..=channel).map(|c| Some(c % file_channels));
+            self.assignment.extend(defaults);
+        }
+        self.assignment[channel] = file_channel;
+        self
+    }
+
+    pub fn file_channels(&self) -> usize {
+        self.kernels.len()
+    }
+
+    // One file channel or "-" (bypass) per pipeline channel, e.g. "1,0" swaps a stereo file,
+    // as in AUDIOSERVER_CORRECTION_CHANNELS
+    pub fn assign_channels(mut self, text: &str) -> Result<Self> {
+        for (channel, item) in text.split(',').map(str::trim).enumerate() {
+            let file_channel = match item {
+                "-" => None,
+                _ => {
+                    let file_channel = item.parse().map_err(|_| anyhow!("Bad correction channel {}", item))?;
+                    if file_channel >= self.file_channels() {
+                        return Err(anyhow!("{} has no channel {}", self.path.display(), file_channel));
+                    }
+                    Some(file_channel)
+                }
+            };
+            self = self.assign(channel, file_channel);
+        }
+        Ok(self)
+    }
+
+    fn file_channel_for(&self, channel: usize) -> Option<usize> {
+        match self.assignment.get(channel) {
+            Some(assigned) => *assigned,
+            None => Some(channel % self.kernels.len()),
+        }
+    }
+
+    // Kernels for every pipeline channel at the pipeline rate, normalized together
+    // so the balance between channels is kept
+    pub fn kernels_for(&self, channels: usize, sample_rate: u32) -> Result<Vec<Option<Vec<f32>>>> {
+        let mut resampled = Vec::with_capacity(self.kernels.len());
+        for kernel in &self.kernels {
+            resampled.push(resample(kernel, self.sample_rate, sample_rate));
+        }
+
+        let gain = match self.normalization {
+            Normalization::None => 1.0,
+            Normalization::Peak => resampled
+                .iter()
+                .flatten()
+                .fold(0.0f32, |max, x| max.max(x.abs())),
+            Normalization::DcGain => resampled
+                .iter()
+                .map(|kernel| kernel.iter().sum::<f32>().abs())
+                .fold(0.0f32, f32::max),
+            Normalization::MaxGain => resampled.iter().map(|kernel| max_magnitude(kernel)).fold(0.0f32, f32::max),
+        };
+        if gain <= 0.0 || !gain.is_finite() {
+            return Err(anyhow!("Cannot normalize {}, the filter is silent", self.path.display()));
+        }
+        for kernel in resampled.iter_mut() {
+            for tap in kernel.iter_mut() {
+                *tap /= gain;
+            }
+        }
+
+        (0..channels)
+            .map(|channel| match self.file_channel_for(channel) {
+                None => Ok(None),
+                Some(file_channel) => resampled
+                    .get(file_channel)
+                    .cloned()
+                    .map(Some)
+                    .ok_or_else(|| anyhow!("{} has no channel {}", self.path.display(), file_channel)),
+            })
+            .collect()
+    }
+}
+
+// Delay of a correction kernel, taken as the position of its main peak
+pub fn kernel_delay(kernel: &[f32]) -> f32 {
+    kernel
+        .iter()
+        .enumerate()
+        .fold((0, 0.0f32), |(best, max), (i, x)| if x.abs() > max { (i, x.abs()) } else { (best, max) })
+        .0 as f32
+}
+
+fn max_magnitude(kernel: &[f32]) -> f32 {
+    let size = kernel.len().next_power_of_two().max(8192);
+    let mut spectrum: Vec<Complex<f32>> = kernel.iter().map(|&x| Complex::new(x, 0.0)).collect();
+    spectrum.resize(size, Complex::new(0.0, 0.0));
+    FftPlanner::new().plan_fft_forward(size).process(&mut spectrum);
+    spectrum[..size / 2 + 1].iter().fold(0.0f32, |max, bin| max.max(bin.norm()))
+}
+
+// Band-limited (windowed sinc) resampling of an impulse response. Taps are
+// scaled by the rate ratio so the frequency response keeps its level.
+pub fn resample(kernel: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || kernel.is_empty() {
+        return kernel.to_vec();
+    }
+    let ratio = to_rate as f64 / from_rate as f64;
+    // Below 1 when downsampling, the kernel then also low-passes at the new Nyquist
+    let cutoff = ratio.min(1.0);
+    let half_width = RESAMPLE_HALF_WIDTH as f64 / cutoff;
+    let length = ((kernel.len() as f64) * ratio).ceil() as usize;
+
+    (0..length)
+        .map(|n| {
+            let position = n as f64 / ratio;
+            let first = (position - half_width).ceil().max(0.0) as usize;
+            let last = ((position + half_width).floor() as usize).min(kernel.len() - 1);
+            let mut sum = 0.0f64;
+            for (k, &tap) in kernel.iter().enumerate().take(last + 1).skip(first) {
+                let x = position - k as f64;
+                let window = 0.5 + 0.5 * (PI * x / half_width).cos();
+                sum += tap as f64 * sinc(cutoff * x) * window;
+            }
+            (sum * cutoff / ratio) as f32
+        })
+        .collect()
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-12 { 1.0 } else { (PI * x).sin() / (PI * x) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stereo() -> Correction {
+        Correction {
+            path: PathBuf::from("room.wav"),
+            sample_rate: 48000,
+            kernels: vec![vec![1.0, 0.5], vec![0.25]],
+            normalization: Normalization::None,
+            assignment: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn channels_are_assigned_from_text() {
+        let correction = stereo().assign_channels("1, -, 0").unwrap();
+        let kernels = correction.kernels_for(4, 48000).unwrap();
+        assert_eq!(kernels, vec![Some(vec![0.25]), None, Some(vec![1.0, 0.5]), Some(vec![0.25])]);
+        assert!(stereo().assign_channels("2").is_err());
+        assert!(stereo().assign_channels("left").is_err());
+        assert_eq!(Normalization::parse("dc").unwrap(), Normalization::DcGain);
+        assert!(Normalization::parse("loud").is_err());
+    }
+
+    #[test]
+    fn resampled_impulse_keeps_its_gain_and_moves_its_peak() {
+        let mut impulse = vec![0.0; 400];
+        impulse[100] = 1.0;
+        let resampled = resample(&impulse, 44100, 48000);
+        assert_eq!(resampled.len(), 436);
+        let dc: f32 = resampled.iter().sum();
+        assert!((dc - 1.0).abs() < 0.01, "DC gain {}", dc);
+        // 100 samples at 44.1 kHz are 108.8 at 48 kHz
+        assert_eq!(kernel_delay(&resampled), 109.0);
+
+        let back = resample(&resampled, 48000, 44100);
+        let dc: f32 = back.iter().sum();
+        assert!((dc - 1.0).abs() < 0.01, "DC gain {}", dc);
+        assert_eq!(kernel_delay(&back), 100.0);
+    }
+
+    #[test]
+    fn every_sample_format_loads_the_same_kernel() {
+        let taps = [0.5f32, -0.25, 0.125, -0.0625, 0.0];
+        let path = std::env::temp_dir().join(format!("audioserver-correction-{}.wav", std::process::id()));
+        let formats = [
+            (hound::SampleFormat::Int, 16),
+            (hound::SampleFormat::Int, 24),
+            (hound::SampleFormat::Int, 32),
+            (hound::SampleFormat::Float, 32),
+        ];
+        for (sample_format, bits_per_sample) in formats {
+            let full_scale = (1u64 << (bits_per_sample - 1)) as f64;
+            let spec = hound::WavSpec {
+                channels: 2,
+                sample_rate: 44100,
+                bits_per_sample,
+                sample_format,
+            };
+            let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+            for &tap in &taps {
+                // The right channel is the left one inverted
+                for sample in [tap, -tap] {
+                    match sample_format {
+                        hound::SampleFormat::Float => writer.write_sample(sample).unwrap(),
+                        hound::SampleFormat::Int => writer.write_sample((sample as f64 * full_scale) as i32).unwrap(),
+                    }
+                }
+            }
+            writer.finalize().unwrap();
+
+            let correction = Correction::from_wav(&path).unwrap();
+            assert_eq!(correction.sample_rate, 44100);
+            let inverted: Vec<f32> = taps.iter().map(|tap| -tap).collect();
+            let label = format!("{} bit {:?}", bits_per_sample, sample_format);
+            assert_eq!(correction.kernels, vec![taps.to_vec(), inverted], "{}", label);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+}
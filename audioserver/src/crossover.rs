@@ -0,0 +1,457 @@
+use anyhow::{anyhow, Result};
+use biquad::{Coefficients, ToHertz, Type};
+use std::f32::consts::PI;
+
+use crate::dsp::biquad::{BiquadBank, BiquadCoefficients};
+use crate::dsp::delay::DelayLine;
+use crate::dsp::fir::FirFilter;
+use crate::filter::AudioFilter;
+use crate::phase;
+use crate::protection::{DynamicBass, DynamicBassProcessor, ExcursionLimiter, ExcursionLimiterProcessor};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PassKind {
+    LowPass,
+    HighPass,
+}
+
+// Filters a band can be built from, designed for the actual rate when the engine starts
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterSpec {
+    // Even orders only: 2, 4, 8 ...
+    LinkwitzRiley { kind: PassKind, frequency: f32, order: u32 },
+    Butterworth { kind: PassKind, frequency: f32, order: u32 },
+    FirstOrder { kind: PassKind, frequency: f32 },
+    SecondOrder { kind: PassKind, frequency: f32, q: f32 },
+    BandPass { frequency: f32, q: f32 },
+    Notch { frequency: f32, q: f32 },
+    Peaking { frequency: f32, q: f32, gain_db: f32 },
+    LowShelf { frequency: f32, q: f32, gain_db: f32 },
+    HighShelf { frequency: f32, q: f32, gain_db: f32 },
+    // Flat magnitude, phase turns 180 degrees through `frequency`
+    AllPassFirstOrder { frequency: f32 },
+    // Flat magnitude, phase turns 360 degrees through `frequency`, faster with higher Q
+    AllPass { frequency: f32, q: f32 },
+}
+
+impl FilterSpec {
+    pub fn sections(&self, sample_rate: u32) -> Result<Vec<BiquadCoefficients>> {
+        match *self {
+            FilterSpec::LinkwitzRiley { kind, frequency, order } => {
+                if order == 0 || !order.is_multiple_of(2) {
+                    return Err(anyhow!("Linkwitz-Riley order must be even, got {}", order));
+                }
+                // LR of order 2n is two cascaded Butterworth filters of order n
+                let mut sections = butterworth(kind, frequency, order / 2, sample_rate)?;
+                sections.extend(butterworth(kind, frequency, order / 2, sample_rate)?);
+                Ok(sections)
+            }
+            FilterSpec::Butterworth { kind, frequency, order } => butterworth(kind, frequency, order, sample_rate),
+            FilterSpec::FirstOrder { kind, frequency } => Ok(vec![first_order(kind, frequency, sample_rate)?]),
+            FilterSpec::SecondOrder { kind, frequency, q } => {
+                let kind = match kind {
+                    PassKind::LowPass => Type::LowPass,
+                    PassKind::HighPass => Type::HighPass,
+                };
+                Ok(vec![second_order(kind, frequency, q, sample_rate)?])
+            }
+            FilterSpec::BandPass { frequency, q } => Ok(vec![second_order(Type::BandPass, frequency, q, sample_rate)?]),
+            FilterSpec::Notch { frequency, q } => Ok(vec![second_order(Type::Notch, frequency, q, sample_rate)?]),
+            FilterSpec::Peaking { frequency, q, gain_db } => {
+                Ok(vec![second_order(Type::PeakingEQ(gain_db), frequency, q, sample_rate)?])
+            }
+            FilterSpec::LowShelf { frequency, q, gain_db } => {
+                Ok(vec![second_order(Type::LowShelf(gain_db), frequency, q, sample_rate)?])
+            }
+            FilterSpec::HighShelf { frequency, q, gain_db } => {
+                Ok(vec![second_order(Type::HighShelf(gain_db), frequency, q, sample_rate)?])
+            }
+            FilterSpec::AllPassFirstOrder { frequency } => Ok(vec![first_order_all_pass(frequency, sample_rate)?]),
+            FilterSpec::AllPass { frequency, q } => Ok(vec![second_order(Type::AllPass, frequency, q, sample_rate)?]),
+        }
+    }
+}
+
+// Q of a shelf with the given slope in dB per octave (12 dB/oct is the steepest, Q 0.707 at any gain)
+pub fn shelf_q(gain_db: f32, slope_db: f32) -> f32 {
+    let a = 10f32.powf(gain_db / 40.0);
+    let s = (slope_db / 12.0).clamp(1e-3, 1.0);
+    1.0 / ((a + 1.0 / a) * (1.0 / s - 1.0) + 2.0).sqrt()
+}
+
+// Q of a peak with the given bandwidth in octaves
+pub fn bandwidth_q(octaves: f32) -> f32 {
+    let n = 2f32.powf(octaves);
+    n.sqrt() / (n - 1.0)
+}
+
+pub(crate) fn second_order(kind: Type<f32>, frequency: f32, q: f32, sample_rate: u32) -> Result<BiquadCoefficients> {
+    Coefficients::<f32>::from_params(kind, (sample_rate as f32).hz(), frequency.hz(), q)
+        .map(BiquadCoefficients::from)
+        .map_err(|e| anyhow!("Invalid filter at {} Hz, Q {}: {:?}", frequency, q, e))
+}
+
+// Bilinear transform of a first order low or high pass
+fn first_order(kind: PassKind, frequency: f32, sample_rate: u32) -> Result<BiquadCoefficients> {
+    if frequency <= 0.0 || frequency >= sample_rate as f32 / 2.0 {
+        return Err(anyhow!("Invalid filter frequency {} Hz", frequency));
+    }
+    let k = (PI * frequency / sample_rate as f32).tan();
+    let norm = 1.0 / (1.0 + k);
+    let (b0, b1) = match kind {
+        PassKind::LowPass => (k * norm, k * norm),
+        PassKind::HighPass => (norm, -norm),
+    };
+    Ok(BiquadCoefficients {
+        b0,
+        b1,
+        b2: 0.0,
+        a1: (k - 1.0) * norm,
+        a2: 0.0,
+    })
+}
+
+// Bilinear transform of a first order all-pass: (c + z^-1) / (1 + c z^-1)
+fn first_order_all_pass(frequency: f32, sample_rate: u32) -> Result<BiquadCoefficients> {
+    if frequency <= 0.0 || frequency >= sample_rate as f32 / 2.0 {
+        return Err(anyhow!("Invalid filter frequency {} Hz", frequency));
+    }
+    let k = (PI * frequency / sample_rate as f32).tan();
+    let c = (k - 1.0) / (k + 1.0);
+    Ok(BiquadCoefficients {
+        b0: c,
+        b1: 1.0,
+        b2: 0.0,
+        a1: c,
+        a2: 0.0,
+    })
+}
+
+pub fn butterworth(kind: PassKind, frequency: f32, order: u32, sample_rate: u32) -> Result<Vec<BiquadCoefficients>> {
+    if order == 0 {
+        return Err(anyhow!("Butterworth order must be at least 1"));
+    }
+    let kind_type = match kind {
+        PassKind::LowPass => Type::LowPass,
+        PassKind::HighPass => Type::HighPass,
+    };
+    let n = order as f32;
+    let mut sections = Vec::new();
+    if !order.is_multiple_of(2) {
+        sections.push(first_order(kind, frequency, sample_rate)?);
+    }
+    for k in 1..=order / 2 {
+        // Pole pair angles of the analog prototype
+        let angle = if order.is_multiple_of(2) {
+            PI * (2 * k - 1) as f32 / (2.0 * n)
+        } else {
+            PI * k as f32 / n
+        };
+        let q = 1.0 / (2.0 * angle.cos());
+        sections.push(second_order(kind_type, frequency, q, sample_rate)?);
+    }
+    Ok(sections)
+}
+
+// One output of the crossover: a filtered copy of an input channel sent to an output channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Band {
+    pub name: String,
+    pub input_channel: usize,
+    pub output_channel: usize,
+    pub filters: Vec<FilterSpec>,
+    pub fir: Option<Vec<f32>>,
+    pub gain_db: f32,
+    pub delay_ms: f32,
+    pub invert: bool,
+    // Woofer and sub protection, applied after the gain
+    pub dynamic_bass: Option<DynamicBass>,
+    pub excursion_limiter: Option<ExcursionLimiter>,
+}
+
+impl Band {
+    pub fn new(name: &str, input_channel: usize, output_channel: usize) -> Self {
+        Band {
+            name: name.to_string(),
+            input_channel,
+            output_channel,
+            filters: Vec::new(),
+            fir: None,
+            gain_db: 0.0,
+            delay_ms: 0.0,
+            invert: false,
+            dynamic_bass: None,
+            excursion_limiter: None,
+        }
+    }
+
+    // A woofer or sub: something low-passes it and nothing high-passes it
+    pub fn is_low(&self) -> bool {
+        let passes = |wanted: PassKind| {
+            self.filters.iter().any(|filter| match *filter {
+                FilterSpec::LinkwitzRiley { kind, .. }
+                | FilterSpec::Butterworth { kind, .. }
+                | FilterSpec::FirstOrder { kind, .. }
+                | FilterSpec::SecondOrder { kind, .. } => kind == wanted,
+                _ => false,
+            })
+        };
+        passes(PassKind::LowPass) && !passes(PassKind::HighPass)
+    }
+
+    pub fn delay_samples(&self, sample_rate: u32) -> usize {
+        (self.delay_ms.max(0.0) * 1e-3 * sample_rate as f32).round() as usize
+    }
+
+    // Fixed delay the band adds: its delay, the group delay of a linear-phase FIR
+    // and the limiter lookahead
+    pub fn latency_frames(&self, sample_rate: u32) -> f32 {
+        let fir_delay = self
+            .fir
+            .as_ref()
+            .and_then(|fir| AudioFilter::new(fir.clone()).linear_phase_delay())
+            .unwrap_or(0.0);
+        let lookahead = self
+            .excursion_limiter
+            .map(|limiter| limiter.lookahead_samples(sample_rate))
+            .unwrap_or(0);
+        (self.delay_samples(sample_rate) + lookahead) as f32 + fir_delay
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Crossover {
+    pub bands: Vec<Band>,
+    // Length of an FIR run on each input channel before the split that undoes the
+    // phase of the summed IIR bands. Costs half its length in latency.
+    pub phase_linearization: Option<usize>,
+}
+
+impl Crossover {
+    // Stereo two-way: outputs are left woofer, left tweeter, right woofer, right tweeter
+    pub fn stereo_two_way(frequency: f32, order: u32) -> Self {
+        let mut bands = Vec::new();
+        for (side, input) in [("Left", 0), ("Right", 1)] {
+            let mut low = Band::new(&format!("{} low", side), input, input * 2);
+            low.filters.push(FilterSpec::LinkwitzRiley { kind: PassKind::LowPass, frequency, order });
+            let mut high = Band::new(&format!("{} high", side), input, input * 2 + 1);
+            high.filters.push(FilterSpec::LinkwitzRiley { kind: PassKind::HighPass, frequency, order });
+            bands.push(low);
+            bands.push(high);
+        }
+        Crossover {
+            bands,
+            phase_linearization: None,
+        }
+    }
+
+    // "<Hz>[:<order>]" for stereo_two_way with Linkwitz-Riley filters, fourth order
+    // unless given, as in AUDIOSERVER_CROSSOVER
+    pub fn parse(text: &str) -> Result<Self> {
+        let (frequency, order) = text.trim().split_once(':').unwrap_or((text.trim(), "4"));
+        let frequency = frequency
+            .trim()
+            .parse::<f32>()
+            .ok()
+            .filter(|frequency| frequency.is_finite() && *frequency > 0.0)
+            .ok_or_else(|| anyhow!("Expected <Hz>[:<order>] for the crossover, got {}", text))?;
+        let order = order
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|order| *order > 0 && order.is_multiple_of(2))
+            .ok_or_else(|| anyhow!("Linkwitz-Riley order must be even, got {}", order))?;
+        Ok(Crossover::stereo_two_way(frequency, order))
+    }
+
+    pub fn output_channels(&self) -> usize {
+        self.bands.iter().map(|band| band.output_channel + 1).max().unwrap_or(0)
+    }
+
+    // Phase linearization kernel for every input channel, None where no band reads the channel
+    pub fn phase_linearizers(&self, channels: usize, sample_rate: u32) -> Result<Option<Vec<Option<Vec<f32>>>>> {
+        let Some(taps) = self.phase_linearization else {
+            return Ok(None);
+        };
+        let mut kernels = Vec::with_capacity(channels);
+        for channel in 0..channels {
+            let bands: Vec<&Band> = self.bands.iter().filter(|band| band.input_channel == channel).collect();
+            kernels.push(if bands.is_empty() {
+                None
+            } else {
+                Some(phase::linearizer(&bands, sample_rate, taps)?)
+            });
+        }
+        Ok(Some(kernels))
+    }
+}
+
+// Realtime state of one band, past its crossover filters
+pub struct BandProcessor {
+    pub input_channel: usize,
+    pub output_channel: usize,
+    sections: Vec<BiquadCoefficients>,
+    fir: Option<FirFilter>,
+    gain: f32,
+    dynamic_bass: Option<DynamicBassProcessor>,
+    limiter: Option<ExcursionLimiterProcessor>,
+    delay: DelayLine,
+    scratch: Vec<f32>,
+}
+
+impl BandProcessor {
+    pub fn new(band: &Band, sample_rate: u32) -> Result<Self> {
+        let mut sections = Vec::new();
+        for spec in &band.filters {
+            sections.extend(spec.sections(sample_rate)?);
+        }
+        let polarity = if band.invert { -1.0 } else { 1.0 };
+        Ok(BandProcessor {
+            input_channel: band.input_channel,
+            output_channel: band.output_channel,
+            sections,
+            fir: band.fir.as_ref().map(|coefficients| FirFilter::new(coefficients)),
+            gain: polarity * 10f32.powf(band.gain_db / 20.0),
+            dynamic_bass: match band.dynamic_bass {
+                Some(config) => Some(DynamicBassProcessor::new(config, sample_rate)?),
+                None => None,
+            },
+            limiter: match band.excursion_limiter {
+                Some(config) => Some(ExcursionLimiterProcessor::new(config, sample_rate)?),
+                None => None,
+            },
+            delay: DelayLine::new(band.delay_samples(sample_rate)),
+            scratch: Vec::new(),
+        })
+    }
+
+    // FIR, gain and dynamic bass on the filtered block
+    fn shape(&mut self, data: &mut [f32]) {
+        if let Some(fir) = self.fir.as_mut() {
+            self.scratch.clear();
+            self.scratch.extend_from_slice(data);
+            fir.process(&self.scratch, data);
+        }
+        for sample in data.iter_mut() {
+            *sample *= self.gain;
+        }
+        if let Some(dynamic_bass) = self.dynamic_bass.as_mut() {
+            dynamic_bass.process(data);
+        }
+    }
+
+    // Limiter and delay, `modelled` being the limiter's excursion model run over the block
+    fn finish(&mut self, data: &mut [f32], modelled: impl Iterator<Item = f32>) {
+        if let Some(limiter) = self.limiter.as_mut() {
+            limiter.process(data, modelled);
+        }
+        self.delay.process(data);
+    }
+}
+
+// The bands one thread runs. Their crossover cascades share a biquad bank with a
+// lane per band, as do the excursion models of the limited ones, so the vector
+// kernels filter all of the thread's bands in one pass.
+pub struct BandGroup {
+    // Crossover index of each band, which picks its output buffer
+    indices: Vec<usize>,
+    bands: Vec<BandProcessor>,
+    biquads: BiquadBank,
+    // Bands with an excursion limiter, in the lane order of `models`
+    limited: Vec<usize>,
+    models: BiquadBank,
+    interleaved: Vec<f32>,
+    modelled: Vec<f32>,
+}
+
+impl BandGroup {
+    pub fn new(bands: Vec<(usize, BandProcessor)>) -> Self {
+        let (indices, bands): (Vec<usize>, Vec<BandProcessor>) = bands.into_iter().unzip();
+        let cascades: Vec<_> = bands.iter().map(|band| band.sections.clone()).collect();
+        let limited: Vec<usize> = (0..bands.len()).filter(|&lane| bands[lane].limiter.is_some()).collect();
+        let models: Vec<_> = limited
+            .iter()
+            .filter_map(|&lane| bands[lane].limiter.as_ref())
+            .map(|limiter| vec![limiter.model()])
+            .collect();
+        BandGroup {
+            indices,
+            biquads: BiquadBank::new(&cascades),
+            models: BiquadBank::new(&models),
+            bands,
+            limited,
+            interleaved: Vec::new(),
+            modelled: Vec::new(),
+        }
+    }
+
+    // `input(channel)` is the planar block of an input channel and `output(index)`
+    // the buffer of the band with that crossover index, both `frames` long.
+    // `output` is called up to twice per band, never while an earlier slice is in use.
+    pub fn process<'a>(
+        &mut self,
+        frames: usize,
+        input: impl Fn(usize) -> Option<&'a [f32]>,
+        mut output: impl FnMut(usize) -> &'a mut [f32],
+    ) {
+        let lanes = self.bands.len();
+        self.interleaved.clear();
+        self.interleaved.resize(frames * lanes, 0.0);
+        for (lane, band) in self.bands.iter().enumerate() {
+            if let Some(input) = input(band.input_channel) {
+                for (frame, &sample) in input[..frames].iter().enumerate() {
+                    self.interleaved[frame * lanes + lane] = sample;
+                }
+            }
+        }
+        self.biquads.process_interleaved(&mut self.interleaved);
+
+        let limited = self.limited.len();
+        self.modelled.clear();
+        self.modelled.resize(frames * limited, 0.0);
+        let mut model_lane = 0;
+        for (lane, (band, &idx)) in self.bands.iter_mut().zip(&self.indices).enumerate() {
+            let data = &mut output(idx)[..frames];
+            for (frame, sample) in data.iter_mut().enumerate() {
+                *sample = self.interleaved[frame * lanes + lane];
+            }
+            band.shape(data);
+            if self.limited.get(model_lane) == Some(&lane) {
+                for (frame, &sample) in data.iter().enumerate() {
+                    self.modelled[frame * limited + model_lane] = sample;
+                }
+                model_lane += 1;
+            }
+        }
+        self.models.process_interleaved(&mut self.modelled);
+
+        let mut model_lane = 0;
+        for (lane, (band, &idx)) in self.bands.iter_mut().zip(&self.indices).enumerate() {
+            let data = &mut output(idx)[..frames];
+            if self.limited.get(model_lane) == Some(&lane) {
+                band.finish(data, self.modelled.iter().skip(model_lane).step_by(limited).copied());
+                model_lane += 1;
+            } else {
+                band.finish(data, std::iter::empty());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_way_from_text() {
+        let crossover = Crossover::parse("2200").unwrap();
+        assert_eq!(crossover, Crossover::stereo_two_way(2200.0, 4));
+        assert_eq!(crossover.output_channels(), 4);
+        let low: Vec<usize> =
+            crossover.bands.iter().filter(|band| band.is_low()).map(|band| band.output_channel).collect();
+        assert_eq!(low, vec![0, 2]);
+        assert_eq!(Crossover::parse("80:8").unwrap(), Crossover::stereo_two_way(80.0, 8));
+        assert!(Crossover::parse("80:3").is_err());
+        assert!(Crossover::parse("high").is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use std::time::Duration;
+use symphonia::core::meta::{MetadataRevision, StandardTagKey, StandardVisualKey, Tag, Value};
+
+// Embedded picture, e.g. an APIC frame, a FLAC PICTURE block or an MP4 covr atom
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoverArt {
+    // MIME type as the file gives it, usually image/jpeg or image/png
+    pub media_type: String,
+    pub data: Vec<u8>,
+}
+
+// Gains relative to the ReplayGain 2.0 reference of -18 LUFS, peaks as linear sample values
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayGainTags {
+    pub track_gain_db: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain_db: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+// Tags of a track from ID3v2, Vorbis comments or MP4 atoms, whichever the file has
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub track_total: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub date: Option<String>,
+    pub genre: Option<String>,
+    pub duration: Option<Duration>,
+    pub cover: Option<CoverArt>,
+    pub replay_gain: ReplayGainTags,
+}
+
+// "3", "03" or "3/12" as ID3 writes it, the total is the part after the slash
+fn parse_number(value: &Value) -> (Option<u32>, Option<u32>) {
+    match value {
+        Value::UnsignedInt(n) => (u32::try_from(*n).ok(), None),
+        Value::SignedInt(n) => (u32::try_from(*n).ok(), None),
+        other => {
+            let text = other.to_string();
+            let (number, total) = match text.split_once('/') {
+                Some((number, total)) => (number, Some(total)),
+                None => (text.as_str(), None),
+            };
+            (
+                number.trim().parse().ok(),
+                total.and_then(|total| total.trim().parse().ok()),
+            )
+        }
+    }
+}
+
+// "-6.53 dB"
+fn parse_gain(value: &Value) -> Option<f32> {
+    let text = value.to_string();
+    let text = text.trim();
+    let text = text
+        .strip_suffix("dB")
+        .or_else(|| text.strip_suffix("db"))
+        .or_else(|| text.strip_suffix("DB"))
+        .unwrap_or(text);
+    text.trim().parse().ok()
+}
+
+fn parse_peak(value: &Value) -> Option<f32> {
+    value.to_string().trim().parse().ok()
+}
+
+// Opus R128_*_GAIN: Q7.8 fixed point relative to -23 LUFS, 5 dB below the ReplayGain reference
+fn parse_r128(value: &Value) -> Option<f32> {
+    let gain: i32 = value.to_string().trim().parse().ok()?;
+    Some(gain as f32 / 256.0 + 5.0)
+}
+
+fn text(value: &Value) -> Option<String> {
+    let text = value.to_string();
+    let text = text.trim_matches(char::from(0)).trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+impl TrackMetadata {
+    // Fill what is still missing from one revision. Called with the container's
+    // own tags first, then with tags in front of it (ID3v2 ahead of an MP3 stream).
+    pub fn merge(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            self.merge_replay_gain(tag);
+            let Some(key) = tag.std_key else {
+                continue;
+            };
+            let field = match key {
+                StandardTagKey::TrackTitle => &mut self.title,
+                StandardTagKey::Artist => &mut self.artist,
+                StandardTagKey::Album => &mut self.album,
+                StandardTagKey::AlbumArtist => &mut self.album_artist,
+                StandardTagKey::Date | StandardTagKey::ReleaseDate | StandardTagKey::OriginalDate => &mut self.date,
+                StandardTagKey::Genre => &mut self.genre,
+                StandardTagKey::TrackNumber => {
+                    let (number, total) = parse_number(&tag.value);
+                    self.track_number = self.track_number.or(number);
+                    self.track_total = self.track_total.or(total);
+                    continue;
+                }
+                StandardTagKey::TrackTotal => {
+                    self.track_total = self.track_total.or(parse_number(&tag.value).0);
+                    continue;
+                }
+                StandardTagKey::DiscNumber => {
+                    self.disc_number = self.disc_number.or(parse_number(&tag.value).0);
+                    continue;
+                }
+                _ => continue,
+            };
+            if field.is_none() {
+                *field = text(&tag.value);
+            }
+        }
+
+        // The front cover, or whatever picture there is
+        let visuals = revision.visuals();
+        let visual = visuals
+            .iter()
+            .find(|visual| visual.usage == Some(StandardVisualKey::FrontCover))
+            .or_else(|| visuals.first());
+        if self.cover.is_none()
+            && let Some(visual) = visual
+            && !visual.data.is_empty()
+        {
+            self.cover = Some(CoverArt {
+                media_type: visual.media_type.clone(),
+                data: visual.data.to_vec(),
+            });
+        }
+    }
+
+    // By standard key, or by name where the format has no mapping for it
+    // (MP4 freeform atoms like ----:com.apple.iTunes:replaygain_track_gain, Opus R128 comments)
+    fn merge_replay_gain(&mut self, tag: &Tag) {
+        let name = tag.key.to_ascii_lowercase();
+        let gains = &mut self.replay_gain;
+        let (field, value) = match tag.std_key {
+            Some(StandardTagKey::ReplayGainTrackGain) => (&mut gains.track_gain_db, parse_gain(&tag.value)),
+            Some(StandardTagKey::ReplayGainTrackPeak) => (&mut gains.track_peak, parse_peak(&tag.value)),
+            Some(StandardTagKey::ReplayGainAlbumGain) => (&mut gains.album_gain_db, parse_gain(&tag.value)),
+            Some(StandardTagKey::ReplayGainAlbumPeak) => (&mut gains.album_peak, parse_peak(&tag.value)),
+            _ if name.ends_with("replaygain_track_gain") => (&mut gains.track_gain_db, parse_gain(&tag.value)),
+            _ if name.ends_with("replaygain_track_peak") => (&mut gains.track_peak, parse_peak(&tag.value)),
+            _ if name.ends_with("replaygain_album_gain") => (&mut gains.album_gain_db, parse_gain(&tag.value)),
+            _ if name.ends_with("replaygain_album_peak") => (&mut gains.album_peak, parse_peak(&tag.value)),
+            _ if name == "r128_track_gain" => (&mut gains.track_gain_db, parse_r128(&tag.value)),
+            _ if name == "r128_album_gain" => (&mut gains.album_gain_db, parse_r128(&tag.value)),
+            _ => return,
+        };
+        if field.is_none() {
+            *field = value;
+        }
+    }
+}
@@ -0,0 +1,509 @@
+mod metadata;
+mod opus;
+mod remote;
+
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+use symphonia::core::audio::{Channels, SampleBuffer, SignalSpec};
+use symphonia::core::codecs::{CodecRegistry, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as DecodeError;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::{Hint, ProbeResult};
+use symphonia::core::units::{Time, TimeBase, TimeStamp};
+
+use opus::OpusDecoder;
+use remote::HttpSource;
+
+pub use metadata::{ReplayGainTags, TrackMetadata};
+
+// Speaker positions in channel mask bit order, which is also the WAVE_FORMAT_EXTENSIBLE order
+const POSITIONS: [(Channels, &str); 26] = [
+    (Channels::FRONT_LEFT, "FL"),
+    (Channels::FRONT_RIGHT, "FR"),
+    (Channels::FRONT_CENTRE, "FC"),
+    (Channels::LFE1, "LFE"),
+    (Channels::REAR_LEFT, "BL"),
+    (Channels::REAR_RIGHT, "BR"),
+    (Channels::FRONT_LEFT_CENTRE, "FLC"),
+    (Channels::FRONT_RIGHT_CENTRE, "FRC"),
+    (Channels::REAR_CENTRE, "BC"),
+    (Channels::SIDE_LEFT, "SL"),
+    (Channels::SIDE_RIGHT, "SR"),
+    (Channels::TOP_CENTRE, "TC"),
+    (Channels::TOP_FRONT_LEFT, "TFL"),
+    (Channels::TOP_FRONT_CENTRE, "TFC"),
+    (Channels::TOP_FRONT_RIGHT, "TFR"),
+    (Channels::TOP_REAR_LEFT, "TBL"),
+    (Channels::TOP_REAR_CENTRE, "TBC"),
+    (Channels::TOP_REAR_RIGHT, "TBR"),
+    (Channels::REAR_LEFT_CENTRE, "BLC"),
+    (Channels::REAR_RIGHT_CENTRE, "BRC"),
+    (Channels::FRONT_LEFT_WIDE, "FLW"),
+    (Channels::FRONT_RIGHT_WIDE, "FRW"),
+    (Channels::FRONT_LEFT_HIGH, "FLH"),
+    (Channels::FRONT_CENTRE_HIGH, "FCH"),
+    (Channels::FRONT_RIGHT_HIGH, "FRH"),
+    (Channels::LFE2, "LFE2"),
+];
+
+// Everything symphonia decodes plus Opus
+fn codecs() -> &'static CodecRegistry {
+    static CODECS: OnceLock<CodecRegistry> = OnceLock::new();
+    CODECS.get_or_init(|| {
+        let mut registry = CodecRegistry::new();
+        symphonia::default::register_enabled_codecs(&mut registry);
+        registry.register_all::<OpusDecoder>();
+        registry
+    })
+}
+
+// What the file holds, before any conversion
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    pub codec: String,
+    pub sample_rate: u32,
+    pub channels: usize,
+    // Speaker position of every channel in stream order, e.g. ["FL", "FR", "FC", "LFE"].
+    // Empty when the container does not say.
+    pub layout: Vec<&'static str>,
+    // Bit depth of the source, None for lossy codecs
+    pub bits_per_sample: Option<u32>,
+    // Length in frames when the container knows it
+    pub frames: Option<u64>,
+}
+
+impl StreamInfo {
+    pub fn duration(&self) -> Option<Duration> {
+        self.frames
+            .map(|frames| Duration::from_secs_f64(frames as f64 / self.sample_rate.max(1) as f64))
+    }
+}
+
+pub fn channel_layout(channels: Channels) -> Vec<&'static str> {
+    POSITIONS
+        .iter()
+        .filter(|(position, _)| channels.contains(*position))
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+fn probe(path: &Path) -> Result<ProbeResult> {
+    let mut hint = Hint::new();
+    let stream = match path.to_str().filter(|_| crate::player::is_url(path)) {
+        // Pushed by a UPnP controller or listed in a playlist
+        Some(url) => {
+            let source = HttpSource::open(url)?;
+            let name = url.split(['?', '#']).next().unwrap_or(url);
+            let extension = name.rsplit('/').next().and_then(|file| file.rsplit_once('.')).map(|(_, e)| e);
+            let content_type = source.content_type();
+            if let Some(content_type) = &content_type {
+                hint.mime_type(content_type);
+            }
+            if let Some(extension) = extension.or_else(|| content_type.as_deref().and_then(remote::extension_for)) {
+                hint.with_extension(extension);
+            }
+            MediaSourceStream::new(Box::new(source), Default::default())
+        }
+        None => {
+            let file = File::open(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+            if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+                hint.with_extension(extension);
+            }
+            MediaSourceStream::new(Box::new(file), Default::default())
+        }
+    };
+    probe_stream(&hint, stream, &path.display().to_string())
+}
+
+fn probe_stream(hint: &Hint, stream: MediaSourceStream, name: &str) -> Result<ProbeResult> {
+    // Gapless trims encoder delay and padding so consecutive tracks join seamlessly
+    let format_options = FormatOptions {
+        enable_gapless: true,
+        ..Default::default()
+    };
+    symphonia::default::get_probe()
+        .format(hint, stream, &format_options, &MetadataOptions::default())
+        .map_err(|e| anyhow!("{}: {}", name, e))
+}
+
+// Tags in the container, then those in front of it
+fn collect_metadata(probed: &mut ProbeResult) -> TrackMetadata {
+    let mut metadata = TrackMetadata::default();
+    if let Some(revision) = probed.format.metadata().skip_to_latest() {
+        metadata.merge(revision);
+    }
+    if let Some(mut probed) = probed.metadata.get()
+        && let Some(revision) = probed.skip_to_latest()
+    {
+        metadata.merge(revision);
+    }
+    metadata
+}
+
+// Tags and cover of a file without setting up a decoder
+pub fn read_metadata(path: &Path) -> Result<TrackMetadata> {
+    let mut probed = probe(path)?;
+    let mut metadata = collect_metadata(&mut probed);
+    metadata.duration = probed
+        .format
+        .default_track()
+        .and_then(|track| Some((track.codec_params.n_frames?, track.codec_params.sample_rate?)))
+        .map(|(frames, rate)| Duration::from_secs_f64(frames as f64 / rate.max(1) as f64));
+    Ok(metadata)
+}
+
+// FLAC, WAV, Ogg Vorbis/Opus, MP3 and AAC/ALAC in MP4, decoded to interleaved f32
+// at the native rate and channel count
+pub struct AudioDecoder {
+    reader: Box<dyn FormatReader>,
+    decoder: Box<dyn symphonia::core::codecs::Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    info: StreamInfo,
+    metadata: TrackMetadata,
+    // Frames still to drop after a seek, up to the requested position
+    skip: u64,
+    spec: Option<SignalSpec>,
+    samples: Option<SampleBuffer<f32>>,
+}
+
+impl AudioDecoder {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::from_probed(probe(path)?, &path.display().to_string())
+    }
+
+    // A source that is only read forward, e.g. internet radio, probed by its MIME type
+    pub fn open_stream(source: Box<dyn MediaSource>, content_type: Option<&str>, name: &str) -> Result<Self> {
+        let mut hint = Hint::new();
+        if let Some(content_type) = content_type {
+            hint.mime_type(content_type);
+            if let Some(extension) = remote::extension_for(content_type) {
+                hint.with_extension(extension);
+            }
+        }
+        let stream = MediaSourceStream::new(source, Default::default());
+        Self::from_probed(probe_stream(&hint, stream, name)?, name)
+    }
+
+    fn from_probed(mut probed: ProbeResult, name: &str) -> Result<Self> {
+        let mut metadata = collect_metadata(&mut probed);
+        let reader = probed.format;
+
+        let track = reader
+            .tracks()
+            .iter()
+            .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or_else(|| anyhow!("{}: no audio track", name))?;
+        let params = &track.codec_params;
+        let decoder = codecs()
+            .make(params, &DecoderOptions::default())
+            .map_err(|e| anyhow!("{}: {}", name, e))?;
+
+        let sample_rate = params
+            .sample_rate
+            .ok_or_else(|| anyhow!("{}: unknown sample rate", name))?;
+        let layout = params.channels.map(channel_layout).unwrap_or_default();
+        let info = StreamInfo {
+            codec: codecs()
+                .get_codec(params.codec)
+                .map(|descriptor| descriptor.short_name.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+            sample_rate,
+            channels: params.channels.map(Channels::count).unwrap_or(layout.len()),
+            layout,
+            bits_per_sample: params.bits_per_sample,
+            frames: params.n_frames,
+        };
+        if info.channels == 0 {
+            return Err(anyhow!("{}: unknown channel count", name));
+        }
+        metadata.duration = info.duration();
+
+        Ok(AudioDecoder {
+            track_id: track.id,
+            time_base: params.time_base,
+            reader,
+            decoder,
+            info,
+            metadata,
+            skip: 0,
+            spec: None,
+            samples: None,
+        })
+    }
+
+    pub fn info(&self) -> &StreamInfo {
+        &self.info
+    }
+
+    pub fn metadata(&self) -> &TrackMetadata {
+        &self.metadata
+    }
+
+    fn seconds(&self, ts: TimeStamp) -> f64 {
+        match self.time_base {
+            Some(time_base) => {
+                let time = time_base.calc_time(ts);
+                time.seconds as f64 + time.frac
+            }
+            None => ts as f64 / self.info.sample_rate as f64,
+        }
+    }
+
+    // Jump to `position` and return where decoding continues. The reader lands on
+    // the packet before the target, the frames up to it are dropped from the next blocks.
+    pub fn seek(&mut self, position: Duration) -> Result<Duration> {
+        let seeked = self.reader.seek(
+            SeekMode::Accurate,
+            SeekTo::Time {
+                time: Time::from(position.as_secs_f64()),
+                track_id: Some(self.track_id),
+            },
+        )?;
+        self.decoder.reset();
+        let early = self.seconds(seeked.required_ts) - self.seconds(seeked.actual_ts);
+        self.skip = (early.max(0.0) * self.info.sample_rate as f64).round() as u64;
+        Ok(Duration::from_secs_f64(self.seconds(seeked.required_ts)))
+    }
+
+    // Next block of interleaved samples, None at the end of the stream.
+    // Packets that fail to decode are skipped.
+    pub fn next_block(&mut self) -> Result<Option<&[f32]>> {
+        loop {
+            let packet = match self.reader.next_packet() {
+                Ok(packet) => packet,
+                Err(DecodeError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(DecodeError::DecodeError(e)) => {
+                    eprintln!("Skipping undecodable packet: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let frames = decoded.frames() as u64;
+            if frames == 0 {
+                continue;
+            }
+
+            let spec = *decoded.spec();
+            if spec.rate != self.info.sample_rate || spec.channels.count() != self.info.channels {
+                return Err(anyhow!("Stream format changed to {} Hz, {} channels", spec.rate, spec.channels.count()));
+            }
+            if self.skip >= frames {
+                self.skip -= frames;
+                continue;
+            }
+            let skip = std::mem::take(&mut self.skip) as usize * spec.channels.count();
+            let capacity = decoded.capacity() * spec.channels.count();
+            if self.spec != Some(spec) || self.samples.as_ref().is_some_and(|s| s.capacity() < capacity) {
+                self.samples = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+                self.spec = Some(spec);
+            }
+            let samples = self.samples.as_mut().unwrap();
+            samples.copy_interleaved_ref(decoded);
+            return Ok(Some(&samples.samples()[skip..]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("audioserver-decoder-{}-{}", std::process::id(), name))
+    }
+
+    // Ramp per channel, full scale / 4 at the last frame, channel c negated when odd
+    fn ramp(frame: usize, channel: usize, full_scale: f64) -> f64 {
+        let value = frame as f64 / 64.0 * full_scale / 4.0;
+        if channel % 2 == 1 { -value } else { value }
+    }
+
+    fn write_wav(path: &Path, spec: hound::WavSpec, frames: usize) {
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        let full_scale = (1u64 << (spec.bits_per_sample - 1)) as f64;
+        for frame in 0..frames {
+            for channel in 0..spec.channels as usize {
+                match spec.sample_format {
+                    hound::SampleFormat::Float => writer.write_sample(ramp(frame, channel, 1.0) as f32).unwrap(),
+                    hound::SampleFormat::Int => writer.write_sample(ramp(frame, channel, full_scale) as i32).unwrap(),
+                }
+            }
+        }
+        writer.finalize().unwrap();
+    }
+
+    fn decode_all(decoder: &mut AudioDecoder) -> Vec<f32> {
+        let mut samples = Vec::new();
+        while let Some(block) = decoder.next_block().unwrap() {
+            samples.extend_from_slice(block);
+        }
+        samples
+    }
+
+    fn assert_ramp(samples: &[f32], channels: usize, frames: usize) {
+        assert_eq!(samples.len(), channels * frames);
+        for (idx, &sample) in samples.iter().enumerate() {
+            let expected = ramp(idx / channels, idx % channels, 1.0) as f32;
+            assert!((sample - expected).abs() < 1e-4, "sample {}: {} for {}", idx, sample, expected);
+        }
+    }
+
+    #[test]
+    fn wav_formats_and_extensible_masks() {
+        let cases = [
+            (2, 16, hound::SampleFormat::Int, 44100, None, vec!["FL", "FR"]),
+            (6, 24, hound::SampleFormat::Int, 48000, None, vec!["FL", "FR", "FC", "LFE", "BL", "BR"]),
+            (2, 32, hound::SampleFormat::Int, 96000, None, vec!["FL", "FR"]),
+            // hound marks mono as front left
+            (1, 32, hound::SampleFormat::Float, 48000, None, vec!["FL"]),
+            // Quad with rear speakers, and 5.1 with side instead of back surrounds
+            (4, 24, hound::SampleFormat::Int, 48000, Some(0x33), vec!["FL", "FR", "BL", "BR"]),
+            (6, 24, hound::SampleFormat::Int, 48000, Some(0x60f), vec!["FL", "FR", "FC", "LFE", "SL", "SR"]),
+        ];
+        for (channels, bits_per_sample, sample_format, sample_rate, mask, layout) in cases {
+            let path = fixture(&format!("{}ch-{}bit-{:?}.wav", channels, bits_per_sample, mask));
+            let spec = hound::WavSpec {
+                channels,
+                sample_rate,
+                bits_per_sample,
+                sample_format,
+            };
+            write_wav(&path, spec, 64);
+            // hound writes the mask of the first n speakers, WAVEFORMATEXTENSIBLE keeps it at offset 40
+            if let Some(mask) = mask {
+                let mut bytes = std::fs::read(&path).unwrap();
+                assert_eq!(&bytes[20..22], &0xfffeu16.to_le_bytes());
+                bytes[40..44].copy_from_slice(&(mask as u32).to_le_bytes());
+                std::fs::write(&path, bytes).unwrap();
+            }
+
+            let mut decoder = AudioDecoder::open(&path).unwrap();
+            let info = decoder.info().clone();
+            let _ = std::fs::remove_file(&path);
+            let label = format!("{} ch {} bit {:?}", channels, bits_per_sample, sample_format);
+            assert!(info.codec.starts_with("pcm"), "{}: {}", label, info.codec);
+            assert_eq!(info.sample_rate, sample_rate, "{}", label);
+            assert_eq!(info.channels, channels as usize, "{}", label);
+            assert_eq!(info.layout, layout, "{}", label);
+            assert_eq!(info.bits_per_sample, Some(bits_per_sample as u32), "{}", label);
+            assert_eq!(info.frames, Some(64), "{}", label);
+            assert_ramp(&decode_all(&mut decoder), channels as usize, 64);
+        }
+    }
+
+    // Writes bits most significant first, as FLAC is laid out
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        bits: u32,
+    }
+
+    impl BitWriter {
+        fn put(&mut self, value: u64, width: u32) {
+            for bit in (0..width).rev() {
+                if self.bits.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                let last = self.bytes.len() - 1;
+                self.bytes[last] |= (((value >> bit) & 1) as u8) << (7 - self.bits % 8);
+                self.bits += 1;
+            }
+        }
+    }
+
+    fn crc8(bytes: &[u8]) -> u8 {
+        bytes.iter().fold(0u8, |crc, &byte| {
+            (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 })
+        })
+    }
+
+    fn crc16(bytes: &[u8]) -> u16 {
+        bytes.iter().fold(0u16, |crc, &byte| {
+            (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+                if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 }
+            })
+        })
+    }
+
+    // FLAC stream of one frame with verbatim subframes, enough for the decoder to read
+    fn flac(sample_rate: u32, channels: usize, bits_per_sample: u32, frames: usize) -> Vec<u8> {
+        let full_scale = (1u64 << (bits_per_sample - 1)) as f64;
+        let mut stream = BitWriter::default();
+        stream.put(u32::from_be_bytes(*b"fLaC") as u64, 32);
+        // Last metadata block, STREAMINFO, 34 bytes
+        stream.put(1, 1);
+        stream.put(0, 7);
+        stream.put(34, 24);
+        stream.put(frames as u64, 16);
+        stream.put(frames as u64, 16);
+        stream.put(0, 24);
+        stream.put(0, 24);
+        stream.put(sample_rate as u64, 20);
+        stream.put(channels as u64 - 1, 3);
+        stream.put(bits_per_sample as u64 - 1, 5);
+        stream.put(frames as u64, 36);
+        stream.put(0, 64);
+        stream.put(0, 64);
+
+        let mut frame = BitWriter::default();
+        frame.put(0b11111111111110, 14);
+        frame.put(0, 2);
+        // Block size in 8 bits after the header, rate and depth from STREAMINFO, independent channels
+        frame.put(0b0110, 4);
+        frame.put(0, 4);
+        frame.put(channels as u64 - 1, 4);
+        frame.put(0, 4);
+        frame.put(0, 8);
+        frame.put(frames as u64 - 1, 8);
+        let crc = crc8(&frame.bytes);
+        frame.put(crc as u64, 8);
+        for channel in 0..channels {
+            frame.put(0b0000_0010, 8);
+            for index in 0..frames {
+                let sample = ramp(index, channel, full_scale) as i64;
+                frame.put(sample as u64 & ((1 << bits_per_sample) - 1), bits_per_sample);
+            }
+        }
+        let crc = crc16(&frame.bytes);
+        frame.put(crc as u64, 16);
+
+        let mut bytes = stream.bytes;
+        bytes.extend(frame.bytes);
+        bytes
+    }
+
+    #[test]
+    fn flac_rate_depth_and_layout() {
+        for (channels, bits_per_sample, sample_rate, layout) in [
+            (2, 16, 44100, vec!["FL", "FR"]),
+            (6, 24, 96000, vec!["FL", "FR", "FC", "LFE", "BL", "BR"]),
+        ] {
+            let path = fixture(&format!("{}ch-{}bit.flac", channels, bits_per_sample));
+            std::fs::write(&path, flac(sample_rate, channels, bits_per_sample, 64)).unwrap();
+            let mut decoder = AudioDecoder::open(&path).unwrap();
+            let info = decoder.info().clone();
+            let _ = std::fs::remove_file(&path);
+            assert_eq!(info.codec, "flac");
+            assert_eq!(info.sample_rate, sample_rate);
+            assert_eq!(info.channels, channels);
+            assert_eq!(info.layout, layout);
+            assert_eq!(info.bits_per_sample, Some(bits_per_sample));
+            assert_eq!(info.duration(), Some(Duration::from_secs_f64(64.0 / sample_rate as f64)));
+            assert_ramp(&decode_all(&mut decoder), channels, 64);
+        }
+    }
+}
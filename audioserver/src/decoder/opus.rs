@@ -0,0 +1,94 @@
+use audiopus::coder::{Decoder as LibOpusDecoder, GenericCtl};
+use audiopus::packet::Packet as OpusPacket;
+use audiopus::{Channels as OpusChannels, MutSignals, SampleRate};
+use std::sync::Mutex;
+use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Signal, SignalSpec};
+use symphonia::core::codecs::{
+    CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS,
+};
+use symphonia::core::errors::{decode_error, unsupported_error, Result};
+use symphonia::core::formats::Packet;
+use symphonia::core::support_codec;
+
+// Opus always decodes at 48 kHz, 120 ms is the longest packet
+const OPUS_RATE: u32 = 48_000;
+const MAX_PACKET_FRAMES: usize = 5760;
+
+// Symphonia demuxes Ogg Opus but has no decoder for it, this one wraps libopus.
+// Only mono and stereo streams (channel mapping family 0 or 1 with up to two channels).
+pub struct OpusDecoder {
+    params: CodecParameters,
+    // libopus state is not Sync, symphonia decoders have to be
+    decoder: Mutex<LibOpusDecoder>,
+    channels: usize,
+    interleaved: Vec<f32>,
+    buf: AudioBuffer<f32>,
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        let Some(layout) = params.channels else {
+            return unsupported_error("opus: no channel layout");
+        };
+        let channels = layout.count();
+        let opus_channels = match channels {
+            1 => OpusChannels::Mono,
+            2 => OpusChannels::Stereo,
+            _ => return unsupported_error("opus: only mono and stereo streams"),
+        };
+        let Ok(decoder) = LibOpusDecoder::new(SampleRate::Hz48000, opus_channels) else {
+            return decode_error("opus: could not create decoder");
+        };
+        Ok(OpusDecoder {
+            params: params.clone(),
+            decoder: Mutex::new(decoder),
+            channels,
+            interleaved: vec![0.0; MAX_PACKET_FRAMES * channels],
+            buf: AudioBuffer::new(MAX_PACKET_FRAMES as u64, SignalSpec::new(OPUS_RATE, layout)),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus")]
+    }
+
+    fn reset(&mut self) {
+        let _ = self.decoder.get_mut().unwrap().reset_state();
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        self.buf.clear();
+        let (Ok(input), Ok(output)) = (
+            OpusPacket::try_from(packet.buf()),
+            MutSignals::try_from(&mut self.interleaved[..]),
+        ) else {
+            return decode_error("opus: empty packet");
+        };
+        let frames = match self.decoder.get_mut().unwrap().decode_float(Some(input), output, false) {
+            Ok(frames) => frames,
+            Err(_) => return decode_error("opus: corrupt packet"),
+        };
+
+        self.buf.render_reserved(Some(frames));
+        for channel in 0..self.channels {
+            for (frame, sample) in self.buf.chan_mut(channel).iter_mut().enumerate() {
+                *sample = self.interleaved[frame * self.channels + channel];
+            }
+        }
+        // Pre-skip and end padding, for gapless playback
+        self.buf.trim(packet.trim_start() as usize, packet.trim_end() as usize);
+        Ok(self.buf.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        FinalizeResult::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buf.as_audio_buffer_ref()
+    }
+}
@@ -0,0 +1,103 @@
+use anyhow::Result;
+use std::io::{Read, Seek, SeekFrom};
+use symphonia::core::io::MediaSource;
+
+use crate::http::{self, Response};
+
+// Forward seeks up to this far read through instead of reconnecting
+const SKIP_READ: u64 = 64 * 1024;
+
+// A file behind an HTTP URL, seekable through range requests when the server allows them
+pub struct HttpSource {
+    url: String,
+    response: Response,
+    position: u64,
+    length: Option<u64>,
+    seekable: bool,
+}
+
+impl HttpSource {
+    pub fn open(url: &str) -> Result<Self> {
+        let response = http::get(url, &[])?;
+        let length = response.content_length();
+        let seekable = length.is_some()
+            && response
+                .header("Accept-Ranges")
+                .is_some_and(|ranges| ranges.eq_ignore_ascii_case("bytes"));
+        Ok(HttpSource {
+            url: url.to_string(),
+            response,
+            position: 0,
+            length,
+            seekable,
+        })
+    }
+
+    pub fn content_type(&self) -> Option<String> {
+        self.response.content_type()
+    }
+}
+
+impl Read for HttpSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.response.read(buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for HttpSource {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        let target = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.length.and_then(|length| length.checked_add_signed(offset)),
+        }
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid seek"))?;
+        if target == self.position {
+            return Ok(target);
+        }
+        if target > self.position && target - self.position <= SKIP_READ {
+            let skip = target - self.position;
+            std::io::copy(&mut self.by_ref().take(skip), &mut std::io::sink())?;
+            if self.position == target {
+                return Ok(target);
+            }
+        }
+        if !self.seekable {
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "server does not support ranges"));
+        }
+
+        let range = format!("bytes={}-", target);
+        let response = http::get(&self.url, &[("Range", &range)]).map_err(std::io::Error::other)?;
+        if response.status != 206 {
+            return Err(std::io::Error::other(format!("range request answered with HTTP {}", response.status)));
+        }
+        self.response = response;
+        self.position = target;
+        Ok(target)
+    }
+}
+
+impl MediaSource for HttpSource {
+    fn is_seekable(&self) -> bool {
+        self.seekable
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.length
+    }
+}
+
+// Symphonia's format hint for a MIME type
+pub fn extension_for(content_type: &str) -> Option<&'static str> {
+    match content_type {
+        "audio/mpeg" | "audio/mp3" | "audio/mpeg3" => Some("mp3"),
+        "audio/flac" | "audio/x-flac" => Some("flac"),
+        "audio/wav" | "audio/x-wav" | "audio/wave" | "audio/vnd.wave" => Some("wav"),
+        "audio/ogg" | "application/ogg" | "audio/vorbis" | "audio/opus" => Some("ogg"),
+        "audio/mp4" | "audio/x-m4a" | "audio/m4a" => Some("m4a"),
+        "audio/aac" | "audio/aacp" | "audio/x-aac" => Some("aac"),
+        _ => None,
+    }
+}
@@ -0,0 +1,321 @@
+use super::Kernel;
+
+// Normalized biquad coefficients (a0 = 1)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BiquadCoefficients {
+    pub b0: f32,
+    pub b1: f32,
+    pub b2: f32,
+    pub a1: f32,
+    pub a2: f32,
+}
+
+impl BiquadCoefficients {
+    // Passes the signal through unchanged, used to pad shorter cascades
+    pub const IDENTITY: BiquadCoefficients = BiquadCoefficients {
+        b0: 1.0,
+        b1: 0.0,
+        b2: 0.0,
+        a1: 0.0,
+        a2: 0.0,
+    };
+}
+
+impl From<biquad::Coefficients<f32>> for BiquadCoefficients {
+    fn from(c: biquad::Coefficients<f32>) -> Self {
+        BiquadCoefficients {
+            b0: c.b0,
+            b1: c.b1,
+            b2: c.b2,
+            a1: c.a1,
+            a2: c.a2,
+        }
+    }
+}
+
+// Cascaded direct form II transposed biquads for several channels at once.
+// Channels map to vector lanes, so one frame of interleaved audio is one pass.
+#[derive(Debug, Clone)]
+pub struct BiquadBank {
+    channels: usize,
+    lanes: usize,
+    sections: usize,
+    // Structure of arrays, index is section * lanes + lane
+    b0: Vec<f32>,
+    b1: Vec<f32>,
+    b2: Vec<f32>,
+    a1: Vec<f32>,
+    a2: Vec<f32>,
+    s1: Vec<f32>,
+    s2: Vec<f32>,
+    frame: Vec<f32>,
+    kernel: Kernel,
+}
+
+impl BiquadBank {
+    // One cascade per channel, shorter cascades are padded with identity sections
+    pub fn new(cascades: &[Vec<BiquadCoefficients>]) -> Self {
+        BiquadBank::with_kernel(cascades, Kernel::detect())
+    }
+
+    pub fn with_kernel(cascades: &[Vec<BiquadCoefficients>], kernel: Kernel) -> Self {
+        let kernel = kernel.for_channels(cascades.len());
+        let channels = cascades.len();
+        let lanes = channels.div_ceil(kernel.lanes()).max(1) * kernel.lanes();
+        let sections = cascades.iter().map(Vec::len).max().unwrap_or(0);
+        let mut bank = BiquadBank {
+            channels,
+            lanes,
+            sections,
+            b0: vec![0.0; sections * lanes],
+            b1: vec![0.0; sections * lanes],
+            b2: vec![0.0; sections * lanes],
+            a1: vec![0.0; sections * lanes],
+            a2: vec![0.0; sections * lanes],
+            s1: vec![0.0; sections * lanes],
+            s2: vec![0.0; sections * lanes],
+            frame: vec![0.0; lanes],
+            kernel,
+        };
+        for section in 0..sections {
+            for lane in 0..lanes {
+                let c = cascades
+                    .get(lane)
+                    .and_then(|cascade| cascade.get(section))
+                    .copied()
+                    .unwrap_or(BiquadCoefficients::IDENTITY);
+                let idx = section * lanes + lane;
+                bank.b0[idx] = c.b0;
+                bank.b1[idx] = c.b1;
+                bank.b2[idx] = c.b2;
+                bank.a1[idx] = c.a1;
+                bank.a2[idx] = c.a2;
+            }
+        }
+        bank
+    }
+
+    // Swap the coefficients of one channel while it runs, the filter state is kept.
+    // Sections past the bank's length are ignored, missing ones become identity.
+    pub fn set_cascade(&mut self, channel: usize, cascade: &[BiquadCoefficients]) {
+        if channel >= self.channels {
+            return;
+        }
+        for section in 0..self.sections {
+            let c = cascade.get(section).copied().unwrap_or(BiquadCoefficients::IDENTITY);
+            let idx = section * self.lanes + channel;
+            self.b0[idx] = c.b0;
+            self.b1[idx] = c.b1;
+            self.b2[idx] = c.b2;
+            self.a1[idx] = c.a1;
+            self.a2[idx] = c.a2;
+        }
+    }
+
+    // Filter an interleaved block in place
+    pub fn process_interleaved(&mut self, data: &mut [f32]) {
+        if self.channels == 0 {
+            return;
+        }
+        for frame in data.chunks_mut(self.channels) {
+            self.frame[..frame.len()].copy_from_slice(frame);
+            self.run_frame();
+            frame.copy_from_slice(&self.frame[..frame.len()]);
+        }
+    }
+
+    fn run_frame(&mut self) {
+        match self.kernel {
+            Kernel::Scalar => self.run_frame_scalar(),
+            // Safety: kernels are only constructed through `or_supported`/`detect`
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Sse => unsafe { self.run_frame_sse() },
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => unsafe { self.run_frame_avx2() },
+            #[cfg(target_arch = "aarch64")]
+            Kernel::Neon => unsafe { self.run_frame_neon() },
+        }
+    }
+
+    fn run_frame_scalar(&mut self) {
+        for section in 0..self.sections {
+            let offset = section * self.lanes;
+            for lane in 0..self.lanes {
+                let idx = offset + lane;
+                let x = self.frame[lane];
+                let y = self.b0[idx] * x + self.s1[idx];
+                self.s1[idx] = self.b1[idx] * x - self.a1[idx] * y + self.s2[idx];
+                self.s2[idx] = self.b2[idx] * x - self.a2[idx] * y;
+                self.frame[lane] = y;
+            }
+        }
+    }
+
+    // The vector kernels perform the same operations in the same order as the
+    // scalar one (no FMA), so results match it bit for bit
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "sse")]
+    unsafe fn run_frame_sse(&mut self) {
+        use std::arch::x86_64::*;
+
+        for section in 0..self.sections {
+            let offset = section * self.lanes;
+            for lane in (0..self.lanes).step_by(4) {
+                let idx = offset + lane;
+                unsafe {
+                    let x = _mm_loadu_ps(self.frame.as_ptr().add(lane));
+                    let s1 = _mm_loadu_ps(self.s1.as_ptr().add(idx));
+                    let s2 = _mm_loadu_ps(self.s2.as_ptr().add(idx));
+                    let b0 = _mm_loadu_ps(self.b0.as_ptr().add(idx));
+                    let b1 = _mm_loadu_ps(self.b1.as_ptr().add(idx));
+                    let b2 = _mm_loadu_ps(self.b2.as_ptr().add(idx));
+                    let a1 = _mm_loadu_ps(self.a1.as_ptr().add(idx));
+                    let a2 = _mm_loadu_ps(self.a2.as_ptr().add(idx));
+
+                    let y = _mm_add_ps(_mm_mul_ps(b0, x), s1);
+                    let s1 = _mm_add_ps(_mm_sub_ps(_mm_mul_ps(b1, x), _mm_mul_ps(a1, y)), s2);
+                    let s2 = _mm_sub_ps(_mm_mul_ps(b2, x), _mm_mul_ps(a2, y));
+
+                    _mm_storeu_ps(self.s1.as_mut_ptr().add(idx), s1);
+                    _mm_storeu_ps(self.s2.as_mut_ptr().add(idx), s2);
+                    _mm_storeu_ps(self.frame.as_mut_ptr().add(lane), y);
+                }
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn run_frame_avx2(&mut self) {
+        use std::arch::x86_64::*;
+
+        for section in 0..self.sections {
+            let offset = section * self.lanes;
+            for lane in (0..self.lanes).step_by(8) {
+                let idx = offset + lane;
+                unsafe {
+                    let x = _mm256_loadu_ps(self.frame.as_ptr().add(lane));
+                    let s1 = _mm256_loadu_ps(self.s1.as_ptr().add(idx));
+                    let s2 = _mm256_loadu_ps(self.s2.as_ptr().add(idx));
+                    let b0 = _mm256_loadu_ps(self.b0.as_ptr().add(idx));
+                    let b1 = _mm256_loadu_ps(self.b1.as_ptr().add(idx));
+                    let b2 = _mm256_loadu_ps(self.b2.as_ptr().add(idx));
+                    let a1 = _mm256_loadu_ps(self.a1.as_ptr().add(idx));
+                    let a2 = _mm256_loadu_ps(self.a2.as_ptr().add(idx));
+
+                    let y = _mm256_add_ps(_mm256_mul_ps(b0, x), s1);
+                    let s1 = _mm256_add_ps(_mm256_sub_ps(_mm256_mul_ps(b1, x), _mm256_mul_ps(a1, y)), s2);
+                    let s2 = _mm256_sub_ps(_mm256_mul_ps(b2, x), _mm256_mul_ps(a2, y));
+
+                    _mm256_storeu_ps(self.s1.as_mut_ptr().add(idx), s1);
+                    _mm256_storeu_ps(self.s2.as_mut_ptr().add(idx), s2);
+                    _mm256_storeu_ps(self.frame.as_mut_ptr().add(lane), y);
+                }
+            }
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[target_feature(enable = "neon")]
+    unsafe fn run_frame_neon(&mut self) {
+        use std::arch::aarch64::*;
+
+        for section in 0..self.sections {
+            let offset = section * self.lanes;
+            for lane in (0..self.lanes).step_by(4) {
+                let idx = offset + lane;
+                unsafe {
+                    let x = vld1q_f32(self.frame.as_ptr().add(lane));
+                    let s1 = vld1q_f32(self.s1.as_ptr().add(idx));
+                    let s2 = vld1q_f32(self.s2.as_ptr().add(idx));
+                    let b0 = vld1q_f32(self.b0.as_ptr().add(idx));
+                    let b1 = vld1q_f32(self.b1.as_ptr().add(idx));
+                    let b2 = vld1q_f32(self.b2.as_ptr().add(idx));
+                    let a1 = vld1q_f32(self.a1.as_ptr().add(idx));
+                    let a2 = vld1q_f32(self.a2.as_ptr().add(idx));
+
+                    let y = vaddq_f32(vmulq_f32(b0, x), s1);
+                    let s1 = vaddq_f32(vsubq_f32(vmulq_f32(b1, x), vmulq_f32(a1, y)), s2);
+                    let s2 = vsubq_f32(vmulq_f32(b2, x), vmulq_f32(a2, y));
+
+                    vst1q_f32(self.s1.as_mut_ptr().add(idx), s1);
+                    vst1q_f32(self.s2.as_mut_ptr().add(idx), s2);
+                    vst1q_f32(self.frame.as_mut_ptr().add(lane), y);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cascades(channels: usize) -> Vec<Vec<BiquadCoefficients>> {
+        (0..channels)
+            .map(|channel| {
+                (0..=channel % 3)
+                    .map(|section| {
+                        let r = 0.5 + 0.1 * section as f32;
+                        let angle = 0.3 + 0.2 * channel as f32;
+                        BiquadCoefficients {
+                            b0: 0.3,
+                            b1: 0.2 - 0.05 * section as f32,
+                            b2: 0.1,
+                            a1: -2.0 * r * angle.cos(),
+                            a2: r * r,
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn signal(len: usize) -> Vec<f32> {
+        (0..len).map(|n| ((n * 7919 % 2000) as f32 / 1000.0) - 1.0).collect()
+    }
+
+    // Straightforward per-channel cascade, the reference every kernel is held to
+    fn reference(cascades: &[Vec<BiquadCoefficients>], data: &mut [f32]) {
+        let channels = cascades.len();
+        for (channel, cascade) in cascades.iter().enumerate() {
+            let mut state = vec![(0.0f32, 0.0f32); cascade.len()];
+            for frame in data.chunks_mut(channels) {
+                let mut x = frame[channel];
+                for (c, (s1, s2)) in cascade.iter().zip(state.iter_mut()) {
+                    let y = c.b0 * x + *s1;
+                    *s1 = c.b1 * x - c.a1 * y + *s2;
+                    *s2 = c.b2 * x - c.a2 * y;
+                    x = y;
+                }
+                frame[channel] = x;
+            }
+        }
+    }
+
+    #[test]
+    fn every_kernel_matches_the_reference() {
+        for channels in [1, 2, 3, 5, 8, 11] {
+            let cascades = cascades(channels);
+            let mut expected = signal(channels * 500);
+            reference(&cascades, &mut expected);
+            for kernel in Kernel::available() {
+                let mut data = signal(channels * 500);
+                BiquadBank::with_kernel(&cascades, kernel).process_interleaved(&mut data);
+                assert_eq!(data, expected, "{:?} with {} channels", kernel, channels);
+            }
+        }
+    }
+
+    #[test]
+    fn lanes_follow_the_channel_count() {
+        let widest = Kernel::detect();
+        let mono = BiquadBank::with_kernel(&cascades(1), widest);
+        assert_eq!((mono.kernel, mono.lanes), (Kernel::Scalar, 1));
+        let stereo = BiquadBank::with_kernel(&cascades(2), widest);
+        assert_eq!(stereo.lanes, widest.lanes().min(4));
+        let many = BiquadBank::with_kernel(&cascades(9), widest);
+        assert_eq!(many.kernel, widest);
+        assert_eq!(many.lanes, 9usize.div_ceil(widest.lanes()) * widest.lanes());
+    }
+}
@@ -0,0 +1,201 @@
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use std::sync::Arc;
+
+use super::fir::FirFilter;
+
+// Taps per partition. The first partition runs in the time domain, so this is
+// also the per-sample cost of the head and the block size of the FFT part.
+pub const PARTITION: usize = 256;
+
+// Streaming convolution with long kernels (room correction, linear-phase FIRs) for
+// one channel. Uniformly partitioned overlap-save: the kernel is cut into
+// PARTITION-tap pieces, the first is a plain FIR and the rest are multiplied with
+// the spectra of past input blocks. The FFT part only needs input that is at
+// least one partition old, so nothing is delayed and the output matches FirFilter.
+pub struct PartitionedConvolver {
+    head: FirFilter,
+    // Spectra of the zero-padded tail partitions, 2 * PARTITION bins each
+    partitions: Vec<Vec<Complex<f32>>>,
+    // Spectra of the last input windows, newest at `newest`
+    history: Vec<Vec<Complex<f32>>>,
+    newest: usize,
+    forward: Arc<dyn Fft<f32>>,
+    inverse: Arc<dyn Fft<f32>>,
+    // Previous and current input block
+    window: Vec<f32>,
+    // Tail contribution to the current output block
+    tail: Vec<f32>,
+    // Samples of the current block seen so far
+    position: usize,
+    spectrum: Vec<Complex<f32>>,
+    accumulator: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    head_output: Vec<f32>,
+}
+
+impl PartitionedConvolver {
+    // Plans the FFTs and transforms the kernel, so this belongs on the control thread
+    pub fn new(coefficients: &[f32]) -> Self {
+        let size = 2 * PARTITION;
+        let mut planner = FftPlanner::new();
+        let forward = planner.plan_fft_forward(size);
+        let inverse = planner.plan_fft_inverse(size);
+
+        let head = &coefficients[..coefficients.len().min(PARTITION)];
+        let partitions: Vec<Vec<Complex<f32>>> = coefficients
+            .get(PARTITION..)
+            .unwrap_or_default()
+            .chunks(PARTITION)
+            .map(|taps| {
+                // Scaled here so the inverse transform needs no normalization pass
+                let mut spectrum: Vec<Complex<f32>> =
+                    taps.iter().map(|&tap| Complex::new(tap / size as f32, 0.0)).collect();
+                spectrum.resize(size, Complex::new(0.0, 0.0));
+                forward.process(&mut spectrum);
+                spectrum
+            })
+            .collect();
+        let scratch_len = forward
+            .get_inplace_scratch_len()
+            .max(inverse.get_inplace_scratch_len());
+
+        PartitionedConvolver {
+            head: FirFilter::new(head),
+            history: vec![vec![Complex::new(0.0, 0.0); size]; partitions.len()],
+            partitions,
+            newest: 0,
+            forward,
+            inverse,
+            window: vec![0.0; size],
+            tail: vec![0.0; PARTITION],
+            position: 0,
+            spectrum: vec![Complex::new(0.0, 0.0); size],
+            accumulator: vec![Complex::new(0.0, 0.0); size],
+            scratch: vec![Complex::new(0.0, 0.0); scratch_len],
+            head_output: Vec::new(),
+        }
+    }
+
+    // Filter `input` into `output`, both of the same length. Allocates only while a
+    // block is longer than any before it.
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        if self.partitions.is_empty() {
+            self.head.process(input, output);
+            return;
+        }
+        self.head_output.resize(input.len(), 0.0);
+        self.head.process(input, &mut self.head_output);
+
+        let mut done = 0;
+        while done < input.len() {
+            let count = (PARTITION - self.position).min(input.len() - done);
+            let block = done..done + count;
+            let slots = self.position..self.position + count;
+            self.window[PARTITION + slots.start..PARTITION + slots.end].copy_from_slice(&input[block.clone()]);
+            for ((out, head), tail) in output[block].iter_mut().zip(&self.head_output[done..]).zip(&self.tail[slots]) {
+                *out = head + tail;
+            }
+            self.position += count;
+            done += count;
+            if self.position == PARTITION {
+                self.next_block();
+            }
+        }
+    }
+
+    // A whole input block is in: its window goes into the history and the tail of
+    // the next output block is computed from it and the older ones
+    fn next_block(&mut self) {
+        let count = self.partitions.len();
+        self.newest = (self.newest + 1) % count;
+        let spectrum = &mut self.history[self.newest];
+        for (bin, &sample) in spectrum.iter_mut().zip(&self.window) {
+            *bin = Complex::new(sample, 0.0);
+        }
+        self.forward.process_with_scratch(spectrum, &mut self.scratch);
+
+        // Partition p meets the window from p blocks ago
+        self.accumulator.fill(Complex::new(0.0, 0.0));
+        for (p, partition) in self.partitions.iter().enumerate() {
+            let past = &self.history[(self.newest + count - p) % count];
+            for ((acc, x), h) in self.accumulator.iter_mut().zip(past).zip(partition) {
+                *acc += x * h;
+            }
+        }
+        self.spectrum.copy_from_slice(&self.accumulator);
+        self.inverse.process_with_scratch(&mut self.spectrum, &mut self.scratch);
+        for (tail, bin) in self.tail.iter_mut().zip(&self.spectrum[PARTITION..]) {
+            *tail = bin.re;
+        }
+
+        self.window.copy_within(PARTITION.., 0);
+        self.position = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(len: usize, seed: u32) -> Vec<f32> {
+        let mut state = seed.max(1);
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state as f32 / u32::MAX as f32) * 2.0 - 1.0
+            })
+            .collect()
+    }
+
+    fn direct(kernel: &[f32], input: &[f32]) -> Vec<f32> {
+        (0..input.len())
+            .map(|n| {
+                (0..kernel.len().min(n + 1))
+                    .map(|k| kernel[k] as f64 * input[n - k] as f64)
+                    .sum::<f64>() as f32
+            })
+            .collect()
+    }
+
+    // Odd block sizes so blocks straddle the partition boundaries
+    fn convolve(kernel: &[f32], input: &[f32], block: usize) -> Vec<f32> {
+        let mut convolver = PartitionedConvolver::new(kernel);
+        let mut output = vec![0.0; input.len()];
+        for (input, output) in input.chunks(block).zip(output.chunks_mut(block)) {
+            convolver.process(input, output);
+        }
+        output
+    }
+
+    fn max_diff(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).fold(0.0, |max, (x, y)| max.max((x - y).abs()))
+    }
+
+    #[test]
+    fn matches_direct_convolution() {
+        let input = noise(4000, 3);
+        for taps in [1, 100, PARTITION, PARTITION + 1, 5 * PARTITION + 37] {
+            let kernel: Vec<f32> = noise(taps, 9).iter().map(|tap| tap / (taps as f32).sqrt()).collect();
+            let expected = direct(&kernel, &input);
+            for block in [1, 64, 300, 1024] {
+                let diff = max_diff(&convolve(&kernel, &input, block), &expected);
+                assert!(diff < 1e-4, "{} taps in blocks of {}: off by {}", taps, block, diff);
+            }
+        }
+    }
+
+    #[test]
+    fn delayed_impulse_comes_out_unchanged() {
+        let mut kernel = vec![0.0; 3 * PARTITION];
+        kernel[2 * PARTITION + 5] = 1.0;
+        let mut input = vec![0.0; 2000];
+        input[10] = 0.5;
+        let output = convolve(&kernel, &input, 128);
+        assert!((output[10 + 2 * PARTITION + 5] - 0.5).abs() < 1e-5);
+        let energy: f32 = output.iter().map(|x| x * x).sum();
+        assert!((energy - 0.25).abs() < 1e-4);
+    }
+}
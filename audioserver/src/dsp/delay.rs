@@ -0,0 +1,29 @@
+// Integer sample delay for one channel
+#[derive(Debug, Clone)]
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    pos: usize,
+}
+
+impl DelayLine {
+    pub fn new(samples: usize) -> Self {
+        DelayLine {
+            buffer: vec![0.0; samples],
+            pos: 0,
+        }
+    }
+
+    pub fn samples(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn process(&mut self, data: &mut [f32]) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        for sample in data.iter_mut() {
+            std::mem::swap(&mut self.buffer[self.pos], sample);
+            self.pos = (self.pos + 1) % self.buffer.len();
+        }
+    }
+}
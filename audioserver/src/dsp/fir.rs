@@ -0,0 +1,147 @@
+use super::Kernel;
+
+// Streaming FIR filter for one channel, keeps its history across blocks
+#[derive(Debug, Clone)]
+pub struct FirFilter {
+    // Coefficients reversed so every output sample is a plain dot product
+    reversed: Vec<f32>,
+    // Last taps-1 input samples followed by the current block
+    buffer: Vec<f32>,
+    kernel: Kernel,
+}
+
+impl FirFilter {
+    pub fn new(coefficients: &[f32]) -> Self {
+        FirFilter::with_kernel(coefficients, Kernel::detect())
+    }
+
+    pub fn with_kernel(coefficients: &[f32], kernel: Kernel) -> Self {
+        FirFilter {
+            reversed: coefficients.iter().rev().copied().collect(),
+            buffer: vec![0.0; coefficients.len().saturating_sub(1)],
+            kernel: kernel.or_supported(),
+        }
+    }
+
+    // Filter `input` into `output`, both of the same length
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        let taps = self.reversed.len();
+        if taps == 0 {
+            output.fill(0.0);
+            return;
+        }
+
+        let history = taps - 1;
+        self.buffer.truncate(history);
+        self.buffer.extend_from_slice(input);
+
+        for (i, out) in output.iter_mut().enumerate().take(input.len()) {
+            *out = dot(self.kernel, &self.buffer[i..i + taps], &self.reversed);
+        }
+
+        let len = self.buffer.len();
+        self.buffer.copy_within(len - history.., 0);
+        self.buffer.truncate(history);
+    }
+}
+
+pub fn dot(kernel: Kernel, a: &[f32], b: &[f32]) -> f32 {
+    match kernel {
+        Kernel::Scalar => dot_scalar(a, b),
+        // Safety: kernels are only constructed through `or_supported`/`detect`
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Sse => unsafe { dot_sse(a, b) },
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Avx2 => unsafe { dot_avx2(a, b) },
+        #[cfg(target_arch = "aarch64")]
+        Kernel::Neon => unsafe { dot_neon(a, b) },
+    }
+}
+
+fn dot_scalar(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse")]
+unsafe fn dot_sse(a: &[f32], b: &[f32]) -> f32 {
+    use std::arch::x86_64::*;
+
+    let n = a.len().min(b.len());
+    let chunks = n / 4;
+    let mut lanes = [0.0f32; 4];
+    unsafe {
+        let mut acc = _mm_setzero_ps();
+        for i in 0..chunks {
+            let x = _mm_loadu_ps(a.as_ptr().add(i * 4));
+            let y = _mm_loadu_ps(b.as_ptr().add(i * 4));
+            acc = _mm_add_ps(acc, _mm_mul_ps(x, y));
+        }
+        _mm_storeu_ps(lanes.as_mut_ptr(), acc);
+    }
+    lanes.iter().sum::<f32>() + dot_scalar(&a[chunks * 4..n], &b[chunks * 4..n])
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn dot_avx2(a: &[f32], b: &[f32]) -> f32 {
+    use std::arch::x86_64::*;
+
+    let n = a.len().min(b.len());
+    let chunks = n / 8;
+    let mut lanes = [0.0f32; 8];
+    unsafe {
+        let mut acc = _mm256_setzero_ps();
+        for i in 0..chunks {
+            let x = _mm256_loadu_ps(a.as_ptr().add(i * 8));
+            let y = _mm256_loadu_ps(b.as_ptr().add(i * 8));
+            acc = _mm256_add_ps(acc, _mm256_mul_ps(x, y));
+        }
+        _mm256_storeu_ps(lanes.as_mut_ptr(), acc);
+    }
+    lanes.iter().sum::<f32>() + dot_scalar(&a[chunks * 8..n], &b[chunks * 8..n])
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn dot_neon(a: &[f32], b: &[f32]) -> f32 {
+    use std::arch::aarch64::*;
+
+    let n = a.len().min(b.len());
+    let chunks = n / 4;
+    let sum = unsafe {
+        let mut acc = vdupq_n_f32(0.0);
+        for i in 0..chunks {
+            let x = vld1q_f32(a.as_ptr().add(i * 4));
+            let y = vld1q_f32(b.as_ptr().add(i * 4));
+            acc = vaddq_f32(acc, vmulq_f32(x, y));
+        }
+        vaddvq_f32(acc)
+    };
+    sum + dot_scalar(&a[chunks * 4..n], &b[chunks * 4..n])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_kernel_matches_direct_convolution() {
+        let input: Vec<f32> = (0..1000).map(|n| ((n * 7919 % 2000) as f32 / 1000.0) - 1.0).collect();
+        for taps in [1, 3, 8, 37, 256] {
+            let coefficients: Vec<f32> = (0..taps).map(|k| 1.0 / (k + 2) as f32).collect();
+            let expected: Vec<f32> = (0..input.len())
+                .map(|n| (0..taps.min(n + 1)).map(|k| coefficients[k] as f64 * input[n - k] as f64).sum::<f64>() as f32)
+                .collect();
+            for kernel in Kernel::available() {
+                let mut filter = FirFilter::with_kernel(&coefficients, kernel);
+                let mut output = vec![0.0; input.len()];
+                for (input, output) in input.chunks(97).zip(output.chunks_mut(97)) {
+                    filter.process(input, output);
+                }
+                let diff = output.iter().zip(&expected).fold(0.0f32, |max, (a, b)| max.max((a - b).abs()));
+                assert!(diff < 1e-5, "{:?} with {} taps: off by {}", kernel, taps, diff);
+            }
+        }
+    }
+}
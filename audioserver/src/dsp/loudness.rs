@@ -0,0 +1,221 @@
+use std::f64::consts::PI;
+
+use super::biquad::{BiquadBank, BiquadCoefficients};
+
+// Gating of ITU-R BS.1770-4: 400 ms blocks every 100 ms, an absolute gate at
+// -70 LUFS and a relative one 10 LU below the level of what passed it
+const SUB_BLOCKS: usize = 4;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+fn lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+// Pre-filter and RLB high-pass of the K-weighting, derived for any rate so
+// they match the coefficients the standard tabulates for 48 kHz
+fn k_weighting(sample_rate: u32) -> Vec<BiquadCoefficients> {
+    let rate = sample_rate as f64;
+
+    let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = BiquadCoefficients {
+        b0: ((vh + vb * k / q + k * k) / a0) as f32,
+        b1: (2.0 * (k * k - vh) / a0) as f32,
+        b2: ((vh - vb * k / q + k * k) / a0) as f32,
+        a1: (2.0 * (k * k - 1.0) / a0) as f32,
+        a2: ((1.0 - k / q + k * k) / a0) as f32,
+    };
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = BiquadCoefficients {
+        b0: 1.0,
+        b1: -2.0,
+        b2: 1.0,
+        a1: (2.0 * (k * k - 1.0) / a0) as f32,
+        a2: ((1.0 - k / q + k * k) / a0) as f32,
+    };
+    vec![shelf, highpass]
+}
+
+// Surround channels count 1.5 dB more, the LFE not at all
+fn channel_weight(position: &str) -> f64 {
+    match position {
+        "BL" | "BR" | "SL" | "SR" => 1.41,
+        "LFE" | "LFE2" => 0.0,
+        _ => 1.0,
+    }
+}
+
+// Result of measuring a track, kept as gating blocks so several tracks can be
+// gated together for an album
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoudnessMeasurement {
+    // Channel-weighted mean square of every 400 ms block
+    pub blocks: Vec<f64>,
+    // Highest absolute sample value
+    pub peak: f32,
+}
+
+impl LoudnessMeasurement {
+    pub fn integrated_lufs(&self) -> Option<f64> {
+        integrated_lufs(&[self])
+    }
+}
+
+// Integrated loudness over the blocks of all measurements, None for silence
+pub fn integrated_lufs(measurements: &[&LoudnessMeasurement]) -> Option<f64> {
+    let gated: Vec<f64> = measurements
+        .iter()
+        .flat_map(|measurement| measurement.blocks.iter().copied())
+        .filter(|&power| power > 0.0 && lufs(power) > ABSOLUTE_GATE)
+        .collect();
+    if gated.is_empty() {
+        return None;
+    }
+    let threshold = lufs(gated.iter().sum::<f64>() / gated.len() as f64) + RELATIVE_GATE;
+    let (sum, count) = gated
+        .iter()
+        .filter(|&&power| lufs(power) > threshold)
+        .fold((0.0, 0usize), |(sum, count), &power| (sum + power, count + 1));
+    (count > 0).then(|| lufs(sum / count as f64))
+}
+
+// EBU R128 / BS.1770 integrated loudness meter for interleaved audio
+pub struct LoudnessMeter {
+    channels: usize,
+    weights: Vec<f64>,
+    filter: BiquadBank,
+    // Frames per 100 ms sub-block
+    sub_block_frames: usize,
+    filled: usize,
+    // Per channel sum of squares of the sub-block being filled
+    sums: Vec<f64>,
+    sub_blocks: Vec<f64>,
+    peak: f32,
+    scratch: Vec<f32>,
+}
+
+impl LoudnessMeter {
+    // `layout` names the speaker of every channel, all count fully when it is empty
+    pub fn new(sample_rate: u32, channels: usize, layout: &[&str]) -> Self {
+        let weights = (0..channels)
+            .map(|ch| layout.get(ch).map_or(1.0, |position| channel_weight(position)))
+            .collect();
+        LoudnessMeter {
+            channels,
+            weights,
+            filter: BiquadBank::new(&vec![k_weighting(sample_rate); channels]),
+            sub_block_frames: (sample_rate as usize / 10).max(1),
+            filled: 0,
+            sums: vec![0.0; channels],
+            sub_blocks: Vec::new(),
+            peak: 0.0,
+            scratch: Vec::new(),
+        }
+    }
+
+    pub fn process(&mut self, data: &[f32]) {
+        self.peak = data.iter().fold(self.peak, |peak, sample| peak.max(sample.abs()));
+        self.scratch.clear();
+        self.scratch.extend_from_slice(data);
+        self.filter.process_interleaved(&mut self.scratch);
+
+        for frame in self.scratch.chunks_exact(self.channels) {
+            for (sum, &sample) in self.sums.iter_mut().zip(frame) {
+                *sum += sample as f64 * sample as f64;
+            }
+            self.filled += 1;
+            if self.filled == self.sub_block_frames {
+                let power = self
+                    .sums
+                    .iter()
+                    .zip(&self.weights)
+                    .map(|(sum, weight)| weight * sum / self.sub_block_frames as f64)
+                    .sum();
+                self.sub_blocks.push(power);
+                self.sums.fill(0.0);
+                self.filled = 0;
+            }
+        }
+    }
+
+    // Gating blocks and peak of everything processed, a trailing partial sub-block is left out
+    pub fn finish(self) -> LoudnessMeasurement {
+        let blocks = self
+            .sub_blocks
+            .windows(SUB_BLOCKS)
+            .map(|window| window.iter().sum::<f64>() / SUB_BLOCKS as f64)
+            .collect();
+        LoudnessMeasurement {
+            blocks,
+            peak: self.peak,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Mean square of a block at `level` LUFS
+    fn power(level: f64) -> f64 {
+        10f64.powf((level + 0.691) / 10.0)
+    }
+
+    #[test]
+    fn sine_at_minus_20_dbfs_reads_minus_20_lufs() {
+        // The K-weighting is flat enough at 997 Hz that two channels of a sine
+        // read its peak level, whatever the rate
+        for rate in [44100, 48000, 96000] {
+            let mut meter = LoudnessMeter::new(rate, 2, &["FL", "FR"]);
+            let samples: Vec<f32> = (0..5 * rate as usize)
+                .flat_map(|n| {
+                    let sample = 0.1 * (2.0 * PI * 997.0 * n as f64 / rate as f64).sin() as f32;
+                    [sample, sample]
+                })
+                .collect();
+            for block in samples.chunks(2 * 1000) {
+                meter.process(block);
+            }
+            let measurement = meter.finish();
+            assert_eq!(measurement.blocks.len(), 47);
+            assert!((measurement.peak - 0.1).abs() < 1e-4, "peak {}", measurement.peak);
+            let lufs = measurement.integrated_lufs().unwrap();
+            assert!((lufs + 20.0).abs() < 0.05, "{} Hz reads {} LUFS", rate, lufs);
+        }
+    }
+
+    #[test]
+    fn gates_drop_silence_and_quiet_blocks() {
+        let measurement = |levels: &[f64]| LoudnessMeasurement {
+            blocks: levels.iter().map(|&level| if level.is_finite() { power(level) } else { 0.0 }).collect(),
+            peak: 1.0,
+        };
+        let loud = measurement(&[-20.0; 4]);
+        assert!((loud.integrated_lufs().unwrap() + 20.0).abs() < 1e-9);
+
+        // Digital silence and blocks under -70 LUFS are below the absolute gate,
+        // -35 LUFS is more than 10 LU under the -20 LUFS that passed it
+        let gated = measurement(&[-20.0, f64::NEG_INFINITY, -80.0, -35.0, -20.0, -35.0]);
+        assert!((gated.integrated_lufs().unwrap() + 20.0).abs() < 1e-9);
+
+        // Within 10 LU both count, as the mean of their powers
+        let mixed = measurement(&[-20.0, -25.0]);
+        let expected = lufs((power(-20.0) + power(-25.0)) / 2.0);
+        assert!((mixed.integrated_lufs().unwrap() - expected).abs() < 1e-9);
+
+        // Albums gate the blocks of all their tracks together
+        let quiet = measurement(&[-35.0; 4]);
+        assert!((quiet.integrated_lufs().unwrap() + 35.0).abs() < 1e-9);
+        assert!((integrated_lufs(&[&loud, &quiet]).unwrap() + 20.0).abs() < 1e-9);
+
+        assert_eq!(measurement(&[f64::NEG_INFINITY, -75.0]).integrated_lufs(), None);
+        assert_eq!(LoudnessMeasurement::default().integrated_lufs(), None);
+    }
+}
@@ -0,0 +1,88 @@
+pub mod biquad;
+pub mod convolver;
+pub mod delay;
+pub mod fir;
+pub mod loudness;
+pub mod resample;
+
+use std::sync::OnceLock;
+
+// Instruction set used by the block kernels, detected once at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kernel {
+    Scalar,
+    #[cfg(target_arch = "x86_64")]
+    Sse,
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
+
+impl Kernel {
+    // Fastest kernel the running CPU supports
+    pub fn detect() -> Self {
+        static DETECTED: OnceLock<Kernel> = OnceLock::new();
+        *DETECTED.get_or_init(|| {
+            Kernel::available()
+                .into_iter()
+                .last()
+                .unwrap_or(Kernel::Scalar)
+        })
+    }
+
+    // Every kernel usable on this CPU, slowest first
+    pub fn available() -> Vec<Kernel> {
+        let mut kernels = vec![Kernel::Scalar];
+        #[cfg(target_arch = "x86_64")]
+        {
+            if std::arch::is_x86_feature_detected!("sse") {
+                kernels.push(Kernel::Sse);
+            }
+            if std::arch::is_x86_feature_detected!("avx2") {
+                kernels.push(Kernel::Avx2);
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            if std::arch::is_aarch64_feature_detected!("neon") {
+                kernels.push(Kernel::Neon);
+            }
+        }
+        kernels
+    }
+
+    // f32 values processed per instruction
+    pub fn lanes(self) -> usize {
+        match self {
+            Kernel::Scalar => 1,
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Sse => 4,
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => 8,
+            #[cfg(target_arch = "aarch64")]
+            Kernel::Neon => 4,
+        }
+    }
+
+    // Kernel for `channels` values side by side: the fewest passes and, among
+    // those, the fewest padding lanes, so mono or stereo doesn't run 8 lanes.
+    // Never wider than `self`.
+    pub fn for_channels(self, channels: usize) -> Self {
+        let widest = self.or_supported().lanes();
+        Kernel::available()
+            .into_iter()
+            .filter(|kernel| kernel.lanes() <= widest)
+            .min_by_key(|kernel| (channels.max(1).div_ceil(kernel.lanes()), kernel.lanes()))
+            .unwrap_or(Kernel::Scalar)
+    }
+
+    // Falls back to scalar for kernels the CPU can't run, so dispatch never hits illegal instructions
+    pub fn or_supported(self) -> Self {
+        if Kernel::available().contains(&self) {
+            self
+        } else {
+            Kernel::Scalar
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::f64::consts::PI;
+
+// Zero crossings on each side of the sinc, and kernel phases between two input samples
+const HALF_WIDTH: usize = 32;
+const PHASES: usize = 256;
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-12 { 1.0 } else { (PI * x).sin() / (PI * x) }
+}
+
+// Band-limited sample rate conversion of an interleaved stream, block by block.
+// The kernel is tabulated per phase and interpolated between phases, so any
+// ratio works. Equal rates pass through untouched.
+pub struct StreamResampler {
+    channels: usize,
+    // Input frames per output frame
+    step: f64,
+    // Input frames on each side of the output position
+    half: usize,
+    // (PHASES + 1) rows of 2 * half taps
+    table: Vec<f32>,
+    history: Vec<f32>,
+    // Position of the next output frame in `history`, in input frames
+    position: f64,
+    bypass: bool,
+}
+
+impl StreamResampler {
+    pub fn new(from_rate: u32, to_rate: u32, channels: usize) -> Self {
+        let ratio = to_rate as f64 / from_rate.max(1) as f64;
+        // Below 1 when downsampling, the kernel then also low-passes at the new Nyquist
+        let cutoff = ratio.min(1.0);
+        let half = (HALF_WIDTH as f64 / cutoff).ceil() as usize;
+        let taps = 2 * half;
+
+        let mut table = vec![0.0; (PHASES + 1) * taps];
+        for phase in 0..=PHASES {
+            let fraction = phase as f64 / PHASES as f64;
+            for tap in 0..taps {
+                // Distance from the output position to the input sample under this tap
+                let x = fraction + (half - 1) as f64 - tap as f64;
+                let window = if x.abs() < half as f64 { 0.5 + 0.5 * (PI * x / half as f64).cos() } else { 0.0 };
+                table[phase * taps + tap] = (cutoff * sinc(cutoff * x) * window) as f32;
+            }
+        }
+
+        StreamResampler {
+            channels,
+            step: 1.0 / ratio,
+            half,
+            table,
+            // Silence before the first sample, so output frame 0 lines up with input frame 0
+            history: vec![0.0; (half - 1) * channels],
+            position: (half - 1) as f64,
+            bypass: from_rate == to_rate,
+        }
+    }
+
+    // Delay the kernel look-ahead adds, in output frames
+    pub fn delay_frames(&self) -> f32 {
+        if self.bypass { 0.0 } else { (self.half as f64 / self.step) as f32 }
+    }
+
+    // Resample `input` and append the result to `output`. Output lags by the
+    // kernel half width until `flush`.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        if self.bypass {
+            output.extend_from_slice(input);
+            return;
+        }
+        let channels = self.channels;
+        let taps = 2 * self.half;
+        self.history.extend_from_slice(input);
+        let frames = self.history.len() / channels;
+
+        while (self.position as usize) + self.half < frames {
+            let index = self.position as usize;
+            let phase = (self.position - index as f64) * PHASES as f64;
+            let row = phase as usize;
+            let weight = (phase - row as f64) as f32;
+            let (low, high) = (
+                &self.table[row * taps..(row + 1) * taps],
+                &self.table[(row + 1) * taps..(row + 2) * taps],
+            );
+            let first = index + 1 - self.half;
+            for channel in 0..channels {
+                let mut sum = 0.0;
+                for tap in 0..taps {
+                    let coefficient = low[tap] + (high[tap] - low[tap]) * weight;
+                    sum += self.history[(first + tap) * channels + channel] * coefficient;
+                }
+                output.push(sum);
+            }
+            self.position += self.step;
+        }
+
+        // Drop input no future output frame reaches back to
+        let consumed = (self.position as usize + 1).saturating_sub(self.half);
+        self.history.drain(..consumed * channels);
+        self.position -= consumed as f64;
+    }
+
+    // Emit what is still held back, e.g. at the end of a track
+    pub fn flush(&mut self, output: &mut Vec<f32>) {
+        if self.bypass {
+            return;
+        }
+        let silence = vec![0.0; self.half * self.channels];
+        self.process(&silence, output);
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.history.resize((self.half - 1) * self.channels, 0.0);
+        self.position = (self.half - 1) as f64;
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::Result;
+
+use crate::crossover::FilterSpec;
+use crate::dsp::biquad::BiquadBank;
+use crate::dsp::delay::DelayLine;
+
+// Filters, gain and delay for one channel
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChannelEq {
+    pub filters: Vec<FilterSpec>,
+    pub gain_db: f32,
+    pub delay_ms: f32,
+    pub invert: bool,
+}
+
+impl ChannelEq {
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty() && self.gain_db == 0.0 && self.delay_ms == 0.0 && !self.invert
+    }
+}
+
+// Parametric EQ on the source channels, before the crossover. Everything in it
+// is linear and time invariant, so `all` and the per-channel settings can be
+// combined in any order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Equalizer {
+    // Applied to every channel
+    pub all: ChannelEq,
+    // Per-channel additions, channels past the end only get `all`
+    pub channels: Vec<ChannelEq>,
+}
+
+impl Equalizer {
+    pub fn channel_mut(&mut self, channel: usize) -> &mut ChannelEq {
+        if self.channels.len() <= channel {
+            self.channels.resize(channel + 1, ChannelEq::default());
+        }
+        &mut self.channels[channel]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.all.is_empty() && self.channels.iter().all(ChannelEq::is_empty)
+    }
+
+    fn delay_samples(&self, channel: usize, sample_rate: u32) -> usize {
+        let extra = self.channels.get(channel).map(|eq| eq.delay_ms).unwrap_or(0.0);
+        ((self.all.delay_ms + extra).max(0.0) * 1e-3 * sample_rate as f32).round() as usize
+    }
+
+    pub fn latency_frames(&self, channel: usize, sample_rate: u32) -> f32 {
+        self.delay_samples(channel, sample_rate) as f32
+    }
+}
+
+// Realtime state of the equalizer for interleaved audio
+pub struct EqStage {
+    biquads: BiquadBank,
+    gains: Vec<f32>,
+    delays: Vec<DelayLine>,
+    planar: Vec<f32>,
+}
+
+impl EqStage {
+    pub fn new(eq: &Equalizer, channels: usize, sample_rate: u32) -> Result<Self> {
+        let mut cascades = Vec::with_capacity(channels);
+        let mut gains = Vec::with_capacity(channels);
+        let mut delays = Vec::with_capacity(channels);
+        for channel in 0..channels {
+            let extra = eq.channels.get(channel).cloned().unwrap_or_default();
+            let mut sections = Vec::new();
+            for spec in eq.all.filters.iter().chain(&extra.filters) {
+                sections.extend(spec.sections(sample_rate)?);
+            }
+            cascades.push(sections);
+
+            let polarity = if eq.all.invert != extra.invert { -1.0 } else { 1.0 };
+            gains.push(polarity * 10f32.powf((eq.all.gain_db + extra.gain_db) / 20.0));
+            delays.push(DelayLine::new(eq.delay_samples(channel, sample_rate)));
+        }
+
+        Ok(EqStage {
+            biquads: BiquadBank::new(&cascades),
+            gains,
+            delays,
+            planar: Vec::new(),
+        })
+    }
+
+    pub fn process(&mut self, data: &mut [f32]) {
+        let channels = self.gains.len();
+        if channels == 0 {
+            return;
+        }
+        self.biquads.process_interleaved(data);
+
+        for frame in data.chunks_mut(channels) {
+            for (sample, gain) in frame.iter_mut().zip(&self.gains) {
+                *sample *= gain;
+            }
+        }
+
+        let frames = data.len() / channels;
+        for (ch, delay) in self.delays.iter_mut().enumerate() {
+            if delay.samples() == 0 {
+                continue;
+            }
+            self.planar.clear();
+            self.planar.extend((0..frames).map(|frame| data[frame * channels + ch]));
+            delay.process(&mut self.planar);
+            for (frame, sample) in self.planar.iter().enumerate() {
+                data[frame * channels + ch] = *sample;
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::{anyhow, bail, Result};
+
+use crate::dsp::convolver::PartitionedConvolver;
+use crate::dsp::fir::FirFilter;
+
+#[derive(Debug, Clone)]
+pub struct AudioFilter {
+    fir_coefficients: Vec<f32>,
+}
+
+impl AudioFilter {
+    pub fn new(coefficients: Vec<f32>) -> Self {
+        AudioFilter { fir_coefficients: coefficients }
+    }
+
+    // Taps separated by whitespace or commas, e.g. a coefficient per line as FIR
+    // designers export them. Lines starting with # or ; are comments.
+    pub fn parse(text: &str) -> Result<Self> {
+        let coefficients = text
+            .lines()
+            .filter(|line| !line.trim_start().starts_with(['#', ';']))
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|tap| !tap.is_empty())
+            .map(|tap| {
+                let value = tap.parse::<f32>().ok().filter(|value| value.is_finite());
+                value.ok_or_else(|| anyhow!("Bad FIR tap {}", tap))
+            })
+            .collect::<Result<Vec<f32>>>()?;
+        if coefficients.is_empty() {
+            bail!("No FIR taps found");
+        }
+        Ok(AudioFilter::new(coefficients))
+    }
+
+    // Group delay in samples if the coefficients are (anti)symmetric, i.e. linear phase
+    pub fn linear_phase_delay(&self) -> Option<f32> {
+        let c = &self.fir_coefficients;
+        let n = c.len();
+        let tolerance = 1e-6 * c.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+        let symmetric = (0..n / 2).all(|i| (c[i] - c[n - 1 - i]).abs() <= tolerance);
+        let antisymmetric = (0..n / 2).all(|i| (c[i] + c[n - 1 - i]).abs() <= tolerance);
+        if n > 0 && (symmetric || antisymmetric) {
+            Some((n - 1) as f32 / 2.0)
+        } else {
+            None
+        }
+    }
+
+    pub fn coefficients(&self) -> &[f32] {
+        &self.fir_coefficients
+    }
+}
+
+// Time-domain FIR for short kernels, partitioned FFT convolution for long ones
+enum ChannelFilter {
+    Direct(FirFilter),
+    Partitioned(Box<PartitionedConvolver>),
+}
+
+impl ChannelFilter {
+    fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        match self {
+            ChannelFilter::Direct(fir) => fir.process(input, output),
+            ChannelFilter::Partitioned(convolver) => convolver.process(input, output),
+        }
+    }
+}
+
+// Streaming version of an AudioFilter for interleaved audio, one FIR per channel
+pub struct FilterStage {
+    // None passes the channel through
+    filters: Vec<Option<ChannelFilter>>,
+    input: Vec<f32>,
+    output: Vec<f32>,
+}
+
+impl FilterStage {
+    pub fn new(filter: &AudioFilter, channels: usize) -> Self {
+        FilterStage {
+            filters: (0..channels)
+                .map(|_| Some(ChannelFilter::Direct(FirFilter::new(filter.coefficients()))))
+                .collect(),
+            input: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    // A different kernel for every channel, convolved in partitions. For room correction,
+    // phase linearization and other kernels of thousands of taps, which a time-domain
+    // FIR cannot keep up with.
+    pub fn partitioned(kernels: &[Option<Vec<f32>>]) -> Self {
+        FilterStage {
+            filters: kernels
+                .iter()
+                .map(|kernel| {
+                    kernel
+                        .as_ref()
+                        .map(|coefficients| ChannelFilter::Partitioned(Box::new(PartitionedConvolver::new(coefficients))))
+                })
+                .collect(),
+            input: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn process(&mut self, data: &mut [f32]) {
+        let channels = self.filters.len();
+        if channels == 0 {
+            return;
+        }
+        let frames = data.len() / channels;
+        self.input.resize(frames, 0.0);
+        self.output.resize(frames, 0.0);
+
+        for (ch, fir) in self.filters.iter_mut().enumerate() {
+            let Some(fir) = fir else {
+                continue;
+            };
+            for (frame, sample) in self.input.iter_mut().enumerate() {
+                *sample = data[frame * channels + ch];
+            }
+            fir.process(&self.input, &mut self.output);
+            for (frame, sample) in self.output.iter().enumerate() {
+                data[frame * channels + ch] = *sample;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn taps_from_text() {
+        let filter = AudioFilter::parse("# rePhase export\n0.25\n0.5, 0.25\n\n").unwrap();
+        assert_eq!(filter.coefficients(), &[0.25, 0.5, 0.25]);
+        assert_eq!(filter.linear_phase_delay(), Some(1.0));
+        assert!(AudioFilter::parse("; nothing").is_err());
+        assert!(AudioFilter::parse("0.5 half").is_err());
+    }
+
+    #[test]
+    fn partitioned_stage_filters_each_channel_with_its_kernel() {
+        // Long enough for the FFT part, channel 1 delayed by 300 frames, channel 2 bypassed
+        let mut delay = vec![0.0; 400];
+        delay[300] = 1.0;
+        let mut stage = FilterStage::partitioned(&[Some(vec![0.5]), Some(delay), None]);
+        let frames = 1000;
+        let mut data: Vec<f32> = (0..frames * 3).map(|i| ((i / 3) % 97) as f32 / 97.0).collect();
+        let input = data.clone();
+        for block in data.chunks_mut(3 * 160) {
+            stage.process(block);
+        }
+        for frame in 0..frames {
+            let x = input[frame * 3];
+            assert!((data[frame * 3] - 0.5 * x).abs() < 1e-6);
+            let delayed = if frame >= 300 { input[(frame - 300) * 3 + 1] } else { 0.0 };
+            assert!((data[frame * 3 + 1] - delayed).abs() < 1e-5);
+            assert_eq!(data[frame * 3 + 2], input[frame * 3 + 2]);
+        }
+    }
+}
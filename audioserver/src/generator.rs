@@ -0,0 +1,340 @@
+use anyhow::{anyhow, bail, Result};
+use biquad::{Biquad, Coefficients, DirectForm2Transposed, ToHertz, Type, Q_BUTTERWORTH_F32};
+use std::f32::consts::PI;
+
+// Default ceiling for generated signals, keeps a mistyped level from frying a tweeter
+pub const DEFAULT_MAX_LEVEL_DB: f32 = -12.0;
+// Noise RMS is measured over this much of the signal when the generator starts
+const CALIBRATION_SECONDS: f32 = 2.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Signal {
+    Sine { frequency: f32 },
+    // Sine stepping logarithmically from start to end, then starting over
+    SteppedSine { start_hz: f32, end_hz: f32, steps_per_octave: f32, step_duration_s: f32 },
+    WhiteNoise,
+    PinkNoise,
+    // Pink noise limited to one crossover band
+    BandNoise { low_hz: f32, high_hz: f32 },
+    // Exponential sweep, repeated with a short gap in between
+    LogSweep { start_hz: f32, end_hz: f32, duration_s: f32 },
+    // Positive-going raised cosine pulses for checking driver polarity
+    PolarityPulse { interval_s: f32, width_ms: f32 },
+}
+
+impl Signal {
+    // As in AUDIOSERVER_GENERATOR: "sine:<Hz>", "steps:<start>-<end>[:<steps per octave>[:<seconds per step>]]",
+    // "white", "pink", "band:<low>-<high>", "sweep:<start>-<end>[:<seconds>]" or
+    // "pulse[:<interval seconds>[:<width ms>]]"
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parts = text.trim().split(':').map(str::trim);
+        let kind = parts.next().unwrap_or_default();
+        let args: Vec<&str> = parts.collect();
+        let arg = |index: usize, default: Option<f32>| -> Result<f32> {
+            match args.get(index) {
+                Some(value) => value
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|value| value.is_finite() && *value > 0.0)
+                    .ok_or_else(|| anyhow!("Bad {} generator parameter {}", kind, value)),
+                None => default.ok_or_else(|| anyhow!("The {} generator needs more parameters, got {}", kind, text)),
+            }
+        };
+        let range = || -> Result<(f32, f32)> {
+            let (low, high) = args
+                .first()
+                .and_then(|range| range.split_once('-'))
+                .and_then(|(low, high)| Some((low.trim().parse::<f32>().ok()?, high.trim().parse::<f32>().ok()?)))
+                .filter(|(low, high)| *low > 0.0 && *high > 0.0 && low.is_finite() && high.is_finite())
+                .ok_or_else(|| anyhow!("The {} generator needs <start>-<end> in Hz, got {}", kind, text))?;
+            Ok((low, high))
+        };
+        let (signal, count) = match kind {
+            "sine" => (Signal::Sine { frequency: arg(0, None)? }, 1),
+            "steps" => {
+                let (start_hz, end_hz) = range()?;
+                let signal = Signal::SteppedSine {
+                    start_hz,
+                    end_hz,
+                    steps_per_octave: arg(1, Some(3.0))?,
+                    step_duration_s: arg(2, Some(1.0))?,
+                };
+                (signal, 3)
+            }
+            "white" => (Signal::WhiteNoise, 0),
+            "pink" => (Signal::PinkNoise, 0),
+            "band" => {
+                let (low_hz, high_hz) = range()?;
+                (Signal::BandNoise { low_hz, high_hz }, 1)
+            }
+            "sweep" => {
+                let (start_hz, end_hz) = range()?;
+                (Signal::LogSweep { start_hz, end_hz, duration_s: arg(1, Some(10.0))? }, 2)
+            }
+            "pulse" => {
+                let signal = Signal::PolarityPulse {
+                    interval_s: arg(0, Some(1.0))?,
+                    width_ms: arg(1, Some(1.0))?,
+                };
+                (signal, 2)
+            }
+            _ => bail!("Unknown generator signal {}, expected sine, steps, white, pink, band, sweep or pulse", kind),
+        };
+        if args.len() > count {
+            bail!("Too many parameters for the {} generator: {}", kind, text);
+        }
+        Ok(signal)
+    }
+
+    fn is_noise(&self) -> bool {
+        matches!(self, Signal::WhiteNoise | Signal::PinkNoise | Signal::BandNoise { .. })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratorConfig {
+    pub signal: Signal,
+    // Peak level for tones and pulses, RMS level for noise
+    pub level_db: f32,
+    // Safety cap, samples never exceed this peak level
+    pub max_level_db: f32,
+    // Output channels carrying the signal, all others stay silent
+    pub channels: Vec<usize>,
+}
+
+impl GeneratorConfig {
+    pub fn new(signal: Signal, level_db: f32, channels: Vec<usize>) -> Self {
+        GeneratorConfig {
+            signal,
+            level_db,
+            max_level_db: DEFAULT_MAX_LEVEL_DB,
+            channels,
+        }
+    }
+}
+
+// Small xorshift generator, good enough for test noise and allocation free
+#[derive(Clone)]
+struct Noise {
+    state: u32,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Noise { state: 0x9E37_79B9 }
+    }
+
+    fn next(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
+
+// Paul Kellet's pink noise filter
+#[derive(Default, Clone)]
+struct PinkFilter {
+    b: [f32; 7],
+}
+
+impl PinkFilter {
+    fn run(&mut self, white: f32) -> f32 {
+        let b = &mut self.b;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.153852;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+        pink * 0.11
+    }
+}
+
+#[derive(Clone)]
+pub struct Generator {
+    config: GeneratorConfig,
+    sample_rate: f32,
+    gain: f32,
+    ceiling: f32,
+    // Running time in samples since the signal (or current step) started
+    position: u64,
+    phase: f32,
+    noise: Noise,
+    pink: PinkFilter,
+    band_filters: Vec<DirectForm2Transposed<f32>>,
+}
+
+impl Generator {
+    pub fn new(config: GeneratorConfig, sample_rate: u32) -> Result<Self> {
+        let fs = sample_rate as f32;
+        let band_filters = match config.signal {
+            Signal::BandNoise { low_hz, high_hz } => {
+                if low_hz >= high_hz {
+                    return Err(anyhow!("Band noise needs low_hz below high_hz"));
+                }
+                let mut filters = Vec::new();
+                // Two cascaded Butterworth sections per edge give 24 dB/oct skirts
+                for _ in 0..2 {
+                    for (kind, freq) in [(Type::HighPass, low_hz), (Type::LowPass, high_hz)] {
+                        let coeffs = Coefficients::<f32>::from_params(kind, fs.hz(), freq.hz(), Q_BUTTERWORTH_F32)
+                            .map_err(|e| anyhow!("Invalid band noise edge {} Hz: {:?}", freq, e))?;
+                        filters.push(DirectForm2Transposed::<f32>::new(coeffs));
+                    }
+                }
+                filters
+            }
+            _ => Vec::new(),
+        };
+
+        let ceiling = db_to_gain(config.max_level_db.min(0.0));
+        let level_db = config.level_db.min(config.max_level_db);
+        let mut generator = Generator {
+            config,
+            sample_rate: fs,
+            gain: db_to_gain(level_db),
+            ceiling,
+            position: 0,
+            phase: 0.0,
+            noise: Noise::new(),
+            pink: PinkFilter::default(),
+            band_filters,
+        };
+        // Each noise loses a different amount in its filters, so the level is set from the RMS of
+        // a copy, skipping the first quarter while the band filters settle
+        if generator.config.signal.is_noise() {
+            let mut probe = generator.clone();
+            let samples = (CALIBRATION_SECONDS * fs) as usize;
+            let power: f64 = (0..samples)
+                .map(|_| probe.next_sample() as f64)
+                .skip(samples / 4)
+                .map(|sample| sample * sample)
+                .sum();
+            let rms = (power / (samples - samples / 4).max(1) as f64).sqrt() as f32;
+            if rms > 0.0 {
+                generator.gain /= rms;
+            }
+        }
+        Ok(generator)
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        // Seconds since start, kept in f64 so long runs don't lose precision
+        let t = self.position as f64 / self.sample_rate as f64;
+        self.position += 1;
+
+        match self.config.signal {
+            Signal::Sine { frequency } => self.oscillate(frequency),
+            Signal::SteppedSine { start_hz, end_hz, steps_per_octave, step_duration_s } => {
+                let octaves = (end_hz / start_hz).log2().max(0.0);
+                let steps = (octaves * steps_per_octave).floor() as u64 + 1;
+                let step = (t / step_duration_s.max(0.01) as f64) as u64 % steps;
+                let frequency = start_hz * 2f32.powf(step as f32 / steps_per_octave.max(0.1));
+                self.oscillate(frequency)
+            }
+            Signal::WhiteNoise => self.noise.next(),
+            Signal::PinkNoise => {
+                let white = self.noise.next();
+                self.pink.run(white)
+            }
+            Signal::BandNoise { .. } => {
+                let white = self.noise.next();
+                let mut sample = self.pink.run(white);
+                for filter in self.band_filters.iter_mut() {
+                    sample = filter.run(sample);
+                }
+                sample
+            }
+            Signal::LogSweep { start_hz, end_hz, duration_s } => {
+                // Half a second of silence between sweeps lets the room decay
+                let t = (t % (duration_s + 0.5) as f64) as f32;
+                if t >= duration_s {
+                    self.phase = 0.0;
+                    return 0.0;
+                }
+                let rate = (end_hz / start_hz).ln() / duration_s;
+                let frequency = start_hz * (rate * t).exp();
+                self.oscillate(frequency)
+            }
+            Signal::PolarityPulse { interval_s, width_ms } => {
+                let t = (t % interval_s.max(0.01) as f64) as f32;
+                let width = width_ms * 1e-3;
+                if t < width {
+                    0.5 - 0.5 * (2.0 * PI * t / width).cos()
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    fn oscillate(&mut self, frequency: f32) -> f32 {
+        let sample = self.phase.sin();
+        self.phase = (self.phase + 2.0 * PI * frequency / self.sample_rate) % (2.0 * PI);
+        sample
+    }
+
+    // Fill an interleaved buffer, only routed channels get the signal
+    pub fn fill(&mut self, data: &mut [f32], channels: usize) {
+        if channels == 0 {
+            return;
+        }
+        for frame in data.chunks_mut(channels) {
+            let sample = (self.next_sample() * self.gain).clamp(-self.ceiling, self.ceiling);
+            for (ch, out) in frame.iter_mut().enumerate() {
+                *out = if self.config.channels.contains(&ch) { sample } else { 0.0 };
+            }
+        }
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signal_from_text() {
+        assert_eq!(Signal::parse("sine:1000").unwrap(), Signal::Sine { frequency: 1000.0 });
+        assert_eq!(Signal::parse("pink").unwrap(), Signal::PinkNoise);
+        assert_eq!(Signal::parse("band:80-500").unwrap(), Signal::BandNoise { low_hz: 80.0, high_hz: 500.0 });
+        assert_eq!(
+            Signal::parse("steps:20-200:6").unwrap(),
+            Signal::SteppedSine { start_hz: 20.0, end_hz: 200.0, steps_per_octave: 6.0, step_duration_s: 1.0 }
+        );
+        assert_eq!(
+            Signal::parse("sweep:20-20000:5").unwrap(),
+            Signal::LogSweep { start_hz: 20.0, end_hz: 20000.0, duration_s: 5.0 }
+        );
+        assert_eq!(Signal::parse("pulse").unwrap(), Signal::PolarityPulse { interval_s: 1.0, width_ms: 1.0 });
+        assert!(Signal::parse("sine").is_err());
+        assert!(Signal::parse("sine:-5").is_err());
+        assert!(Signal::parse("band:80").is_err());
+        assert!(Signal::parse("white:1").is_err());
+        assert!(Signal::parse("square:100").is_err());
+    }
+
+    #[test]
+    fn noise_plays_at_the_requested_rms() {
+        let signals = [Signal::WhiteNoise, Signal::PinkNoise, Signal::BandNoise { low_hz: 100.0, high_hz: 400.0 }];
+        for signal in signals {
+            let config = GeneratorConfig {
+                max_level_db: 0.0,
+                ..GeneratorConfig::new(signal.clone(), -20.0, vec![0])
+            };
+            let mut generator = Generator::new(config, 48000).unwrap();
+            let mut block = vec![0.0; 48000];
+            // Past the band filters settling
+            generator.fill(&mut block, 1);
+            generator.fill(&mut block, 1);
+            let power = block.iter().map(|sample| (sample * sample) as f64).sum::<f64>() / block.len() as f64;
+            let rms_db = 10.0 * power.log10();
+            assert!((rms_db + 20.0).abs() < 0.5, "{:?} at {:.2} dB", signal, rms_db);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use super::{decimal, merge, parse_filter, ImportedFilters};
+use crate::eq::ChannelEq;
+
+// Channel names in Equalizer APO's 7.1 order
+const CHANNEL_NAMES: [&str; 8] = ["L", "R", "C", "SUB", "RL", "RR", "SL", "SR"];
+
+fn channel_index(name: &str) -> Option<usize> {
+    if let Ok(number) = name.parse::<usize>() {
+        return number.checked_sub(1);
+    }
+    let name = name.to_ascii_uppercase();
+    let name = if name == "LFE" { "SUB".to_string() } else { name };
+    CHANNEL_NAMES.iter().position(|n| *n == name)
+}
+
+// Equalizer APO config.txt. Filter, Preamp and Delay apply to the channels
+// picked by the last Channel line, all channels before the first one.
+pub fn parse(text: &str) -> ImportedFilters {
+    let mut imported = ImportedFilters::default();
+    let mut selected: Option<Vec<usize>> = None;
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let unsupported = |what: &str| format!("line {}: {}", number + 1, what);
+        let Some((command, args)) = line.split_once(':') else {
+            imported.unsupported.push(unsupported(line));
+            continue;
+        };
+        let command = command.trim();
+        let args = args.trim();
+
+        let stage = if command == "Filter" || command.starts_with("Filter ") {
+            match parse_filter(args) {
+                Ok(Some(spec)) => ChannelEq { filters: vec![spec], ..Default::default() },
+                Ok(None) => continue,
+                Err(e) => {
+                    imported.unsupported.push(unsupported(&e));
+                    continue;
+                }
+            }
+        } else {
+            match command {
+                "Preamp" => match decimal(args.trim_end_matches("dB").trim()) {
+                    Some(gain_db) => ChannelEq { gain_db, ..Default::default() },
+                    None => {
+                        imported.unsupported.push(unsupported(line));
+                        continue;
+                    }
+                },
+                "Delay" => {
+                    let mut parts = args.split_whitespace();
+                    match (parts.next().and_then(decimal), parts.next()) {
+                        (Some(delay_ms), Some("ms")) => ChannelEq { delay_ms, ..Default::default() },
+                        _ => {
+                            // Delays in samples depend on the device rate APO ran at
+                            imported.unsupported.push(unsupported(&format!("delay not in ms: {}", args)));
+                            continue;
+                        }
+                    }
+                }
+                "Channel" => {
+                    if args.eq_ignore_ascii_case("all") {
+                        selected = None;
+                    } else {
+                        let mut channels = Vec::new();
+                        for name in args.split_whitespace() {
+                            match channel_index(name) {
+                                Some(channel) => channels.push(channel),
+                                None => imported.unsupported.push(unsupported(&format!("channel {}", name))),
+                            }
+                        }
+                        selected = Some(channels);
+                    }
+                    continue;
+                }
+                other => {
+                    // Include, Convolution, Copy, GraphicEQ, Device, Stage, If, Eval ...
+                    imported.unsupported.push(unsupported(&format!("command {}", other)));
+                    continue;
+                }
+            }
+        };
+
+        match &selected {
+            None => merge(&mut imported.equalizer.all, stage),
+            Some(channels) => {
+                for &channel in channels {
+                    merge(imported.equalizer.channel_mut(channel), stage.clone());
+                }
+            }
+        }
+    }
+    imported
+}
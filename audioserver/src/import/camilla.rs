@@ -0,0 +1,236 @@
+use anyhow::{anyhow, Result};
+use serde_yaml::Value;
+
+use super::{merge, ImportedFilters};
+use crate::crossover::{bandwidth_q, shelf_q, Band, Crossover, FilterSpec, PassKind};
+use crate::eq::ChannelEq;
+
+// Speed of sound used for delays given as a distance
+const SPEED_OF_SOUND_MM_PER_MS: f32 = 343.0;
+
+// CamillaDSP YAML config. Filter steps before the first mixer become the
+// equalizer, a mixer where every output has a single source becomes the
+// crossover bands and filter steps after it go into those bands.
+pub fn parse(text: &str) -> Result<ImportedFilters> {
+    let doc: Value = serde_yaml::from_str(text)?;
+    let sample_rate = doc.get("devices").and_then(|d| d.get("samplerate")).and_then(Value::as_f64);
+    let pipeline = doc
+        .get("pipeline")
+        .and_then(Value::as_sequence)
+        .ok_or_else(|| anyhow!("Config has no pipeline"))?;
+
+    let mut imported = ImportedFilters::default();
+    for (idx, step) in pipeline.iter().enumerate() {
+        if step.get("bypassed").and_then(Value::as_bool) == Some(true) {
+            continue;
+        }
+        let kind = step.get("type").and_then(Value::as_str).unwrap_or("");
+        let name = step.get("name").and_then(Value::as_str).unwrap_or("");
+        match kind {
+            "Mixer" => {
+                if imported.crossover.is_some() {
+                    imported.unsupported.push(format!("pipeline step {}: second mixer {}", idx + 1, name));
+                    continue;
+                }
+                match doc.get("mixers").and_then(|m| m.get(name)) {
+                    Some(mixer) => imported.crossover = Some(mixer_bands(name, mixer, &mut imported.unsupported)),
+                    None => imported.unsupported.push(format!("pipeline step {}: unknown mixer {}", idx + 1, name)),
+                }
+            }
+            "Filter" => {
+                // `channel` in v1 configs, `channels` from v2 on, neither in v3 for all channels
+                let channels: Option<Vec<usize>> = match step.get("channels").and_then(Value::as_sequence) {
+                    Some(list) => Some(list.iter().filter_map(Value::as_u64).map(|c| c as usize).collect()),
+                    None => step.get("channel").and_then(Value::as_u64).map(|c| vec![c as usize]),
+                };
+                let names = step.get("names").and_then(Value::as_sequence).cloned().unwrap_or_default();
+                for filter_name in names.iter().filter_map(Value::as_str) {
+                    let stage = match doc.get("filters").and_then(|f| f.get(filter_name)) {
+                        Some(def) => convert_filter(def, sample_rate),
+                        None => Err("not defined".to_string()),
+                    };
+                    let stage = match stage {
+                        Ok(stage) => stage,
+                        Err(e) => {
+                            imported.unsupported.push(format!("filter {}: {}", filter_name, e));
+                            continue;
+                        }
+                    };
+                    let Some(channels) = &channels else {
+                        match imported.crossover.as_mut() {
+                            None => merge(&mut imported.equalizer.all, stage),
+                            Some(crossover) => {
+                                for band in crossover.bands.iter_mut() {
+                                    merge_band(band, stage.clone());
+                                }
+                            }
+                        }
+                        continue;
+                    };
+                    for &channel in channels {
+                        match imported.crossover.as_mut() {
+                            None => merge(imported.equalizer.channel_mut(channel), stage.clone()),
+                            Some(crossover) => {
+                                let mut found = false;
+                                for band in crossover.bands.iter_mut().filter(|b| b.output_channel == channel) {
+                                    merge_band(band, stage.clone());
+                                    found = true;
+                                }
+                                if !found {
+                                    imported
+                                        .unsupported
+                                        .push(format!("filter {}: no mixer output feeds channel {}", filter_name, channel));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            other => imported.unsupported.push(format!("pipeline step {}: {} {}", idx + 1, other, name)),
+        }
+    }
+    Ok(imported)
+}
+
+fn merge_band(band: &mut Band, stage: ChannelEq) {
+    band.filters.extend(stage.filters);
+    band.gain_db += stage.gain_db;
+    band.delay_ms += stage.delay_ms;
+    band.invert ^= stage.invert;
+}
+
+fn gain_db(value: f32, scale: Option<&str>) -> (f32, bool) {
+    match scale {
+        Some("linear") => (20.0 * value.abs().log10(), value < 0.0),
+        _ => (value, false),
+    }
+}
+
+fn mixer_bands(name: &str, mixer: &Value, unsupported: &mut Vec<String>) -> Crossover {
+    let mut bands = Vec::new();
+    let mapping = mixer.get("mapping").and_then(Value::as_sequence).cloned().unwrap_or_default();
+    for entry in &mapping {
+        let Some(dest) = entry.get("dest").and_then(Value::as_u64) else {
+            continue;
+        };
+        if entry.get("mute").and_then(Value::as_bool) == Some(true) {
+            continue;
+        }
+        let sources = entry.get("sources").and_then(Value::as_sequence).cloned().unwrap_or_default();
+        let sources: Vec<&Value> = sources
+            .iter()
+            .filter(|s| s.get("mute").and_then(Value::as_bool) != Some(true))
+            .collect();
+        if sources.len() != 1 {
+            unsupported.push(format!("mixer {}: output {} mixes {} sources", name, dest, sources.len()));
+            continue;
+        }
+        let source = sources[0];
+        let Some(channel) = source.get("channel").and_then(Value::as_u64) else {
+            continue;
+        };
+        let mut band = Band::new(&format!("{} {}", name, dest), channel as usize, dest as usize);
+        let (gain, inverted) = gain_db(
+            number(source, "gain").unwrap_or(0.0),
+            source.get("scale").and_then(Value::as_str),
+        );
+        band.gain_db = gain;
+        band.invert = inverted ^ (source.get("inverted").and_then(Value::as_bool) == Some(true));
+        bands.push(band);
+    }
+    Crossover {
+        bands,
+        ..Default::default()
+    }
+}
+
+fn number(value: &Value, key: &str) -> Option<f32> {
+    value.get(key).and_then(Value::as_f64).map(|v| v as f32)
+}
+
+fn convert_filter(def: &Value, sample_rate: Option<f64>) -> Result<ChannelEq, String> {
+    let kind = def.get("type").and_then(Value::as_str).unwrap_or("");
+    let params = def.get("parameters").cloned().unwrap_or(Value::Null);
+    let subtype = params.get("type").and_then(Value::as_str).unwrap_or("");
+    let need = |key: &str| number(&params, key).ok_or_else(|| format!("{} {} without {}", kind, subtype, key));
+    // Peaks, notches and band passes take either a Q or a bandwidth in octaves
+    let q_or_bandwidth = || {
+        number(&params, "q")
+            .or_else(|| number(&params, "bandwidth").map(bandwidth_q))
+            .ok_or_else(|| format!("{} {} without q or bandwidth", kind, subtype))
+    };
+
+    let mut stage = ChannelEq::default();
+    match kind {
+        "Biquad" => {
+            let spec = match subtype {
+                "Lowpass" | "Highpass" => FilterSpec::SecondOrder {
+                    kind: if subtype == "Lowpass" { PassKind::LowPass } else { PassKind::HighPass },
+                    frequency: need("freq")?,
+                    q: need("q")?,
+                },
+                "LowpassFO" | "HighpassFO" => FilterSpec::FirstOrder {
+                    kind: if subtype == "LowpassFO" { PassKind::LowPass } else { PassKind::HighPass },
+                    frequency: need("freq")?,
+                },
+                "Peaking" => FilterSpec::Peaking {
+                    frequency: need("freq")?,
+                    q: q_or_bandwidth()?,
+                    gain_db: need("gain")?,
+                },
+                "Allpass" => FilterSpec::AllPass { frequency: need("freq")?, q: q_or_bandwidth()? },
+                "AllpassFO" => FilterSpec::AllPassFirstOrder { frequency: need("freq")? },
+                "Notch" => FilterSpec::Notch { frequency: need("freq")?, q: q_or_bandwidth()? },
+                "Bandpass" => FilterSpec::BandPass { frequency: need("freq")?, q: q_or_bandwidth()? },
+                "Lowshelf" | "Highshelf" => {
+                    let frequency = need("freq")?;
+                    let gain_db = need("gain")?;
+                    let q = match number(&params, "q") {
+                        Some(q) => q,
+                        None => shelf_q(gain_db, need("slope")?),
+                    };
+                    if subtype == "Lowshelf" {
+                        FilterSpec::LowShelf { frequency, q, gain_db }
+                    } else {
+                        FilterSpec::HighShelf { frequency, q, gain_db }
+                    }
+                }
+                other => return Err(format!("Biquad type {}", other)),
+            };
+            stage.filters.push(spec);
+        }
+        "BiquadCombo" => {
+            let frequency = need("freq")?;
+            let order = need("order")? as u32;
+            let spec = match subtype {
+                "ButterworthLowpass" => FilterSpec::Butterworth { kind: PassKind::LowPass, frequency, order },
+                "ButterworthHighpass" => FilterSpec::Butterworth { kind: PassKind::HighPass, frequency, order },
+                "LinkwitzRileyLowpass" => FilterSpec::LinkwitzRiley { kind: PassKind::LowPass, frequency, order },
+                "LinkwitzRileyHighpass" => FilterSpec::LinkwitzRiley { kind: PassKind::HighPass, frequency, order },
+                other => return Err(format!("BiquadCombo type {}", other)),
+            };
+            stage.filters.push(spec);
+        }
+        "Gain" => {
+            let (gain, inverted) = gain_db(need("gain")?, params.get("scale").and_then(Value::as_str));
+            stage.gain_db = gain;
+            stage.invert = inverted ^ (params.get("inverted").and_then(Value::as_bool) == Some(true));
+        }
+        "Delay" => {
+            let delay = need("delay")?;
+            stage.delay_ms = match params.get("unit").and_then(Value::as_str).unwrap_or("ms") {
+                "ms" => delay,
+                "us" => delay / 1000.0,
+                "mm" => delay / SPEED_OF_SOUND_MM_PER_MS,
+                "samples" => match sample_rate {
+                    Some(rate) => delay * 1000.0 / rate as f32,
+                    None => return Err("delay in samples without devices.samplerate".to_string()),
+                },
+                other => return Err(format!("delay unit {}", other)),
+            };
+        }
+        // Conv, Volume, Loudness, Dither, DiffEq, Limiter ...
+        other => return Err(format!("filter type {}", other)),
+    }
+    Ok(stage)
+}
@@ -0,0 +1,317 @@
+pub mod apo;
+pub mod camilla;
+pub mod rew;
+
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+use crate::crossover::{bandwidth_q, shelf_q, Crossover, FilterSpec, PassKind};
+use crate::eq::{ChannelEq, Equalizer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    // Filter settings exported from Room EQ Wizard
+    Rew,
+    // Equalizer APO config.txt
+    EqualizerApo,
+    // CamillaDSP YAML config
+    CamillaDsp,
+}
+
+impl ImportFormat {
+    // "rew", "apo" or "camilla", as in AUDIOSERVER_IMPORT_FORMAT
+    pub fn parse(text: &str) -> Result<Self> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "rew" => Ok(ImportFormat::Rew),
+            "apo" | "equalizerapo" => Ok(ImportFormat::EqualizerApo),
+            "camilla" | "camilladsp" => Ok(ImportFormat::CamillaDsp),
+            other => bail!("Unknown import format {}, expected rew, apo or camilla", other),
+        }
+    }
+
+    // YAML is CamillaDSP, REW exports start with their header, anything else is
+    // taken as an Equalizer APO config
+    pub fn guess(path: &Path, text: &str) -> Self {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+        if extension == "yml" || extension == "yaml" {
+            ImportFormat::CamillaDsp
+        } else if text.trim_start().starts_with("Filter Settings file") {
+            ImportFormat::Rew
+        } else {
+            ImportFormat::EqualizerApo
+        }
+    }
+}
+
+// Stages built from an imported config, plus everything that could not be mapped
+#[derive(Debug, Clone, Default)]
+pub struct ImportedFilters {
+    pub equalizer: Equalizer,
+    pub crossover: Option<Crossover>,
+    pub unsupported: Vec<String>,
+}
+
+// Without a format it is guessed from the file
+pub fn import_file(path: &Path, format: Option<ImportFormat>) -> Result<ImportedFilters> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    match format.unwrap_or_else(|| ImportFormat::guess(path, &text)) {
+        ImportFormat::Rew => Ok(rew::parse(&text)),
+        ImportFormat::EqualizerApo => Ok(apo::parse(&text)),
+        ImportFormat::CamillaDsp => camilla::parse(&text),
+    }
+}
+
+// Add the filters, gain, delay and polarity of `other` to `eq`
+pub(crate) fn merge(eq: &mut ChannelEq, other: ChannelEq) {
+    eq.filters.extend(other.filters);
+    eq.gain_db += other.gain_db;
+    eq.delay_ms += other.delay_ms;
+    eq.invert ^= other.invert;
+}
+
+// A number from a REW or APO export, which use the decimal comma of the
+// locale they ran in ("63,0")
+pub(crate) fn decimal(text: &str) -> Option<f32> {
+    text.replace(',', ".").parse().ok()
+}
+
+// One filter in the text format shared by REW and Equalizer APO, e.g.
+// "ON PK Fc 100 Hz Gain -3.0 dB Q 2.00". Ok(None) for filters that are off or empty.
+pub(crate) fn parse_filter(text: &str) -> Result<Option<FilterSpec>, String> {
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    match tokens.first().copied() {
+        Some("ON") => {}
+        Some("OFF") => return Ok(None),
+        _ => return Err(format!("filter without ON/OFF: {}", text.trim())),
+    }
+    let Some(&kind) = tokens.get(1) else {
+        return Ok(None);
+    };
+
+    let number = |key: &str| -> Option<f32> {
+        let idx = tokens.iter().position(|t| t.eq_ignore_ascii_case(key))?;
+        decimal(tokens.get(idx + 1)?)
+    };
+    // Slope written right after the type: "LS 6dB", "LSC 12 dB"
+    let slope = tokens.get(2).and_then(|t| {
+        let value = t.strip_suffix("dB").unwrap_or(t);
+        let unit = t.ends_with("dB") || tokens.get(3) == Some(&"dB");
+        if unit { decimal(value) } else { None }
+    });
+    let frequency = number("Fc");
+    let gain_db = number("Gain");
+    let q = number("Q").or_else(|| {
+        let idx = tokens.iter().position(|t| *t == "BW")?;
+        let octaves = decimal(tokens.get(idx + 2)?)?;
+        (tokens.get(idx + 1) == Some(&"Oct")).then(|| bandwidth_q(octaves))
+    });
+
+    let need = |value: Option<f32>, what: &str| value.ok_or_else(|| format!("{} filter without {}: {}", kind, what, text.trim()));
+    let spec = match kind {
+        "None" => return Ok(None),
+        "PK" | "PEQ" => FilterSpec::Peaking {
+            frequency: need(frequency, "Fc")?,
+            q: need(q, "Q")?,
+            gain_db: need(gain_db, "Gain")?,
+        },
+        "LP" | "LPQ" => FilterSpec::SecondOrder {
+            kind: PassKind::LowPass,
+            frequency: need(frequency, "Fc")?,
+            q: q.unwrap_or(std::f32::consts::FRAC_1_SQRT_2),
+        },
+        "HP" | "HPQ" => FilterSpec::SecondOrder {
+            kind: PassKind::HighPass,
+            frequency: need(frequency, "Fc")?,
+            q: q.unwrap_or(std::f32::consts::FRAC_1_SQRT_2),
+        },
+        "LP1" => FilterSpec::FirstOrder { kind: PassKind::LowPass, frequency: need(frequency, "Fc")? },
+        "HP1" => FilterSpec::FirstOrder { kind: PassKind::HighPass, frequency: need(frequency, "Fc")? },
+        "BP" => FilterSpec::BandPass {
+            frequency: need(frequency, "Fc")?,
+            q: need(q, "Q")?,
+        },
+        "AP" => FilterSpec::AllPass {
+            frequency: need(frequency, "Fc")?,
+            q: q.unwrap_or(std::f32::consts::FRAC_1_SQRT_2),
+        },
+        "NO" => FilterSpec::Notch {
+            frequency: need(frequency, "Fc")?,
+            q: q.unwrap_or(30.0),
+        },
+        "LS" | "LSC" | "HS" | "HSC" => {
+            let frequency = need(frequency, "Fc")?;
+            let gain_db = need(gain_db, "Gain")?;
+            let q = match (q, slope) {
+                (Some(q), _) => q,
+                (None, Some(slope)) => shelf_q(gain_db, slope),
+                (None, None) => std::f32::consts::FRAC_1_SQRT_2,
+            };
+            if kind.starts_with("LS") {
+                FilterSpec::LowShelf { frequency, q, gain_db }
+            } else {
+                FilterSpec::HighShelf { frequency, q, gain_db }
+            }
+        }
+        other => return Err(format!("filter type {}: {}", other, text.trim())),
+    };
+    Ok(Some(spec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REW: &str = "Filter Settings file
+
+Room EQ V5.20
+Dated: 12-Mar-2024 21:14:02
+
+Notes:
+
+Equaliser: Generic
+Filter  1: ON  PK       Fc    63,0 Hz  Gain  -6,5 dB  Q 4,000
+Filter  2: ON  LS 12 dB Fc   105 Hz  Gain   3.0 dB
+Filter  3: OFF PK       Fc   200 Hz  Gain  -2.0 dB  Q 1.00
+Filter  4: ON  None
+Filter  5: ON  XYZ      Fc   300 Hz
+";
+
+    #[test]
+    fn rew_filters_with_decimal_commas() {
+        let imported = rew::parse(REW);
+        assert_eq!(
+            imported.equalizer.all.filters,
+            vec![
+                FilterSpec::Peaking { frequency: 63.0, q: 4.0, gain_db: -6.5 },
+                FilterSpec::LowShelf { frequency: 105.0, q: shelf_q(3.0, 12.0), gain_db: 3.0 },
+            ]
+        );
+        assert!(imported.equalizer.channels.is_empty());
+        assert_eq!(imported.unsupported.len(), 1);
+        assert!(imported.unsupported[0].starts_with("line 13:"));
+    }
+
+    #[test]
+    fn filter_bandwidth_in_octaves() {
+        let spec = parse_filter("ON PK Fc 1000 Hz Gain 2 dB BW Oct 0,5").unwrap();
+        assert_eq!(spec, Some(FilterSpec::Peaking { frequency: 1000.0, q: bandwidth_q(0.5), gain_db: 2.0 }));
+        assert!(parse_filter("PK Fc 1000 Hz").is_err());
+        assert!(parse_filter("ON PK Fc 1000 Hz Q 1").is_err());
+    }
+
+    #[test]
+    fn apo_channel_selection() {
+        let imported = apo::parse(
+            "# headphones
+Preamp: -4,5 dB
+Filter 1: ON HP Fc 30 Hz
+Channel: L
+Delay: 1.5 ms
+Channel: R SUB
+Filter: ON PK Fc 80 Hz Gain -3 dB Q 2
+Channel: all
+Filter: ON HS Fc 8000 Hz Gain 2 dB
+Include: other.txt
+",
+        );
+        let eq = &imported.equalizer;
+        assert_eq!(eq.all.gain_db, -4.5);
+        assert_eq!(eq.all.filters.len(), 2);
+        assert_eq!(eq.channels[0].delay_ms, 1.5);
+        assert!(eq.channels[0].filters.is_empty());
+        let peak = FilterSpec::Peaking { frequency: 80.0, q: 2.0, gain_db: -3.0 };
+        assert_eq!(eq.channels[1].filters, vec![peak.clone()]);
+        assert!(eq.channels[2].is_empty());
+        assert_eq!(eq.channels[3].filters, vec![peak]);
+        assert_eq!(imported.unsupported, vec!["line 10: command Include".to_string()]);
+    }
+
+    const CAMILLA: &str = "
+devices:
+  samplerate: 48000
+filters:
+  room:
+    type: Biquad
+    parameters: { type: Peaking, freq: 45, q: 3, gain: -5 }
+  left_trim:
+    type: Gain
+    parameters: { gain: -1.5 }
+  woofer_lp:
+    type: BiquadCombo
+    parameters: { type: LinkwitzRileyLowpass, freq: 2000, order: 4 }
+  tweeter_hp:
+    type: BiquadCombo
+    parameters: { type: LinkwitzRileyHighpass, freq: 2000, order: 4 }
+  tweeter_delay:
+    type: Delay
+    parameters: { delay: 48, unit: samples }
+  soft_clip:
+    type: Limiter
+    parameters: { clip_limit: -1 }
+mixers:
+  split:
+    channels: { in: 1, out: 2 }
+    mapping:
+      - dest: 0
+        sources: [{ channel: 0, gain: 0 }]
+      - dest: 1
+        sources: [{ channel: 0, gain: -3, inverted: true }]
+pipeline:
+  - type: Filter
+    names: [room]
+  - type: Filter
+    channels: [0]
+    names: [left_trim]
+  - type: Mixer
+    name: split
+  - type: Filter
+    channels: [0]
+    names: [woofer_lp]
+  - type: Filter
+    channels: [1]
+    names: [tweeter_hp, tweeter_delay]
+  - type: Filter
+    names: [soft_clip]
+";
+
+    #[test]
+    fn camilla_filters_mixer_and_all_channel_steps() {
+        let imported = camilla::parse(CAMILLA).unwrap();
+        // A step without channels applies to all of them
+        let room = FilterSpec::Peaking { frequency: 45.0, q: 3.0, gain_db: -5.0 };
+        assert_eq!(imported.equalizer.all.filters, vec![room]);
+        assert_eq!(imported.equalizer.channels[0].gain_db, -1.5);
+
+        let crossover = imported.crossover.unwrap();
+        assert_eq!(crossover.bands.len(), 2);
+        let (woofer, tweeter) = (&crossover.bands[0], &crossover.bands[1]);
+        assert_eq!((woofer.input_channel, woofer.output_channel), (0, 0));
+        assert_eq!(
+            woofer.filters,
+            vec![FilterSpec::LinkwitzRiley { kind: PassKind::LowPass, frequency: 2000.0, order: 4 }]
+        );
+        assert_eq!((tweeter.input_channel, tweeter.output_channel), (0, 1));
+        assert_eq!(tweeter.gain_db, -3.0);
+        assert!(tweeter.invert);
+        assert_eq!(tweeter.delay_ms, 1.0);
+        assert_eq!(imported.unsupported, vec!["filter soft_clip: filter type Limiter".to_string()]);
+    }
+
+    #[test]
+    fn camilla_all_channel_step_after_mixer_goes_into_every_band() {
+        let text = CAMILLA.replace("names: [soft_clip]", "names: [left_trim]");
+        let crossover = camilla::parse(&text).unwrap().crossover.unwrap();
+        assert!(crossover.bands.iter().all(|band| band.filters.len() == 1));
+        assert_eq!(crossover.bands[0].gain_db, -1.5);
+        assert_eq!(crossover.bands[1].gain_db, -4.5);
+    }
+
+    #[test]
+    fn format_from_name_or_file() {
+        assert_eq!(ImportFormat::parse("APO").unwrap(), ImportFormat::EqualizerApo);
+        assert!(ImportFormat::parse("wav").is_err());
+        assert_eq!(ImportFormat::guess(Path::new("camilla.yml"), ""), ImportFormat::CamillaDsp);
+        assert_eq!(ImportFormat::guess(Path::new("room.txt"), REW), ImportFormat::Rew);
+        assert_eq!(ImportFormat::guess(Path::new("config.txt"), "Preamp: -3 dB"), ImportFormat::EqualizerApo);
+    }
+}
@@ -0,0 +1,22 @@
+use super::{parse_filter, ImportedFilters};
+
+// REW "Filter Settings file" export. The header is informational, every
+// "Filter N: ..." line applies to all channels.
+pub fn parse(text: &str) -> ImportedFilters {
+    let mut imported = ImportedFilters::default();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if !line.starts_with("Filter") {
+            continue;
+        }
+        let Some((_, filter)) = line.split_once(':') else {
+            continue;
+        };
+        match parse_filter(filter) {
+            Ok(Some(spec)) => imported.equalizer.all.filters.push(spec),
+            Ok(None) => {}
+            Err(e) => imported.unsupported.push(format!("line {}: {}", number + 1, e)),
+        }
+    }
+    imported
+}
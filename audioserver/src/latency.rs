@@ -0,0 +1,135 @@
+use rodio::cpal;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::meter::AtomicF32;
+
+// Delay of every part of the chain in milliseconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyReport {
+    // Capture timestamp to input callback, as reported by the device
+    pub input_ms: f32,
+    // Audio waiting in the ring buffer between input and output callbacks
+    pub transport_ms: f32,
+    pub resampler_ms: f32,
+    // Output callback to playback timestamp, as reported by the device
+    pub output_ms: f32,
+    // End-to-end delay per output channel, including its linear-phase FIR group delay
+    pub channels_ms: Vec<f32>,
+}
+
+// Live delay measurements written from the callbacks plus the static delays of the pipeline
+#[derive(Debug)]
+pub struct LatencyProbe {
+    source_rate: u32,
+    // Group delay in source frames of the linear-phase stages feeding each output channel
+    stage_frames: Vec<f32>,
+    // Look-ahead of the source's resampler in source frames, set by the source whenever
+    // it starts a resampler since the input rate can change from track to track
+    resampler_frames: Arc<AtomicF32>,
+    input_ns: AtomicU64,
+    output_ns: AtomicU64,
+    transport_frames: AtomicU64,
+}
+
+impl LatencyProbe {
+    pub fn new(source_rate: u32, stage_frames: Vec<f32>, resampler_frames: Arc<AtomicF32>) -> Self {
+        LatencyProbe {
+            source_rate,
+            stage_frames,
+            resampler_frames,
+            input_ns: AtomicU64::new(0),
+            output_ns: AtomicU64::new(0),
+            transport_frames: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_input(&self, info: &cpal::InputCallbackInfo) {
+        let timestamp = info.timestamp();
+        if let Some(delay) = timestamp.callback.duration_since(&timestamp.capture) {
+            self.record_input_delay(delay);
+        }
+    }
+
+    // Capture to input callback
+    pub fn record_input_delay(&self, delay: Duration) {
+        self.input_ns.store(delay.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_output(&self, info: &cpal::OutputCallbackInfo) {
+        let timestamp = info.timestamp();
+        if let Some(delay) = timestamp.playback.duration_since(&timestamp.callback) {
+            self.record_output_delay(delay);
+        }
+    }
+
+    // Output callback to playback
+    pub fn record_output_delay(&self, delay: Duration) {
+        self.output_ns.store(delay.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    // Frames still queued in the transport when the output callback took its block
+    pub fn record_transport(&self, frames: usize) {
+        self.transport_frames.store(frames as u64, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> LatencyReport {
+        let frames_to_ms = |frames: f32| frames * 1000.0 / self.source_rate as f32;
+        let input_ms = self.input_ns.load(Ordering::Relaxed) as f32 / 1e6;
+        let output_ms = self.output_ns.load(Ordering::Relaxed) as f32 / 1e6;
+        let transport_ms = frames_to_ms(self.transport_frames.load(Ordering::Relaxed) as f32);
+        let resampler_ms = frames_to_ms(self.resampler_frames.load());
+        let common_ms = input_ms + transport_ms + resampler_ms + output_ms;
+
+        LatencyReport {
+            input_ms,
+            transport_ms,
+            resampler_ms,
+            output_ms,
+            channels_ms: self
+                .stage_frames
+                .iter()
+                .map(|&frames| common_ms + frames_to_ms(frames))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::AudioFilter;
+    use crate::pipeline::{Pipeline, PipelineConfig};
+    use crate::rt::{RealtimeConfig, RealtimeState};
+
+    #[test]
+    fn channels_add_up_every_stage() {
+        // 97 symmetric taps delay by 48 frames, 1 ms at 48 kHz
+        let mut taps = vec![0.0; 97];
+        taps[48] = 1.0;
+        let config = PipelineConfig {
+            filter: Some(AudioFilter::new(taps)),
+            ..PipelineConfig::default()
+        };
+        let realtime = Arc::new(RealtimeState::new(RealtimeConfig::default()));
+        let pipeline = Pipeline::new(&config, 2, 2, 48000, &realtime).unwrap();
+        assert_eq!(pipeline.latency_frames(), [48.0, 48.0]);
+
+        let resampler = Arc::new(AtomicF32::default());
+        resampler.store(24.0);
+        let probe = LatencyProbe::new(48000, pipeline.latency_frames(), resampler);
+        // As cpal reports them: captured 5 ms before the input callback, played 10 ms after the output callback
+        probe.record_input_delay(Duration::from_millis(5));
+        probe.record_output_delay(Duration::from_millis(10));
+        probe.record_transport(480);
+        let report = probe.report();
+        assert_eq!((report.input_ms, report.output_ms), (5.0, 10.0));
+        assert!((report.transport_ms - 10.0).abs() < 1e-4);
+        assert!((report.resampler_ms - 0.5).abs() < 1e-4);
+        assert_eq!(report.channels_ms.len(), 2);
+        for channel_ms in report.channels_ms {
+            assert!((channel_ms - 26.5).abs() < 1e-3, "{}", channel_ms);
+        }
+    }
+}
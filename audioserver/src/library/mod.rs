@@ -0,0 +1,577 @@
+mod watch;
+
+use anyhow::{anyhow, Result};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::decoder::read_metadata;
+use watch::FolderWatcher;
+
+// Files the decoder reads, by extension
+const AUDIO_EXTENSIONS: [&str; 10] = ["flac", "wav", "ogg", "oga", "opus", "mp3", "m4a", "m4b", "mp4", "aac"];
+// How often the library thread looks at the watcher and the rescan timer
+const POLL: Duration = Duration::from_millis(500);
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS tracks (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL UNIQUE,
+        modified INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        title TEXT,
+        artist TEXT,
+        album TEXT,
+        album_artist TEXT,
+        genre TEXT,
+        track_number INTEGER,
+        disc_number INTEGER,
+        date TEXT,
+        duration REAL,
+        has_cover INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS tracks_artist ON tracks (coalesce(album_artist, artist));
+    CREATE INDEX IF NOT EXISTS tracks_album ON tracks (album);
+    CREATE INDEX IF NOT EXISTS tracks_genre ON tracks (genre);
+    CREATE VIRTUAL TABLE IF NOT EXISTS tracks_fts USING fts5(
+        title, artist, album, album_artist, genre, path,
+        content = 'tracks', content_rowid = 'id', tokenize = 'unicode61 remove_diacritics 2'
+    );
+    CREATE TRIGGER IF NOT EXISTS tracks_insert AFTER INSERT ON tracks BEGIN
+        INSERT INTO tracks_fts (rowid, title, artist, album, album_artist, genre, path)
+        VALUES (new.id, new.title, new.artist, new.album, new.album_artist, new.genre, new.path);
+    END;
+    CREATE TRIGGER IF NOT EXISTS tracks_delete AFTER DELETE ON tracks BEGIN
+        INSERT INTO tracks_fts (tracks_fts, rowid, title, artist, album, album_artist, genre, path)
+        VALUES ('delete', old.id, old.title, old.artist, old.album, old.album_artist, old.genre, old.path);
+    END;
+    CREATE TRIGGER IF NOT EXISTS tracks_update AFTER UPDATE ON tracks BEGIN
+        INSERT INTO tracks_fts (tracks_fts, rowid, title, artist, album, album_artist, genre, path)
+        VALUES ('delete', old.id, old.title, old.artist, old.album, old.album_artist, old.genre, old.path);
+        INSERT INTO tracks_fts (rowid, title, artist, album, album_artist, genre, path)
+        VALUES (new.id, new.title, new.artist, new.album, new.album_artist, new.genre, new.path);
+    END;
+";
+
+const TRACK_COLUMNS: &str = "tracks.id, tracks.path, tracks.title, tracks.artist, tracks.album, tracks.album_artist, \
+    tracks.genre, tracks.track_number, tracks.disc_number, tracks.date, tracks.duration, tracks.has_cover";
+
+#[derive(Debug, Clone)]
+pub struct LibraryConfig {
+    // Music folders, searched recursively
+    pub folders: Vec<PathBuf>,
+    // SQLite file holding the index, created when missing
+    pub database: PathBuf,
+    // Full rescan on top of inotify, which does not see changes made on a NAS by
+    // other machines. None to rely on inotify alone.
+    pub rescan_interval: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LibraryTrack {
+    pub id: i64,
+    pub path: PathBuf,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub genre: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub date: Option<String>,
+    pub duration: Option<Duration>,
+    pub has_cover: bool,
+}
+
+impl LibraryTrack {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(LibraryTrack {
+            id: row.get(0)?,
+            path: PathBuf::from(row.get::<_, String>(1)?),
+            title: row.get(2)?,
+            artist: row.get(3)?,
+            album: row.get(4)?,
+            album_artist: row.get(5)?,
+            genre: row.get(6)?,
+            track_number: row.get(7)?,
+            disc_number: row.get(8)?,
+            date: row.get(9)?,
+            duration: row.get::<_, Option<f64>>(10)?.map(Duration::from_secs_f64),
+            has_cover: row.get(11)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArtistSummary {
+    // Album artist where the tags have one, otherwise the artist
+    pub name: String,
+    pub albums: u32,
+    pub tracks: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlbumSummary {
+    pub title: String,
+    pub artist: Option<String>,
+    pub date: Option<String>,
+    pub tracks: u32,
+    // A track with embedded cover art, to show for the album
+    pub cover_track: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenreSummary {
+    pub name: String,
+    pub tracks: u32,
+}
+
+// Browse filter, fields left None match everything
+#[derive(Debug, Clone, Default)]
+pub struct TrackFilter {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScanSummary {
+    pub indexed: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+    pub failed: usize,
+}
+
+fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| AUDIO_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.'))
+}
+
+// Modification time and size, to skip files that did not change since the last scan
+fn stamp(path: &Path) -> Result<(i64, i64)> {
+    let metadata = std::fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    Ok((modified, metadata.len() as i64))
+}
+
+// Audio files below `dir`, without following symlinked folders or entering hidden ones.
+// Folders that cannot be read go into `failed`.
+fn walk(dir: &Path, files: &mut Vec<PathBuf>, failed: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Library: {}: {}", dir.display(), e);
+            failed.push(dir.to_path_buf());
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if is_hidden(&path) {
+            continue;
+        }
+        if file_type.is_dir() {
+            walk(&path, files, failed);
+        } else if is_audio(&path) {
+            files.push(path);
+        }
+    }
+}
+
+// Quote every word for FTS5 and match it as a prefix, so user input never hits its query syntax
+fn fts_query(text: &str) -> Option<String> {
+    let words: Vec<String> = text
+        .split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect();
+    (!words.is_empty()).then(|| words.join(" "))
+}
+
+// Keeps scans from running side by side
+#[derive(Debug, Default)]
+struct ScanState {
+    scanning: AtomicBool,
+    // Another scan was asked for, the running one repeats
+    pending: AtomicBool,
+}
+
+// Index of the music folders, shared by the API and the scanning thread
+#[derive(Clone)]
+pub struct Library {
+    config: Arc<LibraryConfig>,
+    db: Arc<Mutex<Connection>>,
+    scans: Arc<ScanState>,
+}
+
+impl Library {
+    pub fn open(config: LibraryConfig) -> Result<Self> {
+        if let Some(parent) = config.database.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let db = Connection::open(&config.database).map_err(|e| anyhow!("{}: {}", config.database.display(), e))?;
+        db.execute_batch(SCHEMA)?;
+        Ok(Library {
+            config: Arc::new(config),
+            db: Arc::new(Mutex::new(db)),
+            scans: Arc::default(),
+        })
+    }
+
+    pub fn folders(&self) -> &[PathBuf] {
+        &self.config.folders
+    }
+
+    // Bring the index in line with the folders: new and changed files are read
+    // again, files that are gone are dropped. Files below a folder that could not
+    // be read, like an unmounted share, are kept.
+    pub fn scan(&self) -> Result<ScanSummary> {
+        let known: HashMap<String, (i64, i64)> = {
+            let db = self.db.lock().unwrap();
+            let mut statement = db.prepare("SELECT path, modified, size FROM tracks")?;
+            statement
+                .query_map([], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))?
+                .collect::<rusqlite::Result<_>>()?
+        };
+
+        let mut files = Vec::new();
+        let mut failed = Vec::new();
+        for folder in &self.config.folders {
+            walk(folder, &mut files, &mut failed);
+        }
+
+        let mut summary = ScanSummary::default();
+        let mut seen = std::collections::HashSet::new();
+        for path in &files {
+            let key = path.to_string_lossy().into_owned();
+            let unchanged = stamp(path).is_ok_and(|stamp| known.get(&key) == Some(&stamp));
+            seen.insert(key);
+            if unchanged {
+                summary.unchanged += 1;
+                continue;
+            }
+            match self.index_file(path) {
+                Ok(()) => summary.indexed += 1,
+                Err(e) => {
+                    eprintln!("Library: {}", e);
+                    summary.failed += 1;
+                }
+            }
+        }
+
+        let db = self.db.lock().unwrap();
+        let unreachable = |path: &str| failed.iter().any(|dir| Path::new(path).starts_with(dir));
+        for path in known.keys().filter(|path| !seen.contains(*path) && !unreachable(path)) {
+            summary.removed += db.execute("DELETE FROM tracks WHERE path = ?1", [path])?;
+        }
+        Ok(summary)
+    }
+
+    // Read the tags of one file into the index
+    pub fn index_file(&self, path: &Path) -> Result<()> {
+        let (modified, size) = stamp(path)?;
+        let metadata = read_metadata(path)?;
+        self.db.lock().unwrap().execute(
+            "INSERT INTO tracks (path, modified, size, title, artist, album, album_artist, genre,
+                                 track_number, disc_number, date, duration, has_cover)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+             ON CONFLICT (path) DO UPDATE SET
+                modified = excluded.modified, size = excluded.size, title = excluded.title,
+                artist = excluded.artist, album = excluded.album, album_artist = excluded.album_artist,
+                genre = excluded.genre, track_number = excluded.track_number,
+                disc_number = excluded.disc_number, date = excluded.date,
+                duration = excluded.duration, has_cover = excluded.has_cover",
+            params![
+                path.to_string_lossy(),
+                modified,
+                size,
+                metadata.title,
+                metadata.artist,
+                metadata.album,
+                metadata.album_artist,
+                metadata.genre,
+                metadata.track_number,
+                metadata.disc_number,
+                metadata.date,
+                metadata.duration.map(|d| d.as_secs_f64()),
+                metadata.cover.is_some(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Drop a file, or everything below a folder, returns how many tracks went
+    pub fn remove(&self, path: &Path) -> Result<usize> {
+        let path = path.to_string_lossy();
+        let below = format!("{}/", path.trim_end_matches('/'));
+        Ok(self.db.lock().unwrap().execute(
+            "DELETE FROM tracks WHERE path = ?1 OR substr(path, 1, length(?2)) = ?2",
+            params![path, below],
+        )?)
+    }
+
+    pub fn track(&self, id: i64) -> Result<Option<LibraryTrack>> {
+        let db = self.db.lock().unwrap();
+        Ok(db
+            .query_row(
+                &format!("SELECT {} FROM tracks WHERE id = ?1", TRACK_COLUMNS),
+                [id],
+                LibraryTrack::from_row,
+            )
+            .optional()?)
+    }
+
+    pub fn artists(&self) -> Result<Vec<ArtistSummary>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(
+            "SELECT coalesce(album_artist, artist) AS name, count(DISTINCT album), count(*)
+             FROM tracks WHERE name IS NOT NULL GROUP BY name ORDER BY name COLLATE NOCASE",
+        )?;
+        let artists = statement
+            .query_map([], |row| {
+                Ok(ArtistSummary {
+                    name: row.get(0)?,
+                    albums: row.get(1)?,
+                    tracks: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(artists)
+    }
+
+    // Albums of one artist, or all of them
+    pub fn albums(&self, artist: Option<&str>) -> Result<Vec<AlbumSummary>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(
+            "SELECT album, coalesce(album_artist, artist) AS name, min(date), count(*),
+                    max(CASE WHEN has_cover THEN id END)
+             FROM tracks WHERE album IS NOT NULL AND (?1 IS NULL OR name = ?1)
+             GROUP BY album, name ORDER BY name COLLATE NOCASE, min(date), album COLLATE NOCASE",
+        )?;
+        let albums = statement
+            .query_map([artist], |row| {
+                Ok(AlbumSummary {
+                    title: row.get(0)?,
+                    artist: row.get(1)?,
+                    date: row.get(2)?,
+                    tracks: row.get(3)?,
+                    cover_track: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(albums)
+    }
+
+    pub fn genres(&self) -> Result<Vec<GenreSummary>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(
+            "SELECT genre, count(*) FROM tracks WHERE genre IS NOT NULL
+             GROUP BY genre ORDER BY genre COLLATE NOCASE",
+        )?;
+        let genres = statement
+            .query_map([], |row| {
+                Ok(GenreSummary {
+                    name: row.get(0)?,
+                    tracks: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(genres)
+    }
+
+    // Tracks in album order
+    pub fn tracks(&self, filter: &TrackFilter, limit: usize) -> Result<Vec<LibraryTrack>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(&format!(
+            "SELECT {} FROM tracks
+             WHERE (?1 IS NULL OR coalesce(album_artist, artist) = ?1)
+               AND (?2 IS NULL OR album = ?2) AND (?3 IS NULL OR genre = ?3)
+             ORDER BY album COLLATE NOCASE, disc_number, track_number, path LIMIT ?4",
+            TRACK_COLUMNS
+        ))?;
+        let tracks = statement
+            .query_map(
+                params![filter.artist, filter.album, filter.genre, limit as i64],
+                LibraryTrack::from_row,
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tracks)
+    }
+
+    // Full-text search over title, artist, album, genre and path, best matches first
+    pub fn search(&self, text: &str, limit: usize) -> Result<Vec<LibraryTrack>> {
+        let Some(query) = fts_query(text) else {
+            return Ok(Vec::new());
+        };
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(&format!(
+            "SELECT {} FROM tracks_fts JOIN tracks ON tracks.id = tracks_fts.rowid
+             WHERE tracks_fts MATCH ?1 ORDER BY rank LIMIT ?2",
+            TRACK_COLUMNS
+        ))?;
+        let tracks = statement
+            .query_map(params![query, limit as i64], LibraryTrack::from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tracks)
+    }
+
+    // Scan once, then follow changes until `running` is cleared
+    pub fn start(&self, running: Arc<AtomicBool>) -> JoinHandle<()> {
+        let library = self.clone();
+        std::thread::spawn(move || {
+            // Watches go in before the scan so nothing changed during it is missed
+            let mut watcher = FolderWatcher::new(library.folders())
+                .map_err(|e| eprintln!("Library: not watching for changes: {}", e))
+                .ok();
+            library.rescan();
+            let mut scanned = Instant::now();
+            while running.load(Ordering::SeqCst) {
+                if let Some(watcher) = watcher.as_mut()
+                    && watcher.poll(&library)
+                {
+                    // Events were lost, only a scan catches up
+                    library.rescan();
+                    scanned = Instant::now();
+                }
+                if library.config.rescan_interval.is_some_and(|interval| scanned.elapsed() >= interval) {
+                    library.rescan();
+                    scanned = Instant::now();
+                }
+                std::thread::sleep(POLL);
+            }
+        })
+    }
+
+    // Scan unless one is running, in which case that one goes again once it is done
+    pub fn rescan(&self) {
+        if self.request_scan() {
+            self.run_scans();
+        }
+    }
+
+    // Like rescan, without waiting for it
+    pub fn rescan_in_background(&self) {
+        if self.request_scan() {
+            let library = self.clone();
+            std::thread::spawn(move || library.run_scans());
+        }
+    }
+
+    // True when the caller is now the one to scan
+    fn request_scan(&self) -> bool {
+        self.scans.pending.store(true, Ordering::SeqCst);
+        !self.scans.scanning.swap(true, Ordering::SeqCst)
+    }
+
+    fn run_scans(&self) {
+        loop {
+            while self.scans.pending.swap(false, Ordering::SeqCst) {
+                self.scan_and_log();
+            }
+            self.scans.scanning.store(false, Ordering::SeqCst);
+            // A request that came in after the last check but saw the flag still set
+            if !self.scans.pending.load(Ordering::SeqCst) || self.scans.scanning.swap(true, Ordering::SeqCst) {
+                return;
+            }
+        }
+    }
+
+    fn scan_and_log(&self) {
+        let started = Instant::now();
+        match self.scan() {
+            Ok(summary) => println!(
+                "Library: {} indexed, {} unchanged, {} removed, {} failed in {:.1} s",
+                summary.indexed,
+                summary.unchanged,
+                summary.removed,
+                summary.failed,
+                started.elapsed().as_secs_f32()
+            ),
+            Err(e) => eprintln!("Library scan failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library(folders: Vec<PathBuf>) -> Library {
+        Library::open(LibraryConfig {
+            folders,
+            database: PathBuf::from(":memory:"),
+            rescan_interval: None,
+        })
+        .unwrap()
+    }
+
+    fn insert(library: &Library, path: &Path) {
+        library
+            .db
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO tracks (path, modified, size) VALUES (?1, 0, 0)",
+                [path.to_string_lossy()],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn unreachable_folder_keeps_its_tracks() {
+        let present = std::env::temp_dir().join(format!("audioserver-library-{}", std::process::id()));
+        std::fs::create_dir_all(&present).unwrap();
+        let missing = present.join("unmounted");
+        let library = library(vec![present.clone(), missing.clone()]);
+        insert(&library, &present.join("deleted.flac"));
+        insert(&library, &missing.join("album/kept.flac"));
+
+        let summary = library.scan().unwrap();
+        std::fs::remove_dir_all(&present).unwrap();
+        assert_eq!(summary.removed, 1);
+        let kept: String = library
+            .db
+            .lock()
+            .unwrap()
+            .query_row("SELECT path FROM tracks", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(Path::new(&kept), missing.join("album/kept.flac"));
+    }
+
+    #[test]
+    fn requests_during_a_scan_fold_into_one_more() {
+        let library = library(Vec::new());
+        assert!(library.request_scan());
+        // Another request while scanning does not start a second scanner
+        assert!(!library.request_scan());
+        library.run_scans();
+        assert!(!library.scans.scanning.load(Ordering::SeqCst));
+        assert!(!library.scans.pending.load(Ordering::SeqCst));
+        assert!(library.request_scan());
+    }
+
+    #[test]
+    fn search_words_are_quoted_prefixes() {
+        assert_eq!(fts_query("kind  blue").as_deref(), Some("\"kind\"* \"blue\"*"));
+        assert_eq!(fts_query("say \"hi\" OR").as_deref(), Some("\"say\"* \"\"\"hi\"\"\"* \"OR\"*"));
+        assert_eq!(fts_query("   "), None);
+    }
+}
@@ -0,0 +1,145 @@
+use anyhow::Result;
+use std::path::PathBuf;
+
+use super::Library;
+
+#[cfg(target_os = "linux")]
+pub use linux::FolderWatcher;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::*;
+    use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+    use std::collections::HashMap;
+    use std::io::ErrorKind;
+    use std::path::Path;
+
+    use crate::library::{is_audio, is_hidden, walk};
+
+    // Follows changes below the music folders through inotify, one watch per directory
+    pub struct FolderWatcher {
+        inotify: Inotify,
+        dirs: HashMap<WatchDescriptor, PathBuf>,
+        buffer: Vec<u8>,
+    }
+
+    impl FolderWatcher {
+        pub fn new(folders: &[PathBuf]) -> Result<Self> {
+            let mut watcher = FolderWatcher {
+                inotify: Inotify::init()?,
+                dirs: HashMap::new(),
+                buffer: vec![0; 64 * 1024],
+            };
+            for folder in folders {
+                watcher.watch_tree(folder);
+            }
+            Ok(watcher)
+        }
+
+        fn watch_tree(&mut self, dir: &Path) {
+            let mask = WatchMask::CLOSE_WRITE
+                | WatchMask::CREATE
+                | WatchMask::DELETE
+                | WatchMask::MOVED_FROM
+                | WatchMask::MOVED_TO;
+            match self.inotify.watches().add(dir, mask) {
+                Ok(wd) => {
+                    self.dirs.insert(wd, dir.to_path_buf());
+                }
+                Err(e) => {
+                    // Usually fs.inotify.max_user_watches on a large library
+                    eprintln!("Library: cannot watch {}: {}", dir.display(), e);
+                    return;
+                }
+            }
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                return;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if entry.file_type().is_ok_and(|t| t.is_dir()) && !is_hidden(&path) {
+                    self.watch_tree(&path);
+                }
+            }
+        }
+
+        // Apply pending changes to the index without blocking. True when the
+        // kernel dropped events and a full scan is needed.
+        pub fn poll(&mut self, library: &Library) -> bool {
+            let mut changes = Vec::new();
+            let mut overflow = false;
+            loop {
+                let events = match self.inotify.read_events(&mut self.buffer) {
+                    Ok(events) => events,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        eprintln!("Library: inotify: {}", e);
+                        break;
+                    }
+                };
+                let mut any = false;
+                for event in events {
+                    any = true;
+                    if event.mask.contains(EventMask::Q_OVERFLOW) {
+                        overflow = true;
+                        continue;
+                    }
+                    if event.mask.contains(EventMask::IGNORED) {
+                        self.dirs.remove(&event.wd);
+                        continue;
+                    }
+                    let (Some(dir), Some(name)) = (self.dirs.get(&event.wd), event.name) else {
+                        continue;
+                    };
+                    changes.push((event.mask, dir.join(name)));
+                }
+                if !any {
+                    break;
+                }
+            }
+
+            for (mask, path) in changes {
+                if is_hidden(&path) {
+                    continue;
+                }
+                let is_dir = mask.contains(EventMask::ISDIR);
+                if mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+                    if let Err(e) = library.remove(&path) {
+                        eprintln!("Library: {}", e);
+                    }
+                } else if is_dir && mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                    // A folder copied or moved in, possibly with files already in it
+                    self.watch_tree(&path);
+                    let mut files = Vec::new();
+                    walk(&path, &mut files, &mut Vec::new());
+                    for file in files {
+                        index(library, &file);
+                    }
+                } else if !is_dir && mask.intersects(EventMask::CLOSE_WRITE | EventMask::MOVED_TO) && is_audio(&path) {
+                    index(library, &path);
+                }
+            }
+            overflow
+        }
+    }
+
+    fn index(library: &Library, path: &Path) {
+        if let Err(e) = library.index_file(path) {
+            eprintln!("Library: {}", e);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct FolderWatcher;
+
+#[cfg(not(target_os = "linux"))]
+impl FolderWatcher {
+    pub fn new(_folders: &[PathBuf]) -> Result<Self> {
+        Err(anyhow::anyhow!("not supported on this platform"))
+    }
+
+    pub fn poll(&mut self, _library: &Library) -> bool {
+        false
+    }
+}
@@ -1,50 +1,112 @@
-use anyhow::Result;
+mod analyzer;
+mod api;
+mod correction;
+mod crossover;
+mod decoder;
+mod dsp;
+mod eq;
+mod filter;
+mod generator;
+mod http;
+mod import;
+mod latency;
+mod library;
+mod meter;
+mod mixer;
+mod pcm;
+mod phase;
+mod player;
+mod protection;
+mod pipeline;
+mod radio;
+mod raop;
+mod replaygain;
+mod rt;
+mod standby;
+mod stats;
+mod status;
+mod upnp;
+mod volume;
+mod workers;
+
+use analyzer::{AnalyzerConfig, AnalyzerHub, Averaging, Smoothing, TapPoint};
+use anyhow::{anyhow, Context, Result};
+use api::ApiServer;
+use correction::{Correction, Normalization};
+use crossover::Crossover;
+use eq::Equalizer;
+use filter::AudioFilter;
+use generator::{Generator, GeneratorConfig, Signal};
+use import::ImportFormat;
+use latency::LatencyProbe;
+use library::{Library, LibraryConfig};
+use meter::{AtomicF32, MeterBallistics, MeterBank, MeterHub, MeterProcessor};
+use mixer::{MatrixControl, MatrixPlacement, MixMatrix};
+use pipeline::{Pipeline, PipelineConfig};
+use pcm::{PcmConfig, PcmFormat, PcmInput, PcmTransport};
+use radio::{Radio, RadioConfig};
+use raop::RaopConfig;
+use replaygain::ReplayGainConfig;
+use player::{format_time, PlaybackState, Player};
+use protection::{DriverModel, ExcursionLimiter};
 use rodio::cpal::traits::{HostTrait, StreamTrait};
-use rodio::{cpal, Decoder, Device, DeviceTrait, OutputStream, Sink, Source};
-use std::fs::File;
-use std::io::{self, BufReader, Write};
+use rodio::{cpal, Device, DeviceTrait};
+use rt::{RealtimeConfig, RealtimeState, INPUT_CALLBACK, OUTPUT_CALLBACK};
+use rtrb::RingBuffer;
+use standby::{SignalDetector, StandbyAction, StandbyConfig, StandbyHook, StandbyHub};
+use stats::EngineStats;
+use status::StatusReport;
+use upnp::{UpnpConfig, UpnpRenderer};
+use volume::{LoudnessConfig, VolumeControl};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+// What feeds the processing pipeline
 #[derive(Debug, Clone)]
-struct AudioFilter {
-    fir_coefficients: Vec<f32>,
-}
-
-impl AudioFilter {
-    fn new(coefficients: Vec<f32>) -> Self {
-        AudioFilter { fir_coefficients: coefficients }
-    }
-
-    fn apply(&self, input: &[f32]) -> Vec<f32> {
-        let mut output = Vec::new();
-        for i in 0..input.len() {
-            let mut sum = 0.0;
-            for j in 0..self.fir_coefficients.len() {
-                if i >= j {
-                    sum += input[i - j] * self.fir_coefficients[j];
-                }
-            }
-            output.push(sum);
-        }
-        output
-    }
-}
-
-struct AudioPlayer {
-    sink: Sink,
-    _stream: OutputStream,
-    _stream_handle: rodio::OutputStreamHandle,
+enum InputSource {
+    Capture,
+    Generator(GeneratorConfig),
+    // The play queue, decoded and resampled to the output rate with this many channels
+    Player { channels: usize },
+    // Stereo from AirPlay senders, resampled to the output rate
+    AirPlay(RaopConfig),
+    // Internet radio station, stereo at the output rate
+    Radio(RadioConfig),
+    // Raw PCM another program writes to a pipe or socket, resampled to the output rate
+    Pcm(PcmConfig),
 }
 
 struct AudioTransformer {
     input_device: cpal::Device,
     output_device: cpal::Device,
-    filter: Option<AudioFilter>,
+    source: InputSource,
+    player: Player,
+    // Station status while InputSource::Radio plays
+    radio: Option<Radio>,
+    // Sender status while InputSource::Pcm plays
+    pcm: Option<PcmInput>,
+    pipeline: PipelineConfig,
+    realtime: RealtimeConfig,
+    realtime_state: Option<Arc<RealtimeState>>,
+    meter_ballistics: MeterBallistics,
+    meter_rate_hz: f32,
+    meters: MeterHub,
+    analyzer_config: AnalyzerConfig,
+    analyzer: AnalyzerHub,
+    latency: Option<Arc<LatencyProbe>>,
+    stats: Option<Arc<EngineStats>>,
+    // Load and xrun summaries in the log, none unless asked for
+    stats_log_interval: Option<Duration>,
+    // Auto standby on input silence, off when None
+    standby: Option<StandbyConfig>,
+    standby_hub: StandbyHub,
     running: Arc<AtomicBool>,
     processing_thread: Option<JoinHandle<()>>,
+    // Meter publisher, analyzer and other helpers living as long as the streams
+    aux_threads: Vec<JoinHandle<()>>,
 }
 
 impl AudioTransformer {
@@ -52,14 +114,218 @@ impl AudioTransformer {
         Ok(Self {
             input_device,
             output_device,
-            filter: None,
+            source: InputSource::Capture,
+            player: Player::default(),
+            radio: None,
+            pcm: None,
+            pipeline: PipelineConfig::default(),
+            realtime: RealtimeConfig::default(),
+            realtime_state: None,
+            meter_ballistics: MeterBallistics::default(),
+            meter_rate_hz: 30.0,
+            meters: MeterHub::default(),
+            analyzer_config: AnalyzerConfig::default(),
+            analyzer: AnalyzerHub::default(),
+            latency: None,
+            stats: None,
+            stats_log_interval: None,
+            standby: None,
+            standby_hub: StandbyHub::default(),
             running: Arc::new(AtomicBool::new(false)),
             processing_thread: None,
+            aux_threads: Vec::new(),
         })
     }
 
-    fn set_filter(&mut self, coefficients: Vec<f32>) {
-        self.filter = Some(AudioFilter::new(coefficients));
+    // FIR on every source channel, takes effect on the next start_processing
+    fn set_filter(&mut self, filter: Option<AudioFilter>) {
+        self.pipeline.filter = filter;
+    }
+
+    // Takes effect on the next start_processing
+    fn set_crossover(&mut self, crossover: Option<Crossover>) {
+        self.pipeline.crossover = crossover;
+    }
+
+    // Applies immediately, also while processing
+    fn set_volume_db(&self, volume_db: f32) {
+        self.pipeline.volume.set_volume_db(volume_db);
+    }
+
+    // Shared with remote controls that set the volume themselves
+    fn volume_control(&self) -> VolumeControl {
+        self.pipeline.volume.clone()
+    }
+
+    // None for plain gain, Some to add equal-loudness compensation as the volume goes down.
+    // Applies immediately, also while processing.
+    fn set_loudness(&self, loudness: Option<LoudnessConfig>) {
+        self.pipeline.volume.set_loudness(loudness);
+    }
+
+    // ReplayGain/R128 normalization of the file player, from tags or measured.
+    // Takes effect from the next track.
+    fn set_replay_gain(&self, config: Option<ReplayGainConfig>) {
+        self.player.set_replay_gain(config);
+    }
+
+    // Channel mixing matrix, e.g. MixMatrix::balance(0.2).then(&MixMatrix::width(1.3)).
+    // A new matrix of the same size and placement applies immediately, also while
+    // processing, anything else takes effect on the next start_processing.
+    fn set_matrix(&mut self, matrix: Option<MixMatrix>, placement: MatrixPlacement) -> Result<()> {
+        let Some(matrix) = matrix else {
+            self.pipeline.matrix = None;
+            return Ok(());
+        };
+        let same_place = placement == self.pipeline.matrix_placement;
+        let running = self.pipeline.matrix.as_ref().filter(|control| {
+            let current = control.matrix();
+            same_place && (current.inputs, current.outputs) == (matrix.inputs, matrix.outputs)
+        });
+        match running {
+            Some(control) => control.set(matrix)?,
+            None => self.pipeline.matrix = Some(MatrixControl::new(matrix)),
+        }
+        self.pipeline.matrix_placement = placement;
+        Ok(())
+    }
+
+    // Takes effect on the next start_processing
+    fn set_equalizer(&mut self, eq: Option<Equalizer>) {
+        self.pipeline.eq = eq;
+    }
+
+    // Replace the EQ (and the crossover, if the config defines one) with filters from
+    // another tool. Returns what could not be imported, which is also logged.
+    fn import_filters(&mut self, path: &Path, format: Option<ImportFormat>) -> Result<Vec<String>> {
+        let imported = import::import_file(path, format)?;
+        for item in &imported.unsupported {
+            eprintln!("{}: not imported: {}", path.display(), item);
+        }
+        self.set_equalizer(Some(imported.equalizer));
+        if imported.crossover.is_some() {
+            self.set_crossover(imported.crossover);
+        }
+        Ok(imported.unsupported)
+    }
+
+    // Room correction impulse responses, resampled to the pipeline rate when processing
+    // starts. Takes effect on the next start_processing.
+    fn set_correction(&mut self, correction: Option<Correction>) {
+        self.pipeline.correction = correction;
+    }
+
+    // Takes effect on the next start_processing
+    fn set_worker_threads(&mut self, worker_threads: usize) {
+        self.pipeline.worker_threads = worker_threads;
+    }
+
+    // Scheduling, memory locking and CPU pinning of the audio threads, takes effect on the next start_processing
+    fn set_realtime(&mut self, realtime: RealtimeConfig) {
+        self.realtime = realtime;
+    }
+
+    // Queue and transport of the file player, used with InputSource::Player
+    fn player(&self) -> &Player {
+        &self.player
+    }
+
+    // Takes effect on the next start_processing
+    fn set_source(&mut self, source: InputSource) {
+        self.source = source;
+    }
+
+    // How often the load and xrun summary is logged, takes effect on the next start_processing
+    fn set_stats_log_interval(&mut self, interval: Option<Duration>) {
+        self.stats_log_interval = interval;
+    }
+
+    // Standby after the input has been silent for a while, takes effect on the next start_processing
+    fn set_standby(&mut self, standby: Option<StandbyConfig>) {
+        self.standby = standby;
+    }
+
+    // Shared with the API, which reports every transition between active and standby
+    fn standby_hub(&self) -> StandbyHub {
+        self.standby_hub.clone()
+    }
+
+    // Takes effect on the next start_processing
+    fn set_meter_ballistics(&mut self, ballistics: MeterBallistics, rate_hz: f32) {
+        self.meter_ballistics = ballistics;
+        self.meter_rate_hz = rate_hz;
+    }
+
+    // Shared with the API, which streams the levels and resets the clip indicators
+    fn meters(&self) -> MeterHub {
+        self.meters.clone()
+    }
+
+    // Taps and analyzer settings take effect on the next start_processing
+    fn add_analyzer_tap(&mut self, point: TapPoint) {
+        self.analyzer.add_tap(point);
+    }
+
+    fn set_analyzer_config(&mut self, config: AnalyzerConfig) {
+        self.analyzer_config = config;
+    }
+
+    // Shared with the API, which streams the spectra of every tap
+    fn analyzer(&self) -> AnalyzerHub {
+        self.analyzer.clone()
+    }
+
+    fn status(&self) -> StatusReport {
+        StatusReport {
+            running: self.processing_thread.is_some(),
+            source: match &self.source {
+                InputSource::Capture => format!("capture: {}", device_name(&self.input_device)),
+                InputSource::Generator(config) => format!("generator: {:?}", config.signal),
+                InputSource::Player { .. } => {
+                    let status = self.player.status();
+                    match status.entry {
+                        Some(entry) => format!(
+                            "player: {} {} / {}",
+                            entry.path.display(),
+                            format_time(status.position),
+                            status.duration.map_or("?".to_string(), format_time)
+                        ),
+                        None => "player: stopped".to_string(),
+                    }
+                }
+                InputSource::AirPlay(config) => format!("airplay: {}", config.name),
+                InputSource::Radio(config) => match &self.radio {
+                    Some(radio) => format!(
+                        "radio: {}{}{}",
+                        radio.station(),
+                        radio.title().map(|title| format!(" - {}", title)).unwrap_or_default(),
+                        match (radio.connected(), radio.buffering()) {
+                            (false, _) => " (connecting)",
+                            (true, true) => " (buffering)",
+                            (true, false) => "",
+                        }
+                    ),
+                    None => format!("radio: {}", config.url),
+                },
+                InputSource::Pcm(config) => match &self.pcm {
+                    Some(pcm) => format!(
+                        "pcm: {} {}, {} underruns, {} frames dropped{}",
+                        config.transport,
+                        config.format,
+                        pcm.underruns(),
+                        pcm.dropped(),
+                        if pcm.playing() { "" } else { " (waiting)" }
+                    ),
+                    None => format!("pcm: {} {}", config.transport, config.format),
+                },
+            },
+            latency: self.latency.as_ref().map(|probe| probe.report()),
+            stats: self.stats.as_ref().map(|stats| stats.report()),
+            realtime: self.realtime_state.as_ref().map(|state| state.report()),
+            volume_db: self.pipeline.volume.volume_db(),
+            loudness_shelves_db: self.pipeline.volume.loudness().map(|_| self.pipeline.volume.shelf_gains_db()),
+            power: self.standby_hub.state(),
+        }
     }
 
     fn start_processing(&mut self) -> Result<()> {
@@ -67,61 +333,336 @@ impl AudioTransformer {
             return Ok(());  // Already running
         }
 
+        // Source threads stop as soon as they see the flag cleared, so it is set before
+        // any of them spawns. A failed start stops and joins whatever did start.
+        self.running.store(true, Ordering::SeqCst);
+        if let Err(e) = self.spawn_processing() {
+            self.stop_processing();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn spawn_processing(&mut self) -> Result<()> {
         let input_device = self.input_device.clone();
         let output_device = self.output_device.clone();
-        let filter = self.filter.clone();
         let running = self.running.clone();
 
-        running.store(true, Ordering::SeqCst);
+        // Setup input and output stream configs, only capture needs the input device
+        let input_config = match self.source {
+            InputSource::Capture => Some(input_device.default_input_config()?),
+            _ => None,
+        };
+        let output_config = output_device.default_output_config()?;
+
+        // The generator plays straight into the output layout, capture keeps the device layout
+        let (source_channels, source_rate) = match (&self.source, input_config.as_ref()) {
+            (InputSource::Capture, Some(config)) => (config.channels() as usize, config.sample_rate().0),
+            (InputSource::Player { channels }, _) => (*channels, output_config.sample_rate().0),
+            (InputSource::AirPlay(_) | InputSource::Radio(_), _) => (2, output_config.sample_rate().0),
+            (InputSource::Pcm(config), _) => (config.format.channels, output_config.sample_rate().0),
+            _ => (output_config.channels() as usize, output_config.sample_rate().0),
+        };
+        let output_channels = output_config.channels() as usize;
+        let mut generator = match &self.source {
+            InputSource::Generator(config) => Some(Generator::new(config.clone(), source_rate)?),
+            _ => None,
+        };
+        // Sources that resample report their look-ahead here for the latency report
+        let resampler_delay = Arc::new(AtomicF32::default());
+        let mut player_source = match &self.source {
+            InputSource::Player { .. } => {
+                let (source, decoder_thread) =
+                    self.player.start(source_rate, source_channels, resampler_delay.clone(), running.clone());
+                self.aux_threads.push(decoder_thread);
+                Some(source)
+            }
+            _ => None,
+        };
+        let mut airplay_source = match &self.source {
+            InputSource::AirPlay(config) => {
+                let (source, threads) = raop::start(
+                    config.clone(),
+                    source_rate,
+                    self.pipeline.volume.clone(),
+                    resampler_delay.clone(),
+                    running.clone(),
+                )?;
+                self.aux_threads.extend(threads);
+                Some(source)
+            }
+            _ => None,
+        };
+        let mut radio_source = match &self.source {
+            InputSource::Radio(config) => {
+                let (source, radio, thread) =
+                    radio::start(config.clone(), source_rate, resampler_delay.clone(), running.clone());
+                self.aux_threads.push(thread);
+                self.radio = Some(radio);
+                Some(source)
+            }
+            _ => None,
+        };
+        let mut pcm_source = match &self.source {
+            InputSource::Pcm(config) => {
+                let (source, pcm, thread) =
+                    pcm::start(config.clone(), source_rate, resampler_delay.clone(), running.clone())?;
+                self.aux_threads.push(thread);
+                self.pcm = Some(pcm);
+                Some(source)
+            }
+            _ => None,
+        };
+        // Everything but capture renders in the output callback
+        let renders_in_output = input_config.is_none();
+
+        // Meters for every input and output channel
+        let input_bank = Arc::new(MeterBank::new(source_channels));
+        let output_bank = Arc::new(MeterBank::new(output_channels));
+        let mut input_meter = MeterProcessor::new(
+            input_bank.clone(),
+            source_rate,
+            self.meter_ballistics,
+        );
+        let mut output_meter = MeterProcessor::new(
+            output_bank.clone(),
+            output_config.sample_rate().0,
+            self.meter_ballistics,
+        );
+
+        self.aux_threads.push(self.meters.start_publisher(
+            input_bank,
+            output_bank,
+            self.meter_rate_hz,
+            running.clone(),
+        ));
+
+        // Analyzer taps at the pipeline input and band outputs
+        let (mut taps, analyzer_thread) = self.analyzer.start(
+            self.analyzer_config,
+            source_rate,
+            running.clone(),
+        );
+        self.aux_threads.extend(analyzer_thread);
+
+        // Memory is locked before the pipeline allocates, threads are promoted once they run
+        let realtime_state = Arc::new(RealtimeState::new(self.realtime.clone()));
+        realtime_state.lock_memory();
+        self.realtime_state = Some(realtime_state.clone());
+
+        // Filter, correction and crossover bands, built for the actual rate before anything starts
+        let mut pipeline = Pipeline::new(
+            &self.pipeline,
+            source_channels,
+            output_channels,
+            source_rate,
+            &realtime_state,
+        )?;
+
+        // Static delays of the chain, the callbacks fill in the device and transport parts
+        let latency = Arc::new(LatencyProbe::new(
+            source_rate,
+            pipeline.latency_frames(),
+            resampler_delay,
+        ));
+        self.latency = Some(latency.clone());
+
+        // Callback timing and transport health
+        let stats = Arc::new(EngineStats::default());
+        self.stats = Some(stats.clone());
+        self.aux_threads.push(stats.start_logger(self.stats_log_interval, running.clone()));
+        let output_rate = output_config.sample_rate().0;
+
+        // Signal detection on the source for auto standby
+        let detector = self.standby.as_ref().map(|config| Arc::new(SignalDetector::new(config.threshold_db)));
+        if let (Some(config), Some(detector)) = (self.standby.as_ref(), detector.as_ref()) {
+            self.aux_threads.push(self.standby_hub.start_monitor(detector.clone(), config.clone(), running.clone()));
+        }
+        // Only capture can pause the output, other sources run in the output callback
+        let stop_output = !renders_in_output
+            && self.standby.as_ref().map(|config| config.action) == Some(StandbyAction::StopOutput);
+        let output_stopped = Arc::new(AtomicBool::new(false));
+
+        // Ring buffer between the input and output callbacks, 200 ms of output frames
+        let (mut producer, mut consumer) = RingBuffer::<f32>::new(
+            (source_rate as usize / 5).max(1024) * output_channels,
+        );
+
+        let process_detector = detector.clone();
+        // Pipeline shared by every source: input meters and taps, filter, correction and bands, band taps
+        let process = move |data: &[f32], processed_data: &mut Vec<f32>| {
+            input_meter.process(data);
+            if let Some(detector) = process_detector.as_ref() {
+                detector.observe(data);
+            }
+            for tap in taps.iter_mut() {
+                if let TapPoint::Input(channel) = tap.point() {
+                    tap.push(data, source_channels, channel);
+                }
+            }
+
+            pipeline.process(data, processed_data);
+
+            for tap in taps.iter_mut() {
+                if let TapPoint::Band(band) = tap.point() {
+                    // Without a crossover, band i is output channel i
+                    match pipeline.band_output(band) {
+                        Some(samples) => tap.push(samples, 1, 0),
+                        None if !pipeline.has_bands() => tap.push(processed_data, output_channels, band),
+                        None => {}
+                    }
+                }
+            }
+        };
 
         let handle = std::thread::spawn(move || {
-            // Setup input stream config
-            let input_config = input_device.default_input_config().unwrap();
-
-            // Setup output stream config
-            let output_config = output_device.default_output_config().unwrap();
-
-            // Create a channel to transfer audio data
-            let (tx, rx) = std::sync::mpsc::channel();
-
-            // Setup input stream
-            let input_stream = input_device.build_input_stream(
-                &input_config.into(),
-                move |data: &[f32], _: &_| {
-                    // Apply filter if available
-                    let processed_data = if let Some(ref f) = filter {
-                        f.apply(data)
-                    } else {
-                        data.to_vec()
-                    };
+            // The pipeline runs in the input callback for capture, in the output callback otherwise
+            let (capture_process, mut source_process) = if renders_in_output {
+                (None, Some(process))
+            } else {
+                (Some(process), None)
+            };
 
-                    let _ = tx.send(processed_data);
-                },
-                |err| eprintln!("Input stream error: {}", err),
+            // Setup input stream, only for capture
+            let input_latency = latency.clone();
+            let input_stats = stats.clone();
+            let input_realtime = realtime_state.clone();
+            let input_stopped = output_stopped.clone();
+            let input_stream = if let (Some(mut process), Some(input_config)) = (capture_process, input_config) {
+                let mut processed_data = Vec::new();
+                let mut announced = false;
+                let stream = input_device.build_input_stream(
+                    &input_config.into(),
+                    move |data: &[f32], info: &cpal::InputCallbackInfo| {
+                        // Callbacks run on threads owned by the audio backend, the loop below promotes them
+                        if !announced {
+                            input_realtime.announce_callback(INPUT_CALLBACK);
+                            announced = true;
+                        }
+                        let started = Instant::now();
+                        input_latency.record_input(info);
+                        process(data, &mut processed_data);
+
+                        // Nothing is queued while the output is paused for standby
+                        if input_stopped.load(Ordering::Relaxed) {
+                            input_stats.input.record(started.elapsed(), data.len() / source_channels, source_rate);
+                            return;
+                        }
+
+                        // Only whole blocks go into the transport so frames stay aligned
+                        if producer.slots() >= processed_data.len() {
+                            for &sample in processed_data.iter() {
+                                let _ = producer.push(sample);
+                            }
+                        } else {
+                            input_stats.record_overrun();
+                        }
+
+                        input_stats.input.record(started.elapsed(), data.len() / source_channels, source_rate);
+                    },
+                    |err| eprintln!("Input stream error: {}", err),
+                    None
+                ).unwrap();
+                Some(stream)
+            } else {
                 None
-            ).unwrap();
+            };
 
             // Setup output stream
+            let mut source_buffer = Vec::new();
+            let mut processed_data = Vec::new();
+            let mut announced = false;
+            let output_realtime = realtime_state.clone();
+            let output_detector = detector.clone();
+            let resumed = output_stopped.clone();
+            // Underruns only count once the input has started delivering
+            let mut primed = false;
             let output_stream = output_device.build_output_stream(
                 &output_config.into(),
-                move |data: &mut [f32], _: &_| {
-                    if let Ok(processed_data) = rx.try_recv() {
-                        // Copy processed data to output buffer
-                        let len = std::cmp::min(data.len(), processed_data.len());
-                        data[..len].copy_from_slice(&processed_data[..len]);
+                move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+                    if !announced {
+                        output_realtime.announce_callback(OUTPUT_CALLBACK);
+                        announced = true;
+                    }
+                    let started = Instant::now();
+                    latency.record_output(info);
+                    if let Some(process) = source_process.as_mut() {
+                        source_buffer.resize(data.len() / output_channels * source_channels, 0.0);
+                        if let Some(generator) = generator.as_mut() {
+                            generator.fill(&mut source_buffer, source_channels);
+                        } else if let Some(player) = player_source.as_mut() {
+                            player.fill(&mut source_buffer);
+                        } else if let Some(airplay) = airplay_source.as_mut() {
+                            airplay.fill(&mut source_buffer);
+                        } else if let Some(radio) = radio_source.as_mut() {
+                            radio.fill(&mut source_buffer);
+                        } else if let Some(pcm) = pcm_source.as_mut() {
+                            pcm.fill(&mut source_buffer);
+                        }
+                        process(&source_buffer, &mut processed_data);
+                        data.copy_from_slice(&processed_data);
+                    } else {
+                        // Back from standby: drop what was queued before the pause and prime again
+                        if resumed.load(Ordering::Relaxed) {
+                            while consumer.pop().is_ok() {}
+                            primed = false;
+                            resumed.store(false, Ordering::Relaxed);
+                        }
+                        let queued = consumer.slots() / output_channels;
+                        latency.record_transport(queued);
+                        primed |= queued > 0;
+                        if primed && queued < data.len() / output_channels {
+                            stats.record_underrun();
+                        }
+
+                        // The transport carries output frames, silence when it runs dry
+                        for frame in data.chunks_mut(output_channels) {
+                            if consumer.slots() < output_channels {
+                                frame.fill(0.0);
+                                continue;
+                            }
+                            for out in frame.iter_mut() {
+                                *out = consumer.pop().unwrap_or(0.0);
+                            }
+                        }
+                    }
+
+                    if output_detector.as_ref().is_some_and(|detector| detector.is_standby()) {
+                        data.fill(0.0);
                     }
+
+                    output_meter.process(data);
+                    stats.output.record(started.elapsed(), data.len() / output_channels, output_rate);
                 },
                 |err| eprintln!("Output stream error: {}", err),
                 None
             ).unwrap();
 
             // Start streams
-            input_stream.play().unwrap();
+            if let Some(stream) = input_stream.as_ref() {
+                stream.play().unwrap();
+            }
             output_stream.play().unwrap();
 
-            // Keep thread alive while processing
+            // Keep thread alive while processing, promoting the callback threads once they
+            // have run and pausing the output during standby if asked to
+            let mut paused = false;
             while running.load(Ordering::SeqCst) {
                 std::thread::sleep(std::time::Duration::from_millis(100));
+                realtime_state.apply_announced();
+                let standby = stop_output && detector.as_ref().is_some_and(|detector| detector.is_standby());
+                if standby != paused {
+                    let result = if standby {
+                        output_stream.pause().map_err(|e| e.to_string())
+                    } else {
+                        output_stream.play().map_err(|e| e.to_string())
+                    };
+                    if let Err(e) = result {
+                        eprintln!("Output stream {}: {}", if standby { "pause" } else { "resume" }, e);
+                    }
+                    output_stopped.store(standby, Ordering::Relaxed);
+                    paused = standby;
+                }
             }
         });
 
@@ -130,167 +671,46 @@ impl AudioTransformer {
     }
 
     fn stop_processing(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
         if let Some(handle) = self.processing_thread.take() {
-            self.running.store(false, Ordering::SeqCst);
             let _ = handle.join();
         }
+        for handle in self.aux_threads.drain(..) {
+            let _ = handle.join();
+        }
+        self.latency = None;
+        self.radio = None;
+        self.pcm = None;
+        self.stats = None;
+        self.realtime_state = None;
     }
 }
 
-impl AudioPlayer {
-    fn new() -> Result<Self> {
-        // Use default device
-        let (stream, stream_handle) = OutputStream::try_default()?;
-        let sink = Sink::try_new(&stream_handle)?;
-        
-        Ok(AudioPlayer {
-            sink,
-            _stream: stream,
-            _stream_handle: stream_handle,
-        })
-    }
-
-    fn new_with_cpal_device(device: &cpal::Device) -> Result<Self> {
-        let (stream, stream_handle) = OutputStream::try_from_device(&device)?;
-        let sink = Sink::try_new(&stream_handle)?;
-        
-        Ok(AudioPlayer {
-            sink,
-            _stream: stream,
-            _stream_handle: stream_handle,
-        })
-    }
-
-    // List all available audio input devices
-    fn list_input_devices() -> Vec<cpal::Device> {
-        let host = cpal::default_host();
-        host.input_devices()
-            .expect("Failed to get audio input devices")
-            .collect()
-    }
-
-    // List all available audio output_devices
-    fn list_output_devices() -> Vec<cpal::Device> {
-        let host = cpal::default_host();
-        host.output_devices()
-            .expect("Failed to get audio output devices")
-            .collect()
-    }
-
-    // Get device name
-    fn get_device_name(device: &Device) -> String {
-        device.name()
-            .unwrap_or_else(|_| "Unknown Device".to_string())
-    }
-
-    fn play_file(&self, path: &str) -> Result<()> {
-        let file = BufReader::new(File::open(path)?);
-        let source = Decoder::new(file)?;
-        
-        // // Convert i16 samples to f32 and apply low-pass filter
-        // let samples = source.sa .samples().collect::<Vec<f32>>();
-        // let filter = AudioFilter::new(vec![0.2, 0.3, 0.5]);
-        // let filtered_samples = filter.apply(&samples);
-        // let filtered_source = rodio::source::from_iter(filtered_samples.into_iter());
-        // self.sink.append(filtered_source);
-        // self.sink.play();
-
-        let converted_source = source.convert_samples::<f32>();
-        let filtered_source = converted_source;
-        self.sink.append(filtered_source);
-        self.sink.play();
-        
-        Ok(())
-    }
-
-    fn apply_low_pass(&self, path: &str) -> Result<()> {
-        let file = BufReader::new(File::open(path)?);
-        let source = Decoder::new(file)?;
-
-        // // Define 6-order FIR filter coefficients
-        // // These coefficients should be adjusted based on the desired filter response
-        // let fir_coefficients = [0.1, 0.2, 0.3, 0.3, 0.2, 0.1]; // Example coefficients
-
-        // // Apply the FIR filter to the source
-        // let filtered_source = rodio::source::Zero; // ::source::Source::filter(
-        //     converted_source,
-        //     move |samples: &mut [f32], _: &cpal::SampleFormat| {
-        //         // Buffer for previous samples (for FIR calculation)
-        //         let mut buffer = vec![0.0; fir_coefficients.len()];
-
-        //         for sample in samples.iter_mut() {
-        //             // Shift buffer values
-        //             for i in (1..buffer.len()).rev() {
-        //                 buffer[i] = buffer[i-1];
-        //             }
-        //             buffer[0] = *sample;
-
-        //             // Apply FIR filter
-        //             let mut filtered_value = 0.0;
-        //             for (i, coeff) in fir_coefficients.iter().enumerate() {
-        //                 filtered_value += buffer[i] * coeff;
-        //             }
-
-        //             *sample = filtered_value;
-        //         }
-        //     },
-        // );
-        
-        // Convert i16 samples to f32 and apply low-pass filter
-        let converted_source = source.convert_samples::<f32>();
-        let filtered_source = converted_source.low_pass(200);
-        self.sink.append(filtered_source);
-        self.sink.play();
-        
-        Ok(())
-    }
-
-    fn pause(&self) {
-        self.sink.pause();
-    }
-
-    fn resume(&self) {
-        self.sink.play();
-    }
-
-    fn stop(&self) {
-        self.sink.stop();
-    }
+// List all available audio input devices
+fn list_input_devices() -> Vec<cpal::Device> {
+    let host = cpal::default_host();
+    host.input_devices()
+        .expect("Failed to get audio input devices")
+        .collect()
+}
 
-    fn set_volume(&self, volume: f32) {
-        self.sink.set_volume(volume);
-    }
+// List all available audio output_devices
+fn list_output_devices() -> Vec<cpal::Device> {
+    let host = cpal::default_host();
+    host.output_devices()
+        .expect("Failed to get audio output devices")
+        .collect()
+}
 
-    // Basic high-pass filter implementation
-    fn apply_high_pass(&self, path: &str, cutoff_frequency: u32) -> Result<()> {
-        let file = BufReader::new(File::open(path)?);
-        let source = Decoder::new(file)?;
-        let converted_source = source.convert_samples::<f32>();
-        let filtered_source = converted_source.high_pass(cutoff_frequency);
-        self.sink.append(filtered_source);
-        self.sink.play();
-        Ok(())
-    }
+fn device_name(device: &Device) -> String {
+    device.name()
+        .unwrap_or_else(|_| "Unknown Device".to_string())
 }
 
+// The first output device
 fn select_device() -> Result<Device> {
-    let devices = AudioPlayer::list_output_devices();
-    
-    // println!("Available audio devices:");
-    // for (idx, device) in devices.iter().enumerate() {
-    //     println!("{}: {}", idx, AudioPlayer::get_device_name(device));
-    // }
-
-    // print!("Select device number: ");
-    // io::stdout().flush()?;
-    
-    // let mut input = String::new();
-    // io::stdin().read_line(&mut input)?;
-    
-    // let device_idx = input.trim().parse::<usize>()?;
-
-    let device_idx = 0;
-    devices.get(device_idx)
+    list_output_devices()
+        .first()
         .cloned()
         .ok_or_else(|| anyhow::anyhow!("Invalid device index"))
 }
@@ -298,34 +718,207 @@ fn select_device() -> Result<Device> {
 fn main() -> Result<()> {
     // List all available audio input devices
     println!("Available audio input devices:");
-    let devices = AudioPlayer::list_input_devices();
+    let devices = list_input_devices();
     for (idx, device) in devices.iter().enumerate() {
-        println!("{}: {}", idx, AudioPlayer::get_device_name(device));
+        println!("{}: {}", idx, device_name(device));
     }
 
     // List all available audio output devices
     println!("Available audio output devices:");
-    let devices = AudioPlayer::list_output_devices();
+    let devices = list_output_devices();
     for (idx, device) in devices.iter().enumerate() {
-        println!("{}: {}", idx, AudioPlayer::get_device_name(device));
+        println!("{}: {}", idx, device_name(device));
     }
 
     // Example: Use the first available device (if any)
-    let player = if let Ok(device) = select_device().as_ref() {
-        println!("Using device: {}", AudioPlayer::get_device_name(device));
-        AudioPlayer::new_with_cpal_device(device)?
+    let output_device = match select_device() {
+        Ok(device) => device,
+        Err(_) => {
+            println!("No devices found, using default");
+            cpal::default_host()
+                .default_output_device()
+                .ok_or_else(|| anyhow::anyhow!("No output device"))?
+        }
+    };
+    println!("Using device: {}", device_name(&output_device));
+
+    // The player feeds the pipeline itself, the input device is only there for capture
+    let input_device = cpal::default_host()
+        .default_input_device()
+        .unwrap_or_else(|| output_device.clone());
+    let mut transformer = AudioTransformer::new(input_device, output_device)?;
+    // AUDIOSERVER_AIRPLAY=<name> turns the engine into an AirPlay receiver instead of playing files,
+    // AUDIOSERVER_RADIO=<url> plays an internet radio station and AUDIOSERVER_PCM=<transport> raw
+    // PCM of the format in AUDIOSERVER_PCM_FORMAT. AUDIOSERVER_GENERATOR=<signal> plays a test signal
+    // at AUDIOSERVER_GENERATOR_LEVEL dB on the AUDIOSERVER_GENERATOR_CHANNELS=0,1 instead of all that.
+    let generator = std::env::var("AUDIOSERVER_GENERATOR").ok().filter(|signal| !signal.is_empty());
+    let airplay = std::env::var("AUDIOSERVER_AIRPLAY").ok().filter(|name| !name.is_empty());
+    let radio = std::env::var("AUDIOSERVER_RADIO").ok().filter(|url| !url.is_empty());
+    let pcm = std::env::var("AUDIOSERVER_PCM").ok().filter(|transport| !transport.is_empty());
+    if let Some(signal) = &generator {
+        let level_db = match std::env::var("AUDIOSERVER_GENERATOR_LEVEL") {
+            Ok(level) => {
+                let level_db = level.trim().parse();
+                level_db.map_err(|_| anyhow!("AUDIOSERVER_GENERATOR_LEVEL: expected dB, got {}", level))?
+            }
+            Err(_) => -20.0,
+        };
+        let channels = match std::env::var("AUDIOSERVER_GENERATOR_CHANNELS") {
+            Ok(channels) => channels
+                .split(',')
+                .map(|channel| channel.trim().parse().map_err(|_| anyhow!("Bad generator channel {}", channel)))
+                .collect::<Result<Vec<usize>>>()?,
+            Err(_) => vec![0, 1],
+        };
+        let config = GeneratorConfig::new(Signal::parse(signal)?, level_db, channels);
+        transformer.set_source(InputSource::Generator(config));
+    } else if let Some(name) = &airplay {
+        transformer.set_source(InputSource::AirPlay(RaopConfig {
+            name: name.clone(),
+            ..RaopConfig::default()
+        }));
+    } else if let Some(url) = &radio {
+        transformer.set_source(InputSource::Radio(RadioConfig::new(url)));
+    } else if let Some(transport) = &pcm {
+        let format = std::env::var("AUDIOSERVER_PCM_FORMAT").unwrap_or_else(|_| "s16le:44100:2".to_string());
+        let config = PcmConfig::new(PcmTransport::parse(transport)?, PcmFormat::parse(&format)?);
+        transformer.set_source(InputSource::Pcm(config));
     } else {
-        println!("No devices found, using default");
-        AudioPlayer::new()?
+        transformer.set_source(InputSource::Player { channels: 2 });
+    }
+    let plays_queue = generator.is_none() && airplay.is_none() && radio.is_none() && pcm.is_none();
+    transformer.set_volume_db(-3.0);
+    transformer.set_replay_gain(Some(ReplayGainConfig::default()));
+    // AUDIOSERVER_LOUDNESS=<reference phon>[:<reference volume dB>[:<max boost dB>]] adds equal-loudness
+    // compensation as the volume goes down
+    if let Ok(loudness) = std::env::var("AUDIOSERVER_LOUDNESS") {
+        transformer.set_loudness(Some(LoudnessConfig::parse(&loudness)?));
+    }
+    // AUDIOSERVER_FILTER=<file> runs the FIR taps listed in the file on every source channel
+    if let Some(path) = std::env::var_os("AUDIOSERVER_FILTER").filter(|path| !path.is_empty()) {
+        let text = std::fs::read_to_string(&path).with_context(|| format!("AUDIOSERVER_FILTER {}", path.display()))?;
+        transformer.set_filter(Some(AudioFilter::parse(&text)?));
+    }
+    // AUDIOSERVER_CROSSOVER=<Hz>[:<order>] splits stereo into left woofer, left tweeter, right woofer and
+    // right tweeter outputs, AUDIOSERVER_WOOFER=<Fs Hz>:<Qts>:<Xmax mm>:<mm at full scale> limits the
+    // excursion of the woofers
+    if let Ok(crossover) = std::env::var("AUDIOSERVER_CROSSOVER") {
+        let mut crossover = Crossover::parse(&crossover)?;
+        if let Ok(woofer) = std::env::var("AUDIOSERVER_WOOFER") {
+            let limiter = ExcursionLimiter::new(DriverModel::parse(&woofer)?);
+            for band in crossover.bands.iter_mut().filter(|band| band.is_low()) {
+                band.excursion_limiter = Some(limiter);
+            }
+        }
+        transformer.set_crossover(Some(crossover));
+    }
+    // AUDIOSERVER_IMPORT=<file> takes the EQ and crossover from a REW, Equalizer APO or
+    // CamillaDSP config, AUDIOSERVER_IMPORT_FORMAT=rew|apo|camilla if guessing gets it wrong
+    if let Some(path) = std::env::var_os("AUDIOSERVER_IMPORT").filter(|path| !path.is_empty()) {
+        let format = match std::env::var("AUDIOSERVER_IMPORT_FORMAT") {
+            Ok(format) => Some(ImportFormat::parse(&format)?),
+            Err(_) => None,
+        };
+        transformer.import_filters(Path::new(&path), format)?;
+    }
+    // AUDIOSERVER_CORRECTION=<wav> convolves with room correction impulse responses, normalized by
+    // AUDIOSERVER_CORRECTION_NORMALIZATION=none|peak|dc|max (none unless given). AUDIOSERVER_CORRECTION_CHANNELS
+    // picks a file channel or - (none) for each pipeline channel, e.g. 1,0 to swap them.
+    if let Some(path) = std::env::var_os("AUDIOSERVER_CORRECTION").filter(|path| !path.is_empty()) {
+        let normalization = match std::env::var("AUDIOSERVER_CORRECTION_NORMALIZATION") {
+            Ok(normalization) => Normalization::parse(&normalization)?,
+            Err(_) => Normalization::None,
+        };
+        let mut correction = Correction::from_wav(Path::new(&path))?.with_normalization(normalization);
+        if let Ok(channels) = std::env::var("AUDIOSERVER_CORRECTION_CHANNELS") {
+            correction = correction.assign_channels(&channels)?;
+        }
+        transformer.set_correction(Some(correction));
+    }
+    // AUDIOSERVER_MATRIX=balance:0.2,width:1.3 mixes the stereo channels, before the crossover unless
+    // AUDIOSERVER_MATRIX_PLACEMENT=after
+    if let Ok(matrix) = std::env::var("AUDIOSERVER_MATRIX") {
+        let placement = match std::env::var("AUDIOSERVER_MATRIX_PLACEMENT") {
+            Ok(placement) => MatrixPlacement::parse(&placement)?,
+            Err(_) => MatrixPlacement::default(),
+        };
+        transformer.set_matrix(Some(MixMatrix::parse(&matrix)?), placement)?;
+    }
+    // AUDIOSERVER_REALTIME=fifo|rr[:<priority>] schedules the audio threads, AUDIOSERVER_CPUS=2,3 or 2-5
+    // pins them and AUDIOSERVER_MLOCK=1 locks the process memory
+    let mut realtime = match std::env::var("AUDIOSERVER_REALTIME") {
+        Ok(policy) => RealtimeConfig::parse(&policy)?,
+        Err(_) => RealtimeConfig::default(),
     };
-    
+    if let Ok(cpus) = std::env::var("AUDIOSERVER_CPUS") {
+        realtime.cpus = rt::parse_cpus(&cpus)?;
+    }
+    realtime.lock_memory = std::env::var("AUDIOSERVER_MLOCK").is_ok_and(|value| value == "1");
+    transformer.set_realtime(realtime);
+    // AUDIOSERVER_STANDBY=<hold seconds>[:<threshold dB>[:mute|stop]] turns on auto standby, with
+    // AUDIOSERVER_STANDBY_COMMAND=<shell command> and AUDIOSERVER_STANDBY_GPIO=<value file>[:low] as hooks
+    if let Ok(standby) = std::env::var("AUDIOSERVER_STANDBY") {
+        let mut config = StandbyConfig::parse(&standby)?;
+        if let Ok(command) = std::env::var("AUDIOSERVER_STANDBY_COMMAND") {
+            config.hooks.push(StandbyHook::Command(command));
+        }
+        if let Ok(gpio) = std::env::var("AUDIOSERVER_STANDBY_GPIO") {
+            config.hooks.push(StandbyHook::gpio(&gpio));
+        }
+        transformer.set_standby(Some(config));
+    }
+    // AUDIOSERVER_WORKERS=<n> runs the crossover bands on n threads besides the audio callback
+    if let Ok(workers) = std::env::var("AUDIOSERVER_WORKERS") {
+        let threads =
+            workers.trim().parse().map_err(|_| anyhow!("AUDIOSERVER_WORKERS: expected a number, got {}", workers))?;
+        transformer.set_worker_threads(threads);
+    }
+    // AUDIOSERVER_ANALYZER=input:0,band:1 analyzes those points for GET /api/v1/analyzer/spectrum
+    if let Ok(taps) = std::env::var("AUDIOSERVER_ANALYZER") {
+        for tap in taps.split(',').filter(|tap| !tap.trim().is_empty()) {
+            transformer.add_analyzer_tap(TapPoint::parse(tap)?);
+        }
+    }
+    // AUDIOSERVER_ANALYZER_SMOOTHING=none|3|6|12 octave smoothing and
+    // AUDIOSERVER_ANALYZER_AVERAGING=none|exp:<seconds>|linear:<spectra>|peak
+    let mut analyzer_config = AnalyzerConfig::default();
+    if let Ok(smoothing) = std::env::var("AUDIOSERVER_ANALYZER_SMOOTHING") {
+        analyzer_config.smoothing = Smoothing::parse(&smoothing)?;
+    }
+    if let Ok(averaging) = std::env::var("AUDIOSERVER_ANALYZER_AVERAGING") {
+        analyzer_config.averaging = Averaging::parse(&averaging)?;
+    }
+    transformer.set_analyzer_config(analyzer_config);
+    // AUDIOSERVER_METER_RATE=<Hz> and AUDIOSERVER_METER_BALLISTICS=<rms window ms>[:<peak hold ms>[:<peak release ms>]]
+    // for the level meters, AUDIOSERVER_STATS=<seconds> between load and xrun summaries in the log
+    let meter_rate = match std::env::var("AUDIOSERVER_METER_RATE") {
+        Ok(rate) => rate
+            .trim()
+            .parse::<f32>()
+            .ok()
+            .filter(|rate| rate.is_finite() && *rate > 0.0)
+            .ok_or_else(|| anyhow!("AUDIOSERVER_METER_RATE: expected Hz, got {}", rate))?,
+        Err(_) => 30.0,
+    };
+    let ballistics = match std::env::var("AUDIOSERVER_METER_BALLISTICS") {
+        Ok(ballistics) => MeterBallistics::parse(&ballistics)?,
+        Err(_) => MeterBallistics::default(),
+    };
+    transformer.set_meter_ballistics(ballistics, meter_rate);
+    if let Ok(stats) = std::env::var("AUDIOSERVER_STATS") {
+        let seconds = stats
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|seconds| seconds.is_finite() && *seconds > 0.0)
+            .ok_or_else(|| anyhow!("AUDIOSERVER_STATS: expected seconds, got {}", stats))?;
+        transformer.set_stats_log_interval(Some(Duration::from_secs_f64(seconds)));
+    }
+
     // // Example usage
     // println!("Playing audio file...");
     // player.play_file("./test.mp3")?;
     
-    // // Set volume (0.0 to 1.0)
-    player.set_volume(0.7);
-    
     // // Wait for a few seconds
     // std::thread::sleep(Duration::from_secs(5));
     
@@ -339,10 +932,119 @@ fn main() -> Result<()> {
     // player.resume();
     // std::thread::sleep(Duration::from_secs(5));
     
-    println!("Applying high-pass filter...");
-    player.play_file("./audioserver/test.mp3")?;
-    std::thread::sleep(Duration::from_secs(27));
-    player.stop();
+    // Play the queue through the pipeline until it runs out
+    let queue = transformer.player().clone();
+    for arg in std::env::args().skip(1) {
+        let path = Path::new(&arg);
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("m3u" | "m3u8" | "pls") => {
+                queue.enqueue_playlist(path)?;
+            }
+            _ => {
+                queue.enqueue(path);
+            }
+        }
+    }
+    // AUDIOSERVER_UPNP=<name> makes the player a UPnP/DLNA renderer that control points push to
+    let upnp_name = std::env::var("AUDIOSERVER_UPNP").ok().filter(|name| !name.is_empty());
+    if queue.queue().is_empty() && upnp_name.is_none() {
+        queue.enqueue(Path::new("./audioserver/test.mp3"));
+    }
+    // Music library, AUDIOSERVER_LIBRARY lists the folders like PATH does
+    let library_running = Arc::new(AtomicBool::new(true));
+    let mut library_thread = None;
+    let library = match std::env::var_os("AUDIOSERVER_LIBRARY") {
+        Some(folders) => {
+            let database = std::env::var_os("AUDIOSERVER_LIBRARY_DB")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| {
+                    let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+                    home.join(".local/share/audioserver/library.db")
+                });
+            let config = LibraryConfig {
+                folders: std::env::split_paths(&folders).collect(),
+                database,
+                rescan_interval: Some(Duration::from_secs(3600)),
+            };
+            match Library::open(config) {
+                Ok(library) => {
+                    library_thread = Some(library.start(library_running.clone()));
+                    Some(library)
+                }
+                Err(e) => {
+                    eprintln!("Library not opened: {}", e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    // Control API for the web UI, AUDIOSERVER_API=<address:port> to listen elsewhere
+    let api_address = std::env::var("AUDIOSERVER_API").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
+    let volume = transformer.volume_control();
+    let analyzer = transformer.analyzer();
+    let power = transformer.standby_hub();
+    let meters = transformer.meters();
+    // Shared with the API thread, which reads the status through it
+    let transformer = Arc::new(Mutex::new(transformer));
+    let status_transformer = transformer.clone();
+    let status = Box::new(move || status_transformer.lock().unwrap().status());
+    let api = ApiServer::start(&api_address, queue.clone(), library, status, analyzer, power, meters)
+        .map_err(|e| eprintln!("Control API not started: {}", e))
+        .ok();
+
+    let upnp = match (&upnp_name, plays_queue) {
+        (Some(_), false) => {
+            eprintln!("UPnP renderer not started, the player is not the source");
+            None
+        }
+        (Some(name), true) => {
+            let config = UpnpConfig {
+                name: name.clone(),
+                ..UpnpConfig::default()
+            };
+            UpnpRenderer::start(config, queue.clone(), volume)
+                .map_err(|e| eprintln!("UPnP renderer not started: {}", e))
+                .ok()
+        }
+        (None, _) => None,
+    };
+
+    let events = queue.subscribe();
+    queue.play();
+    transformer.lock().unwrap().start_processing()?;
+    let mut playing = None;
+    // The receivers run until the process is stopped
+    while !plays_queue || upnp.is_some() || queue.state() != PlaybackState::Stopped {
+        for status in events.try_iter() {
+            let id = status.entry.as_ref().map(|entry| entry.id);
+            if id != playing && let Some(entry) = &status.entry {
+                let tags = status.metadata.as_deref();
+                let name = match tags.and_then(|tags| tags.title.as_ref()) {
+                    Some(title) => match tags.and_then(|tags| tags.artist.as_ref()) {
+                        Some(artist) => format!("{} - {}", artist, title),
+                        None => title.clone(),
+                    },
+                    None => entry.path.display().to_string(),
+                };
+                println!("Playing {} ({})", name, status.duration.map_or("?".to_string(), format_time));
+            }
+            playing = id;
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    transformer.lock().unwrap().stop_processing();
+    if let Some(api) = api {
+        api.stop();
+    }
+    if let Some(upnp) = upnp {
+        upnp.stop();
+    }
+    library_running.store(false, Ordering::SeqCst);
+    if let Some(thread) = library_thread {
+        let _ = thread.join();
+    }
 
     // // Apply high-pass filter
     // println!("Applying high-pass filter...");
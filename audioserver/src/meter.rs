@@ -0,0 +1,440 @@
+use anyhow::{anyhow, Result};
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// Oversampling factor and taps per phase of the true-peak interpolator
+const TRUE_PEAK_OVERSAMPLING: usize = 4;
+const TRUE_PEAK_TAPS: usize = 12;
+
+// Lowest level reported by the meters, anything below is shown as silence
+pub const METER_FLOOR_DB: f32 = -120.0;
+// Snapshots queued for a subscriber that falls behind, newer ones are dropped until it catches up
+const SUBSCRIBER_BACKLOG: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct MeterBallistics {
+    // Time for a peak reading to fall by 20 dB once the hold time is over
+    pub peak_release_ms: f32,
+    pub peak_hold_ms: f32,
+    // Integration time constant of the RMS detector
+    pub rms_window_ms: f32,
+    // Linear sample magnitude counted as a clip
+    pub clip_threshold: f32,
+}
+
+impl MeterBallistics {
+    // "<rms window ms>[:<peak hold ms>[:<peak release ms>]]", as in AUDIOSERVER_METER_BALLISTICS
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut ballistics = MeterBallistics::default();
+        let fields = [&mut ballistics.rms_window_ms, &mut ballistics.peak_hold_ms, &mut ballistics.peak_release_ms];
+        let values: Vec<&str> = text.trim().split(':').collect();
+        if values.len() > fields.len() {
+            return Err(anyhow!("Expected <rms window ms>[:<peak hold ms>[:<peak release ms>]], got {}", text));
+        }
+        for (field, value) in fields.into_iter().zip(values) {
+            *field = value
+                .trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|ms| ms.is_finite() && *ms >= 0.0)
+                .ok_or_else(|| anyhow!("Bad meter time {} in {}", value, text))?;
+        }
+        Ok(ballistics)
+    }
+}
+
+impl Default for MeterBallistics {
+    fn default() -> Self {
+        MeterBallistics {
+            peak_release_ms: 1700.0,
+            peak_hold_ms: 500.0,
+            rms_window_ms: 300.0,
+            clip_threshold: 1.0,
+        }
+    }
+}
+
+// f32 stored in an AtomicU32 so the audio thread never has to lock
+#[derive(Debug, Default)]
+pub struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    pub fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn store(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default)]
+struct SharedLevels {
+    peak: AtomicF32,
+    rms: AtomicF32,
+    true_peak: AtomicF32,
+    clips: AtomicU64,
+    overload: AtomicBool,
+}
+
+// Levels of one direction (input or output), written by the audio thread
+#[derive(Debug)]
+pub struct MeterBank {
+    channels: Vec<SharedLevels>,
+}
+
+impl MeterBank {
+    pub fn new(channels: usize) -> Self {
+        MeterBank {
+            channels: (0..channels).map(|_| SharedLevels::default()).collect(),
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    // Clear clip counters and sticky overload flags
+    pub fn reset_clips(&self) {
+        for levels in &self.channels {
+            levels.clips.store(0, Ordering::Relaxed);
+            levels.overload.store(false, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> Vec<ChannelLevels> {
+        self.channels
+            .iter()
+            .map(|levels| ChannelLevels {
+                peak_db: to_db(levels.peak.load()),
+                rms_db: to_db(levels.rms.load()),
+                true_peak_db: to_db(levels.true_peak.load()),
+                clips: levels.clips.load(Ordering::Relaxed),
+                overload: levels.overload.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelLevels {
+    pub peak_db: f32,
+    pub rms_db: f32,
+    pub true_peak_db: f32,
+    pub clips: u64,
+    pub overload: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeterSnapshot {
+    pub input: Vec<ChannelLevels>,
+    pub output: Vec<ChannelLevels>,
+}
+
+pub fn to_db(level: f32) -> f32 {
+    if level <= 0.0 {
+        METER_FLOOR_DB
+    } else {
+        (20.0 * level.log10()).max(METER_FLOOR_DB)
+    }
+}
+
+// Windowed-sinc polyphase interpolator used to estimate inter-sample peaks
+#[derive(Debug, Clone)]
+struct TruePeakDetector {
+    phases: Vec<[f32; TRUE_PEAK_TAPS]>,
+    history: [f32; TRUE_PEAK_TAPS],
+    pos: usize,
+}
+
+impl TruePeakDetector {
+    fn new() -> Self {
+        let len = TRUE_PEAK_OVERSAMPLING * TRUE_PEAK_TAPS;
+        let center = (len - 1) as f32 / 2.0;
+        let mut phases = vec![[0.0; TRUE_PEAK_TAPS]; TRUE_PEAK_OVERSAMPLING];
+        for n in 0..len {
+            let t = (n as f32 - center) / TRUE_PEAK_OVERSAMPLING as f32;
+            let sinc = if t == 0.0 { 1.0 } else { (PI * t).sin() / (PI * t) };
+            let window = 0.5 - 0.5 * (2.0 * PI * (n as f32 + 0.5) / len as f32).cos();
+            phases[n % TRUE_PEAK_OVERSAMPLING][n / TRUE_PEAK_OVERSAMPLING] = sinc * window;
+        }
+        TruePeakDetector {
+            phases,
+            history: [0.0; TRUE_PEAK_TAPS],
+            pos: 0,
+        }
+    }
+
+    // Push one sample and return the largest magnitude among the interpolated points
+    fn process(&mut self, sample: f32) -> f32 {
+        self.history[self.pos] = sample;
+        self.pos = (self.pos + 1) % TRUE_PEAK_TAPS;
+
+        let mut max = sample.abs();
+        for phase in &self.phases {
+            let mut sum = 0.0;
+            for (k, coeff) in phase.iter().enumerate() {
+                let idx = (self.pos + TRUE_PEAK_TAPS - 1 - k) % TRUE_PEAK_TAPS;
+                sum += self.history[idx] * coeff;
+            }
+            max = max.max(sum.abs());
+        }
+        max
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ChannelMeter {
+    peak: f32,
+    true_peak: f32,
+    hold_left: u32,
+    true_peak_hold_left: u32,
+    mean_square: f32,
+    true_peak_detector: TruePeakDetector,
+}
+
+impl ChannelMeter {
+    fn new() -> Self {
+        ChannelMeter {
+            peak: 0.0,
+            true_peak: 0.0,
+            hold_left: 0,
+            true_peak_hold_left: 0,
+            mean_square: 0.0,
+            true_peak_detector: TruePeakDetector::new(),
+        }
+    }
+}
+
+// Audio thread side of the meters: owns the detector state and publishes into a MeterBank
+pub struct MeterProcessor {
+    channels: Vec<ChannelMeter>,
+    bank: Arc<MeterBank>,
+    release_coeff: f32,
+    rms_coeff: f32,
+    hold_samples: u32,
+    clip_threshold: f32,
+}
+
+impl MeterProcessor {
+    pub fn new(bank: Arc<MeterBank>, sample_rate: u32, ballistics: MeterBallistics) -> Self {
+        let fs = sample_rate as f32;
+        let release_samples = (ballistics.peak_release_ms * 1e-3 * fs).max(1.0);
+        let rms_samples = (ballistics.rms_window_ms * 1e-3 * fs).max(1.0);
+        MeterProcessor {
+            channels: vec![ChannelMeter::new(); bank.channels()],
+            release_coeff: 10f32.powf(-1.0 / release_samples),
+            rms_coeff: 1.0 - (-1.0 / rms_samples).exp(),
+            hold_samples: (ballistics.peak_hold_ms * 1e-3 * fs) as u32,
+            clip_threshold: ballistics.clip_threshold,
+            bank,
+        }
+    }
+
+    // Feed an interleaved block; never blocks or allocates
+    pub fn process(&mut self, data: &[f32]) {
+        let channels = self.channels.len();
+        if channels == 0 {
+            return;
+        }
+
+        for frame in data.chunks(channels) {
+            for (ch, &sample) in frame.iter().enumerate() {
+                let meter = &mut self.channels[ch];
+                let levels = &self.bank.channels[ch];
+                let magnitude = sample.abs();
+
+                if magnitude >= meter.peak {
+                    meter.peak = magnitude;
+                    meter.hold_left = self.hold_samples;
+                } else if meter.hold_left > 0 {
+                    meter.hold_left -= 1;
+                } else {
+                    meter.peak *= self.release_coeff;
+                }
+
+                let true_peak = meter.true_peak_detector.process(sample);
+                if true_peak >= meter.true_peak {
+                    meter.true_peak = true_peak;
+                    meter.true_peak_hold_left = self.hold_samples;
+                } else if meter.true_peak_hold_left > 0 {
+                    meter.true_peak_hold_left -= 1;
+                } else {
+                    meter.true_peak *= self.release_coeff;
+                }
+
+                meter.mean_square += self.rms_coeff * (sample * sample - meter.mean_square);
+
+                if magnitude >= self.clip_threshold {
+                    levels.clips.fetch_add(1, Ordering::Relaxed);
+                    levels.overload.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+
+        for (meter, levels) in self.channels.iter().zip(&self.bank.channels) {
+            levels.peak.store(meter.peak);
+            levels.true_peak.store(meter.true_peak);
+            levels.rms.store(meter.mean_square.max(0.0).sqrt());
+        }
+    }
+}
+
+struct ActiveBanks {
+    input: Arc<MeterBank>,
+    output: Arc<MeterBank>,
+}
+
+// Fan-out of meter snapshots to any number of subscribers
+#[derive(Clone, Default)]
+pub struct MeterHub {
+    subscribers: Arc<Mutex<Vec<SyncSender<MeterSnapshot>>>>,
+    banks: Arc<Mutex<Option<ActiveBanks>>>,
+}
+
+impl MeterHub {
+    pub fn subscribe(&self) -> Receiver<MeterSnapshot> {
+        let (tx, rx) = mpsc::sync_channel(SUBSCRIBER_BACKLOG);
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn reset_clips(&self) {
+        if let Some(banks) = self.banks.lock().unwrap().as_ref() {
+            banks.input.reset_clips();
+            banks.output.reset_clips();
+        }
+    }
+
+    // Latest levels without subscribing, None while the engine is stopped
+    pub fn snapshot(&self) -> Option<MeterSnapshot> {
+        self.banks
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|banks| MeterSnapshot {
+                input: banks.input.snapshot(),
+                output: banks.output.snapshot(),
+            })
+    }
+
+    // Publish snapshots at a fixed rate until `running` is cleared
+    pub fn start_publisher(
+        &self,
+        input: Arc<MeterBank>,
+        output: Arc<MeterBank>,
+        rate_hz: f32,
+        running: Arc<AtomicBool>,
+    ) -> JoinHandle<()> {
+        *self.banks.lock().unwrap() = Some(ActiveBanks { input, output });
+        let hub = self.clone();
+        let period = Duration::from_secs_f32(1.0 / rate_hz.max(1.0));
+
+        std::thread::spawn(move || {
+            while running.load(Ordering::SeqCst) {
+                if let Some(snapshot) = hub.snapshot() {
+                    hub.subscribers
+                        .lock()
+                        .unwrap()
+                        .retain(|tx| !matches!(tx.try_send(snapshot.clone()), Err(TrySendError::Disconnected(_))));
+                }
+                std::thread::sleep(period);
+            }
+            *hub.banks.lock().unwrap() = None;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ballistics_from_text() {
+        let ballistics = MeterBallistics::parse("100:0").unwrap();
+        assert_eq!(ballistics.rms_window_ms, 100.0);
+        assert_eq!((ballistics.peak_hold_ms, ballistics.peak_release_ms), (0.0, 1700.0));
+        assert!(MeterBallistics::parse("100:0:1:2").is_err());
+        assert!(MeterBallistics::parse("-5").is_err());
+    }
+
+    fn meter(sample_rate: u32, ballistics: MeterBallistics) -> (Arc<MeterBank>, MeterProcessor) {
+        let bank = Arc::new(MeterBank::new(1));
+        let processor = MeterProcessor::new(bank.clone(), sample_rate, ballistics);
+        (bank, processor)
+    }
+
+    #[test]
+    fn peak_holds_then_falls_20_db_over_the_release() {
+        let ballistics = MeterBallistics {
+            peak_hold_ms: 100.0,
+            peak_release_ms: 1000.0,
+            ..MeterBallistics::default()
+        };
+        let (bank, mut processor) = meter(1000, ballistics);
+        processor.process(&[0.5]);
+        processor.process(&[0.0; 100]);
+        assert_eq!(bank.snapshot()[0].peak_db, to_db(0.5));
+        processor.process(&[0.0; 1000]);
+        assert!((bank.snapshot()[0].peak_db - (to_db(0.5) - 20.0)).abs() < 0.1);
+    }
+
+    #[test]
+    fn rms_of_a_full_scale_sine() {
+        // Ten time constants of the detector, so it has settled
+        let ballistics = MeterBallistics {
+            rms_window_ms: 100.0,
+            ..MeterBallistics::default()
+        };
+        let (bank, mut processor) = meter(48000, ballistics);
+        let sine: Vec<f32> = (0..48000).map(|n| (2.0 * PI * 1000.0 * n as f32 / 48000.0).sin()).collect();
+        processor.process(&sine);
+        let levels = &bank.snapshot()[0];
+        assert!((levels.rms_db + 3.01).abs() < 0.1, "{}", levels.rms_db);
+        assert!(levels.peak_db.abs() < 0.01);
+    }
+
+    #[test]
+    fn true_peak_finds_the_peaks_between_samples() {
+        // fs/4 at 45 degrees: every sample is at 0.707, the waveform peaks at 1.0 between them
+        let (bank, mut processor) = meter(48000, MeterBallistics::default());
+        let signal: Vec<f32> = (0..4800).map(|n| (PI / 2.0 * n as f32 + PI / 4.0).sin()).collect();
+        processor.process(&signal);
+        let levels = &bank.snapshot()[0];
+        assert!((levels.peak_db + 3.01).abs() < 0.01);
+        let over = levels.true_peak_db - levels.peak_db;
+        assert!((over - 3.01).abs() < 0.3, "{}", over);
+    }
+
+    #[test]
+    fn counts_clips_until_reset() {
+        let (bank, mut processor) = meter(48000, MeterBallistics::default());
+        processor.process(&[0.5, 1.0, -1.2, 0.99]);
+        assert_eq!((bank.snapshot()[0].clips, bank.snapshot()[0].overload), (2, true));
+        processor.process(&[0.0; 100]);
+        assert_eq!((bank.snapshot()[0].clips, bank.snapshot()[0].overload), (2, true));
+        bank.reset_clips();
+        assert_eq!((bank.snapshot()[0].clips, bank.snapshot()[0].overload), (0, false));
+    }
+
+    #[test]
+    fn slow_subscribers_get_a_bounded_backlog() {
+        let hub = MeterHub::default();
+        let slow = hub.subscribe();
+        drop(hub.subscribe());
+        let running = Arc::new(AtomicBool::new(true));
+        let banks = (Arc::new(MeterBank::new(1)), Arc::new(MeterBank::new(2)));
+        let publisher = hub.start_publisher(banks.0, banks.1, 1000.0, running.clone());
+        std::thread::sleep(Duration::from_millis(100));
+        running.store(false, Ordering::SeqCst);
+        publisher.join().unwrap();
+        assert_eq!(slow.try_iter().count(), SUBSCRIBER_BACKLOG);
+        // The one that went away is no longer published to
+        assert_eq!(hub.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(hub.snapshot(), None);
+    }
+}
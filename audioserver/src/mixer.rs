@@ -0,0 +1,311 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+
+// Gain from every input channel to every output channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct MixMatrix {
+    pub inputs: usize,
+    pub outputs: usize,
+    // Row per output: gains[output * inputs + input]
+    pub gains: Vec<f32>,
+}
+
+impl MixMatrix {
+    // All gains zero
+    pub fn new(inputs: usize, outputs: usize) -> Self {
+        MixMatrix {
+            inputs,
+            outputs,
+            gains: vec![0.0; inputs * outputs],
+        }
+    }
+
+    pub fn identity(channels: usize) -> Self {
+        let mut matrix = MixMatrix::new(channels, channels);
+        for channel in 0..channels {
+            matrix.set(channel, channel, 1.0);
+        }
+        matrix
+    }
+
+    pub fn gain(&self, output: usize, input: usize) -> f32 {
+        self.gains[output * self.inputs + input]
+    }
+
+    pub fn set(&mut self, output: usize, input: usize, gain: f32) {
+        self.gains[output * self.inputs + input] = gain;
+    }
+
+    // Stereo balance from -1 (left only) to 1 (right only), the louder side stays at unity
+    pub fn balance(balance: f32) -> Self {
+        let balance = balance.clamp(-1.0, 1.0);
+        let mut matrix = MixMatrix::identity(2);
+        matrix.set(0, 0, (1.0 - balance).min(1.0));
+        matrix.set(1, 1, (1.0 + balance).min(1.0));
+        matrix
+    }
+
+    // Both outputs carry (L + R) / 2
+    pub fn mono() -> Self {
+        MixMatrix {
+            inputs: 2,
+            outputs: 2,
+            gains: vec![0.5, 0.5, 0.5, 0.5],
+        }
+    }
+
+    pub fn swap() -> Self {
+        MixMatrix {
+            inputs: 2,
+            outputs: 2,
+            gains: vec![0.0, 1.0, 1.0, 0.0],
+        }
+    }
+
+    // L/R to M/S, scaled so decode(encode(x)) is x
+    pub fn mid_side_encode() -> Self {
+        MixMatrix {
+            inputs: 2,
+            outputs: 2,
+            gains: vec![FRAC_1_SQRT_2, FRAC_1_SQRT_2, FRAC_1_SQRT_2, -FRAC_1_SQRT_2],
+        }
+    }
+
+    pub fn mid_side_decode() -> Self {
+        MixMatrix::mid_side_encode()
+    }
+
+    // Stereo width: 0 is mono, 1 leaves the image alone, above 1 widens it
+    pub fn width(width: f32) -> Self {
+        let width = width.max(0.0);
+        let mut side = MixMatrix::identity(2);
+        side.set(1, 1, width);
+        MixMatrix::mid_side_encode()
+            .then(&side)
+            .then(&MixMatrix::mid_side_decode())
+    }
+
+    // Comma separated steps applied in order, as in AUDIOSERVER_MATRIX:
+    // "balance:<-1..1>", "width:<0..>", "mono" and "swap", e.g. "balance:0.2,width:1.3"
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut matrix = MixMatrix::identity(2);
+        for step in text.split(',').map(str::trim).filter(|step| !step.is_empty()) {
+            let (name, value) = match step.split_once(':') {
+                Some((name, value)) => (name.trim(), Some(value.trim())),
+                None => (step, None),
+            };
+            let amount = || {
+                value
+                    .and_then(|value| value.parse::<f32>().ok())
+                    .filter(|value| value.is_finite())
+                    .ok_or_else(|| anyhow!("Matrix step {} needs a number, got {}", name, step))
+            };
+            let next = match name {
+                "balance" => MixMatrix::balance(amount()?),
+                "width" => MixMatrix::width(amount()?),
+                "mono" => MixMatrix::mono(),
+                "swap" => MixMatrix::swap(),
+                _ => bail!("Unknown matrix step {}, expected balance, width, mono or swap", step),
+            };
+            matrix = matrix.then(&next);
+        }
+        Ok(matrix)
+    }
+
+    // `self` followed by `next`, as one matrix
+    pub fn then(&self, next: &MixMatrix) -> MixMatrix {
+        let mut combined = MixMatrix::new(self.inputs, next.outputs);
+        for output in 0..next.outputs {
+            for input in 0..self.inputs {
+                let gain = (0..next.inputs.min(self.outputs))
+                    .map(|middle| next.gain(output, middle) * self.gain(middle, input))
+                    .sum();
+                combined.set(output, input, gain);
+            }
+        }
+        combined
+    }
+
+    // Mix one interleaved block, `output` is resized to match
+    pub fn process(&self, input: &[f32], output: &mut Vec<f32>) {
+        let frames = input.len() / self.inputs.max(1);
+        output.resize(frames * self.outputs, 0.0);
+        for (in_frame, out_frame) in input.chunks(self.inputs).zip(output.chunks_mut(self.outputs)) {
+            for (o, out) in out_frame.iter_mut().enumerate() {
+                let row = &self.gains[o * self.inputs..(o + 1) * self.inputs];
+                *out = row.iter().zip(in_frame).map(|(gain, sample)| gain * sample).sum();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatrixPlacement {
+    // On the source channels, every later stage and the crossover see the matrix outputs
+    #[default]
+    BeforeCrossover,
+    // On the output channels, after the bands are mixed
+    AfterCrossover,
+}
+
+impl MatrixPlacement {
+    // "before" or "after" the crossover, as in AUDIOSERVER_MATRIX_PLACEMENT
+    pub fn parse(text: &str) -> Result<Self> {
+        match text.trim() {
+            "before" => Ok(MatrixPlacement::BeforeCrossover),
+            "after" => Ok(MatrixPlacement::AfterCrossover),
+            other => bail!("Unknown matrix placement {}, expected before or after", other),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MatrixShared {
+    matrix: Mutex<MixMatrix>,
+    generation: AtomicU64,
+}
+
+// Matrix shared with the running pipeline, so balance or width can change while it plays
+#[derive(Debug, Clone)]
+pub struct MatrixControl {
+    shared: Arc<MatrixShared>,
+}
+
+impl MatrixControl {
+    pub fn new(matrix: MixMatrix) -> Self {
+        MatrixControl {
+            shared: Arc::new(MatrixShared {
+                matrix: Mutex::new(matrix),
+                generation: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn matrix(&self) -> MixMatrix {
+        self.shared.matrix.lock().unwrap().clone()
+    }
+
+    // Same size only, a running pipeline keeps the channel counts it started with
+    pub fn set(&self, matrix: MixMatrix) -> Result<()> {
+        let mut current = self.shared.matrix.lock().unwrap();
+        if (matrix.inputs, matrix.outputs) != (current.inputs, current.outputs) {
+            bail!(
+                "Mixing matrix is {}x{}, the running one {}x{}",
+                matrix.inputs,
+                matrix.outputs,
+                current.inputs,
+                current.outputs
+            );
+        }
+        if matrix.gains.len() != matrix.inputs * matrix.outputs {
+            bail!("Mixing matrix has {} gains for {}x{}", matrix.gains.len(), matrix.inputs, matrix.outputs);
+        }
+        *current = matrix;
+        self.shared.generation.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+}
+
+// Realtime side, fades between old and new gains over one block
+pub struct MatrixStage {
+    control: MatrixControl,
+    generation: u64,
+    current: MixMatrix,
+    target: MixMatrix,
+}
+
+impl MatrixStage {
+    pub fn new(control: MatrixControl) -> Self {
+        let matrix = control.matrix();
+        MatrixStage {
+            generation: control.shared.generation.load(Ordering::Acquire),
+            control,
+            current: matrix.clone(),
+            target: matrix,
+        }
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.current.inputs
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.current.outputs
+    }
+
+    pub fn matrix(&self) -> &MixMatrix {
+        &self.current
+    }
+
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        let generation = self.control.shared.generation.load(Ordering::Acquire);
+        if generation != self.generation {
+            // Never block the audio thread, try again next block if the control side holds the lock
+            if let Ok(matrix) = self.control.shared.matrix.try_lock() {
+                if matrix.inputs == self.current.inputs && matrix.outputs == self.current.outputs {
+                    self.target.gains.copy_from_slice(&matrix.gains);
+                }
+                self.generation = generation;
+            }
+        }
+
+        if self.current.gains == self.target.gains {
+            self.current.process(input, output);
+            return;
+        }
+
+        let (inputs, outputs) = (self.current.inputs, self.current.outputs);
+        let frames = input.len() / inputs.max(1);
+        output.resize(frames * outputs, 0.0);
+        for (frame, (in_frame, out_frame)) in input.chunks(inputs).zip(output.chunks_mut(outputs)).enumerate() {
+            let t = (frame + 1) as f32 / frames as f32;
+            for (o, out) in out_frame.iter_mut().enumerate() {
+                let mut sum = 0.0;
+                for (i, sample) in in_frame.iter().enumerate() {
+                    let idx = o * inputs + i;
+                    let gain = self.current.gains[idx] + (self.target.gains[idx] - self.current.gains[idx]) * t;
+                    sum += gain * sample;
+                }
+                *out = sum;
+            }
+        }
+        self.current.gains.copy_from_slice(&self.target.gains);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_from_text() {
+        assert_eq!(MixMatrix::parse("").unwrap(), MixMatrix::identity(2));
+        assert_eq!(MixMatrix::parse("balance:0.5").unwrap(), MixMatrix::balance(0.5));
+        let chained = MixMatrix::parse("swap, width:0").unwrap();
+        assert_eq!(chained, MixMatrix::swap().then(&MixMatrix::width(0.0)));
+        assert!(MixMatrix::parse("balance").is_err());
+        assert!(MixMatrix::parse("width:wide").is_err());
+        assert!(MixMatrix::parse("rotate:1").is_err());
+        assert_eq!(MatrixPlacement::parse("after").unwrap(), MatrixPlacement::AfterCrossover);
+        assert!(MatrixPlacement::parse("middle").is_err());
+    }
+
+    #[test]
+    fn set_keeps_the_size() {
+        let control = MatrixControl::new(MixMatrix::identity(2));
+        let mut stage = MatrixStage::new(control.clone());
+        assert!(control.set(MixMatrix::new(2, 3)).is_err());
+        assert!(control.set(MixMatrix { gains: vec![1.0], ..MixMatrix::identity(2) }).is_err());
+        assert_eq!(control.matrix(), MixMatrix::identity(2));
+
+        control.set(MixMatrix::swap()).unwrap();
+        let mut output = Vec::new();
+        // The first block fades over, the second one is fully swapped
+        stage.process(&[1.0, 0.0, 1.0, 0.0], &mut output);
+        stage.process(&[1.0, 0.0], &mut output);
+        assert_eq!(output, vec![0.0, 1.0]);
+    }
+}
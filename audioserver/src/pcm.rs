@@ -0,0 +1,537 @@
+use anyhow::{anyhow, Result};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::io::{ErrorKind, Read};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::dsp::resample::StreamResampler;
+use crate::meter::AtomicF32;
+
+// How long the reader waits for data on a pipe or for room in the buffer
+const TICK: Duration = Duration::from_millis(5);
+// Socket reads and accepts wake up this often to look at `running`
+const POLL: Duration = Duration::from_millis(100);
+const READ_SIZE: usize = 16 * 1024;
+// Largest UDP payload
+const DATAGRAM_SIZE: usize = 64 * 1024;
+
+// Interleaved little endian samples, in ALSA's naming
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    S16,
+    // 24 bits in the low three bytes of four, librespot's S24
+    S24,
+    // Packed three byte samples, librespot's S24_3
+    S24Packed,
+    S32,
+    F32,
+}
+
+impl SampleFormat {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().replace('_', "").as_str() {
+            "s16le" | "s16" => Ok(SampleFormat::S16),
+            "s24le" | "s24" => Ok(SampleFormat::S24),
+            "s243le" | "s243" => Ok(SampleFormat::S24Packed),
+            "s32le" | "s32" => Ok(SampleFormat::S32),
+            "f32le" | "f32" | "floatle" | "float" => Ok(SampleFormat::F32),
+            _ => Err(anyhow!("{}: unknown sample format, use s16le, s24le, s24_3le, s32le or f32le", name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SampleFormat::S16 => "s16le",
+            SampleFormat::S24 => "s24le",
+            SampleFormat::S24Packed => "s24_3le",
+            SampleFormat::S32 => "s32le",
+            SampleFormat::F32 => "f32le",
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        match self {
+            SampleFormat::S16 => 2,
+            SampleFormat::S24Packed => 3,
+            SampleFormat::S24 | SampleFormat::S32 | SampleFormat::F32 => 4,
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> f32 {
+        match self {
+            SampleFormat::S16 => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+            // Sign extended from bit 23
+            SampleFormat::S24 => {
+                (i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) << 8 >> 8) as f32 / 8_388_608.0
+            }
+            SampleFormat::S24Packed => (i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8) as f32 / 8_388_608.0,
+            SampleFormat::S32 => {
+                i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32 / 2_147_483_648.0
+            }
+            SampleFormat::F32 => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        }
+    }
+}
+
+// What the sender writes, there is no header to tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcmFormat {
+    pub sample: SampleFormat,
+    pub sample_rate: u32,
+    pub channels: usize,
+}
+
+impl PcmFormat {
+    // <sample format>:<rate>:<channels>, e.g. s16le:44100:2
+    pub fn parse(text: &str) -> Result<Self> {
+        let parts: Vec<&str> = text.split(':').collect();
+        let [sample, rate, channels] = parts.as_slice() else {
+            return Err(anyhow!("{}: expected <sample format>:<rate>:<channels>", text));
+        };
+        let sample_rate = rate.parse().ok().filter(|&rate| rate > 0).ok_or_else(|| anyhow!("{}: bad rate", text))?;
+        let channels = channels
+            .parse()
+            .ok()
+            .filter(|channels| (1..=32).contains(channels))
+            .ok_or_else(|| anyhow!("{}: bad channel count", text))?;
+        Ok(PcmFormat {
+            sample: SampleFormat::parse(sample)?,
+            sample_rate,
+            channels,
+        })
+    }
+
+    pub fn frame_bytes(&self) -> usize {
+        self.sample.bytes() * self.channels
+    }
+}
+
+impl std::fmt::Display for PcmFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} Hz {} ch", self.sample.name(), self.sample_rate, self.channels)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PcmTransport {
+    // Named pipe, created when missing. Writers may come and go.
+    Fifo(PathBuf),
+    Stdin,
+    // One sender at a time, the next one is accepted when it disconnects
+    Tcp(SocketAddr),
+    // Datagrams from anyone, frames may span them
+    Udp(SocketAddr),
+}
+
+impl PcmTransport {
+    // fifo:<path>, stdin or -, tcp:[<address>:]<port>, udp:[<address>:]<port>
+    pub fn parse(text: &str) -> Result<Self> {
+        let address = |spec: &str| -> Result<SocketAddr> {
+            match spec.parse::<u16>() {
+                Ok(port) => Ok(SocketAddr::from(([0, 0, 0, 0], port))),
+                Err(_) => spec.parse().map_err(|_| anyhow!("{}: not a port or address:port", text)),
+            }
+        };
+        match text.split_once(':') {
+            _ if text == "stdin" || text == "-" => Ok(PcmTransport::Stdin),
+            Some(("fifo", path)) if !path.is_empty() => Ok(PcmTransport::Fifo(PathBuf::from(path))),
+            Some(("tcp", spec)) => Ok(PcmTransport::Tcp(address(spec)?)),
+            Some(("udp", spec)) => Ok(PcmTransport::Udp(address(spec)?)),
+            _ => Err(anyhow!("{}: expected fifo:<path>, stdin, tcp:<port> or udp:<port>", text)),
+        }
+    }
+}
+
+impl std::fmt::Display for PcmTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PcmTransport::Fifo(path) => write!(f, "fifo {}", path.display()),
+            PcmTransport::Stdin => write!(f, "stdin"),
+            PcmTransport::Tcp(address) => write!(f, "tcp {}", address),
+            PcmTransport::Udp(address) => write!(f, "udp {}", address),
+        }
+    }
+}
+
+// Raw PCM from another program, e.g. librespot, shairport-sync or MPD pipe outputs
+#[derive(Debug, Clone)]
+pub struct PcmConfig {
+    pub transport: PcmTransport,
+    pub format: PcmFormat,
+    // Audio collected before playing starts, and again after the sender paused
+    // or fell behind. Adds this much latency, up to four times it is buffered.
+    pub latency: Duration,
+}
+
+impl PcmConfig {
+    pub fn new(transport: PcmTransport, format: PcmFormat) -> Self {
+        PcmConfig {
+            transport,
+            format,
+            latency: Duration::from_millis(50),
+        }
+    }
+}
+
+// State shared by the reader thread, the output callback and status reports
+#[derive(Default)]
+struct PcmShared {
+    // The source is playing what the sender wrote, not filling in silence
+    playing: AtomicBool,
+    // Times the buffer ran dry while playing
+    underruns: AtomicU64,
+    // Frames thrown away because the buffer was full, only datagrams are
+    dropped: AtomicU64,
+}
+
+// Status side of a running input
+#[derive(Clone)]
+pub struct PcmInput {
+    shared: Arc<PcmShared>,
+}
+
+impl PcmInput {
+    pub fn playing(&self) -> bool {
+        self.shared.playing.load(Ordering::Relaxed)
+    }
+
+    pub fn underruns(&self) -> u64 {
+        self.shared.underruns.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+enum Input {
+    // FIFO or stdin, nonblocking
+    Pipe(Box<dyn Read + Send>),
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+// Opened for writing as well, so the pipe does not end when a writer closes it
+#[cfg(target_os = "linux")]
+fn open_fifo(path: &Path) -> Result<Box<dyn Read + Send>> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+
+    match std::fs::metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_fifo() => {
+            return Err(anyhow!("{}: not a named pipe", path.display()));
+        }
+        Ok(_) => {}
+        Err(_) => {
+            let name = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+            // Safety: plain syscall on a valid C string
+            if unsafe { libc::mkfifo(name.as_ptr(), 0o660) } != 0 {
+                return Err(anyhow!("{}: {}", path.display(), std::io::Error::last_os_error()));
+            }
+        }
+    }
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    Ok(Box::new(file))
+}
+
+// Standard input switched to nonblocking so the reader notices when processing
+// stops. The descriptor is shared with the shell, the flags go back when dropped.
+#[cfg(target_os = "linux")]
+struct NonblockingStdin {
+    stdin: std::io::Stdin,
+    flags: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+impl Read for NonblockingStdin {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stdin.read(buf)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for NonblockingStdin {
+    fn drop(&mut self) {
+        // Safety: plain syscall on the standard input descriptor
+        unsafe { libc::fcntl(libc::STDIN_FILENO, libc::F_SETFL, self.flags) };
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_stdin() -> Result<Box<dyn Read + Send>> {
+    // Safety: plain syscalls on the standard input descriptor
+    let flags = unsafe { libc::fcntl(libc::STDIN_FILENO, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(libc::STDIN_FILENO, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(anyhow!("stdin: {}", std::io::Error::last_os_error()));
+    }
+    Ok(Box::new(NonblockingStdin {
+        stdin: std::io::stdin(),
+        flags,
+    }))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_fifo(_path: &Path) -> Result<Box<dyn Read + Send>> {
+    Err(anyhow!("not supported on this platform"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_stdin() -> Result<Box<dyn Read + Send>> {
+    Err(anyhow!("not supported on this platform"))
+}
+
+// Read the sender at its declared format and play it at `sample_rate`. Returns the
+// source for the output callback, the status handle and the reader thread.
+// `resampler_delay` is set to the look-ahead of the resampler, in output frames.
+pub fn start(
+    config: PcmConfig,
+    sample_rate: u32,
+    resampler_delay: Arc<AtomicF32>,
+    running: Arc<AtomicBool>,
+) -> Result<(PcmSource, PcmInput, JoinHandle<()>)> {
+    let input = match &config.transport {
+        PcmTransport::Fifo(path) => Input::Pipe(open_fifo(path)?),
+        PcmTransport::Stdin => Input::Pipe(open_stdin()?),
+        PcmTransport::Tcp(address) => {
+            let listener = TcpListener::bind(address).map_err(|e| anyhow!("PCM {}: {}", address, e))?;
+            listener.set_nonblocking(true)?;
+            Input::Tcp(listener)
+        }
+        PcmTransport::Udp(address) => {
+            let socket = UdpSocket::bind(address).map_err(|e| anyhow!("PCM {}: {}", address, e))?;
+            socket.set_read_timeout(Some(POLL))?;
+            Input::Udp(socket)
+        }
+    };
+    println!("PCM: reading {} from {}", config.format, config.transport);
+
+    let channels = config.format.channels;
+    let prebuffer = ((sample_rate as f32 * config.latency.as_secs_f32()) as usize).max(64) * channels;
+    let (producer, consumer) = RingBuffer::new(prebuffer * 4);
+    let shared = Arc::new(PcmShared::default());
+    let source = PcmSource {
+        shared: shared.clone(),
+        consumer,
+        channels,
+        prebuffer,
+        waiting: true,
+    };
+    let status = PcmInput { shared: shared.clone() };
+    let resampler = StreamResampler::new(config.format.sample_rate, sample_rate, channels);
+    resampler_delay.store(resampler.delay_frames());
+    let reader = Reader {
+        format: config.format,
+        shared,
+        producer,
+        running,
+        partial: Vec::new(),
+        samples: Vec::new(),
+        resampler,
+        resampled: Vec::new(),
+    };
+    Ok((source, status, std::thread::spawn(move || reader.run(input))))
+}
+
+struct Reader {
+    format: PcmFormat,
+    shared: Arc<PcmShared>,
+    producer: Producer<f32>,
+    running: Arc<AtomicBool>,
+    // Bytes of a frame that is not complete yet
+    partial: Vec<u8>,
+    samples: Vec<f32>,
+    resampler: StreamResampler,
+    resampled: Vec<f32>,
+}
+
+impl Reader {
+    fn run(mut self, input: Input) {
+        match input {
+            Input::Pipe(mut pipe) => {
+                self.read_stream(&mut pipe);
+                if self.running.load(Ordering::SeqCst) {
+                    println!("PCM: end of input");
+                }
+            }
+            Input::Tcp(listener) => {
+                while self.running.load(Ordering::SeqCst) {
+                    match listener.accept() {
+                        Ok((mut connection, peer)) => {
+                            let configured = connection
+                                .set_nonblocking(false)
+                                .and_then(|_| connection.set_read_timeout(Some(POLL)));
+                            if let Err(e) = configured {
+                                eprintln!("PCM: {}: {}", peer, e);
+                                continue;
+                            }
+                            println!("PCM: {} connected", peer);
+                            self.read_stream(&mut connection);
+                            println!("PCM: {} disconnected", peer);
+                            // The next sender starts on a frame boundary of its own
+                            self.partial.clear();
+                            self.resampler.reset();
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(POLL),
+                        Err(e) => {
+                            eprintln!("PCM: {}", e);
+                            std::thread::sleep(POLL);
+                        }
+                    }
+                }
+            }
+            Input::Udp(socket) => {
+                let mut buffer = vec![0u8; DATAGRAM_SIZE];
+                while self.running.load(Ordering::SeqCst) {
+                    match socket.recv(&mut buffer) {
+                        Ok(length) => self.consume(&buffer[..length], false),
+                        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                        Err(e) => {
+                            eprintln!("PCM: {}", e);
+                            std::thread::sleep(POLL);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Until the stream ends or fails, or `running` is cleared
+    fn read_stream(&mut self, stream: &mut dyn Read) {
+        let mut buffer = vec![0u8; READ_SIZE];
+        while self.running.load(Ordering::SeqCst) {
+            match stream.read(&mut buffer) {
+                Ok(0) => return,
+                Ok(length) => self.consume(&buffer[..length], true),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(TICK),
+                Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+                Err(e) => {
+                    eprintln!("PCM: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Convert the whole frames, resample them and queue them for the output. Pipes
+    // and TCP senders are held back while the buffer is full, datagrams are dropped.
+    fn consume(&mut self, bytes: &[u8], wait: bool) {
+        let sample = self.format.sample;
+        let channels = self.format.channels;
+        self.partial.extend_from_slice(bytes);
+        let whole = self.partial.len() / self.format.frame_bytes() * self.format.frame_bytes();
+        self.samples.clear();
+        self.samples
+            .extend(self.partial[..whole].chunks_exact(sample.bytes()).map(|bytes| sample.decode(bytes)));
+        self.partial.drain(..whole);
+        self.resampled.clear();
+        self.resampler.process(&self.samples, &mut self.resampled);
+
+        let mut pushed = 0;
+        while pushed < self.resampled.len() {
+            let count = (self.producer.slots() / channels * channels).min(self.resampled.len() - pushed);
+            for &sample in &self.resampled[pushed..pushed + count] {
+                let _ = self.producer.push(sample);
+            }
+            pushed += count;
+            if pushed == self.resampled.len() {
+                break;
+            }
+            if !wait {
+                let frames = (self.resampled.len() - pushed) / channels;
+                self.shared.dropped.fetch_add(frames as u64, Ordering::Relaxed);
+                break;
+            }
+            if !self.running.load(Ordering::SeqCst) {
+                break;
+            }
+            std::thread::sleep(TICK);
+        }
+    }
+}
+
+// Realtime side, pulled by the output callback
+pub struct PcmSource {
+    shared: Arc<PcmShared>,
+    consumer: Consumer<f32>,
+    channels: usize,
+    // Samples to collect before playing
+    prebuffer: usize,
+    waiting: bool,
+}
+
+impl PcmSource {
+    // Interleaved at the declared channel count and the output rate, silence
+    // whenever the sender has nothing
+    pub fn fill(&mut self, data: &mut [f32]) {
+        if self.waiting {
+            if self.consumer.slots() < self.prebuffer {
+                data.fill(0.0);
+                return;
+            }
+            self.waiting = false;
+            self.shared.playing.store(true, Ordering::Relaxed);
+        }
+        let mut ran_dry = false;
+        for frame in data.chunks_mut(self.channels) {
+            if self.consumer.slots() < self.channels {
+                frame.fill(0.0);
+                ran_dry = true;
+                continue;
+            }
+            for sample in frame.iter_mut() {
+                *sample = self.consumer.pop().unwrap_or(0.0);
+            }
+        }
+        // Collect the latency again instead of playing whatever trickles in
+        if ran_dry {
+            self.waiting = true;
+            self.shared.playing.store(false, Ordering::Relaxed);
+            self.shared.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_from_text() {
+        let format = PcmFormat::parse("S24_3LE:48000:2").unwrap();
+        assert_eq!(format.sample, SampleFormat::S24Packed);
+        assert_eq!((format.sample_rate, format.channels, format.frame_bytes()), (48000, 2, 6));
+        assert!(PcmFormat::parse("s16le:44100").is_err());
+        assert!(PcmFormat::parse("s16le:0:2").is_err());
+        assert!(PcmFormat::parse("s16le:44100:33").is_err());
+        assert!(PcmFormat::parse("u8:44100:2").is_err());
+    }
+
+    #[test]
+    fn transport_from_text() {
+        assert_eq!(PcmTransport::parse("-").unwrap(), PcmTransport::Stdin);
+        assert_eq!(PcmTransport::parse("fifo:/tmp/snapfifo").unwrap(), PcmTransport::Fifo("/tmp/snapfifo".into()));
+        assert_eq!(PcmTransport::parse("tcp:4953").unwrap(), PcmTransport::Tcp(([0, 0, 0, 0], 4953).into()));
+        let udp = PcmTransport::Udp(([127, 0, 0, 1], 9124).into());
+        assert_eq!(PcmTransport::parse("udp:127.0.0.1:9124").unwrap(), udp);
+        assert!(PcmTransport::parse("fifo:").is_err());
+        assert!(PcmTransport::parse("tcp:localhost").is_err());
+        assert!(PcmTransport::parse("/tmp/snapfifo").is_err());
+    }
+
+    #[test]
+    fn decodes_samples() {
+        assert_eq!(SampleFormat::S16.decode(&[0x00, 0x80]), -1.0);
+        assert_eq!(SampleFormat::S24.decode(&[0x00, 0x00, 0x80, 0x00]), -1.0);
+        assert_eq!(SampleFormat::S24Packed.decode(&[0x00, 0x00, 0x40]), 0.5);
+        assert_eq!(SampleFormat::F32.decode(&0.25f32.to_le_bytes()), 0.25);
+    }
+}
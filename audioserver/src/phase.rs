@@ -0,0 +1,74 @@
+use anyhow::{anyhow, Result};
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use std::f64::consts::PI;
+
+use crate::crossover::Band;
+use crate::dsp::biquad::BiquadCoefficients;
+
+// Frequency response of a biquad cascade at `omega` radians per sample
+fn cascade_response(sections: &[BiquadCoefficients], omega: f64) -> Complex<f64> {
+    let z1 = Complex::from_polar(1.0, -omega);
+    let z2 = z1 * z1;
+    sections.iter().fold(Complex::new(1.0, 0.0), |acc, c| {
+        let numerator = c.b0 as f64 + z1 * c.b1 as f64 + z2 * c.b2 as f64;
+        let denominator = 1.0 + z1 * c.a1 as f64 + z2 * c.a2 as f64;
+        acc * numerator / denominator
+    })
+}
+
+// FIR with unit magnitude and the inverse phase of the summed IIR filters of
+// `bands`, plus a delay of half its length to keep it causal. Only the biquads,
+// gain and polarity of the bands count; their delays and FIRs are left alone.
+// The length is rounded up to a power of two.
+pub fn linearizer(bands: &[&Band], sample_rate: u32, taps: usize) -> Result<Vec<f32>> {
+    if taps < 16 {
+        return Err(anyhow!("Phase linearization needs at least 16 taps, got {}", taps));
+    }
+    let size = taps.next_power_of_two();
+
+    let mut responses = Vec::with_capacity(bands.len());
+    for band in bands {
+        let mut sections = Vec::new();
+        for spec in &band.filters {
+            sections.extend(spec.sections(sample_rate)?);
+        }
+        let polarity = if band.invert { -1.0 } else { 1.0 };
+        responses.push((sections, polarity * 10f64.powf(band.gain_db as f64 / 20.0)));
+    }
+
+    // Desired spectrum e^-j(phase + omega * delay), mirrored so the kernel is real
+    let delay = (size / 2) as f64;
+    let mut spectrum = vec![Complex::new(0.0, 0.0); size];
+    for bin in 0..=size / 2 {
+        let omega = 2.0 * PI * bin as f64 / size as f64;
+        let sum: Complex<f64> = responses
+            .iter()
+            .map(|(sections, gain)| cascade_response(sections, omega) * *gain)
+            .sum();
+        // Where the bands cancel there is no phase to correct
+        let phase = if sum.norm() > 1e-9 { sum.arg() } else { 0.0 };
+        spectrum[bin] = Complex::from_polar(1.0, -phase - omega * delay);
+        if bin == 0 || bin == size / 2 {
+            spectrum[bin] = Complex::new(spectrum[bin].re, 0.0);
+        } else {
+            spectrum[size - bin] = spectrum[bin].conj();
+        }
+    }
+    FftPlanner::new().plan_fft_inverse(size).process(&mut spectrum);
+
+    // Hann window around the centre tames the truncation of the inverse response
+    Ok(spectrum
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let window = 0.5 - 0.5 * (2.0 * PI * i as f64 / size as f64).cos();
+            (value.re / size as f64 * window) as f32
+        })
+        .collect())
+}
+
+// Delay of a linearization kernel of `taps` taps
+pub fn linearizer_delay(taps: usize) -> f32 {
+    (taps.next_power_of_two() / 2) as f32
+}
@@ -0,0 +1,286 @@
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+
+use crate::correction::{kernel_delay, Correction};
+use crate::crossover::{BandProcessor, Crossover};
+use crate::eq::{EqStage, Equalizer};
+use crate::filter::{AudioFilter, FilterStage};
+use crate::mixer::{MatrixControl, MatrixPlacement, MatrixStage};
+use crate::phase::linearizer_delay;
+use crate::rt::RealtimeState;
+use crate::volume::{VolumeControl, VolumeStage};
+use crate::workers::BandPool;
+
+// What the pipeline is built from, kept by the transformer and turned into
+// realtime state for the actual rate when processing starts
+#[derive(Debug, Clone, Default)]
+pub struct PipelineConfig {
+    pub filter: Option<AudioFilter>,
+    // Parametric EQ per source channel
+    pub eq: Option<Equalizer>,
+    // Room correction, applied per channel before the crossover
+    pub correction: Option<Correction>,
+    pub crossover: Option<Crossover>,
+    // Channel mixing, before everything else or on the output channels
+    pub matrix: Option<MatrixControl>,
+    pub matrix_placement: MatrixPlacement,
+    // Live volume, with loudness compensation before the split
+    pub volume: VolumeControl,
+    // Threads besides the callback that run crossover bands, 0 runs them all in the callback
+    pub worker_threads: usize,
+}
+
+// Everything between the source and the output device: the mixing matrix when
+// placed before the crossover, the global filter, EQ, room correction, volume,
+// phase linearization, then either the crossover bands or a straight channel
+// copy, and the mixing matrix when placed after the crossover
+pub struct Pipeline {
+    source_channels: usize,
+    // Channels the stages before the split work on, the matrix outputs if it comes first
+    channels: usize,
+    output_channels: usize,
+    pre_matrix: Option<MatrixStage>,
+    post_matrix: Option<MatrixStage>,
+    filter: Option<FilterStage>,
+    eq: Option<EqStage>,
+    correction: Option<FilterStage>,
+    volume: VolumeStage,
+    phase: Option<FilterStage>,
+    bands: Option<BandPool>,
+    latency_frames: Vec<f32>,
+    scratch: Vec<f32>,
+    mixed: Vec<f32>,
+    frames: usize,
+}
+
+impl Pipeline {
+    pub fn new(
+        config: &PipelineConfig,
+        source_channels: usize,
+        output_channels: usize,
+        sample_rate: u32,
+        realtime: &Arc<RealtimeState>,
+    ) -> Result<Self> {
+        let (pre_matrix, post_matrix) = match (config.matrix.as_ref(), config.matrix_placement) {
+            (None, _) => (None, None),
+            (Some(control), MatrixPlacement::BeforeCrossover) => {
+                let stage = MatrixStage::new(control.clone());
+                if stage.inputs() != source_channels {
+                    return Err(anyhow!(
+                        "Mixing matrix takes {} channels, the source has {}",
+                        stage.inputs(),
+                        source_channels
+                    ));
+                }
+                (Some(stage), None)
+            }
+            (Some(control), MatrixPlacement::AfterCrossover) => {
+                let stage = MatrixStage::new(control.clone());
+                if stage.inputs() != output_channels || stage.outputs() != output_channels {
+                    return Err(anyhow!(
+                        "Mixing matrix after the crossover must be {0}x{0} for the output device, got {1}x{2}",
+                        output_channels,
+                        stage.inputs(),
+                        stage.outputs()
+                    ));
+                }
+                (None, Some(stage))
+            }
+        };
+        let channels = pre_matrix.as_ref().map(MatrixStage::outputs).unwrap_or(source_channels);
+
+        let bands = match config.crossover.as_ref() {
+            Some(crossover) if !crossover.bands.is_empty() => {
+                if crossover.output_channels() > output_channels {
+                    return Err(anyhow!(
+                        "Crossover needs {} output channels, the device has {}",
+                        crossover.output_channels(),
+                        output_channels
+                    ));
+                }
+                let processors = crossover
+                    .bands
+                    .iter()
+                    .map(|band| BandProcessor::new(band, sample_rate))
+                    .collect::<Result<Vec<_>>>()?;
+                Some(BandPool::new(processors, channels, config.worker_threads, realtime))
+            }
+            _ => None,
+        };
+
+        let correction_kernels = match config.correction.as_ref() {
+            Some(correction) => Some(correction.kernels_for(channels, sample_rate)?),
+            None => None,
+        };
+
+        let phase_kernels = match config.crossover.as_ref().filter(|_| bands.is_some()) {
+            Some(crossover) => crossover.phase_linearizers(channels, sample_rate)?,
+            None => None,
+        };
+
+        let filter_delay = config
+            .filter
+            .as_ref()
+            .and_then(|f| f.linear_phase_delay())
+            .unwrap_or(0.0);
+        let source_delay = |channel: usize| {
+            let correction_delay = correction_kernels
+                .as_ref()
+                .and_then(|kernels| kernels.get(channel))
+                .and_then(|kernel| kernel.as_deref())
+                .map(kernel_delay)
+                .unwrap_or(0.0);
+            let eq_delay = config
+                .eq
+                .as_ref()
+                .map(|eq| eq.latency_frames(channel, sample_rate))
+                .unwrap_or(0.0);
+            let phase_delay = match (&phase_kernels, config.crossover.as_ref()) {
+                (Some(kernels), Some(crossover)) if matches!(kernels.get(channel), Some(Some(_))) => {
+                    crossover.phase_linearization.map(linearizer_delay).unwrap_or(0.0)
+                }
+                _ => 0.0,
+            };
+            filter_delay + eq_delay + correction_delay + phase_delay
+        };
+        let mut latency_frames: Vec<f32> = (0..output_channels)
+            .map(|ch| match config.crossover.as_ref().filter(|_| bands.is_some()) {
+                Some(crossover) => crossover
+                    .bands
+                    .iter()
+                    .filter(|band| band.output_channel == ch)
+                    .map(|band| source_delay(band.input_channel) + band.latency_frames(sample_rate))
+                    .fold(0.0, f32::max),
+                None => source_delay(ch),
+            })
+            .collect();
+        // Mixed channels are as late as the latest channel feeding them
+        if let Some(stage) = post_matrix.as_ref() {
+            let matrix = stage.matrix();
+            latency_frames = (0..matrix.outputs)
+                .map(|output| {
+                    (0..matrix.inputs)
+                        .filter(|&input| matrix.gain(output, input) != 0.0)
+                        .map(|input| latency_frames[input])
+                        .fold(0.0, f32::max)
+                })
+                .collect();
+        }
+
+        Ok(Pipeline {
+            source_channels,
+            channels,
+            output_channels,
+            pre_matrix,
+            post_matrix,
+            filter: config.filter.as_ref().map(|f| FilterStage::new(f, channels)),
+            eq: match config.eq.as_ref().filter(|eq| !eq.is_empty()) {
+                Some(eq) => Some(EqStage::new(eq, channels, sample_rate)?),
+                None => None,
+            },
+            correction: correction_kernels.as_deref().map(FilterStage::partitioned),
+            volume: VolumeStage::new(config.volume.clone(), channels, sample_rate),
+            phase: phase_kernels.as_deref().map(FilterStage::partitioned),
+            bands,
+            latency_frames,
+            scratch: Vec::new(),
+            mixed: Vec::new(),
+            frames: 0,
+        })
+    }
+
+    // Fixed delay in source frames for every output channel
+    pub fn latency_frames(&self) -> Vec<f32> {
+        self.latency_frames.clone()
+    }
+
+    // Process an interleaved source block into an interleaved output block
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        let frames = input.len() / self.source_channels.max(1);
+        self.frames = frames;
+        output.resize(frames * self.output_channels, 0.0);
+
+        match self.pre_matrix.as_mut() {
+            Some(matrix) => matrix.process(input, &mut self.scratch),
+            None => {
+                self.scratch.clear();
+                self.scratch.extend_from_slice(input);
+            }
+        }
+        if let Some(stage) = self.filter.as_mut() {
+            stage.process(&mut self.scratch);
+        }
+        if let Some(stage) = self.eq.as_mut() {
+            stage.process(&mut self.scratch);
+        }
+        if let Some(stage) = self.correction.as_mut() {
+            stage.process(&mut self.scratch);
+        }
+        self.volume.process(&mut self.scratch);
+        if let Some(stage) = self.phase.as_mut() {
+            stage.process(&mut self.scratch);
+        }
+
+        match self.bands.as_mut() {
+            Some(bands) => bands.process(&self.scratch, self.channels, output, self.output_channels),
+            None => {
+                for (in_frame, out_frame) in self
+                    .scratch
+                    .chunks(self.channels)
+                    .zip(output.chunks_mut(self.output_channels))
+                {
+                    for (ch, out) in out_frame.iter_mut().enumerate() {
+                        *out = in_frame.get(ch).copied().unwrap_or(0.0);
+                    }
+                }
+            }
+        }
+
+        if let Some(matrix) = self.post_matrix.as_mut() {
+            self.mixed.clear();
+            self.mixed.extend_from_slice(output);
+            matrix.process(&self.mixed, output);
+        }
+    }
+
+    // Planar output of a crossover band from the last block
+    pub fn band_output(&self, band: usize) -> Option<&[f32]> {
+        self.bands.as_ref().and_then(|bands| bands.band_output(band, self.frames))
+    }
+
+    pub fn has_bands(&self) -> bool {
+        self.bands.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crossover::Band;
+    use crate::mixer::MixMatrix;
+    use crate::rt::RealtimeConfig;
+
+    #[test]
+    fn mixed_outputs_are_as_late_as_their_latest_input() {
+        // Left 1 ms (48 frames) late, right on time
+        let mut left = Band::new("Left", 0, 0);
+        left.delay_ms = 1.0;
+        let crossover = Crossover {
+            bands: vec![left, Band::new("Right", 1, 1)],
+            phase_linearization: None,
+        };
+        let mut config = PipelineConfig {
+            crossover: Some(crossover),
+            ..PipelineConfig::default()
+        };
+        let realtime = Arc::new(RealtimeState::new(RealtimeConfig::default()));
+        let latency = |config: &PipelineConfig| Pipeline::new(config, 2, 2, 48000, &realtime).unwrap().latency_frames();
+        assert_eq!(latency(&config), [48.0, 0.0]);
+
+        config.matrix_placement = MatrixPlacement::AfterCrossover;
+        config.matrix = Some(MatrixControl::new(MixMatrix::swap()));
+        assert_eq!(latency(&config), [0.0, 48.0]);
+        config.matrix = Some(MatrixControl::new(MixMatrix::mono()));
+        assert_eq!(latency(&config), [48.0, 48.0]);
+    }
+}
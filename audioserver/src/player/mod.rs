@@ -0,0 +1,951 @@
+mod playlist;
+mod queue;
+
+use anyhow::Result;
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::decoder::{AudioDecoder, StreamInfo, TrackMetadata};
+use crate::dsp::resample::StreamResampler;
+use crate::meter::AtomicF32;
+use crate::replaygain::{resolve_gain, GainMode, LoudnessScanner, ReplayGainConfig};
+
+pub use playlist::{is_url, parse_m3u, parse_pls};
+pub use queue::{PlayQueue, QueueEntry, RepeatMode};
+
+// Decoded audio buffered ahead of the output
+const BUFFER_SECONDS: f32 = 0.5;
+// How long the decode thread waits when there is nothing to do
+const IDLE: Duration = Duration::from_millis(10);
+// Progress updates while playing, changes of track or state go out right away
+const PROGRESS_PERIOD: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackState {
+    Stopped,
+    Playing,
+    Paused,
+}
+
+impl PlaybackState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlaybackState::Stopped => "stopped",
+            PlaybackState::Playing => "playing",
+            PlaybackState::Paused => "paused",
+        }
+    }
+}
+
+// Where playback is, sent to subscribers on every change and periodically while playing
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerStatus {
+    pub state: PlaybackState,
+    // The entry the output is playing
+    pub entry: Option<QueueEntry>,
+    // Tags and cover of that entry, read when the file was opened
+    pub metadata: Option<Arc<TrackMetadata>>,
+    pub position: Duration,
+    // ReplayGain applied to the entry, None with normalization off
+    pub gain_db: Option<f32>,
+    // None when the file does not say how long it is
+    pub duration: Option<Duration>,
+    // Decoded but not played yet
+    pub buffered: Duration,
+    // Playing with nothing decoded to play, e.g. while a file opens after a seek
+    pub buffering: bool,
+}
+
+// m:ss, or h:mm:ss from an hour on
+pub fn format_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    if seconds >= 3600 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+// A track as handed to the output
+#[derive(Clone)]
+struct TimelineEntry {
+    // Output frame the track starts at
+    start: u64,
+    entry: QueueEntry,
+    // Track position of that first frame, nonzero after a seek
+    offset: Duration,
+    duration: Option<Duration>,
+    metadata: Arc<TrackMetadata>,
+    gain_db: Option<f32>,
+}
+
+struct PlayerShared {
+    queue: Mutex<PlayQueue>,
+    state: Mutex<PlaybackState>,
+    // Read by the output callback
+    paused: AtomicBool,
+    // Bumped whenever the decoder has to drop what it buffered and start over at the current entry
+    restart: AtomicU64,
+    // Decode thread asks the output callback to empty the ring, which confirms
+    flush_request: AtomicU64,
+    flush_done: AtomicU64,
+    // Frames the output callback took from the ring
+    frames_played: AtomicU64,
+    // Frames the decoder produced, in the same count
+    frames_decoded: AtomicU64,
+    // Output rate of the running session
+    sample_rate: AtomicU32,
+    // The queue ran out and the output is playing what is left
+    draining: AtomicBool,
+    // Where the next restart starts the current entry
+    seek: Mutex<Option<Duration>>,
+    // Every track handed to the output, oldest first
+    timeline: Mutex<VecDeque<TimelineEntry>>,
+    subscribers: Mutex<Vec<Sender<PlayerStatus>>>,
+    replay_gain: Mutex<Option<ReplayGainConfig>>,
+    scanner: LoudnessScanner,
+}
+
+// Play queue feeding the pipeline like any other source. The control side is
+// cheap to clone, `start` runs the decoding for one processing session.
+#[derive(Clone)]
+pub struct Player {
+    shared: Arc<PlayerShared>,
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Player {
+            shared: Arc::new(PlayerShared {
+                queue: Mutex::new(PlayQueue::default()),
+                state: Mutex::new(PlaybackState::Stopped),
+                paused: AtomicBool::new(false),
+                restart: AtomicU64::new(0),
+                flush_request: AtomicU64::new(0),
+                flush_done: AtomicU64::new(0),
+                frames_played: AtomicU64::new(0),
+                frames_decoded: AtomicU64::new(0),
+                sample_rate: AtomicU32::new(0),
+                draining: AtomicBool::new(false),
+                seek: Mutex::new(None),
+                timeline: Mutex::new(VecDeque::new()),
+                subscribers: Mutex::new(Vec::new()),
+                replay_gain: Mutex::new(None),
+                scanner: LoudnessScanner::default(),
+            }),
+        }
+    }
+}
+
+impl Player {
+    pub fn enqueue(&self, path: &Path) -> u64 {
+        self.scan(path);
+        self.shared.queue.lock().unwrap().enqueue(path.to_path_buf(), None)
+    }
+
+    pub fn insert(&self, index: usize, path: &Path) -> u64 {
+        self.scan(path);
+        self.shared.queue.lock().unwrap().insert(index, path.to_path_buf(), None)
+    }
+
+    // A file behind an HTTP URL, with the title the sender gave it
+    pub fn enqueue_url(&self, url: &str, title: Option<String>) -> u64 {
+        self.shared.queue.lock().unwrap().enqueue(url.into(), title)
+    }
+
+    // Measure queued files ahead of time while normalization is on. URLs are
+    // not fetched twice, they go by their tags.
+    fn scan(&self, path: &Path) {
+        if self.shared.replay_gain.lock().unwrap().is_some() && !is_url(path) {
+            self.shared.scanner.scan(path);
+        }
+    }
+
+    // Loudness normalization of every track before the crossover, None turns it off.
+    // Takes effect from the next track.
+    pub fn set_replay_gain(&self, config: Option<ReplayGainConfig>) {
+        *self.shared.replay_gain.lock().unwrap() = config;
+        for entry in self.queue() {
+            self.scan(&entry.path);
+        }
+    }
+
+    // Append every entry of an M3U/M3U8 or PLS playlist, returns how many were added
+    pub fn enqueue_playlist(&self, path: &Path) -> Result<usize> {
+        let entries = playlist::load(path)?;
+        let mut queue = self.shared.queue.lock().unwrap();
+        let mut added = 0;
+        for entry in entries {
+            if is_url(&entry.location) {
+                eprintln!("{}: skipping stream {}", path.display(), entry.location.display());
+                continue;
+            }
+            self.scan(&entry.location);
+            queue.enqueue(entry.location, entry.title);
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    pub fn remove(&self, id: u64) {
+        let was_current = self.shared.queue.lock().unwrap().remove(id);
+        if was_current && self.state() != PlaybackState::Stopped {
+            self.restart();
+        }
+    }
+
+    pub fn move_entry(&self, id: u64, index: usize) -> bool {
+        self.shared.queue.lock().unwrap().move_entry(id, index)
+    }
+
+    pub fn clear(&self) {
+        self.shared.queue.lock().unwrap().clear();
+        self.stop();
+    }
+
+    pub fn queue(&self) -> Vec<QueueEntry> {
+        self.shared.queue.lock().unwrap().entries().to_vec()
+    }
+
+    // The entry the decoder is on, or the one play() starts at
+    pub fn current(&self) -> Option<QueueEntry> {
+        self.shared.queue.lock().unwrap().current().cloned()
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.shared.queue.lock().unwrap().repeat()
+    }
+
+    pub fn shuffle(&self) -> bool {
+        self.shared.queue.lock().unwrap().shuffle()
+    }
+
+    pub fn set_repeat(&self, repeat: RepeatMode) {
+        self.shared.queue.lock().unwrap().set_repeat(repeat);
+    }
+
+    pub fn set_shuffle(&self, shuffle: bool) {
+        self.shared.queue.lock().unwrap().set_shuffle(shuffle);
+    }
+
+    pub fn state(&self) -> PlaybackState {
+        *self.shared.state.lock().unwrap()
+    }
+
+    fn set_state(&self, state: PlaybackState) {
+        *self.shared.state.lock().unwrap() = state;
+        self.shared.paused.store(state == PlaybackState::Paused, Ordering::Relaxed);
+    }
+
+    fn restart(&self) {
+        self.restart_at(None);
+    }
+
+    // Start over at `position` in the current entry, or at its beginning
+    fn restart_at(&self, position: Option<Duration>) {
+        *self.shared.seek.lock().unwrap() = position;
+        self.shared.restart.fetch_add(1, Ordering::Release);
+    }
+
+    // Resume when paused, otherwise start at the current entry (or the first)
+    pub fn play(&self) {
+        match self.state() {
+            PlaybackState::Playing => {}
+            PlaybackState::Paused => self.set_state(PlaybackState::Playing),
+            PlaybackState::Stopped => {
+                {
+                    let mut queue = self.shared.queue.lock().unwrap();
+                    if queue.current().is_none() && queue.rewind().is_none() {
+                        return;
+                    }
+                }
+                self.set_state(PlaybackState::Playing);
+                self.restart();
+            }
+        }
+    }
+
+    pub fn pause(&self) {
+        if self.state() == PlaybackState::Playing {
+            self.set_state(PlaybackState::Paused);
+        }
+    }
+
+    pub fn stop(&self) {
+        self.set_state(PlaybackState::Stopped);
+        self.restart();
+    }
+
+    pub fn next(&self) {
+        if self.shared.queue.lock().unwrap().advance(false).is_none() {
+            self.stop();
+            return;
+        }
+        self.restart();
+    }
+
+    pub fn previous(&self) {
+        self.shared.queue.lock().unwrap().previous();
+        self.restart();
+    }
+
+    // Play a queue entry now
+    pub fn jump(&self, id: u64) -> bool {
+        if !self.shared.queue.lock().unwrap().set_current(id) {
+            return false;
+        }
+        if self.state() == PlaybackState::Stopped {
+            self.set_state(PlaybackState::Playing);
+        }
+        self.restart();
+        true
+    }
+
+    // Continue the playing track at `position`, false when nothing is playing
+    pub fn seek(&self, position: Duration) -> bool {
+        let Some(playing) = self.playing() else {
+            return false;
+        };
+        // The decoder may be a track ahead already
+        if !self.shared.queue.lock().unwrap().set_current(playing.entry.id) {
+            return false;
+        }
+        let position = match playing.duration {
+            Some(duration) => position.min(duration),
+            None => position,
+        };
+        self.restart_at(Some(position));
+        true
+    }
+
+    // Seek relative to the current position, back when negative
+    pub fn seek_by(&self, seconds: f64) -> bool {
+        let position = self.status().position.as_secs_f64() + seconds;
+        self.seek(Duration::from_secs_f64(position.max(0.0)))
+    }
+
+    fn playing(&self) -> Option<TimelineEntry> {
+        if self.state() == PlaybackState::Stopped {
+            return None;
+        }
+        let played = self.shared.frames_played.load(Ordering::Relaxed);
+        let mut timeline = self.shared.timeline.lock().unwrap();
+        while timeline.len() > 1 && timeline[1].start <= played {
+            timeline.pop_front();
+        }
+        timeline.front().cloned()
+    }
+
+    // The entry the output is playing, which trails the decoder by the buffer
+    pub fn now_playing(&self) -> Option<QueueEntry> {
+        self.playing().map(|playing| playing.entry)
+    }
+
+    // Position is only known while a processing session runs, it is zero otherwise
+    pub fn status(&self) -> PlayerStatus {
+        let state = self.state();
+        let rate = self.shared.sample_rate.load(Ordering::Relaxed).max(1) as f64;
+        let played = self.shared.frames_played.load(Ordering::Relaxed);
+        let ahead = self.shared.frames_decoded.load(Ordering::Relaxed).saturating_sub(played);
+        let playing = self.playing();
+        let position = playing.as_ref().map_or(Duration::ZERO, |playing| {
+            let position = playing.offset + Duration::from_secs_f64(played.saturating_sub(playing.start) as f64 / rate);
+            playing.duration.map_or(position, |duration| position.min(duration))
+        });
+        PlayerStatus {
+            state,
+            duration: playing.as_ref().and_then(|playing| playing.duration),
+            metadata: playing.as_ref().map(|playing| playing.metadata.clone()),
+            gain_db: playing.as_ref().and_then(|playing| playing.gain_db),
+            entry: playing.map(|playing| playing.entry),
+            position,
+            buffered: Duration::from_secs_f64(ahead as f64 / rate),
+            buffering: state == PlaybackState::Playing
+                && ahead == 0
+                && !self.shared.draining.load(Ordering::Relaxed),
+        }
+    }
+
+    // Receive the status whenever the track or state changes, and every
+    // PROGRESS_PERIOD while playing. Only sent while a processing session runs.
+    pub fn subscribe(&self) -> Receiver<PlayerStatus> {
+        let (tx, rx) = mpsc::channel();
+        self.shared.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    fn publish(&self, status: PlayerStatus) {
+        self.shared.subscribers.lock().unwrap().retain(|tx| tx.send(status.clone()).is_ok());
+    }
+
+    // Decode the queue at `sample_rate` with `channels` channels until `running` is cleared
+    // `resampler_delay` follows the look-ahead of the resampler, in output frames
+    pub fn start(
+        &self,
+        sample_rate: u32,
+        channels: usize,
+        resampler_delay: Arc<AtomicF32>,
+        running: Arc<AtomicBool>,
+    ) -> (PlayerSource, JoinHandle<()>) {
+        let (producer, consumer) =
+            RingBuffer::new(((sample_rate as f32 * BUFFER_SECONDS) as usize).max(1024) * channels);
+        let source = PlayerSource {
+            shared: self.shared.clone(),
+            consumer,
+            channels,
+            flush_seen: self.shared.flush_request.load(Ordering::Acquire),
+        };
+        self.shared.sample_rate.store(sample_rate, Ordering::Relaxed);
+        let player = self.clone();
+        let handle = std::thread::spawn(move || {
+            DecodeSession::new(player, producer, sample_rate, channels, resampler_delay, running).run();
+        });
+        (source, handle)
+    }
+}
+
+// Realtime side, pulled by the output callback
+pub struct PlayerSource {
+    shared: Arc<PlayerShared>,
+    consumer: Consumer<f32>,
+    channels: usize,
+    flush_seen: u64,
+}
+
+impl PlayerSource {
+    pub fn fill(&mut self, data: &mut [f32]) {
+        let request = self.shared.flush_request.load(Ordering::Acquire);
+        if request != self.flush_seen {
+            while self.consumer.pop().is_ok() {}
+            self.flush_seen = request;
+            self.shared.flush_done.store(request, Ordering::Release);
+        }
+        if self.shared.paused.load(Ordering::Relaxed) {
+            data.fill(0.0);
+            return;
+        }
+
+        let mut frames = 0;
+        for frame in data.chunks_mut(self.channels) {
+            if self.consumer.slots() < self.channels {
+                frame.fill(0.0);
+                continue;
+            }
+            for sample in frame.iter_mut() {
+                *sample = self.consumer.pop().unwrap_or(0.0);
+            }
+            frames += 1;
+        }
+        self.shared.frames_played.fetch_add(frames, Ordering::Relaxed);
+    }
+}
+
+// Gain of every source channel into each of two outputs, for downmixing surround to stereo
+fn stereo_downmix(layout: &[&str]) -> Vec<(f32, f32)> {
+    const SIDE: f32 = std::f32::consts::FRAC_1_SQRT_2;
+    layout
+        .iter()
+        .map(|&position| match position {
+            "FL" => (1.0, 0.0),
+            "FR" => (0.0, 1.0),
+            "FC" => (SIDE, SIDE),
+            "LFE" | "LFE2" => (0.0, 0.0),
+            "BC" | "TC" | "TFC" | "TBC" | "FCH" => (0.5, 0.5),
+            left if left.contains('L') => (SIDE, 0.0),
+            _ => (0.0, SIDE),
+        })
+        .collect()
+}
+
+// Fit a decoded block to the engine's channel count: mono goes to every channel,
+// surround with a known layout is downmixed to stereo, anything else maps channel by channel
+pub fn map_channels(block: &[f32], info: &StreamInfo, channels: usize, out: &mut Vec<f32>) {
+    out.clear();
+    let from = info.channels;
+    if from == channels {
+        out.extend_from_slice(block);
+        return;
+    }
+    if from == 1 {
+        for &sample in block {
+            out.extend(std::iter::repeat_n(sample, channels));
+        }
+        return;
+    }
+    if channels == 2 && info.layout.len() == from {
+        let gains = stereo_downmix(&info.layout);
+        // Keep the sum of the gains into each side at most 1 so nothing clips
+        let left_sum: f32 = gains.iter().map(|g| g.0).sum::<f32>().max(1.0);
+        let right_sum: f32 = gains.iter().map(|g| g.1).sum::<f32>().max(1.0);
+        for frame in block.chunks(from) {
+            let (mut left, mut right) = (0.0, 0.0);
+            for (sample, gain) in frame.iter().zip(&gains) {
+                left += sample * gain.0;
+                right += sample * gain.1;
+            }
+            out.push(left / left_sum);
+            out.push(right / right_sum);
+        }
+        return;
+    }
+    for frame in block.chunks(from) {
+        out.extend((0..channels).map(|ch| frame.get(ch).copied().unwrap_or(0.0)));
+    }
+}
+
+// One processing session of the decode thread
+struct DecodeSession {
+    player: Player,
+    producer: Producer<f32>,
+    sample_rate: u32,
+    channels: usize,
+    running: Arc<AtomicBool>,
+    track: Option<AudioDecoder>,
+    // Copy of the track's info, the decoder is borrowed while its blocks are used
+    info: Option<StreamInfo>,
+    // Kept across tracks of the same rate so gapless albums join without a seam
+    resampler: Option<(u32, StreamResampler)>,
+    resampler_delay: Arc<AtomicF32>,
+    mapped: Vec<f32>,
+    // Linear ReplayGain of the track being decoded
+    gain: f32,
+    pending: Vec<f32>,
+    pending_pos: usize,
+    // The queue ran out, stop once the output has played the rest
+    ended: bool,
+    failures: usize,
+    // Seek target of the last restart, used when the track opens
+    seek: Option<Duration>,
+    // Flush request the output has not confirmed yet, nothing goes into the ring until it has
+    flushing: Option<u64>,
+    // Track and state of the last status sent, and when it went out
+    published: Option<(PlaybackState, Option<u64>)>,
+    published_at: Instant,
+}
+
+impl DecodeSession {
+    fn new(
+        player: Player,
+        producer: Producer<f32>,
+        sample_rate: u32,
+        channels: usize,
+        resampler_delay: Arc<AtomicF32>,
+        running: Arc<AtomicBool>,
+    ) -> Self {
+        DecodeSession {
+            player,
+            producer,
+            sample_rate,
+            channels,
+            running,
+            track: None,
+            info: None,
+            resampler: None,
+            resampler_delay,
+            mapped: Vec::new(),
+            gain: 1.0,
+            pending: Vec::new(),
+            pending_pos: 0,
+            ended: false,
+            failures: 0,
+            seek: None,
+            flushing: None,
+            published: None,
+            published_at: Instant::now(),
+        }
+    }
+
+    fn shared(&self) -> &PlayerShared {
+        &self.player.shared
+    }
+
+    fn run(mut self) {
+        // Start with a flush so nothing from an earlier session is left
+        let mut restart_seen = None;
+        while self.running.load(Ordering::SeqCst) {
+            self.publish();
+            let restart = self.shared().restart.load(Ordering::Acquire);
+            if restart_seen != Some(restart) {
+                restart_seen = Some(restart);
+                self.reset();
+            }
+            if !self.flushed() {
+                std::thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            if self.player.state() == PlaybackState::Stopped {
+                std::thread::sleep(IDLE);
+                continue;
+            }
+            if !self.push_pending() {
+                std::thread::sleep(IDLE);
+                continue;
+            }
+            if self.ended {
+                let shared = self.player.shared.clone();
+                if shared.frames_played.load(Ordering::Relaxed) >= shared.frames_decoded.load(Ordering::Relaxed) {
+                    self.ended = false;
+                    shared.draining.store(false, Ordering::Relaxed);
+                    self.player.set_state(PlaybackState::Stopped);
+                    shared.timeline.lock().unwrap().clear();
+                } else {
+                    std::thread::sleep(IDLE);
+                }
+                continue;
+            }
+            if self.track.is_none() {
+                self.open_current();
+                continue;
+            }
+            self.decode_block();
+        }
+        self.publish();
+    }
+
+    fn publish(&mut self) {
+        let status = self.player.status();
+        let key = (status.state, status.entry.as_ref().map(|entry| entry.id));
+        let due = status.state == PlaybackState::Playing && self.published_at.elapsed() >= PROGRESS_PERIOD;
+        if self.published == Some(key) && !due {
+            return;
+        }
+        self.published = Some(key);
+        self.published_at = Instant::now();
+        self.player.publish(status);
+    }
+
+    // Drop everything buffered, here and in the ring
+    fn reset(&mut self) {
+        self.track = None;
+        self.resampler = None;
+        self.pending.clear();
+        self.pending_pos = 0;
+        self.ended = false;
+        self.failures = 0;
+        self.seek = self.player.shared.seek.lock().unwrap().take();
+        // Send the new position as soon as the track is open again
+        self.published = None;
+
+        let shared = &self.player.shared;
+        shared.draining.store(false, Ordering::Relaxed);
+        self.flushing = Some(shared.flush_request.fetch_add(1, Ordering::AcqRel) + 1);
+    }
+
+    // True once the output emptied the ring for the last reset. The output may be
+    // stopped, e.g. paused for standby, so the loop checks this instead of waiting.
+    fn flushed(&mut self) -> bool {
+        let Some(request) = self.flushing else {
+            return true;
+        };
+        let shared = &self.player.shared;
+        if shared.flush_done.load(Ordering::Acquire) != request {
+            return false;
+        }
+        shared
+            .frames_decoded
+            .store(shared.frames_played.load(Ordering::Relaxed), Ordering::Relaxed);
+        shared.timeline.lock().unwrap().clear();
+        self.flushing = None;
+        true
+    }
+
+    // Move decoded audio into the ring, true once nothing is left over
+    fn push_pending(&mut self) -> bool {
+        if self.pending_pos >= self.pending.len() {
+            return true;
+        }
+        let available = self.producer.slots() / self.channels * self.channels;
+        let count = available.min(self.pending.len() - self.pending_pos);
+        for &sample in &self.pending[self.pending_pos..self.pending_pos + count] {
+            let _ = self.producer.push(sample);
+        }
+        self.pending_pos += count;
+        if self.pending_pos < self.pending.len() {
+            return false;
+        }
+        self.pending.clear();
+        self.pending_pos = 0;
+        true
+    }
+
+    fn append(&mut self, samples_from: usize) {
+        let frames = ((self.pending.len() - samples_from) / self.channels) as u64;
+        self.shared().frames_decoded.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    fn open_current(&mut self) {
+        // Only for the entry the restart was for, not one after it
+        let seek = self.seek.take();
+        let entry = self.shared().queue.lock().unwrap().current().cloned();
+        let Some(entry) = entry else {
+            self.finish();
+            return;
+        };
+        let mut decoder = match AudioDecoder::open(&entry.path) {
+            Ok(decoder) => decoder,
+            Err(e) => {
+                eprintln!("Skipping {}: {}", entry.path.display(), e);
+                self.failures += 1;
+                let queue_len = self.shared().queue.lock().unwrap().entries().len();
+                if self.failures >= queue_len.max(1) || self.shared().queue.lock().unwrap().advance(false).is_none() {
+                    self.finish();
+                }
+                return;
+            }
+        };
+
+        let offset = match seek {
+            Some(position) => decoder.seek(position).unwrap_or_else(|e| {
+                eprintln!("Seeking {} failed: {}", entry.path.display(), e);
+                Duration::ZERO
+            }),
+            None => Duration::ZERO,
+        };
+
+        // A new rate needs a new resampler, the old one hands over its tail first
+        let rate = decoder.info().sample_rate;
+        if self.resampler.as_ref().is_some_and(|(r, _)| *r != rate) {
+            self.flush_resampler();
+        }
+        if self.resampler.is_none() {
+            let resampler = StreamResampler::new(rate, self.sample_rate, self.channels);
+            self.resampler_delay.store(resampler.delay_frames());
+            self.resampler = Some((rate, resampler));
+        }
+        let gain_db = self.replay_gain(&entry.path, decoder.metadata());
+        self.gain = gain_db.map_or(1.0, |db| 10f32.powf(db / 20.0));
+        let start = self.shared().frames_decoded.load(Ordering::Relaxed);
+        self.shared().timeline.lock().unwrap().push_back(TimelineEntry {
+            start,
+            entry,
+            offset,
+            duration: decoder.info().duration(),
+            metadata: Arc::new(decoder.metadata().clone()),
+            gain_db,
+        });
+        self.info = Some(decoder.info().clone());
+        self.track = Some(decoder);
+    }
+
+    // Tags are enough when they have the gain the mode needs, otherwise the
+    // scanner's measurement. Measuring here would hold up the decoder for seconds,
+    // so a track the scanner has not got to yet plays at the fallback gain while
+    // the one after it moves to the front of the scan.
+    fn replay_gain(&self, path: &Path, metadata: &TrackMetadata) -> Option<f32> {
+        let config = (*self.shared().replay_gain.lock().unwrap())?;
+        let scanner = &self.shared().scanner;
+        let tags = metadata.replay_gain;
+        let upcoming = self.shared().queue.lock().unwrap().upcoming().map(|entry| entry.path.clone());
+        if let Some(upcoming) = upcoming.filter(|upcoming| upcoming != path && !is_url(upcoming)) {
+            scanner.prioritize(&upcoming);
+        }
+        let track = scanner.get(path);
+        let album = if config.mode == GainMode::Album && tags.album_gain_db.is_none() {
+            let paths: Vec<_> = self.player.queue().into_iter().map(|entry| entry.path).collect();
+            scanner.album(path, &paths)
+        } else {
+            None
+        };
+        let (gain_db, source) = resolve_gain(
+            &config,
+            &tags,
+            track.as_ref().and_then(|track| track.measurement.as_ref()),
+            album.as_ref(),
+        );
+        println!("ReplayGain {:+.1} dB ({}) for {}", gain_db, source, path.display());
+        Some(gain_db)
+    }
+
+    fn flush_resampler(&mut self) {
+        if let Some((_, mut resampler)) = self.resampler.take() {
+            let from = self.pending.len();
+            resampler.flush(&mut self.pending);
+            self.append(from);
+        }
+    }
+
+    fn finish(&mut self) {
+        self.flush_resampler();
+        self.ended = true;
+        self.shared().draining.store(true, Ordering::Relaxed);
+    }
+
+    fn decode_block(&mut self) {
+        let (Some(track), Some(info)) = (self.track.as_mut(), self.info.as_ref()) else {
+            return;
+        };
+        match track.next_block() {
+            Ok(Some(block)) => {
+                self.failures = 0;
+                map_channels(block, info, self.channels, &mut self.mapped);
+                if self.gain != 1.0 {
+                    let gain = self.gain;
+                    self.mapped.iter_mut().for_each(|sample| *sample *= gain);
+                }
+                if let Some((_, resampler)) = self.resampler.as_mut() {
+                    let from = self.pending.len();
+                    resampler.process(&self.mapped, &mut self.pending);
+                    self.append(from);
+                }
+            }
+            result => {
+                if let Err(e) = result {
+                    eprintln!("Decoding stopped: {}", e);
+                }
+                self.track = None;
+                // The next track opens on the next turn and continues the stream without a gap
+                if self.shared().queue.lock().unwrap().advance(true).is_none() {
+                    self.finish();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const RATE: u32 = 48000;
+
+    // Stereo 16 bit WAV, `level(frame)` on the left and its negative on the right
+    fn track(name: &str, frames: usize, level: impl Fn(usize) -> f32) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("audioserver-player-{}-{}.wav", std::process::id(), name));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for frame in 0..frames {
+            let sample = (level(frame) * 32768.0) as i16;
+            writer.write_sample(sample).unwrap();
+            writer.write_sample(-sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    fn session(player: &Player) -> (PlayerSource, JoinHandle<()>, Arc<AtomicBool>) {
+        let running = Arc::new(AtomicBool::new(true));
+        let (source, handle) = player.start(RATE, 2, Arc::new(AtomicF32::default()), running.clone());
+        (source, handle, running)
+    }
+
+    // Waits up to two seconds for `ready`, given the status and the frames in the ring.
+    // The output is polled with empty blocks meanwhile, which answers flushes but plays nothing.
+    fn wait(source: &mut PlayerSource, player: &Player, ready: impl Fn(&PlayerStatus, usize) -> bool) {
+        let started = Instant::now();
+        while !ready(&player.status(), source.consumer.slots() / source.channels) {
+            assert!(started.elapsed() < Duration::from_secs(2), "player never got ready: {:?}", player.status());
+            source.fill(&mut []);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn stop(handle: JoinHandle<()>, running: Arc<AtomicBool>, tracks: &[PathBuf]) {
+        running.store(false, Ordering::SeqCst);
+        handle.join().unwrap();
+        for track in tracks {
+            let _ = std::fs::remove_file(track);
+        }
+    }
+
+    #[test]
+    fn same_rate_tracks_play_back_to_back() {
+        let first = track("gapless-1", 4800, |_| 0.25);
+        let second = track("gapless-2", 3000, |_| 0.5);
+        let player = Player::default();
+        player.enqueue(&first);
+        player.enqueue(&second);
+        player.play();
+        let (mut source, handle, running) = session(&player);
+        // Both tracks fit the buffer, so the output reads them without depending on timing
+        wait(&mut source, &player, |_, ring| ring >= 7800);
+
+        let mut output = vec![1.0; 2 * 9000];
+        source.fill(&mut output);
+        for (frame, samples) in output.chunks(2).enumerate() {
+            let level = match frame {
+                0..4800 => 0.25,
+                4800..7800 => 0.5,
+                _ => 0.0,
+            };
+            assert_eq!(samples, [level, -level], "frame {}", frame);
+        }
+        stop(handle, running, &[first, second]);
+    }
+
+    #[test]
+    fn position_and_duration_follow_the_output_and_seeks() {
+        // One second that counts up every 8 frames, so a sample tells where it came from
+        let level = |frame: usize| (frame / 8) as f32 / 32768.0;
+        let path = track("seek", RATE as usize, level);
+        let player = Player::default();
+        player.enqueue(&path);
+        player.play();
+        let (mut source, handle, running) = session(&player);
+        // The output only plays what is in the ring, so each block waits until it is there
+        wait(&mut source, &player, |_, ring| ring >= 4800);
+
+        let status = player.status();
+        assert_eq!(status.duration, Some(Duration::from_secs(1)));
+        assert_eq!(status.position, Duration::ZERO);
+        assert!(status.buffered <= Duration::from_secs_f32(BUFFER_SECONDS));
+        let mut output = vec![0.0; 2 * 4800];
+        source.fill(&mut output);
+        assert_eq!(player.status().position, Duration::from_millis(100));
+        assert_eq!(output[2 * 4799], level(4799));
+
+        // The position jumps once the output has dropped what was buffered before the seek
+        assert!(player.seek(Duration::from_millis(500)));
+        wait(&mut source, &player, |status, ring| {
+            status.position == Duration::from_millis(500) && ring >= 2400
+        });
+        let mut output = vec![0.0; 2 * 2400];
+        source.fill(&mut output);
+        assert_eq!(output[0], level(24000));
+        assert_eq!(player.status().position, Duration::from_millis(550));
+        assert_eq!(player.status().duration, Some(Duration::from_secs(1)));
+
+        // Seeking past the end finishes the track, and with it the queue
+        assert!(player.seek(Duration::from_secs(5)));
+        wait(&mut source, &player, |status, _| status.state == PlaybackState::Stopped);
+        stop(handle, running, &[path]);
+    }
+
+    #[test]
+    fn a_stopped_output_does_not_hold_up_the_decoder() {
+        let path = track("stopped-output", 4800, |_| 0.25);
+        let player = Player::default();
+        let updates = player.subscribe();
+        player.enqueue(&path);
+        player.play();
+        let (mut source, handle, running) = session(&player);
+
+        // Nothing answers the flush of the restart, the decoder still follows the controls
+        player.stop();
+        let started = Instant::now();
+        loop {
+            let status = updates.recv_timeout(Duration::from_secs(1)).expect("no status update");
+            if status.state == PlaybackState::Stopped {
+                break;
+            }
+            assert!(started.elapsed() < Duration::from_secs(1));
+        }
+        assert_eq!(player.status().buffered, Duration::ZERO);
+
+        // Once the output runs again playback starts
+        player.play();
+        wait(&mut source, &player, |_, ring| ring > 0);
+        stop(handle, running, &[path]);
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaylistEntry {
+    // Local path, relative entries resolved against the playlist's folder. URLs are kept as they are.
+    pub location: PathBuf,
+    pub title: Option<String>,
+}
+
+pub fn is_url(location: &Path) -> bool {
+    location.to_str().is_some_and(|s| s.contains("://"))
+}
+
+fn resolve(base: &Path, location: &str) -> PathBuf {
+    let location = location.trim();
+    // file:// URLs are plain local paths
+    let location = location.strip_prefix("file://").unwrap_or(location);
+    let path = PathBuf::from(location);
+    if path.is_absolute() || location.contains("://") {
+        path
+    } else {
+        base.join(path)
+    }
+}
+
+// M3U or M3U8 (extended or plain) and PLS, chosen by extension
+pub fn load(path: &Path) -> Result<Vec<PlaylistEntry>> {
+    let bytes = std::fs::read(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    // Plain .m3u is often Latin-1, anything that is not UTF-8 is read as such
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    };
+    let base = path.parent().unwrap_or(Path::new("."));
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    match extension.as_str() {
+        "m3u" | "m3u8" => Ok(parse_m3u(&text, base)),
+        "pls" => parse_pls(&text, base),
+        other => Err(anyhow!("{}: unknown playlist type {}", path.display(), other)),
+    }
+}
+
+pub fn parse_m3u(text: &str, base: &Path) -> Vec<PlaylistEntry> {
+    let mut entries = Vec::new();
+    let mut title = None;
+    for line in text.trim_start_matches('\u{feff}').lines() {
+        let line = line.trim();
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>[ attributes],<title>
+            title = info.split_once(',').map(|(_, t)| t.trim().to_string()).filter(|t| !t.is_empty());
+        } else if line.is_empty() || line.starts_with('#') {
+            continue;
+        } else {
+            entries.push(PlaylistEntry {
+                location: resolve(base, line),
+                title: title.take(),
+            });
+        }
+    }
+    entries
+}
+
+pub fn parse_pls(text: &str, base: &Path) -> Result<Vec<PlaylistEntry>> {
+    // FileN and TitleN keys, in N order whatever order the file lists them in
+    let mut files = BTreeMap::new();
+    let mut titles = BTreeMap::new();
+    let mut in_playlist = false;
+    let mut found = false;
+    for line in text.trim_start_matches('\u{feff}').lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_playlist = line.eq_ignore_ascii_case("[playlist]");
+            found |= in_playlist;
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if !in_playlist {
+            continue;
+        }
+        let key = key.trim().to_ascii_lowercase();
+        if let Some(n) = key.strip_prefix("file").and_then(|n| n.parse::<u32>().ok()) {
+            files.insert(n, value.trim().to_string());
+        } else if let Some(n) = key.strip_prefix("title").and_then(|n| n.parse::<u32>().ok()) {
+            titles.insert(n, value.trim().to_string());
+        }
+    }
+    if !found {
+        return Err(anyhow!("No [playlist] section"));
+    }
+    Ok(files
+        .into_iter()
+        .map(|(n, file)| PlaylistEntry {
+            location: resolve(base, &file),
+            title: titles.remove(&n).filter(|t| !t.is_empty()),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extended_m3u() {
+        let text = "\u{feff}#EXTM3U\n#EXTINF:215,Artist - One\none.flac\n\n# comment\n/music/two.flac\n\
+                    #EXTINF:-1 tvg-id=\"x\",Radio\nhttp://radio.example/stream\nfile:///music/three.flac\n";
+        let entries = parse_m3u(text, Path::new("/lists"));
+        let locations: Vec<&Path> = entries.iter().map(|entry| entry.location.as_path()).collect();
+        assert_eq!(
+            locations,
+            [
+                Path::new("/lists/one.flac"),
+                Path::new("/music/two.flac"),
+                Path::new("http://radio.example/stream"),
+                Path::new("/music/three.flac"),
+            ]
+        );
+        let titles: Vec<Option<&str>> = entries.iter().map(|entry| entry.title.as_deref()).collect();
+        assert_eq!(titles, [Some("Artist - One"), None, Some("Radio"), None]);
+        assert!(is_url(&entries[2].location));
+    }
+
+    #[test]
+    fn pls_in_entry_order() {
+        let text = "[playlist]\nFile2=two.mp3\nTitle1=One\nfile1=http://radio.example/one\nNumberOfEntries=2\n";
+        let entries = parse_pls(text, Path::new("/lists")).unwrap();
+        assert_eq!(
+            entries,
+            [
+                PlaylistEntry {
+                    location: PathBuf::from("http://radio.example/one"),
+                    title: Some("One".to_string()),
+                },
+                PlaylistEntry {
+                    location: PathBuf::from("/lists/two.mp3"),
+                    title: None,
+                },
+            ]
+        );
+        assert!(parse_pls("File1=one.mp3\n", Path::new("/lists")).is_err());
+    }
+}
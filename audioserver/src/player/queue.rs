@@ -0,0 +1,424 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    // The current track again when it ends, next/previous still move on
+    One,
+    // Back to the start after the last track
+    All,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueEntry {
+    // Stays the same when entries move, unlike the index
+    pub id: u64,
+    pub path: PathBuf,
+    // Title from the playlist, if it had one
+    pub title: Option<String>,
+}
+
+// Small xorshift generator for shuffling
+struct Shuffler {
+    state: u64,
+}
+
+impl Shuffler {
+    fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0x9E37_79B9_7F4A_7C15);
+        Shuffler { state: seed | 1 }
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state % n.max(1) as u64) as usize
+    }
+}
+
+// Tracks in list order plus the order they play in, which differs when shuffled
+pub struct PlayQueue {
+    entries: Vec<QueueEntry>,
+    // Entry ids in play order
+    order: Vec<u64>,
+    current: Option<u64>,
+    // Played past the last entry, or it was removed while current. Advancing
+    // stays at the end until `rewind` starts over.
+    ended: bool,
+    repeat: RepeatMode,
+    shuffle: bool,
+    next_id: u64,
+    shuffler: Shuffler,
+}
+
+impl Default for PlayQueue {
+    fn default() -> Self {
+        PlayQueue {
+            entries: Vec::new(),
+            order: Vec::new(),
+            current: None,
+            ended: false,
+            repeat: RepeatMode::Off,
+            shuffle: false,
+            next_id: 1,
+            shuffler: Shuffler::new(),
+        }
+    }
+}
+
+impl PlayQueue {
+    pub fn entries(&self) -> &[QueueEntry] {
+        &self.entries
+    }
+
+    pub fn current(&self) -> Option<&QueueEntry> {
+        self.current.and_then(|id| self.entry(id))
+    }
+
+    pub fn entry(&self, id: u64) -> Option<&QueueEntry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    pub fn shuffle(&self) -> bool {
+        self.shuffle
+    }
+
+    pub fn enqueue(&mut self, path: PathBuf, title: Option<String>) -> u64 {
+        self.insert(self.entries.len(), path, title)
+    }
+
+    // Insert at `index` in list order, clamped to the end
+    pub fn insert(&mut self, index: usize, path: PathBuf, title: Option<String>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let index = index.min(self.entries.len());
+        self.entries.insert(index, QueueEntry { id, path, title });
+        if self.shuffle {
+            // Somewhere after the current track, so it still gets played this round
+            let after = self.position().map(|p| p + 1).unwrap_or(0);
+            let at = after + self.shuffler.below(self.order.len() - after + 1);
+            self.order.insert(at, id);
+        } else {
+            self.rebuild_order();
+        }
+        id
+    }
+
+    // Returns whether it was the current entry. The one after it in play order then
+    // becomes current, as if it had played to its end: past the last entry the
+    // queue ends, or starts over when repeating all of it.
+    pub fn remove(&mut self, id: u64) -> bool {
+        let was_current = self.current == Some(id);
+        if was_current {
+            self.current = match self.position() {
+                Some(position) if position + 1 < self.order.len() => Some(self.order[position + 1]),
+                Some(_) if self.repeat == RepeatMode::All => self.order.first().copied().filter(|&first| first != id),
+                _ => None,
+            };
+            self.ended = self.current.is_none();
+        }
+        self.entries.retain(|entry| entry.id != id);
+        self.order.retain(|&entry| entry != id);
+        was_current
+    }
+
+    // Move an entry to `index` in list order
+    pub fn move_entry(&mut self, id: u64, index: usize) -> bool {
+        let Some(from) = self.entries.iter().position(|entry| entry.id == id) else {
+            return false;
+        };
+        let entry = self.entries.remove(from);
+        self.entries.insert(index.min(self.entries.len()), entry);
+        if !self.shuffle {
+            self.rebuild_order();
+        }
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.current = None;
+        self.ended = false;
+    }
+
+    pub fn set_current(&mut self, id: u64) -> bool {
+        if self.entry(id).is_none() {
+            return false;
+        }
+        self.current = Some(id);
+        self.ended = false;
+        true
+    }
+
+    // Start over at the first entry in play order
+    pub fn rewind(&mut self) -> Option<&QueueEntry> {
+        self.current = self.order.first().copied();
+        self.ended = false;
+        self.current()
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+    }
+
+    // Shuffling keeps the current track and plays the rest in random order after it
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        self.shuffle = shuffle;
+        self.rebuild_order();
+    }
+
+    // Move on from the current entry. `ended` is true when the track played to
+    // its end, where repeat-one plays it again. None at the end of the queue.
+    pub fn advance(&mut self, ended: bool) -> Option<&QueueEntry> {
+        if ended && self.repeat == RepeatMode::One && self.current().is_some() {
+            return self.current();
+        }
+        let next = match self.position() {
+            Some(position) if position + 1 < self.order.len() => Some(self.order[position + 1]),
+            Some(_) if self.repeat == RepeatMode::All => {
+                if self.shuffle {
+                    // New round in a new order, not starting with the track that just played
+                    let last = self.current.take();
+                    self.rebuild_order();
+                    if self.order.len() > 1 && self.order.first().copied() == last {
+                        self.order.rotate_left(1);
+                    }
+                }
+                self.order.first().copied()
+            }
+            Some(_) => None,
+            None if self.ended && self.repeat != RepeatMode::All => None,
+            // Nothing played yet, or starting over after the end
+            None => self.order.first().copied(),
+        };
+        self.ended = next.is_none();
+        self.current = next;
+        self.current()
+    }
+
+    // What `advance` would move to after the current entry plays to its end, without
+    // a reshuffle at the end of a repeated shuffled queue
+    pub fn upcoming(&self) -> Option<&QueueEntry> {
+        let id = match self.position() {
+            Some(_) if self.repeat == RepeatMode::One => self.current,
+            Some(position) if position + 1 < self.order.len() => Some(self.order[position + 1]),
+            Some(_) if self.repeat == RepeatMode::All => self.order.first().copied(),
+            Some(_) => None,
+            None if self.ended && self.repeat != RepeatMode::All => None,
+            None => self.order.first().copied(),
+        };
+        id.and_then(|id| self.entry(id))
+    }
+
+    // Back one entry, the first entry stays put unless repeating the whole queue.
+    // From the end of the queue that is the last entry.
+    pub fn previous(&mut self) -> Option<&QueueEntry> {
+        let previous = match self.position() {
+            Some(0) if self.repeat == RepeatMode::All => self.order.last().copied(),
+            Some(0) => self.order.first().copied(),
+            Some(position) => Some(self.order[position - 1]),
+            None if self.ended => self.order.last().copied(),
+            None => self.order.first().copied(),
+        };
+        self.current = previous;
+        self.ended = false;
+        self.current()
+    }
+
+    fn position(&self) -> Option<usize> {
+        self.current.and_then(|id| self.order.iter().position(|&entry| entry == id))
+    }
+
+    fn rebuild_order(&mut self) {
+        self.order = self.entries.iter().map(|entry| entry.id).collect();
+        if !self.shuffle {
+            return;
+        }
+        // Fisher-Yates, then the current track goes first
+        for i in (1..self.order.len()).rev() {
+            let j = self.shuffler.below(i + 1);
+            self.order.swap(i, j);
+        }
+        if let Some(current) = self.current
+            && let Some(position) = self.order.iter().position(|&id| id == current)
+        {
+            self.order.remove(position);
+            self.order.insert(0, current);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Queue of tracks "a", "b", "c" ... with their ids
+    fn queue(names: &[&str]) -> (PlayQueue, Vec<u64>) {
+        let mut queue = PlayQueue::default();
+        let ids = names.iter().map(|name| queue.enqueue(PathBuf::from(name), None)).collect();
+        (queue, ids)
+    }
+
+    fn names(queue: &PlayQueue) -> Vec<String> {
+        queue.entries().iter().map(|entry| entry.path.display().to_string()).collect()
+    }
+
+    fn current(queue: &PlayQueue) -> Option<String> {
+        queue.current().map(|entry| entry.path.display().to_string())
+    }
+
+    fn play_order(queue: &mut PlayQueue) -> Vec<u64> {
+        let mut played = Vec::new();
+        let mut next = queue.rewind().map(|entry| entry.id);
+        while let Some(id) = next {
+            played.push(id);
+            next = queue.advance(true).map(|entry| entry.id);
+        }
+        played
+    }
+
+    #[test]
+    fn insert_remove_and_move_keep_list_order() {
+        let (mut queue, ids) = queue(&["a", "b", "c"]);
+        let d = queue.insert(1, PathBuf::from("d"), Some("Dee".to_string()));
+        queue.insert(99, PathBuf::from("e"), None);
+        assert_eq!(names(&queue), ["a", "d", "b", "c", "e"]);
+        assert_eq!(queue.entry(d).and_then(|entry| entry.title.as_deref()), Some("Dee"));
+
+        assert!(queue.move_entry(ids[2], 0));
+        assert!(queue.move_entry(ids[0], 99));
+        assert!(!queue.move_entry(999, 0));
+        assert_eq!(names(&queue), ["c", "d", "b", "e", "a"]);
+
+        assert!(!queue.remove(d));
+        assert_eq!(names(&queue), ["c", "b", "e", "a"]);
+        // Ids stay unique after removals
+        let f = queue.enqueue(PathBuf::from("f"), None);
+        assert!(queue.entries().iter().filter(|entry| entry.id == f).count() == 1);
+        assert_eq!(play_order(&mut queue).len(), 5);
+    }
+
+    #[test]
+    fn advance_upcoming_and_previous() {
+        let (mut queue, _) = queue(&["a", "b", "c"]);
+        assert_eq!(queue.upcoming().map(|entry| entry.path.clone()), Some(PathBuf::from("a")));
+        assert_eq!(queue.advance(false).map(|entry| entry.path.clone()), Some(PathBuf::from("a")));
+        queue.advance(true);
+        assert_eq!(current(&queue).as_deref(), Some("b"));
+        assert_eq!(queue.upcoming().map(|entry| entry.path.clone()), Some(PathBuf::from("c")));
+
+        queue.previous();
+        assert_eq!(current(&queue).as_deref(), Some("a"));
+        // The first entry stays put
+        queue.previous();
+        assert_eq!(current(&queue).as_deref(), Some("a"));
+
+        queue.advance(true);
+        queue.advance(true);
+        assert!(queue.upcoming().is_none());
+        assert!(queue.advance(true).is_none());
+        // Past the end nothing moves on until playback starts over
+        assert!(queue.upcoming().is_none());
+        assert!(queue.advance(false).is_none());
+        assert_eq!(queue.previous().map(|entry| entry.path.clone()), Some(PathBuf::from("c")));
+        queue.advance(true);
+        assert_eq!(queue.rewind().map(|entry| entry.path.clone()), Some(PathBuf::from("a")));
+    }
+
+    #[test]
+    fn repeat_one_and_all() {
+        let (mut queue, ids) = queue(&["a", "b"]);
+        queue.set_repeat(RepeatMode::One);
+        queue.rewind();
+        assert_eq!(queue.upcoming().map(|entry| entry.id), Some(ids[0]));
+        assert_eq!(queue.advance(true).map(|entry| entry.id), Some(ids[0]));
+        // Skipping still moves on, also from the last entry to the end
+        assert_eq!(queue.advance(false).map(|entry| entry.id), Some(ids[1]));
+        assert!(queue.advance(false).is_none());
+
+        queue.set_repeat(RepeatMode::All);
+        queue.rewind();
+        queue.advance(true);
+        assert_eq!(queue.upcoming().map(|entry| entry.id), Some(ids[0]));
+        assert_eq!(queue.advance(true).map(|entry| entry.id), Some(ids[0]));
+        assert_eq!(queue.previous().map(|entry| entry.id), Some(ids[1]));
+    }
+
+    #[test]
+    fn removing_the_current_entry_moves_on_as_if_it_ended() {
+        let (mut queue, ids) = queue(&["a", "b", "c"]);
+        queue.set_current(ids[1]);
+        assert!(queue.remove(ids[1]));
+        assert_eq!(current(&queue).as_deref(), Some("c"));
+
+        // The last one ends the queue, the next advance doesn't start it over ...
+        assert!(queue.remove(ids[2]));
+        assert!(queue.current().is_none());
+        assert!(queue.advance(false).is_none());
+        assert!(queue.advance(true).is_none());
+        // ... but playing does
+        assert_eq!(queue.rewind().map(|entry| entry.id), Some(ids[0]));
+
+        let (mut queue, ids) = self::queue(&["a", "b", "c"]);
+        queue.set_repeat(RepeatMode::All);
+        queue.set_current(ids[2]);
+        assert!(queue.remove(ids[2]));
+        assert_eq!(current(&queue).as_deref(), Some("a"));
+
+        let (mut queue, ids) = self::queue(&["a"]);
+        queue.set_repeat(RepeatMode::All);
+        queue.rewind();
+        assert!(queue.remove(ids[0]));
+        assert!(queue.current().is_none());
+        assert!(queue.advance(true).is_none());
+    }
+
+    #[test]
+    fn shuffle_plays_everything_once_starting_with_the_current_entry() {
+        let (mut queue, ids) = queue(&["a", "b", "c", "d", "e", "f", "g", "h"]);
+        queue.set_current(ids[5]);
+        queue.set_shuffle(true);
+        assert!(queue.shuffle());
+        assert_eq!(queue.upcoming().map(|entry| entry.id), queue.order.get(1).copied());
+        assert_eq!(queue.order[0], ids[5]);
+
+        // Added while shuffled: after the current entry, so it plays this round
+        queue.advance(true);
+        let added = queue.enqueue(PathBuf::from("i"), None);
+        let position = queue.order.iter().position(|&id| id == added).unwrap();
+        assert!(position > queue.position().unwrap());
+        // List order is untouched by shuffling
+        assert_eq!(names(&queue), ["a", "b", "c", "d", "e", "f", "g", "h", "i"]);
+
+        let mut played = play_order(&mut queue);
+        played.sort();
+        let mut all: Vec<u64> = queue.entries().iter().map(|entry| entry.id).collect();
+        all.sort();
+        assert_eq!(played, all);
+
+        // A repeated round is reshuffled, without playing the last track twice in a row
+        queue.set_repeat(RepeatMode::All);
+        for _ in 0..20 {
+            queue.rewind();
+            for _ in 1..queue.entries().len() {
+                queue.advance(true);
+            }
+            let last = queue.current().map(|entry| entry.id);
+            assert_ne!(queue.advance(true).map(|entry| entry.id), last);
+        }
+
+        queue.set_shuffle(false);
+        assert_eq!(queue.order, all);
+    }
+}
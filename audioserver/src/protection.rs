@@ -0,0 +1,311 @@
+use std::collections::VecDeque;
+
+use anyhow::{anyhow, Result};
+use biquad::Type;
+
+use crate::crossover::second_order;
+use crate::dsp::biquad::{BiquadBank, BiquadCoefficients};
+use crate::dsp::delay::DelayLine;
+
+// Only move the shelf when the boost changed by more than this
+const BOOST_STEP_DB: f32 = 0.1;
+// Levels below this count as silence
+const LEVEL_FLOOR: f32 = 1e-12;
+
+fn time_constant(ms: f32, sample_rate: u32) -> f32 {
+    if ms <= 0.0 {
+        return 1.0;
+    }
+    1.0 - (-1.0 / (ms * 1e-3 * sample_rate as f32)).exp()
+}
+
+// Low shelf boost that backs off as the low-frequency level rises, so small
+// woofers get bass at low volume without bottoming out when it is loud
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DynamicBass {
+    pub frequency: f32,
+    // Boost at low levels
+    pub boost_db: f32,
+    // Low-frequency RMS level (dBFS) where the boost starts to back off ...
+    pub threshold_db: f32,
+    // ... and how far above the threshold it is gone completely
+    pub range_db: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+}
+
+impl Default for DynamicBass {
+    fn default() -> Self {
+        DynamicBass {
+            frequency: 60.0,
+            boost_db: 6.0,
+            threshold_db: -30.0,
+            range_db: 20.0,
+            attack_ms: 20.0,
+            release_ms: 300.0,
+        }
+    }
+}
+
+// Small-signal model of a driver in a sealed box: excursion follows the input
+// through a second order low pass at Fs with Q Qts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DriverModel {
+    pub fs_hz: f32,
+    pub qts: f32,
+    // Linear excursion limit, one way
+    pub xmax_mm: f32,
+    // Peak excursion a full scale signal well below Fs causes with the amplifier in use
+    pub excursion_at_full_scale_mm: f32,
+}
+
+impl DriverModel {
+    // "<Fs Hz>:<Qts>:<Xmax mm>:<mm at full scale>", as in AUDIOSERVER_WOOFER
+    pub fn parse(text: &str) -> Result<Self> {
+        let values = text
+            .trim()
+            .split(':')
+            .map(|value| value.trim().parse::<f32>().ok().filter(|value| value.is_finite() && *value > 0.0))
+            .collect::<Option<Vec<f32>>>();
+        match values.as_deref() {
+            Some(&[fs_hz, qts, xmax_mm, excursion_at_full_scale_mm]) => Ok(DriverModel {
+                fs_hz,
+                qts,
+                xmax_mm,
+                excursion_at_full_scale_mm,
+            }),
+            _ => Err(anyhow!("Expected <Fs Hz>:<Qts>:<Xmax mm>:<mm at full scale>, all above 0, got {}", text)),
+        }
+    }
+}
+
+// Limits the band so the modelled excursion stays within Xmax. Meant for woofer
+// and sub bands, whose crossover already keeps the rest of the spectrum out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExcursionLimiter {
+    pub driver: DriverModel,
+    // The signal is delayed this much so the gain is already down when a peak arrives
+    pub lookahead_ms: f32,
+    pub release_ms: f32,
+}
+
+impl ExcursionLimiter {
+    pub fn new(driver: DriverModel) -> Self {
+        ExcursionLimiter {
+            driver,
+            lookahead_ms: 2.0,
+            release_ms: 200.0,
+        }
+    }
+
+    pub fn lookahead_samples(&self, sample_rate: u32) -> usize {
+        (self.lookahead_ms.max(0.0) * 1e-3 * sample_rate as f32).round() as usize
+    }
+}
+
+pub struct DynamicBassProcessor {
+    config: DynamicBass,
+    sample_rate: u32,
+    // The level detector on lane 0 and the shelf on lane 1, both run on the band
+    filters: BiquadBank,
+    envelope: f32,
+    attack: f32,
+    release: f32,
+    boost_db: f32,
+    scratch: Vec<f32>,
+}
+
+impl DynamicBassProcessor {
+    pub fn new(config: DynamicBass, sample_rate: u32) -> Result<Self> {
+        let detector = second_order(Type::LowPass, config.frequency, std::f32::consts::FRAC_1_SQRT_2, sample_rate)?;
+        let shelf = second_order(Type::LowShelf(config.boost_db), config.frequency, 0.7, sample_rate)?;
+        Ok(DynamicBassProcessor {
+            config,
+            sample_rate,
+            filters: BiquadBank::new(&[vec![detector], vec![shelf]]),
+            envelope: 0.0,
+            attack: time_constant(config.attack_ms, sample_rate),
+            release: time_constant(config.release_ms, sample_rate),
+            boost_db: config.boost_db,
+            scratch: Vec::new(),
+        })
+    }
+
+    // Boost for a low-frequency level in dBFS
+    fn boost_for(&self, level_db: f32) -> f32 {
+        let over = (level_db - self.config.threshold_db).max(0.0);
+        let fraction = if self.config.range_db > 0.0 {
+            (1.0 - over / self.config.range_db).clamp(0.0, 1.0)
+        } else if over > 0.0 {
+            0.0
+        } else {
+            1.0
+        };
+        self.config.boost_db * fraction
+    }
+
+    pub fn process(&mut self, data: &mut [f32]) {
+        // The boost for this block follows the level measured up to its start
+        let level_db = 10.0 * self.envelope.max(LEVEL_FLOOR).log10();
+        let boost_db = self.boost_for(level_db);
+        if (boost_db - self.boost_db).abs() > BOOST_STEP_DB
+            && let Ok(shelf) = second_order(Type::LowShelf(boost_db), self.config.frequency, 0.7, self.sample_rate)
+        {
+            self.filters.set_cascade(1, &[shelf]);
+            self.boost_db = boost_db;
+        }
+
+        self.scratch.clear();
+        self.scratch.extend(data.iter().flat_map(|&sample| [sample, sample]));
+        self.filters.process_interleaved(&mut self.scratch);
+        for (sample, filtered) in data.iter_mut().zip(self.scratch.chunks_exact(2)) {
+            let power = filtered[0] * filtered[0];
+            let coefficient = if power > self.envelope { self.attack } else { self.release };
+            self.envelope += (power - self.envelope) * coefficient;
+            *sample = filtered[1];
+        }
+    }
+}
+
+pub struct ExcursionLimiterProcessor {
+    xmax_mm: f32,
+    scale_mm: f32,
+    model: BiquadCoefficients,
+    lookahead: DelayLine,
+    lookahead_samples: usize,
+    // Candidates for the lowest required gain of the last lookahead + 1 samples, with their position
+    window: VecDeque<(u64, f32)>,
+    position: u64,
+    release: f32,
+    envelope: f32,
+    // Last lookahead samples of the envelope, averaged into the applied gain
+    ramp: Vec<f32>,
+    ramp_index: usize,
+    ramp_sum: f64,
+    gains: Vec<f32>,
+}
+
+impl ExcursionLimiterProcessor {
+    pub fn new(config: ExcursionLimiter, sample_rate: u32) -> Result<Self> {
+        let driver = config.driver;
+        let model = second_order(Type::LowPass, driver.fs_hz, driver.qts, sample_rate)?;
+        let lookahead = config.lookahead_samples(sample_rate);
+        Ok(ExcursionLimiterProcessor {
+            xmax_mm: driver.xmax_mm,
+            scale_mm: driver.excursion_at_full_scale_mm,
+            model,
+            lookahead: DelayLine::new(lookahead),
+            lookahead_samples: lookahead,
+            window: VecDeque::with_capacity(lookahead + 2),
+            position: 0,
+            release: time_constant(config.release_ms, sample_rate),
+            envelope: 1.0,
+            ramp: vec![1.0; lookahead],
+            ramp_index: 0,
+            ramp_sum: lookahead as f64,
+            gains: Vec::new(),
+        })
+    }
+
+    // Second order low pass at Fs with Q Qts; times the excursion at full scale it gives
+    // the cone excursion. The caller runs it, so several bands share one biquad bank.
+    pub fn model(&self) -> BiquadCoefficients {
+        self.model
+    }
+
+    // `modelled` is the model run over `data`
+    pub fn process(&mut self, data: &mut [f32], modelled: impl Iterator<Item = f32>) {
+        // Predicted excursion of the undelayed signal sets the gain ...
+        self.gains.clear();
+        let lookahead = self.lookahead_samples;
+        for sample in modelled.take(data.len()) {
+            let excursion = (sample * self.scale_mm).abs();
+            let required = if excursion > self.xmax_mm { self.xmax_mm / excursion } else { 1.0 };
+            // Holding the lowest requirement for the lookahead keeps it until the peak is out of the delay line
+            while self.window.back().is_some_and(|&(_, gain)| gain >= required) {
+                self.window.pop_back();
+            }
+            self.window.push_back((self.position, required));
+            while self.window.front().is_some_and(|&(position, _)| position + (lookahead as u64) < self.position) {
+                self.window.pop_front();
+            }
+            self.position += 1;
+            let lowest = self.window.front().map_or(required, |&(_, gain)| gain);
+            if lowest < self.envelope {
+                self.envelope = lowest;
+            } else {
+                self.envelope += (lowest - self.envelope) * self.release;
+            }
+            // Averaging over the lookahead ramps the gain down so it arrives as the peak does
+            let gain = if lookahead == 0 {
+                self.envelope
+            } else {
+                self.ramp_sum += (self.envelope - self.ramp[self.ramp_index]) as f64;
+                self.ramp[self.ramp_index] = self.envelope;
+                self.ramp_index = (self.ramp_index + 1) % lookahead;
+                (self.ramp_sum / lookahead as f64) as f32
+            };
+            self.gains.push(gain);
+        }
+
+        // ... which then applies to the delayed signal
+        self.lookahead.process(data);
+        for (sample, gain) in data.iter_mut().zip(&self.gains) {
+            *sample *= gain;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn driver_from_text() {
+        let driver = DriverModel::parse("38:0.45:6.5:12").unwrap();
+        assert_eq!((driver.fs_hz, driver.qts), (38.0, 0.45));
+        assert_eq!((driver.xmax_mm, driver.excursion_at_full_scale_mm), (6.5, 12.0));
+        assert!(DriverModel::parse("38:0.45:6.5").is_err());
+        assert!(DriverModel::parse("38:0:6.5:12").is_err());
+    }
+
+    #[test]
+    fn limiter_ramps_down_over_the_lookahead() {
+        let sample_rate = 48000;
+        let driver = DriverModel {
+            fs_hz: 40.0,
+            qts: 0.7,
+            xmax_mm: 4.0,
+            excursion_at_full_scale_mm: 10.0,
+        };
+        let config = ExcursionLimiter::new(driver);
+        let lookahead = config.lookahead_samples(sample_rate);
+        let mut limiter = ExcursionLimiterProcessor::new(config, sample_rate).unwrap();
+
+        // Silence, then a full scale 30 Hz burst
+        let input: Vec<f32> = (0..8000)
+            .map(|n| if n < 2000 { 0.0 } else { (std::f32::consts::TAU * 30.0 * n as f32 / sample_rate as f32).sin() })
+            .collect();
+        assert_eq!(limiter.model(), second_order(Type::LowPass, 40.0, 0.7, sample_rate).unwrap());
+        let mut modelled = input.clone();
+        BiquadBank::new(&[vec![limiter.model()]]).process_interleaved(&mut modelled);
+        let required: Vec<f32> = modelled
+            .iter()
+            .map(|sample| (4.0 / (sample * 10.0).abs()).min(1.0))
+            .collect();
+        let mut data = input.clone();
+        limiter.process(&mut data, modelled.iter().copied());
+
+        let gains = &limiter.gains;
+        assert!(gains.iter().cloned().fold(1.0, f32::min) < 0.5);
+        // Every input sample comes out of the delay line with at most the gain it needs ...
+        for (position, required) in required.iter().enumerate().take(gains.len() - lookahead) {
+            assert!(gains[position + lookahead] <= required + 1e-4, "sample {}", position);
+        }
+        // ... and the gain gets there in steps no larger than a lookahead's share of the drop
+        for pair in gains.windows(2) {
+            assert!(pair[0] - pair[1] <= 1.0 / lookahead as f32 + 1e-4);
+        }
+        assert_eq!(data[2000 + lookahead], input[2000] * gains[2000 + lookahead]);
+    }
+}
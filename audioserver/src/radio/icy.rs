@@ -0,0 +1,113 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
+use symphonia::core::io::MediaSource;
+
+use super::RadioShared;
+use crate::http::Response;
+
+// The title out of a metadata block, StreamTitle='Artist - Title';StreamUrl='...';
+fn stream_title(block: &str) -> Option<String> {
+    let start = block.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &block[start..];
+    // Titles can have quotes of their own, the field ends at the one before ';'
+    let end = rest
+        .find("';")
+        .unwrap_or_else(|| rest.trim_end_matches('\0').trim_end_matches('\'').len());
+    let title = rest[..end].trim();
+    (!title.is_empty()).then(|| title.to_string())
+}
+
+// Body of a SHOUTcast/Icecast stream with the metadata blocks taken out. With
+// Icy-MetaData: 1 in the request, a block follows every icy-metaint bytes of audio.
+pub struct IcyReader {
+    response: Response,
+    interval: Option<usize>,
+    // Audio bytes until the next block
+    left: usize,
+    shared: Arc<RadioShared>,
+}
+
+impl IcyReader {
+    pub fn new(response: Response, shared: Arc<RadioShared>) -> Self {
+        let interval = response
+            .header("icy-metaint")
+            .and_then(|interval| interval.trim().parse().ok())
+            .filter(|&interval| interval > 0);
+        IcyReader {
+            response,
+            interval,
+            left: interval.unwrap_or(0),
+            shared,
+        }
+    }
+
+    fn read_metadata(&mut self) -> std::io::Result<()> {
+        let mut length = [0u8];
+        self.response.read_exact(&mut length)?;
+        // In units of 16 bytes, zero when nothing changed
+        let mut block = vec![0u8; length[0] as usize * 16];
+        self.response.read_exact(&mut block)?;
+        if block.is_empty() {
+            return Ok(());
+        }
+        // Mostly UTF-8, older servers send Latin-1
+        let text = match String::from_utf8(block) {
+            Ok(text) => text,
+            Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+        };
+        if text.contains("StreamTitle=") {
+            self.shared.set_title(stream_title(&text));
+        }
+        Ok(())
+    }
+}
+
+impl Read for IcyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(interval) = self.interval else {
+            return self.response.read(buf);
+        };
+        if self.left == 0 {
+            self.read_metadata()?;
+            self.left = interval;
+        }
+        let max = buf.len().min(self.left);
+        let read = self.response.read(&mut buf[..max])?;
+        self.left -= read;
+        Ok(read)
+    }
+}
+
+// Live streams only go forward
+impl Seek for IcyReader {
+    fn seek(&mut self, _: SeekFrom) -> std::io::Result<u64> {
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "live streams cannot seek"))
+    }
+}
+
+impl MediaSource for IcyReader {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn title_from_metadata() {
+        assert_eq!(
+            stream_title("StreamTitle='Artist - Title';StreamUrl='http://x';\0\0").as_deref(),
+            Some("Artist - Title")
+        );
+        assert_eq!(stream_title("StreamTitle='Don't Stop';").as_deref(), Some("Don't Stop"));
+        assert_eq!(stream_title("StreamTitle='Unterminated\0\0").as_deref(), Some("Unterminated"));
+        assert_eq!(stream_title("StreamTitle='';"), None);
+        assert_eq!(stream_title("StreamUrl='http://x';"), None);
+    }
+}
@@ -0,0 +1,309 @@
+mod icy;
+
+use anyhow::{anyhow, Result};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::decoder::AudioDecoder;
+use crate::dsp::resample::StreamResampler;
+use crate::http::{self, Response};
+use crate::meter::AtomicF32;
+use crate::player::{is_url, map_channels, parse_m3u, parse_pls};
+use icy::IcyReader;
+
+// Stations are played as stereo, mono ones on both sides
+const CHANNELS: usize = 2;
+// Reconnect delay, doubling up to the maximum while the station stays away
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(30);
+// Station playlists pointing at other playlists are followed this deep
+const MAX_PLAYLIST_DEPTH: usize = 3;
+// Anything longer is not a station playlist
+const MAX_PLAYLIST_BYTES: u64 = 64 * 1024;
+// How long the stream thread waits for room in the buffer
+const TICK: Duration = Duration::from_millis(10);
+
+// Internet radio over HTTP, SHOUTcast or Icecast: MP3, AAC or Ogg
+#[derive(Debug, Clone)]
+pub struct RadioConfig {
+    // The stream, or an M3U/PLS playlist of the station
+    pub url: String,
+    // Decoded audio collected before playing starts, and again whenever the
+    // buffer runs dry. Twice this fits in the buffer.
+    pub prebuffer: Duration,
+}
+
+impl RadioConfig {
+    pub fn new(url: &str) -> Self {
+        RadioConfig {
+            url: url.to_string(),
+            prebuffer: Duration::from_secs(2),
+        }
+    }
+}
+
+// State shared by the stream thread, the output callback and status reports
+struct RadioShared {
+    config: RadioConfig,
+    // icy-name of the station
+    station: Mutex<Option<String>>,
+    // StreamTitle of the last ICY metadata
+    title: Mutex<Option<String>>,
+    connected: AtomicBool,
+    // The source is collecting the prebuffer
+    buffering: AtomicBool,
+}
+
+impl RadioShared {
+    fn set_title(&self, title: Option<String>) {
+        let mut current = self.title.lock().unwrap();
+        if *current != title {
+            if let Some(title) = &title {
+                println!("Radio: now playing {}", title);
+            }
+            *current = title;
+        }
+    }
+}
+
+// Status side of a playing station
+#[derive(Clone)]
+pub struct Radio {
+    shared: Arc<RadioShared>,
+}
+
+impl Radio {
+    // The name the station sends, or its URL
+    pub fn station(&self) -> String {
+        self.shared
+            .station
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| self.shared.config.url.clone())
+    }
+
+    pub fn title(&self) -> Option<String> {
+        self.shared.title.lock().unwrap().clone()
+    }
+
+    pub fn connected(&self) -> bool {
+        self.shared.connected.load(Ordering::Relaxed)
+    }
+
+    pub fn buffering(&self) -> bool {
+        self.shared.buffering.load(Ordering::Relaxed)
+    }
+}
+
+// Play the station at `sample_rate` until `running` is cleared. Returns the source
+// for the output callback, the status handle and the stream thread. `resampler_delay`
+// follows the look-ahead of the resampler, in output frames.
+pub fn start(
+    config: RadioConfig,
+    sample_rate: u32,
+    resampler_delay: Arc<AtomicF32>,
+    running: Arc<AtomicBool>,
+) -> (RadioSource, Radio, JoinHandle<()>) {
+    let prebuffer = ((sample_rate as f32 * config.prebuffer.as_secs_f32()) as usize).max(512) * CHANNELS;
+    let (producer, consumer) = RingBuffer::new(prebuffer * 2);
+    let shared = Arc::new(RadioShared {
+        config,
+        station: Mutex::new(None),
+        title: Mutex::new(None),
+        connected: AtomicBool::new(false),
+        buffering: AtomicBool::new(true),
+    });
+    let source = RadioSource {
+        shared: shared.clone(),
+        consumer,
+        prebuffer,
+    };
+    let radio = Radio { shared: shared.clone() };
+    let tuner = Tuner {
+        shared,
+        producer,
+        sample_rate,
+        resampler_delay,
+        running,
+        mapped: Vec::new(),
+        pending: Vec::new(),
+        pending_pos: 0,
+    };
+    (source, radio, std::thread::spawn(move || tuner.run()))
+}
+
+// Playlist type from the MIME type, or the extension when the server does not say
+fn playlist_kind(content_type: Option<&str>, url: &str) -> Option<&'static str> {
+    match content_type {
+        Some("audio/x-scpls" | "application/pls+xml") => return Some("pls"),
+        Some("audio/x-mpegurl" | "audio/mpegurl" | "application/x-mpegurl" | "application/vnd.apple.mpegurl") => {
+            return Some("m3u");
+        }
+        Some(content_type) if content_type.starts_with("audio/") || content_type == "application/ogg" => return None,
+        _ => {}
+    }
+    let path = url.split(['?', '#']).next().unwrap_or(url).to_ascii_lowercase();
+    if path.ends_with(".pls") {
+        Some("pls")
+    } else if path.ends_with(".m3u") || path.ends_with(".m3u8") {
+        Some("m3u")
+    } else {
+        None
+    }
+}
+
+// Connect to the stream, through the station's playlists when the URL is one.
+// Returns the response and the URL it came from.
+fn connect(url: &str) -> Result<(Response, String)> {
+    let mut url = url.to_string();
+    for _ in 0..=MAX_PLAYLIST_DEPTH {
+        let response = http::get(&url, &[("Icy-MetaData", "1")])?;
+        let Some(kind) = playlist_kind(response.content_type().as_deref(), &url) else {
+            return Ok((response, url));
+        };
+        let mut bytes = Vec::new();
+        response.take(MAX_PLAYLIST_BYTES).read_to_end(&mut bytes)?;
+        let text = String::from_utf8_lossy(&bytes);
+        if text.contains("#EXT-X-") {
+            return Err(anyhow!("{}: HLS is not supported", url));
+        }
+        let entries = match kind {
+            "pls" => parse_pls(&text, Path::new(""))?,
+            _ => parse_m3u(&text, Path::new("")),
+        };
+        url = entries
+            .into_iter()
+            .map(|entry| entry.location)
+            .find(|location| is_url(location))
+            .and_then(|location| location.to_str().map(str::to_string))
+            .ok_or_else(|| anyhow!("{}: no stream in the playlist", url))?;
+    }
+    Err(anyhow!("{}: playlists nested too deep", url))
+}
+
+// Stream thread: connects, decodes into the ring and reconnects when the stream drops
+struct Tuner {
+    shared: Arc<RadioShared>,
+    producer: Producer<f32>,
+    sample_rate: u32,
+    resampler_delay: Arc<AtomicF32>,
+    running: Arc<AtomicBool>,
+    mapped: Vec<f32>,
+    // Resampled audio waiting for room in the ring
+    pending: Vec<f32>,
+    pending_pos: usize,
+}
+
+impl Tuner {
+    fn run(mut self) {
+        let mut retry = RETRY_MIN;
+        while self.running.load(Ordering::SeqCst) {
+            let connected = Instant::now();
+            if let Err(e) = self.play() {
+                eprintln!("Radio: {}", e);
+            }
+            self.shared.connected.store(false, Ordering::Relaxed);
+            if !self.running.load(Ordering::SeqCst) {
+                break;
+            }
+            // A stream that played for a while starts over with a short delay
+            if connected.elapsed() > RETRY_MAX {
+                retry = RETRY_MIN;
+            }
+            println!("Radio: reconnecting in {} s", retry.as_secs());
+            let wait = Instant::now();
+            while wait.elapsed() < retry && self.running.load(Ordering::SeqCst) {
+                std::thread::sleep(TICK);
+            }
+            retry = (retry * 2).min(RETRY_MAX);
+        }
+    }
+
+    // One connection, until it drops or `running` is cleared
+    fn play(&mut self) -> Result<()> {
+        let (response, url) = connect(&self.shared.config.url)?;
+        let content_type = response.content_type();
+        *self.shared.station.lock().unwrap() = response
+            .header("icy-name")
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty());
+        let reader = IcyReader::new(response, self.shared.clone());
+        let mut decoder = AudioDecoder::open_stream(Box::new(reader), content_type.as_deref(), &url)?;
+        let info = decoder.info().clone();
+        println!("Radio: {} {} Hz, {} channels from {}", info.codec, info.sample_rate, info.channels, url);
+        self.shared.connected.store(true, Ordering::Relaxed);
+
+        let mut resampler = StreamResampler::new(info.sample_rate, self.sample_rate, CHANNELS);
+        self.resampler_delay.store(resampler.delay_frames());
+        while self.running.load(Ordering::SeqCst) {
+            if !self.push_pending() {
+                std::thread::sleep(TICK);
+                continue;
+            }
+            let block = decoder
+                .next_block()?
+                .ok_or_else(|| anyhow!("{}: stream ended", url))?;
+            map_channels(block, &info, CHANNELS, &mut self.mapped);
+            resampler.process(&self.mapped, &mut self.pending);
+        }
+        Ok(())
+    }
+
+    // Move resampled audio into the ring, true once nothing is left over
+    fn push_pending(&mut self) -> bool {
+        let available = self.producer.slots() / CHANNELS * CHANNELS;
+        let count = available.min(self.pending.len() - self.pending_pos);
+        for &sample in &self.pending[self.pending_pos..self.pending_pos + count] {
+            let _ = self.producer.push(sample);
+        }
+        self.pending_pos += count;
+        if self.pending_pos < self.pending.len() {
+            return false;
+        }
+        self.pending.clear();
+        self.pending_pos = 0;
+        true
+    }
+}
+
+// Realtime side, pulled by the output callback
+pub struct RadioSource {
+    shared: Arc<RadioShared>,
+    consumer: Consumer<f32>,
+    // Samples to collect before playing
+    prebuffer: usize,
+}
+
+impl RadioSource {
+    // Interleaved stereo at the output rate, silence while buffering
+    pub fn fill(&mut self, data: &mut [f32]) {
+        if self.shared.buffering.load(Ordering::Relaxed) {
+            if self.consumer.slots() < self.prebuffer {
+                data.fill(0.0);
+                return;
+            }
+            self.shared.buffering.store(false, Ordering::Relaxed);
+        }
+        let mut ran_dry = false;
+        for frame in data.chunks_mut(CHANNELS) {
+            if self.consumer.slots() < CHANNELS {
+                frame.fill(0.0);
+                ran_dry = true;
+                continue;
+            }
+            for sample in frame.iter_mut() {
+                *sample = self.consumer.pop().unwrap_or(0.0);
+            }
+        }
+        // Wait for a full prebuffer again instead of stuttering along
+        if ran_dry {
+            self.shared.buffering.store(true, Ordering::Relaxed);
+        }
+    }
+}
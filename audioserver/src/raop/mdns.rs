@@ -0,0 +1,88 @@
+use anyhow::Result;
+
+// Registered with Avahi over the system bus, withdrawn when dropped
+#[cfg(target_os = "linux")]
+pub struct Advertisement {
+    connection: dbus::blocking::Connection,
+    group: dbus::Path<'static>,
+}
+
+#[cfg(target_os = "linux")]
+mod avahi {
+    use super::*;
+    use dbus::blocking::Connection;
+    use std::time::Duration;
+
+    const SERVICE: &str = "org.freedesktop.Avahi";
+    const SERVER: &str = "org.freedesktop.Avahi.Server";
+    const ENTRY_GROUP: &str = "org.freedesktop.Avahi.EntryGroup";
+    const TIMEOUT: Duration = Duration::from_secs(2);
+    // AVAHI_IF_UNSPEC and AVAHI_PROTO_UNSPEC
+    const UNSPEC: i32 = -1;
+
+    // What the receiver supports, in the _raop._tcp TXT record: stereo 44.1 kHz
+    // 16 bit, PCM or ALAC (cn), unencrypted only (et)
+    const TXT: [&str; 13] = [
+        "txtvers=1",
+        "ch=2",
+        "cn=0,1",
+        "et=0",
+        "sv=false",
+        "da=true",
+        "sr=44100",
+        "ss=16",
+        "pw=false",
+        "vn=3",
+        "tp=UDP",
+        "vs=130.14",
+        "am=AudioServer",
+    ];
+
+    // Senders list RAOP receivers as "<hardware address>@<name>"
+    fn hardware_address() -> String {
+        let address = std::fs::read_dir("/sys/class/net")
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| entry.file_name() != "lo")
+            .filter_map(|entry| std::fs::read_to_string(entry.path().join("address")).ok())
+            .map(|address| address.trim().replace(':', "").to_uppercase())
+            .find(|address| address.len() == 12 && address != "000000000000");
+        address.unwrap_or_else(|| "0A0B0C0D0E0F".to_string())
+    }
+
+    pub fn advertise(name: &str, port: u16) -> Result<Advertisement> {
+        let connection = Connection::new_system()?;
+        let server = connection.with_proxy(SERVICE, "/", TIMEOUT);
+        let (group,): (dbus::Path<'static>,) = server.method_call(SERVER, "EntryGroupNew", ())?;
+
+        let txt: Vec<Vec<u8>> = TXT.iter().map(|entry| entry.as_bytes().to_vec()).collect();
+        let service_name = format!("{}@{}", hardware_address(), name);
+        let entries = connection.with_proxy(SERVICE, group.clone(), TIMEOUT);
+        entries.method_call::<(), _, _, _>(
+            ENTRY_GROUP,
+            "AddService",
+            (UNSPEC, UNSPEC, 0u32, service_name, "_raop._tcp", "", "", port, txt),
+        )?;
+        entries.method_call::<(), _, _, _>(ENTRY_GROUP, "Commit", ())?;
+        Ok(Advertisement { connection, group })
+    }
+
+    impl Drop for Advertisement {
+        fn drop(&mut self) {
+            let entries = self.connection.with_proxy(SERVICE, self.group.clone(), TIMEOUT);
+            let _ = entries.method_call::<(), _, _, _>(ENTRY_GROUP, "Free", ());
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use avahi::advertise;
+
+#[cfg(not(target_os = "linux"))]
+pub struct Advertisement;
+
+#[cfg(not(target_os = "linux"))]
+pub fn advertise(_name: &str, _port: u16) -> Result<Advertisement> {
+    Err(anyhow::anyhow!("not supported on this platform"))
+}
@@ -0,0 +1,174 @@
+mod mdns;
+mod rtsp;
+mod stream;
+
+use anyhow::{anyhow, Result};
+use rtrb::{Consumer, RingBuffer};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::meter::AtomicF32;
+use crate::volume::VolumeControl;
+use stream::Session;
+
+// RAOP always streams stereo
+const CHANNELS: usize = 2;
+// Output ring buffer between the playout thread and the output callback
+const BUFFER_SECONDS: f32 = 0.5;
+// How often the listener looks for new connections and at `running`
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+// AirPlay 1 receiver, what shairport-sync does but feeding the pipeline directly
+#[derive(Debug, Clone)]
+pub struct RaopConfig {
+    // Name shown in the senders' device lists
+    pub name: String,
+    // RTSP port, advertised over mDNS
+    pub port: u16,
+    // Follow the sender's volume slider with the master volume
+    pub sender_volume: bool,
+}
+
+impl Default for RaopConfig {
+    fn default() -> Self {
+        RaopConfig {
+            name: "Virtual Crossover".to_string(),
+            port: 5000,
+            sender_volume: true,
+        }
+    }
+}
+
+// State shared by the RTSP connections, the playout thread and the output callback
+struct RaopShared {
+    config: RaopConfig,
+    volume: VolumeControl,
+    // The sender streaming right now, a new one takes over from the last
+    session: Mutex<Option<Arc<Session>>>,
+    // Bumped by FLUSH, the source drops what is buffered and confirms
+    flush_request: AtomicU64,
+    flush_done: AtomicU64,
+}
+
+impl RaopShared {
+    fn flush(&self) {
+        self.flush_request.fetch_add(1, Ordering::AcqRel);
+    }
+
+    // Start playing `session`, cutting off whoever streamed before
+    fn begin(&self, session: Arc<Session>) {
+        let mut current = self.session.lock().unwrap();
+        if let Some(previous) = current.replace(session.clone())
+            && !Arc::ptr_eq(&previous, &session)
+        {
+            previous.close();
+        }
+    }
+
+    fn end(&self, session: &Arc<Session>) {
+        session.close();
+        let mut current = self.session.lock().unwrap();
+        if current.as_ref().is_some_and(|current| Arc::ptr_eq(current, session)) {
+            *current = None;
+        }
+    }
+}
+
+// Listen for senders and play what they stream at `sample_rate`. Returns the
+// source for the output callback and the listener and playout threads.
+// `resampler_delay` follows the look-ahead of the resampler, in output frames.
+pub fn start(
+    config: RaopConfig,
+    sample_rate: u32,
+    volume: VolumeControl,
+    resampler_delay: Arc<AtomicF32>,
+    running: Arc<AtomicBool>,
+) -> Result<(RaopSource, Vec<JoinHandle<()>>)> {
+    let listener = TcpListener::bind(("0.0.0.0", config.port))
+        .map_err(|e| anyhow!("AirPlay port {}: {}", config.port, e))?;
+    listener.set_nonblocking(true)?;
+
+    let (producer, consumer) =
+        RingBuffer::new(((sample_rate as f32 * BUFFER_SECONDS) as usize).max(1024) * CHANNELS);
+    let shared = Arc::new(RaopShared {
+        config,
+        volume,
+        session: Mutex::new(None),
+        flush_request: AtomicU64::new(0),
+        flush_done: AtomicU64::new(0),
+    });
+    let source = RaopSource {
+        shared: shared.clone(),
+        consumer,
+        flush_seen: 0,
+    };
+
+    let listener_shared = shared.clone();
+    let listener_running = running.clone();
+    let listener_thread = std::thread::spawn(move || listen(listener, listener_shared, listener_running));
+    let playout_thread = std::thread::spawn(move || {
+        stream::playout(shared, producer, sample_rate, resampler_delay, running)
+    });
+    Ok((source, vec![listener_thread, playout_thread]))
+}
+
+fn listen(listener: TcpListener, shared: Arc<RaopShared>, running: Arc<AtomicBool>) {
+    // Withdrawn when dropped at the end of processing
+    let _advertisement = mdns::advertise(&shared.config.name, shared.config.port)
+        .map_err(|e| eprintln!("AirPlay: not advertised over mDNS: {}", e))
+        .ok();
+    println!("AirPlay: \"{}\" listening on port {}", shared.config.name, shared.config.port);
+
+    while running.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((connection, _)) => {
+                // Senders close the connection when they are done, nothing waits for these
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = rtsp::serve(connection, shared) {
+                        eprintln!("AirPlay: {}", e);
+                    }
+                });
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => std::thread::sleep(ACCEPT_POLL),
+            Err(e) => {
+                eprintln!("AirPlay: {}", e);
+                std::thread::sleep(ACCEPT_POLL);
+            }
+        }
+    }
+    if let Some(session) = shared.session.lock().unwrap().take() {
+        session.close();
+    }
+}
+
+// Realtime side, pulled by the output callback
+pub struct RaopSource {
+    shared: Arc<RaopShared>,
+    consumer: Consumer<f32>,
+    flush_seen: u64,
+}
+
+impl RaopSource {
+    // Interleaved stereo at the output rate, silence while nothing streams
+    pub fn fill(&mut self, data: &mut [f32]) {
+        let request = self.shared.flush_request.load(Ordering::Acquire);
+        if request != self.flush_seen {
+            while self.consumer.pop().is_ok() {}
+            self.flush_seen = request;
+            self.shared.flush_done.store(request, Ordering::Release);
+        }
+        for frame in data.chunks_mut(CHANNELS) {
+            if self.consumer.slots() < CHANNELS {
+                frame.fill(0.0);
+                continue;
+            }
+            for sample in frame.iter_mut() {
+                *sample = self.consumer.pop().unwrap_or(0.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use anyhow::{anyhow, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use super::stream::{Session, StreamFormat};
+use super::RaopShared;
+
+// AirPlay volume runs from -30 to 0 dB, -144 is mute
+const MIN_VOLUME_DB: f32 = -30.0;
+const MUTE_DB: f32 = -144.0;
+// Largest request body taken from a sender, SDP and parameters are a few hundred bytes
+// and cover art stays well under this. Anything bigger ends the connection.
+const MAX_BODY: usize = 64 * 1024;
+
+struct Request {
+    method: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+impl Response {
+    fn ok() -> Self {
+        Response::status("200 OK")
+    }
+
+    fn status(status: &'static str) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    fn write(&self, writer: &mut impl Write, cseq: &str) -> Result<()> {
+        let mut text = format!("RTSP/1.0 {}\r\nCSeq: {}\r\nServer: AirTunes/105.1\r\n", self.status, cseq);
+        for (name, value) in &self.headers {
+            text.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !self.body.is_empty() {
+            text.push_str(&format!("Content-Type: text/parameters\r\nContent-Length: {}\r\n", self.body.len()));
+        }
+        text.push_str("\r\n");
+        text.push_str(&self.body);
+        writer.write_all(text.as_bytes())?;
+        Ok(())
+    }
+}
+
+// None when the sender closed the connection
+fn read_request(reader: &mut impl BufRead) -> Result<Option<Request>> {
+    let mut line = String::new();
+    // Blank lines between requests are allowed
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if !line.trim().is_empty() {
+            break;
+        }
+    }
+    let method = line
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow!("bad request line"))?
+        .to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    let mut request = Request {
+        method,
+        headers,
+        body: Vec::new(),
+    };
+    let length: usize = request
+        .header("Content-Length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    if length > MAX_BODY {
+        return Err(anyhow!("request body of {} bytes is over the {} byte limit", length, MAX_BODY));
+    }
+    request.body.resize(length, 0);
+    reader.read_exact(&mut request.body)?;
+    Ok(Some(request))
+}
+
+// "control_port=6001" out of a Transport or RTP-Info header
+fn parameter(transport: &str, name: &str) -> Option<u16> {
+    transport
+        .split(';')
+        .filter_map(|part| part.split_once('='))
+        .find(|(key, _)| key.trim() == name)
+        .and_then(|(_, value)| value.trim().parse().ok())
+}
+
+// One sender's RTSP connection, which owns its session for as long as it stays open
+pub fn serve(connection: TcpStream, shared: Arc<RaopShared>) -> Result<()> {
+    let peer = connection.peer_addr()?;
+    // Accepted from a non-blocking listener
+    connection.set_nonblocking(false)?;
+    let mut reader = BufReader::new(connection.try_clone()?);
+    let mut writer = connection;
+    let mut format: Option<StreamFormat> = None;
+    let mut session: Option<Arc<Session>> = None;
+
+    let result = loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        let response = match request.method.as_str() {
+            "OPTIONS" => Response::ok().header(
+                "Public",
+                "ANNOUNCE, SETUP, RECORD, PAUSE, FLUSH, TEARDOWN, OPTIONS, GET_PARAMETER, SET_PARAMETER".to_string(),
+            ),
+            "ANNOUNCE" => match StreamFormat::from_sdp(&String::from_utf8_lossy(&request.body)) {
+                Ok(announced) => {
+                    format = Some(announced);
+                    Response::ok()
+                }
+                Err(e) => {
+                    eprintln!("AirPlay: {} refused: {}", peer.ip(), e);
+                    Response::status("415 Unsupported Media Type")
+                }
+            },
+            "SETUP" => {
+                let transport = request.header("Transport").unwrap_or_default();
+                let control_port = parameter(transport, "control_port");
+                let timing_port = parameter(transport, "timing_port");
+                match (format.clone(), control_port, timing_port) {
+                    (Some(format), Some(control_port), Some(timing_port)) => {
+                        let opened = Session::open(format, peer.ip(), control_port, timing_port)
+                            .and_then(|opened| opened.ports().map(|ports| (opened, ports)));
+                        match opened {
+                            Ok((opened, (audio, control, timing))) => {
+                                if let Some(previous) = session.replace(opened) {
+                                    shared.end(&previous);
+                                }
+                                Response::ok()
+                                    .header(
+                                        "Transport",
+                                        format!(
+                                            "RTP/AVP/UDP;unicast;mode=record;server_port={};control_port={};timing_port={}",
+                                            audio, control, timing
+                                        ),
+                                    )
+                                    .header("Session", "1".to_string())
+                                    .header("Audio-Jack-Status", "connected; type=analog".to_string())
+                            }
+                            Err(e) => {
+                                eprintln!("AirPlay: {}", e);
+                                Response::status("500 Internal Server Error")
+                            }
+                        }
+                    }
+                    _ => Response::status("455 Method Not Valid in This State"),
+                }
+            }
+            "RECORD" => match &session {
+                Some(session) => {
+                    println!("AirPlay: streaming from {}", peer.ip());
+                    shared.begin(session.clone());
+                    Response::ok()
+                }
+                None => Response::status("455 Method Not Valid in This State"),
+            },
+            "FLUSH" => {
+                // RTP-Info: seq=<first packet after the flush>;rtptime=...
+                let until = request
+                    .header("RTP-Info")
+                    .and_then(|info| parameter(info, "seq"));
+                if let Some(session) = &session {
+                    session.flush(until);
+                    shared.flush();
+                }
+                Response::ok()
+            }
+            "SET_PARAMETER" => {
+                // Also track info and cover art, which are not used
+                let body = String::from_utf8_lossy(&request.body);
+                let volume = body
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.trim() == "volume")
+                    .and_then(|(_, value)| value.trim().parse::<f32>().ok());
+                if let Some(volume) = volume
+                    && shared.config.sender_volume
+                {
+                    shared.volume.set_volume_db(if volume < MIN_VOLUME_DB { MUTE_DB } else { volume });
+                }
+                Response::ok()
+            }
+            "GET_PARAMETER" => {
+                let mut response = Response::ok();
+                if String::from_utf8_lossy(&request.body).contains("volume") {
+                    let volume = shared.volume.volume_db();
+                    let volume = if volume < MIN_VOLUME_DB { MUTE_DB } else { volume };
+                    response.body = format!("volume: {:.6}\r\n", volume);
+                }
+                response
+            }
+            "TEARDOWN" => {
+                if let Some(session) = session.take() {
+                    shared.end(&session);
+                }
+                Response::ok().header("Connection", "close".to_string())
+            }
+            _ => Response::status("501 Not Implemented"),
+        };
+        if let Err(e) = response.write(&mut writer, request.header("CSeq").unwrap_or("0")) {
+            break Err(e);
+        }
+    };
+
+    if let Some(session) = session {
+        shared.end(&session);
+    }
+    println!("AirPlay: {} disconnected", peer.ip());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn reads_body_of_content_length() {
+        let text = "SET_PARAMETER rtsp://10.0.0.2/1 RTSP/1.0\r\nCSeq: 4\r\nContent-Length: 14\r\n\r\nvolume: -11.5\n";
+        let request = read_request(&mut Cursor::new(text)).unwrap().unwrap();
+        assert_eq!(request.method, "SET_PARAMETER");
+        assert_eq!(request.header("cseq"), Some("4"));
+        assert_eq!(request.body, b"volume: -11.5\n");
+    }
+
+    #[test]
+    fn rejects_oversized_body() {
+        let text = "ANNOUNCE rtsp://10.0.0.2/1 RTSP/1.0\r\nContent-Length: 18446744073709551615\r\n\r\n";
+        assert!(read_request(&mut Cursor::new(text)).is_err());
+        let text = format!("ANNOUNCE * RTSP/1.0\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1);
+        assert!(read_request(&mut Cursor::new(text)).is_err());
+    }
+
+    #[test]
+    fn closed_connection_is_none() {
+        assert!(read_request(&mut Cursor::new("\r\n")).unwrap().is_none());
+    }
+
+    #[test]
+    fn transport_parameters() {
+        let transport = "RTP/AVP/UDP;unicast;mode=record;timing_port=6002;control_port=6001";
+        assert_eq!(parameter(transport, "control_port"), Some(6001));
+        assert_eq!(parameter(transport, "timing_port"), Some(6002));
+        assert_eq!(parameter(transport, "server_port"), None);
+    }
+}
@@ -0,0 +1,746 @@
+use anyhow::{anyhow, Result};
+use rtrb::Producer;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CodecParameters, Decoder, DecoderOptions, CODEC_TYPE_ALAC};
+use symphonia::core::formats::Packet;
+
+use super::{RaopShared, CHANNELS};
+use crate::dsp::resample::StreamResampler;
+use crate::meter::AtomicF32;
+
+// Seconds between the NTP epoch (1900) and the Unix one
+const NTP_UNIX_OFFSET: f64 = 2_208_988_800.0;
+// Play delay until the sender's first sync packet says otherwise, what iTunes uses
+const DEFAULT_LATENCY: f64 = 2.0;
+// Audio the playout thread keeps queued ahead of the output
+const AHEAD: f64 = 0.2;
+// Drift the playout lets pass before it inserts silence or drops frames
+const TOLERANCE: f64 = 0.02;
+// About 8 s of 352 frame packets
+const MAX_PACKETS: usize = 1024;
+// Socket reads wake up this often to notice a closed session
+const READ_TIMEOUT: Duration = Duration::from_millis(200);
+const TIMING_PERIOD: Duration = Duration::from_secs(3);
+// Clock measurements kept, the one with the shortest round trip is used
+const TIMING_SAMPLES: usize = 8;
+// How long the playout thread waits when there is nothing to do
+const TICK: Duration = Duration::from_millis(5);
+
+// RTP payload types of RAOP
+const AUDIO: u8 = 0x60;
+const TIMING_REQUEST: u8 = 0x52;
+const TIMING_REPLY: u8 = 0x53;
+const SYNC: u8 = 0x54;
+const RESEND_REQUEST: u8 = 0x55;
+const RESENT_AUDIO: u8 = 0x56;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Codec {
+    // ALACSpecificConfig built from the fmtp line
+    Alac([u8; 24]),
+    // L16, big endian
+    Pcm,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamFormat {
+    pub codec: Codec,
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub frames_per_packet: u32,
+}
+
+impl StreamFormat {
+    // From the SDP body of ANNOUNCE
+    pub fn from_sdp(sdp: &str) -> Result<Self> {
+        let mut rtpmap = None;
+        let mut fmtp = None;
+        for line in sdp.lines().map(str::trim) {
+            if line.starts_with("a=rsaaeskey") {
+                return Err(anyhow!("encrypted streams are not supported"));
+            }
+            if let Some(value) = line.strip_prefix("a=rtpmap:") {
+                rtpmap = value.split_once(' ').map(|(_, encoding)| encoding.trim().to_string());
+            } else if let Some(value) = line.strip_prefix("a=fmtp:") {
+                fmtp = value
+                    .split_whitespace()
+                    .skip(1)
+                    .map(|field| field.parse::<u32>().ok())
+                    .collect::<Option<Vec<u32>>>();
+            }
+        }
+        let rtpmap = rtpmap.ok_or_else(|| anyhow!("no rtpmap in the SDP"))?;
+
+        if rtpmap.starts_with("AppleLossless") {
+            // frameLength compatibleVersion bitDepth pb mb kb numChannels maxRun maxFrameBytes avgBitRate sampleRate
+            let fmtp = fmtp
+                .filter(|fields| fields.len() == 11)
+                .ok_or_else(|| anyhow!("bad ALAC fmtp in the SDP"))?;
+            let mut cookie = [0u8; 24];
+            cookie[0..4].copy_from_slice(&fmtp[0].to_be_bytes());
+            for (i, &field) in fmtp[1..7].iter().enumerate() {
+                cookie[4 + i] = field as u8;
+            }
+            cookie[10..12].copy_from_slice(&(fmtp[7] as u16).to_be_bytes());
+            cookie[12..16].copy_from_slice(&fmtp[8].to_be_bytes());
+            cookie[16..20].copy_from_slice(&fmtp[9].to_be_bytes());
+            cookie[20..24].copy_from_slice(&fmtp[10].to_be_bytes());
+            Ok(StreamFormat {
+                codec: Codec::Alac(cookie),
+                sample_rate: fmtp[10],
+                channels: fmtp[6] as usize,
+                frames_per_packet: fmtp[0],
+            })
+        } else if let Some(parameters) = rtpmap.strip_prefix("L16/") {
+            // L16/44100/2
+            let mut parts = parameters.split('/').map(|part| part.trim().parse::<u32>().ok());
+            let sample_rate = parts.next().flatten().ok_or_else(|| anyhow!("bad L16 rtpmap"))?;
+            let channels = parts.next().flatten().unwrap_or(2) as usize;
+            Ok(StreamFormat {
+                codec: Codec::Pcm,
+                sample_rate,
+                channels,
+                frames_per_packet: 352,
+            })
+        } else {
+            Err(anyhow!("unsupported codec {}", rtpmap))
+        }
+    }
+}
+
+// Payloads to interleaved stereo at the output rate
+struct PacketDecoder {
+    format: StreamFormat,
+    alac: Option<Box<dyn Decoder>>,
+    samples: Option<SampleBuffer<f32>>,
+    stereo: Vec<f32>,
+    resampler: StreamResampler,
+}
+
+impl PacketDecoder {
+    fn new(format: &StreamFormat, sample_rate: u32) -> Result<Self> {
+        let alac = match &format.codec {
+            Codec::Alac(cookie) => {
+                let mut parameters = CodecParameters::new();
+                parameters
+                    .for_codec(CODEC_TYPE_ALAC)
+                    .with_sample_rate(format.sample_rate)
+                    .with_extra_data(cookie.to_vec().into_boxed_slice());
+                Some(symphonia::default::get_codecs().make(&parameters, &DecoderOptions::default())?)
+            }
+            Codec::Pcm => None,
+        };
+        Ok(PacketDecoder {
+            format: format.clone(),
+            alac,
+            samples: None,
+            stereo: Vec::new(),
+            resampler: StreamResampler::new(format.sample_rate, sample_rate, CHANNELS),
+        })
+    }
+
+    fn decode(&mut self, payload: &[u8], output: &mut Vec<f32>) -> Result<()> {
+        // Mono goes to both sides, anything past two channels is dropped
+        self.stereo.clear();
+        match self.alac.as_mut() {
+            Some(decoder) => {
+                let packet = Packet::new_from_slice(0, 0, self.format.frames_per_packet as u64, payload);
+                let decoded = decoder.decode(&packet)?;
+                let spec = *decoded.spec();
+                let channels = spec.channels.count().max(1);
+                if self.samples.as_ref().is_none_or(|samples| samples.capacity() < decoded.capacity() * channels) {
+                    self.samples = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+                }
+                let samples = self.samples.as_mut().unwrap();
+                samples.copy_interleaved_ref(decoded);
+                for frame in samples.samples().chunks_exact(channels) {
+                    self.stereo.extend_from_slice(&[frame[0], frame[frame.len().min(2) - 1]]);
+                }
+            }
+            None => {
+                let channels = self.format.channels.max(1);
+                for frame in payload.chunks_exact(2 * channels) {
+                    let left = i16::from_be_bytes([frame[0], frame[1]]) as f32 / 32768.0;
+                    let right = frame
+                        .get(2..4)
+                        .map_or(left, |right| i16::from_be_bytes([right[0], right[1]]) as f32 / 32768.0);
+                    self.stereo.extend_from_slice(&[left, right]);
+                }
+            }
+        }
+        output.clear();
+        self.resampler.process(&self.stereo, output);
+        Ok(())
+    }
+
+    // A packet's worth of silence, for one that never arrived
+    fn silence(&mut self, output: &mut Vec<f32>) {
+        self.stereo.clear();
+        self.stereo.resize(self.format.frames_per_packet as usize * CHANNELS, 0.0);
+        output.clear();
+        self.resampler.process(&self.stereo, output);
+    }
+
+    fn reset(&mut self) {
+        self.resampler.reset();
+        if let Some(decoder) = self.alac.as_mut() {
+            decoder.reset();
+        }
+    }
+}
+
+fn ntp_to_secs(ntp: u64) -> f64 {
+    (ntp >> 32) as f64 + (ntp & 0xffff_ffff) as f64 / 4_294_967_296.0
+}
+
+fn secs_to_ntp(secs: f64) -> u64 {
+    ((secs.trunc() as u64) << 32) | (secs.fract() * 4_294_967_296.0) as u64
+}
+
+fn read_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([data[at], data[at + 1]])
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+// Local time on the NTP scale, following Instant so it never jumps with the wall clock
+#[derive(Debug, Clone, Copy)]
+struct Clock {
+    base: Instant,
+    ntp: f64,
+}
+
+impl Clock {
+    fn new() -> Self {
+        let unix = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Clock {
+            base: Instant::now(),
+            ntp: unix.as_secs_f64() + NTP_UNIX_OFFSET,
+        }
+    }
+
+    fn now(&self) -> f64 {
+        self.ntp + self.base.elapsed().as_secs_f64()
+    }
+}
+
+// How a sync point was found, better ones replace worse ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum SyncKind {
+    // The default latency after the first packet arrived
+    FirstPacket,
+    // The sender's sync packet, taken as arriving when it was sent
+    Arrival,
+    // The sender's sync packet, through the measured clock offset
+    Clock,
+}
+
+// The frame with RTP timestamp `rtp` plays at local time `local`
+#[derive(Debug, Clone, Copy)]
+struct SyncPoint {
+    rtp: u32,
+    local: f64,
+    kind: SyncKind,
+}
+
+impl SyncPoint {
+    fn time_of(&self, timestamp: u32, sample_rate: u32) -> f64 {
+        self.local + timestamp.wrapping_sub(self.rtp) as i32 as f64 / sample_rate as f64
+    }
+}
+
+struct AudioPacket {
+    timestamp: u32,
+    payload: Vec<u8>,
+}
+
+// Packets by sequence number, extended past the 16 bit wrap
+#[derive(Default)]
+struct JitterBuffer {
+    packets: BTreeMap<i64, AudioPacket>,
+    highest: Option<i64>,
+    // Next packet to play
+    next: Option<i64>,
+    // After a flush, packets before this one are from before it
+    resume: Option<u16>,
+}
+
+impl JitterBuffer {
+    // Returns the first packet and count of a gap in front of this one, to ask for again
+    fn insert(&mut self, seq: u16, packet: AudioPacket) -> Option<(i64, i64)> {
+        if let Some(resume) = self.resume {
+            if (seq.wrapping_sub(resume) as i16) < 0 {
+                return None;
+            }
+            self.resume = None;
+        }
+        let (seq, gap) = match self.highest {
+            Some(highest) => {
+                let seq = highest + seq.wrapping_sub(highest as u16) as i16 as i64;
+                (seq, (seq > highest + 1).then(|| (highest + 1, seq - highest - 1)))
+            }
+            None => (seq as i64, None),
+        };
+        self.highest = Some(self.highest.map_or(seq, |highest| highest.max(seq)));
+        let next = *self.next.get_or_insert(seq);
+        if seq < next {
+            return None; // Too late
+        }
+        self.packets.insert(seq, packet);
+        // Nothing is playing them, keep the newest
+        while self.packets.len() > MAX_PACKETS {
+            if let Some((dropped, _)) = self.packets.pop_first() {
+                self.next = Some(next.max(dropped + 1));
+            }
+        }
+        gap
+    }
+
+    fn flush(&mut self, until: Option<u16>) {
+        *self = JitterBuffer {
+            resume: until,
+            ..Default::default()
+        };
+    }
+}
+
+// Clock offset to the sender from timing exchanges, NTP style
+#[derive(Default)]
+struct ClockOffset {
+    // (round trip, remote minus local)
+    samples: VecDeque<(f64, f64)>,
+}
+
+impl ClockOffset {
+    fn add(&mut self, sent: f64, received: f64, transmitted: f64, arrived: f64) {
+        let delay = (arrived - sent) - (transmitted - received);
+        let offset = ((received - sent) + (transmitted - arrived)) / 2.0;
+        self.samples.push_back((delay, offset));
+        if self.samples.len() > TIMING_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    fn offset(&self) -> Option<f64> {
+        self.samples
+            .iter()
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|&(_, offset)| offset)
+    }
+}
+
+pub enum Next {
+    // Nothing due yet
+    Wait,
+    // Due at local time `at`
+    Packet { payload: Vec<u8>, at: f64 },
+    // Never arrived, silence at `at`
+    Lost { at: f64 },
+}
+
+// One sender's stream, from SETUP to TEARDOWN
+pub struct Session {
+    pub format: StreamFormat,
+    clock: Clock,
+    audio: UdpSocket,
+    control: UdpSocket,
+    timing: UdpSocket,
+    // The sender's control and timing ports
+    remote_control: SocketAddr,
+    remote_timing: SocketAddr,
+    buffer: Mutex<JitterBuffer>,
+    sync: Mutex<Option<SyncPoint>>,
+    offset: Mutex<ClockOffset>,
+    closed: AtomicBool,
+}
+
+impl Session {
+    // Binds the audio, control and timing ports and starts receiving
+    pub fn open(format: StreamFormat, sender: IpAddr, control_port: u16, timing_port: u16) -> Result<Arc<Self>> {
+        let bind = || -> Result<UdpSocket> {
+            let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+            socket.set_read_timeout(Some(READ_TIMEOUT))?;
+            Ok(socket)
+        };
+        let session = Arc::new(Session {
+            format,
+            clock: Clock::new(),
+            audio: bind()?,
+            control: bind()?,
+            timing: bind()?,
+            remote_control: SocketAddr::new(sender, control_port),
+            remote_timing: SocketAddr::new(sender, timing_port),
+            buffer: Mutex::new(JitterBuffer::default()),
+            sync: Mutex::new(None),
+            offset: Mutex::new(ClockOffset::default()),
+            closed: AtomicBool::new(false),
+        });
+        // The threads end with the session, nothing waits for them
+        let audio = session.clone();
+        std::thread::spawn(move || audio.receive_loop(&audio.audio));
+        let control = session.clone();
+        std::thread::spawn(move || control.receive_loop(&control.control));
+        let timing = session.clone();
+        std::thread::spawn(move || timing.timing_loop());
+        Ok(session)
+    }
+
+    // Local audio, control and timing ports for the SETUP response
+    pub fn ports(&self) -> Result<(u16, u16, u16)> {
+        Ok((
+            self.audio.local_addr()?.port(),
+            self.control.local_addr()?.port(),
+            self.timing.local_addr()?.port(),
+        ))
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    // Drop what is buffered, up to packet `until` where FLUSH names one
+    pub fn flush(&self, until: Option<u16>) {
+        self.buffer.lock().unwrap().flush(until);
+    }
+
+    fn now(&self) -> f64 {
+        self.clock.now()
+    }
+
+    fn receive_loop(&self, socket: &UdpSocket) {
+        let mut data = [0u8; 2048];
+        while !self.is_closed() {
+            let length = match socket.recv(&mut data) {
+                Ok(length) => length,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+                Err(e) => {
+                    eprintln!("AirPlay: {}", e);
+                    break;
+                }
+            };
+            let data = &data[..length];
+            if data.len() < 4 {
+                continue;
+            }
+            match data[1] & 0x7f {
+                AUDIO => self.receive_audio(data),
+                // The answer to a resend request, an audio packet behind a 4 byte header
+                RESENT_AUDIO => self.receive_audio(&data[4..]),
+                SYNC => self.receive_sync(data),
+                _ => {}
+            }
+        }
+    }
+
+    fn receive_audio(&self, data: &[u8]) {
+        if data.len() < 12 {
+            return;
+        }
+        let seq = read_u16(data, 2);
+        let timestamp = read_u32(data, 4);
+        let gap = self.buffer.lock().unwrap().insert(
+            seq,
+            AudioPacket {
+                timestamp,
+                payload: data[12..].to_vec(),
+            },
+        );
+        if let Some((first, count)) = gap {
+            self.request_resend(first, count);
+        }
+        let mut sync = self.sync.lock().unwrap();
+        if sync.is_none() {
+            *sync = Some(SyncPoint {
+                rtp: timestamp,
+                local: self.now() + DEFAULT_LATENCY,
+                kind: SyncKind::FirstPacket,
+            });
+        }
+    }
+
+    // The frame at the first timestamp plays at the NTP time, the second one
+    // is the frame the sender is at, their difference the latency
+    fn receive_sync(&self, data: &[u8]) {
+        if data.len() < 20 {
+            return;
+        }
+        let rtp = read_u32(data, 4);
+        let remote = ntp_to_secs(read_u64(data, 8));
+        let (local, kind) = match self.offset.lock().unwrap().offset() {
+            Some(offset) => (remote - offset, SyncKind::Clock),
+            None => (self.now(), SyncKind::Arrival),
+        };
+        let mut sync = self.sync.lock().unwrap();
+        // Without a clock only the first one counts, the arrival jitter would move the stream around
+        if sync.is_none_or(|sync| kind == SyncKind::Clock || kind > sync.kind) {
+            *sync = Some(SyncPoint { rtp, local, kind });
+        }
+    }
+
+    fn timing_loop(&self) {
+        let mut data = [0u8; 128];
+        let mut last_request: Option<Instant> = None;
+        let mut requests = 0;
+        while !self.is_closed() {
+            // A few quick ones to get a clock, then every few seconds
+            let period = if requests < 4 { Duration::from_millis(300) } else { TIMING_PERIOD };
+            if last_request.is_none_or(|last| last.elapsed() >= period) {
+                let mut request = [0u8; 32];
+                request[0] = 0x80;
+                request[1] = 0x80 | TIMING_REQUEST;
+                request[3] = 0x07;
+                request[24..32].copy_from_slice(&secs_to_ntp(self.now()).to_be_bytes());
+                if let Err(e) = self.timing.send_to(&request, self.remote_timing) {
+                    eprintln!("AirPlay: timing request: {}", e);
+                }
+                last_request = Some(Instant::now());
+                requests += 1;
+            }
+
+            let (length, from) = match self.timing.recv_from(&mut data) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+                Err(e) => {
+                    eprintln!("AirPlay: {}", e);
+                    break;
+                }
+            };
+            let arrived = self.now();
+            if length < 32 {
+                continue;
+            }
+            match data[1] & 0x7f {
+                TIMING_REPLY => self.offset.lock().unwrap().add(
+                    ntp_to_secs(read_u64(&data, 8)),
+                    ntp_to_secs(read_u64(&data, 16)),
+                    ntp_to_secs(read_u64(&data, 24)),
+                    arrived,
+                ),
+                // Some senders ask as well
+                TIMING_REQUEST => {
+                    let mut reply = [0u8; 32];
+                    reply[0] = 0x80;
+                    reply[1] = 0x80 | TIMING_REPLY;
+                    reply[3] = 0x07;
+                    reply[8..16].copy_from_slice(&data[24..32]);
+                    reply[16..24].copy_from_slice(&secs_to_ntp(arrived).to_be_bytes());
+                    reply[24..32].copy_from_slice(&secs_to_ntp(self.now()).to_be_bytes());
+                    let _ = self.timing.send_to(&reply, from);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn request_resend(&self, first: i64, count: i64) {
+        let mut request = [0u8; 8];
+        request[0] = 0x80;
+        request[1] = 0x80 | RESEND_REQUEST;
+        request[3] = 0x01;
+        request[4..6].copy_from_slice(&(first as u16).to_be_bytes());
+        request[6..8].copy_from_slice(&(count.min(u16::MAX as i64) as u16).to_be_bytes());
+        if let Err(e) = self.control.send_to(&request, self.remote_control) {
+            eprintln!("AirPlay: resend request: {}", e);
+        }
+    }
+
+    // The next packet in order if it plays before local time `until`. A packet
+    // still missing by then is played as silence.
+    fn next(&self, until: f64) -> Next {
+        let Some(sync) = *self.sync.lock().unwrap() else {
+            return Next::Wait;
+        };
+        let rate = self.format.sample_rate;
+        let mut buffer = self.buffer.lock().unwrap();
+        let Some(next) = buffer.next else {
+            return Next::Wait;
+        };
+        if let Some(packet) = buffer.packets.get(&next) {
+            let at = sync.time_of(packet.timestamp, rate);
+            if at > until {
+                return Next::Wait;
+            }
+            let packet = buffer.packets.remove(&next).unwrap();
+            buffer.next = Some(next + 1);
+            return Next::Packet {
+                payload: packet.payload,
+                at,
+            };
+        }
+
+        let Some((&later, packet)) = buffer.packets.range(next..).next() else {
+            return Next::Wait;
+        };
+        let missing = later - next;
+        let timestamp = packet
+            .timestamp
+            .wrapping_sub((missing as u32).wrapping_mul(self.format.frames_per_packet));
+        let at = sync.time_of(timestamp, rate);
+        if at > until {
+            return Next::Wait;
+        }
+        buffer.next = Some(next + 1);
+        Next::Lost { at }
+    }
+}
+
+fn same(a: &Option<Arc<Session>>, b: &Option<Arc<Session>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn push(producer: &mut Producer<f32>, samples: &[f32]) {
+    for &sample in samples {
+        if producer.push(sample).is_err() {
+            break;
+        }
+    }
+}
+
+// Decodes the current session's packets into the ring buffer, each at the time
+// the sender asked for. The output is kept in step by inserting silence or
+// dropping frames when it drifts off by more than TOLERANCE.
+pub fn playout(
+    shared: Arc<RaopShared>,
+    mut producer: Producer<f32>,
+    sample_rate: u32,
+    resampler_delay: Arc<AtomicF32>,
+    running: Arc<AtomicBool>,
+) {
+    let capacity = producer.buffer().capacity();
+    let rate = sample_rate as f64;
+    let mut current: Option<Arc<Session>> = None;
+    let mut decoder: Option<PacketDecoder> = None;
+    let mut started = false;
+    let mut flush_seen = shared.flush_request.load(Ordering::Acquire);
+    let mut output = Vec::new();
+    let silence = vec![0.0; (AHEAD * rate) as usize * CHANNELS];
+
+    while running.load(Ordering::SeqCst) {
+        let session = shared.session.lock().unwrap().clone();
+        if !same(&session, &current) {
+            decoder = session.as_ref().and_then(|session| {
+                PacketDecoder::new(&session.format, sample_rate)
+                    .map_err(|e| eprintln!("AirPlay: {}", e))
+                    .ok()
+            });
+            if let Some(decoder) = decoder.as_ref() {
+                resampler_delay.store(decoder.resampler.delay_frames());
+            }
+            current = session;
+            started = false;
+        }
+
+        // Wait for the source to drop what was queued before the flush
+        let request = shared.flush_request.load(Ordering::Acquire);
+        if request != flush_seen {
+            if shared.flush_done.load(Ordering::Acquire) != request {
+                std::thread::sleep(TICK);
+                continue;
+            }
+            flush_seen = request;
+            started = false;
+            if let Some(decoder) = decoder.as_mut() {
+                decoder.reset();
+            }
+        }
+
+        let (Some(session), Some(decoder)) = (current.as_ref(), decoder.as_mut()) else {
+            std::thread::sleep(TICK);
+            continue;
+        };
+        let queued = (capacity - producer.slots()) / CHANNELS;
+        let queued_secs = queued as f64 / rate;
+        if queued_secs >= AHEAD {
+            std::thread::sleep(TICK);
+            continue;
+        }
+        // When what is pushed now gets played
+        let horizon = session.now() + queued_secs;
+        let at = match session.next(horizon + AHEAD) {
+            Next::Wait => {
+                std::thread::sleep(TICK);
+                continue;
+            }
+            Next::Packet { payload, at } => {
+                if let Err(e) = decoder.decode(&payload, &mut output) {
+                    eprintln!("AirPlay: {}", e);
+                    decoder.silence(&mut output);
+                }
+                at
+            }
+            Next::Lost { at } => {
+                decoder.silence(&mut output);
+                at
+            }
+        };
+
+        let error = at - horizon;
+        if !started && error < -TOLERANCE {
+            continue; // Already late, catch up before starting
+        }
+        let mut skip = 0;
+        if !started || error > TOLERANCE {
+            let frames = (error.clamp(0.0, AHEAD) * rate) as usize;
+            push(&mut producer, &silence[..frames * CHANNELS]);
+        } else if error < -TOLERANCE {
+            skip = ((-error * rate) as usize).min(output.len() / CHANNELS);
+        }
+        started = true;
+        push(&mut producer, &output[skip * CHANNELS..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alac_format_from_sdp() {
+        let sdp = "v=0\r\no=iTunes 1 0 IN IP4 10.0.0.2\r\nm=audio 0 RTP/AVP 96\r\na=rtpmap:96 AppleLossless\r\n\
+                   a=fmtp:96 352 0 16 40 10 14 2 255 0 0 44100\r\n";
+        let format = StreamFormat::from_sdp(sdp).unwrap();
+        assert_eq!((format.sample_rate, format.channels, format.frames_per_packet), (44100, 2, 352));
+        let Codec::Alac(cookie) = format.codec else {
+            panic!("not ALAC");
+        };
+        assert_eq!(&cookie[0..4], &352u32.to_be_bytes());
+        assert_eq!(&cookie[4..10], &[0, 16, 40, 10, 14, 2]);
+        assert_eq!(&cookie[10..12], &255u16.to_be_bytes());
+        assert_eq!(&cookie[20..24], &44100u32.to_be_bytes());
+    }
+
+    #[test]
+    fn pcm_format_from_sdp() {
+        let format = StreamFormat::from_sdp("m=audio 0 RTP/AVP 96\na=rtpmap:96 L16/48000/1\n").unwrap();
+        assert_eq!(format.codec, Codec::Pcm);
+        assert_eq!((format.sample_rate, format.channels), (48000, 1));
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_play() {
+        assert!(StreamFormat::from_sdp("a=rtpmap:96 AppleLossless\na=fmtp:96 352 0 16\n").is_err());
+        assert!(StreamFormat::from_sdp("a=rtpmap:96 mpeg4-generic/44100/2\n").is_err());
+        assert!(StreamFormat::from_sdp("a=rtpmap:96 AppleLossless\na=rsaaeskey:AAAA\n").is_err());
+        assert!(StreamFormat::from_sdp("v=0\n").is_err());
+    }
+}
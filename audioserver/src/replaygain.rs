@@ -0,0 +1,373 @@
+use anyhow::Result;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::decoder::{AudioDecoder, ReplayGainTags};
+use crate::dsp::loudness::{LoudnessMeasurement, LoudnessMeter};
+
+// ReplayGain 2.0 plays everything at -18 LUFS
+const REFERENCE_LUFS: f64 = -18.0;
+
+// Scanned files kept, the oldest go first. Measurements hold 10 blocks a second,
+// so this is a few MB. Album mode uses track gains in longer queues.
+const MAX_RESULTS: usize = 512;
+
+// The scan thread ends after this long without work, the next file starts another
+const SCAN_IDLE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GainMode {
+    // Every track at the same loudness
+    #[default]
+    Track,
+    // Whole albums at the same loudness, keeping the level differences between their tracks
+    Album,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplayGainConfig {
+    pub mode: GainMode,
+    // Added to every gain
+    pub preamp_db: f32,
+    // Lower the gain where the track's peak would otherwise go over full scale
+    pub prevent_clipping: bool,
+    // For tracks without tags that could not be measured either
+    pub fallback_db: f32,
+}
+
+impl Default for ReplayGainConfig {
+    fn default() -> Self {
+        ReplayGainConfig {
+            mode: GainMode::Track,
+            preamp_db: 0.0,
+            prevent_clipping: true,
+            // Unknown loudness, err on the quiet side
+            fallback_db: -6.0,
+        }
+    }
+}
+
+// Loudness of one file, from its tags and, where they are missing, measured
+#[derive(Debug, Clone, Default)]
+pub struct TrackLoudness {
+    // Album artist (or artist) and album, to group untagged albums
+    pub album: Option<(String, String)>,
+    pub measurement: Option<LoudnessMeasurement>,
+}
+
+// Read the tags of a file and run it through a BS.1770 meter unless they
+// already have both gains
+pub fn analyze(path: &Path) -> Result<TrackLoudness> {
+    let mut decoder = AudioDecoder::open(path)?;
+    let metadata = decoder.metadata();
+    let tags = metadata.replay_gain;
+    let album = metadata
+        .album
+        .clone()
+        .map(|album| (metadata.album_artist.clone().or(metadata.artist.clone()).unwrap_or_default(), album));
+    if tags.track_gain_db.is_some() && tags.album_gain_db.is_some() {
+        return Ok(TrackLoudness {
+            album,
+            measurement: None,
+        });
+    }
+
+    let info = decoder.info().clone();
+    let mut meter = LoudnessMeter::new(info.sample_rate, info.channels, &info.layout);
+    while let Some(block) = decoder.next_block()? {
+        meter.process(block);
+    }
+    Ok(TrackLoudness {
+        album,
+        measurement: Some(meter.finish()),
+    })
+}
+
+fn measured_gain(measurement: &LoudnessMeasurement) -> Option<(f32, Option<f32>)> {
+    measurement
+        .integrated_lufs()
+        .map(|lufs| ((REFERENCE_LUFS - lufs) as f32, Some(measurement.peak)))
+}
+
+// Gain in dB for a track and where it came from. Tags win over measurements,
+// album mode falls back to the track gain when the album's is not known.
+pub fn resolve_gain(
+    config: &ReplayGainConfig,
+    tags: &ReplayGainTags,
+    track: Option<&LoudnessMeasurement>,
+    album: Option<&LoudnessMeasurement>,
+) -> (f32, &'static str) {
+    let track_gain = tags
+        .track_gain_db
+        .map(|gain| (gain, tags.track_peak, "track tag"))
+        .or_else(|| track.and_then(measured_gain).map(|(gain, peak)| (gain, peak, "measured track")));
+    let album_gain = tags
+        .album_gain_db
+        .map(|gain| (gain, tags.album_peak, "album tag"))
+        .or_else(|| album.and_then(measured_gain).map(|(gain, peak)| (gain, peak, "measured album")));
+    let chosen = match config.mode {
+        GainMode::Track => track_gain,
+        GainMode::Album => album_gain.or(track_gain),
+    };
+    let Some((gain, peak, source)) = chosen else {
+        return (config.fallback_db, "fallback");
+    };
+
+    let mut gain = gain + config.preamp_db;
+    // What clips is this track, so its own peak counts where known
+    let peak = tags
+        .track_peak
+        .or(track.map(|measurement| measurement.peak))
+        .or(peak);
+    if config.prevent_clipping
+        && let Some(peak) = peak
+        && peak > 0.0
+    {
+        gain = gain.min(-20.0 * peak.log10());
+    }
+    (gain, source)
+}
+
+#[derive(Default)]
+struct ScannerShared {
+    // None when the file could not be read
+    results: Mutex<HashMap<PathBuf, Option<Arc<TrackLoudness>>>>,
+    // Keys of `results` in the order they were stored
+    stored: Mutex<VecDeque<PathBuf>>,
+    pending: Mutex<VecDeque<PathBuf>>,
+    wake: Condvar,
+    started: AtomicBool,
+}
+
+// Analyses queued files in the background, so most tracks are known before they play
+#[derive(Clone, Default)]
+pub struct LoudnessScanner {
+    shared: Arc<ScannerShared>,
+}
+
+impl LoudnessScanner {
+    pub fn scan(&self, path: &Path) {
+        self.queue(path, false);
+    }
+
+    // Scan `path` before anything else that is queued, e.g. the track that plays next
+    pub fn prioritize(&self, path: &Path) {
+        self.queue(path, true);
+    }
+
+    fn queue(&self, path: &Path, first: bool) {
+        if self.shared.results.lock().unwrap().contains_key(path) {
+            return;
+        }
+        let mut pending = self.shared.pending.lock().unwrap();
+        pending.retain(|queued| !(first && queued == path));
+        if first {
+            pending.push_front(path.to_path_buf());
+        } else if !pending.iter().any(|queued| queued == path) {
+            pending.push_back(path.to_path_buf());
+        }
+        drop(pending);
+        self.shared.wake.notify_one();
+        if !self.shared.started.swap(true, Ordering::SeqCst) {
+            let shared = self.shared.clone();
+            std::thread::spawn(move || scan_loop(shared));
+        }
+    }
+
+    // Scanned result, None when it has not been scanned yet or failed
+    pub fn get(&self, path: &Path) -> Option<Arc<TrackLoudness>> {
+        self.shared.results.lock().unwrap().get(path).cloned().flatten()
+    }
+
+    // Blocks of every track of `path`'s album among `paths`, once all of
+    // `paths` are scanned so the album gain does not change while it plays
+    pub fn album(&self, path: &Path, paths: &[PathBuf]) -> Option<LoudnessMeasurement> {
+        let results = self.shared.results.lock().unwrap();
+        let key = results.get(path).cloned().flatten()?.album.clone()?;
+        if !paths.iter().all(|path| results.contains_key(path)) {
+            return None;
+        }
+        let tracks: Vec<&LoudnessMeasurement> = paths
+            .iter()
+            .filter_map(|path| results.get(path).and_then(Option::as_ref))
+            .filter(|track| track.album.as_ref() == Some(&key))
+            .filter_map(|track| track.measurement.as_ref())
+            .collect();
+        if tracks.is_empty() {
+            return None;
+        }
+        Some(LoudnessMeasurement {
+            blocks: tracks.iter().flat_map(|track| track.blocks.iter().copied()).collect(),
+            peak: tracks.iter().map(|track| track.peak).fold(0.0, f32::max),
+        })
+    }
+}
+
+fn store(shared: &ScannerShared, path: &Path, result: Result<TrackLoudness>) -> Option<Arc<TrackLoudness>> {
+    let result = result
+        .map_err(|e| eprintln!("Loudness scan of {} failed: {}", path.display(), e))
+        .ok()
+        .map(Arc::new);
+    let mut results = shared.results.lock().unwrap();
+    let mut stored = shared.stored.lock().unwrap();
+    if results.insert(path.to_path_buf(), result.clone()).is_none() {
+        stored.push_back(path.to_path_buf());
+    }
+    while stored.len() > MAX_RESULTS {
+        if let Some(oldest) = stored.pop_front() {
+            results.remove(&oldest);
+        }
+    }
+    result
+}
+
+// Scans until nothing was queued for SCAN_IDLE
+fn scan_loop(shared: Arc<ScannerShared>) {
+    loop {
+        let path = {
+            let mut pending = shared.pending.lock().unwrap();
+            loop {
+                if let Some(path) = pending.pop_front() {
+                    break path;
+                }
+                let (guard, wait) = shared.wake.wait_timeout(pending, SCAN_IDLE).unwrap();
+                pending = guard;
+                // Cleared under the lock, so a file queued from now on starts a new thread
+                if wait.timed_out() && pending.is_empty() {
+                    shared.started.store(false, Ordering::SeqCst);
+                    return;
+                }
+            }
+        };
+        if shared.results.lock().unwrap().contains_key(&path) {
+            continue;
+        }
+        store(&shared, &path, analyze(&path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two blocks at `lufs`
+    fn measured(lufs: f64, peak: f32) -> LoudnessMeasurement {
+        LoudnessMeasurement {
+            blocks: vec![10f64.powf((lufs + 0.691) / 10.0); 2],
+            peak,
+        }
+    }
+
+    fn close(gain: (f32, &'static str), expected: (f32, &'static str)) -> bool {
+        (gain.0 - expected.0).abs() < 1e-4 && gain.1 == expected.1
+    }
+
+    #[test]
+    fn gain_adds_the_preamp_and_prefers_tags() {
+        let config = ReplayGainConfig {
+            preamp_db: 2.0,
+            prevent_clipping: false,
+            ..ReplayGainConfig::default()
+        };
+        let tags = ReplayGainTags {
+            track_gain_db: Some(-7.5),
+            ..ReplayGainTags::default()
+        };
+        let track = measured(-10.0, 0.5);
+        let gain = resolve_gain(&config, &tags, Some(&track), None);
+        assert!(close(gain, (-5.5, "track tag")), "{:?}", gain);
+        let gain = resolve_gain(&config, &ReplayGainTags::default(), Some(&track), None);
+        assert!(close(gain, (-6.0, "measured track")), "{:?}", gain);
+        // The fallback is used as is
+        let gain = resolve_gain(&config, &ReplayGainTags::default(), None, None);
+        assert!(close(gain, (-6.0, "fallback")), "{:?}", gain);
+    }
+
+    #[test]
+    fn album_mode_falls_back_to_the_track_gain() {
+        let config = ReplayGainConfig {
+            mode: GainMode::Album,
+            prevent_clipping: false,
+            ..ReplayGainConfig::default()
+        };
+        let track = measured(-10.0, 0.5);
+        let album = measured(-14.0, 0.5);
+        let gain = resolve_gain(&config, &ReplayGainTags::default(), Some(&track), Some(&album));
+        assert!(close(gain, (-4.0, "measured album")), "{:?}", gain);
+        let tags = ReplayGainTags {
+            album_gain_db: Some(-3.0),
+            ..ReplayGainTags::default()
+        };
+        let gain = resolve_gain(&config, &tags, Some(&track), Some(&album));
+        assert!(close(gain, (-3.0, "album tag")), "{:?}", gain);
+        // The album is not known until all of its queued tracks are scanned
+        let gain = resolve_gain(&config, &ReplayGainTags::default(), Some(&track), None);
+        assert!(close(gain, (-8.0, "measured track")), "{:?}", gain);
+    }
+
+    #[test]
+    fn clipping_is_prevented_with_the_track_peak() {
+        let config = ReplayGainConfig {
+            mode: GainMode::Album,
+            preamp_db: 6.0,
+            ..ReplayGainConfig::default()
+        };
+        // A quiet track peaking at -12 dBFS can go up 12 dB at most
+        let track = measured(-30.0, 0.25);
+        let gain = resolve_gain(&config, &ReplayGainTags::default(), Some(&track), None);
+        assert!(close(gain, (-20.0 * 0.25f32.log10(), "measured track")), "{:?}", gain);
+        // In an album the track's own peak counts, not the album's
+        let album = measured(-30.0, 1.0);
+        let gain = resolve_gain(&config, &ReplayGainTags::default(), Some(&track), Some(&album));
+        assert!(close(gain, (-20.0 * 0.25f32.log10(), "measured album")), "{:?}", gain);
+        // A tagged peak wins over the measured one
+        let tags = ReplayGainTags {
+            track_peak: Some(0.5),
+            ..ReplayGainTags::default()
+        };
+        let gain = resolve_gain(&config, &tags, Some(&track), None);
+        assert!(close(gain, (-20.0 * 0.5f32.log10(), "measured track")), "{:?}", gain);
+        // Gains that do not clip are left alone
+        let loud = measured(-12.0, 0.25);
+        let gain = resolve_gain(&config, &ReplayGainTags::default(), Some(&loud), None);
+        assert!(close(gain, (0.0, "measured track")), "{:?}", gain);
+    }
+
+    #[test]
+    fn scanner_keeps_the_latest_results() {
+        let scanner = LoudnessScanner::default();
+        let path = |n: usize| PathBuf::from(format!("/music/{}.flac", n));
+        let track = || {
+            Ok(TrackLoudness {
+                album: None,
+                measurement: Some(measured(-18.0, 1.0)),
+            })
+        };
+        for n in 0..MAX_RESULTS + 10 {
+            store(&scanner.shared, &path(n), track());
+        }
+        // Storing a file again does not count twice
+        store(&scanner.shared, &path(MAX_RESULTS), track());
+        assert_eq!(scanner.shared.results.lock().unwrap().len(), MAX_RESULTS);
+        assert_eq!(scanner.shared.stored.lock().unwrap().len(), MAX_RESULTS);
+        assert!(scanner.get(&path(9)).is_none());
+        assert!(scanner.get(&path(10)).is_some());
+        assert!(scanner.get(&path(MAX_RESULTS + 9)).is_some());
+    }
+
+    #[test]
+    fn failed_scans_are_remembered() {
+        let scanner = LoudnessScanner::default();
+        let path = std::env::temp_dir().join(format!("audioserver-missing-{}.flac", std::process::id()));
+        scanner.scan(&path);
+        let started = std::time::Instant::now();
+        while !scanner.shared.results.lock().unwrap().contains_key(&path) {
+            assert!(started.elapsed() < Duration::from_secs(2), "scan never finished");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(scanner.get(&path).is_none());
+    }
+}
@@ -0,0 +1,412 @@
+use anyhow::{anyhow, bail, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// CPU slots of the audio callbacks, band workers follow from 2
+pub const OUTPUT_CALLBACK: usize = 0;
+pub const INPUT_CALLBACK: usize = 1;
+const CALLBACK_NAMES: [&str; 2] = ["output callback", "input callback"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedPolicy {
+    // Leave the threads at normal priority
+    Normal,
+    Fifo,
+    RoundRobin,
+}
+
+// Realtime treatment of the audio callbacks and band workers, all off by default.
+// CPU slots: 0 output callback, 1 input callback, 2.. band workers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RealtimeConfig {
+    pub policy: SchedPolicy,
+    // 1 (lowest) to 99, clamped to what the system allows
+    pub priority: i32,
+    // Lock all current and future pages of the process into RAM
+    pub lock_memory: bool,
+    // CPUs audio threads are pinned to, round robin in thread order; empty leaves them floating
+    pub cpus: Vec<usize>,
+}
+
+impl Default for RealtimeConfig {
+    fn default() -> Self {
+        RealtimeConfig {
+            policy: SchedPolicy::Normal,
+            priority: 70,
+            lock_memory: false,
+            cpus: Vec::new(),
+        }
+    }
+}
+
+impl RealtimeConfig {
+    // "<policy>[:<priority>]" with fifo, rr or normal, as in AUDIOSERVER_REALTIME
+    pub fn parse(text: &str) -> Result<Self> {
+        let (policy, priority) = match text.trim().split_once(':') {
+            Some((policy, priority)) => (policy, Some(priority)),
+            None => (text.trim(), None),
+        };
+        let policy = match policy.to_ascii_lowercase().as_str() {
+            "fifo" => SchedPolicy::Fifo,
+            "rr" => SchedPolicy::RoundRobin,
+            "normal" => SchedPolicy::Normal,
+            other => bail!("Unknown scheduling policy {}, expected fifo, rr or normal", other),
+        };
+        let defaults = RealtimeConfig::default();
+        let priority = match priority {
+            Some(priority) => match priority.parse() {
+                Ok(priority @ 1..=99) => priority,
+                _ => bail!("Realtime priority must be 1 to 99, got {}", priority),
+            },
+            None => defaults.priority,
+        };
+        Ok(RealtimeConfig {
+            policy,
+            priority,
+            ..defaults
+        })
+    }
+}
+
+// CPU list like "2,3" or "2-5", as in AUDIOSERVER_CPUS
+pub fn parse_cpus(text: &str) -> Result<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for item in text.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        let cpu = |text: &str| text.trim().parse::<usize>().map_err(|_| anyhow!("Bad CPU {} in {}", text, item));
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (cpu(first)?, cpu(last)?);
+                if last < first {
+                    bail!("Reversed CPU range {}", item);
+                }
+                cpus.extend(first..=last)
+            }
+            None => cpus.push(cpu(item)?),
+        }
+    }
+    Ok(cpus)
+}
+
+// How one thread ended up being scheduled
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadReport {
+    pub name: String,
+    pub scheduling: String,
+    pub cpu: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RealtimeReport {
+    pub memory_locked: bool,
+    pub threads: Vec<ThreadReport>,
+}
+
+// Applies the config to each audio thread and collects what it got, shared with
+// the status report. Band workers apply it as they start; the audio callbacks only
+// announce their thread, since promotion may block on D-Bus, and a control thread
+// applies it for them.
+#[derive(Debug)]
+pub struct RealtimeState {
+    config: RealtimeConfig,
+    report: Mutex<RealtimeReport>,
+    // Thread id each callback slot announced, 0 until its first call ...
+    announced: [AtomicU64; 2],
+    // ... and the one the config was last applied to
+    applied: [AtomicU64; 2],
+}
+
+impl RealtimeState {
+    pub fn new(config: RealtimeConfig) -> Self {
+        RealtimeState {
+            config,
+            report: Mutex::new(RealtimeReport::default()),
+            announced: [AtomicU64::new(0), AtomicU64::new(0)],
+            applied: [AtomicU64::new(0), AtomicU64::new(0)],
+        }
+    }
+
+    pub fn report(&self) -> RealtimeReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    // Lock the process memory if configured, failures are logged and not fatal
+    pub fn lock_memory(&self) {
+        if !self.config.lock_memory {
+            return;
+        }
+        match lock_memory() {
+            Ok(()) => self.report.lock().unwrap().memory_locked = true,
+            Err(e) => eprintln!("Memory locking failed, audio may glitch under memory pressure: {}", e),
+        }
+    }
+
+    // Record the calling callback thread for `apply_announced`. Doesn't block or
+    // allocate, so it is safe in the audio callback.
+    pub fn announce_callback(&self, slot: usize) {
+        if let Some(announced) = self.announced.get(slot) {
+            announced.store(current_thread_id(), Ordering::Release);
+        }
+    }
+
+    // Promote and pin callback threads announced since the last call, from a control thread
+    pub fn apply_announced(&self) {
+        for (slot, name) in CALLBACK_NAMES.iter().enumerate() {
+            let thread = self.announced[slot].load(Ordering::Acquire);
+            if thread != 0 && self.applied[slot].swap(thread, Ordering::Relaxed) != thread {
+                self.apply_to_thread(thread, name, slot);
+            }
+        }
+    }
+
+    pub fn apply_to_current_thread(&self, name: &str, slot: usize) {
+        self.apply_to_thread(current_thread_id(), name, slot);
+    }
+
+    // Promote and pin a thread of this process. `slot` picks the CPU from the configured list.
+    // Runs once per thread, failures are logged and the thread keeps running as it was.
+    fn apply_to_thread(&self, thread: u64, name: &str, slot: usize) {
+        let config = &self.config;
+        let scheduling = match config.policy {
+            SchedPolicy::Normal => "normal".to_string(),
+            policy => match promote_thread(thread, policy, config.priority) {
+                Ok(granted) => granted,
+                Err(e) => {
+                    eprintln!("{}: realtime scheduling unavailable, running at normal priority: {}", name, e);
+                    "normal".to_string()
+                }
+            },
+        };
+
+        let cpu = if config.cpus.is_empty() {
+            None
+        } else {
+            let cpu = config.cpus[slot % config.cpus.len()];
+            match pin_thread(thread, cpu) {
+                Ok(()) => Some(cpu),
+                Err(e) => {
+                    eprintln!("{}: could not pin to CPU {}: {}", name, cpu, e);
+                    None
+                }
+            }
+        };
+
+        self.report.lock().unwrap().threads.push(ThreadReport {
+            name: name.to_string(),
+            scheduling,
+            cpu,
+        });
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn last_error() -> std::io::Error {
+    std::io::Error::last_os_error()
+}
+
+// Kernel thread id, never 0
+#[cfg(target_os = "linux")]
+pub fn current_thread_id() -> u64 {
+    // Safety: plain syscall
+    unsafe { libc::syscall(libc::SYS_gettid) as u64 }
+}
+
+#[cfg(target_os = "linux")]
+pub fn lock_memory() -> Result<()> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // Safety: plain syscalls on valid pointers
+    unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) };
+    let privileged = unsafe { libc::geteuid() } == 0;
+
+    // Locking future pages under a finite limit would make later allocations fail
+    // once the limit is reached, so only lock what is mapped now in that case
+    let flags = if privileged || limit.rlim_cur == libc::RLIM_INFINITY {
+        libc::MCL_CURRENT | libc::MCL_FUTURE
+    } else {
+        eprintln!(
+            "Memory lock limit is {} KiB, locking current pages only; set memlock to unlimited for full locking",
+            limit.rlim_cur / 1024
+        );
+        libc::MCL_CURRENT
+    };
+
+    if unsafe { libc::mlockall(flags) } != 0 {
+        let err = last_error();
+        return Err(match err.raw_os_error() {
+            Some(libc::ENOMEM) | Some(libc::EPERM) => anyhow!(
+                "{} (memlock limit {} KiB; raise it in /etc/security/limits.d or grant CAP_IPC_LOCK)",
+                err,
+                limit.rlim_cur / 1024
+            ),
+            _ => anyhow!("{}", err),
+        });
+    }
+    Ok(())
+}
+
+// Returns a description of the scheduling the thread got
+#[cfg(target_os = "linux")]
+pub fn promote_thread(thread: u64, policy: SchedPolicy, priority: i32) -> Result<String> {
+    let (sched, label) = match policy {
+        SchedPolicy::Normal => return Ok("normal".to_string()),
+        SchedPolicy::Fifo => (libc::SCHED_FIFO, "SCHED_FIFO"),
+        SchedPolicy::RoundRobin => (libc::SCHED_RR, "SCHED_RR"),
+    };
+    // Safety: plain syscalls on valid pointers
+    let (min, max) = unsafe { (libc::sched_get_priority_min(sched), libc::sched_get_priority_max(sched)) };
+    let priority = priority.clamp(min, max);
+    let param = libc::sched_param { sched_priority: priority };
+
+    if unsafe { libc::sched_setscheduler(thread as libc::pid_t, sched, &param) } == 0 {
+        return Ok(format!("{} {}", label, priority));
+    }
+    let err = last_error();
+    match err.raw_os_error() {
+        Some(libc::EPERM) => match rtkit::make_thread_realtime(thread, priority) {
+            Ok(granted) => Ok(format!("SCHED_RR {} via rtkit", granted)),
+            Err(e) => Err(anyhow!(
+                "{} denied (no CAP_SYS_NICE or rtprio limit) and rtkit failed: {}; \
+                 add an rtprio limit for this user in /etc/security/limits.d or run rtkit-daemon",
+                label,
+                e
+            )),
+        },
+        _ => Err(anyhow!("{}", err)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn pin_thread(thread: u64, cpu: usize) -> Result<()> {
+    // Safety: the set is zeroed before use and sized for the call
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        if cpu >= libc::CPU_SETSIZE as usize {
+            return Err(anyhow!("CPU index out of range"));
+        }
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(thread as libc::pid_t, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(anyhow!("{}", last_error()));
+        }
+    }
+    Ok(())
+}
+
+// Unprivileged fallback: ask the RealtimeKit daemon over the system bus
+#[cfg(target_os = "linux")]
+mod rtkit {
+    use anyhow::{anyhow, Result};
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+    use dbus::blocking::Connection;
+    use std::time::Duration;
+
+    const SERVICE: &str = "org.freedesktop.RealtimeKit1";
+    const PATH: &str = "/org/freedesktop/RealtimeKit1";
+
+    // Returns the priority that was granted
+    pub fn make_thread_realtime(thread: u64, priority: i32) -> Result<i32> {
+        let connection = Connection::new_system()?;
+        let proxy = connection.with_proxy(SERVICE, PATH, Duration::from_secs(2));
+
+        let max_priority: i32 = proxy.get(SERVICE, "MaxRealtimePriority")?;
+        let rttime_max: i64 = proxy.get(SERVICE, "RTTimeUSecMax")?;
+        let priority = priority.clamp(1, max_priority.max(1));
+
+        // rtkit refuses threads that could hog the CPU forever, so their runtime is
+        // capped first. RLIMIT_RTTIME covers the whole process: every realtime thread,
+        // also those promoted directly, gets SIGXCPU and then SIGKILL after that much
+        // CPU time without blocking. Audio threads block every period and stay far
+        // below it. A lower limit already in place is kept.
+        let rttime_max = rttime_max as libc::rlim_t;
+        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        // Safety: plain syscalls on valid pointers
+        unsafe { libc::getrlimit(libc::RLIMIT_RTTIME, &mut limit) };
+        if limit.rlim_max > rttime_max {
+            let limit = libc::rlimit {
+                rlim_cur: limit.rlim_cur.min(rttime_max),
+                rlim_max: rttime_max,
+            };
+            if unsafe { libc::setrlimit(libc::RLIMIT_RTTIME, &limit) } != 0 {
+                return Err(anyhow!("could not set RLIMIT_RTTIME: {}", std::io::Error::last_os_error()));
+            }
+        }
+
+        proxy.method_call::<(), _, _, _>(SERVICE, "MakeThreadRealtime", (thread, priority as u32))?;
+        Ok(priority)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn lock_memory() -> Result<()> {
+    Err(anyhow!("not supported on this platform"))
+}
+
+// Stands in for a thread id where there is no way to address other threads
+#[cfg(not(target_os = "linux"))]
+pub fn current_thread_id() -> u64 {
+    1
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn promote_thread(_thread: u64, policy: SchedPolicy, _priority: i32) -> Result<String> {
+    match policy {
+        SchedPolicy::Normal => Ok("normal".to_string()),
+        _ => Err(anyhow!("not supported on this platform")),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_thread(_thread: u64, _cpu: usize) -> Result<()> {
+    Err(anyhow!("not supported on this platform"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_and_priority() {
+        let config = RealtimeConfig::parse("fifo:80").unwrap();
+        assert_eq!((config.policy, config.priority), (SchedPolicy::Fifo, 80));
+        let config = RealtimeConfig::parse("RR").unwrap();
+        assert_eq!((config.policy, config.priority), (SchedPolicy::RoundRobin, 70));
+        assert_eq!(RealtimeConfig::parse("normal").unwrap(), RealtimeConfig::default());
+        assert!(RealtimeConfig::parse("fifo:0").is_err());
+        assert!(RealtimeConfig::parse("fifo:100").is_err());
+        assert!(RealtimeConfig::parse("idle").is_err());
+    }
+
+    #[test]
+    fn cpu_lists_and_ranges() {
+        assert_eq!(parse_cpus("2,3").unwrap(), vec![2, 3]);
+        assert_eq!(parse_cpus("1, 4-6").unwrap(), vec![1, 4, 5, 6]);
+        assert!(parse_cpus("").unwrap().is_empty());
+        assert!(parse_cpus("a").is_err());
+        assert!(parse_cpus("5-2").is_err());
+        assert_eq!(parse_cpus("3-3").unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn callbacks_are_applied_once_from_the_control_thread() {
+        let state = RealtimeState::new(RealtimeConfig::default());
+        state.apply_announced();
+        assert!(state.report().threads.is_empty());
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| state.announce_callback(INPUT_CALLBACK));
+        });
+        state.announce_callback(OUTPUT_CALLBACK);
+        state.apply_announced();
+        state.apply_announced();
+        let mut names: Vec<String> = state.report().threads.into_iter().map(|thread| thread.name).collect();
+        names.sort();
+        assert_eq!(names, vec!["input callback", "output callback"]);
+
+        // A callback that moved to another thread is applied again
+        std::thread::scope(|scope| {
+            scope.spawn(|| state.announce_callback(OUTPUT_CALLBACK));
+        });
+        state.apply_announced();
+        assert_eq!(state.report().threads.len(), 3);
+        assert_eq!(state.report().threads[2].scheduling, "normal");
+    }
+}
@@ -0,0 +1,294 @@
+use anyhow::{anyhow, bail, Result};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// How often the monitor looks at the detector
+const MONITOR_PERIOD: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    Active,
+    Standby,
+}
+
+impl PowerState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PowerState::Active => "active",
+            PowerState::Standby => "standby",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StandbyAction {
+    // Keep the streams running and output silence
+    Mute,
+    // Pause the output stream. With the generator as source, which runs in the
+    // output callback, this mutes instead.
+    StopOutput,
+}
+
+// Run on every transition, e.g. to switch the amplifiers with a relay
+#[derive(Debug, Clone, PartialEq)]
+pub enum StandbyHook {
+    // Run with `sh -c`, AUDIOSERVER_POWER is set to "active" or "standby"
+    Command(String),
+    // Sysfs GPIO value file like /sys/class/gpio/gpio17/value, written 1 when active
+    Gpio { path: PathBuf, active_low: bool },
+}
+
+impl StandbyHook {
+    // "<value file>[:low]", as in AUDIOSERVER_STANDBY_GPIO
+    pub fn gpio(text: &str) -> Self {
+        match text.strip_suffix(":low") {
+            Some(path) => StandbyHook::Gpio { path: PathBuf::from(path), active_low: true },
+            None => StandbyHook::Gpio { path: PathBuf::from(text), active_low: false },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StandbyConfig {
+    // Input peak level in dBFS that counts as signal
+    pub threshold_db: f32,
+    // Time without signal before going to standby
+    pub hold: Duration,
+    pub action: StandbyAction,
+    pub hooks: Vec<StandbyHook>,
+}
+
+impl StandbyConfig {
+    // "<hold seconds>[:<threshold dB>[:mute|stop]]", as in AUDIOSERVER_STANDBY
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parts = text.trim().split(':');
+        let defaults = StandbyConfig::default();
+        let hold = parts
+            .next()
+            .and_then(|hold| hold.parse::<f64>().ok())
+            .filter(|hold| hold.is_finite() && *hold >= 0.0)
+            .ok_or_else(|| anyhow!("Expected <hold seconds>[:<threshold dB>[:mute|stop]], got {}", text))?;
+        let threshold_db = match parts.next() {
+            Some(threshold) => threshold.parse().map_err(|_| anyhow!("Bad standby threshold {}", threshold))?,
+            None => defaults.threshold_db,
+        };
+        let action = match parts.next() {
+            None | Some("mute") => StandbyAction::Mute,
+            Some("stop") => StandbyAction::StopOutput,
+            Some(other) => bail!("Unknown standby action {}, expected mute or stop", other),
+        };
+        if parts.next().is_some() {
+            bail!("Expected <hold seconds>[:<threshold dB>[:mute|stop]], got {}", text);
+        }
+        Ok(StandbyConfig {
+            threshold_db,
+            hold: Duration::from_secs_f64(hold),
+            action,
+            ..defaults
+        })
+    }
+}
+
+impl Default for StandbyConfig {
+    fn default() -> Self {
+        StandbyConfig {
+            threshold_db: -65.0,
+            hold: Duration::from_secs(15 * 60),
+            action: StandbyAction::Mute,
+            hooks: Vec::new(),
+        }
+    }
+}
+
+// Fed by the audio thread with every input block
+#[derive(Debug)]
+pub struct SignalDetector {
+    threshold: f32,
+    epoch: Instant,
+    // Milliseconds since `epoch` the input was last above the threshold
+    last_signal_ms: AtomicU64,
+    standby: AtomicBool,
+}
+
+impl SignalDetector {
+    pub fn new(threshold_db: f32) -> Self {
+        SignalDetector {
+            threshold: 10f32.powf(threshold_db / 20.0),
+            epoch: Instant::now(),
+            last_signal_ms: AtomicU64::new(0),
+            standby: AtomicBool::new(false),
+        }
+    }
+
+    pub fn observe(&self, data: &[f32]) {
+        if data.iter().any(|sample| sample.abs() >= self.threshold) {
+            self.last_signal_ms
+                .store(self.epoch.elapsed().as_millis() as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::Relaxed)
+    }
+
+    fn last_signal_ms(&self) -> u64 {
+        self.last_signal_ms.load(Ordering::Relaxed)
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.epoch.elapsed().saturating_sub(Duration::from_millis(self.last_signal_ms()))
+    }
+}
+
+fn run_hook(hook: &StandbyHook, state: PowerState) {
+    match hook {
+        StandbyHook::Command(command) => {
+            let child = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .env("AUDIOSERVER_POWER", state.name())
+                .spawn();
+            match child {
+                // Reaped on its own thread so a slow relay script does not delay the monitor
+                Ok(mut child) => {
+                    let command = command.clone();
+                    std::thread::spawn(move || match child.wait() {
+                        Ok(status) if !status.success() => eprintln!("Standby hook `{}` failed: {}", command, status),
+                        Err(e) => eprintln!("Standby hook `{}` failed: {}", command, e),
+                        _ => {}
+                    });
+                }
+                Err(e) => eprintln!("Standby hook `{}` could not start: {}", command, e),
+            }
+        }
+        StandbyHook::Gpio { path, active_low } => {
+            let high = (state == PowerState::Active) != *active_low;
+            if let Err(e) = std::fs::write(path, if high { "1" } else { "0" }) {
+                eprintln!("Standby hook {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+// Turns detector readings into power state transitions, hooks and events
+#[derive(Clone, Default)]
+pub struct StandbyHub {
+    subscribers: Arc<Mutex<Vec<Sender<PowerState>>>>,
+    state: Arc<Mutex<Option<PowerState>>>,
+}
+
+impl StandbyHub {
+    // Receive every transition
+    pub fn subscribe(&self) -> Receiver<PowerState> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    // None while the engine is stopped or auto standby is off
+    pub fn state(&self) -> Option<PowerState> {
+        *self.state.lock().unwrap()
+    }
+
+    fn transition(&self, detector: &SignalDetector, config: &StandbyConfig, state: PowerState) {
+        detector.standby.store(state == PowerState::Standby, Ordering::Relaxed);
+        *self.state.lock().unwrap() = Some(state);
+        println!("Power: {}", state.name());
+        for hook in &config.hooks {
+            run_hook(hook, state);
+        }
+        self.subscribers.lock().unwrap().retain(|tx| tx.send(state).is_ok());
+    }
+
+    // Watch the detector until `running` is cleared. The engine starts active,
+    // and goes to standby when it stops so the amplifiers switch off with it.
+    pub fn start_monitor(
+        &self,
+        detector: Arc<SignalDetector>,
+        config: StandbyConfig,
+        running: Arc<AtomicBool>,
+    ) -> JoinHandle<()> {
+        let hub = self.clone();
+        std::thread::spawn(move || {
+            hub.transition(&detector, &config, PowerState::Active);
+            let mut standby_since = None;
+            while running.load(Ordering::SeqCst) {
+                std::thread::sleep(MONITOR_PERIOD);
+                match standby_since {
+                    None if detector.idle_for() >= config.hold => {
+                        standby_since = Some(detector.last_signal_ms());
+                        hub.transition(&detector, &config, PowerState::Standby);
+                    }
+                    Some(last) if detector.last_signal_ms() != last => {
+                        standby_since = None;
+                        hub.transition(&detector, &config, PowerState::Active);
+                    }
+                    _ => {}
+                }
+            }
+            if standby_since.is_none() {
+                hub.transition(&detector, &config, PowerState::Standby);
+            }
+            *hub.state.lock().unwrap() = None;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_from_text() {
+        let config = StandbyConfig::parse("600").unwrap();
+        assert_eq!(config.hold, Duration::from_secs(600));
+        assert_eq!((config.threshold_db, config.action), (-65.0, StandbyAction::Mute));
+        let config = StandbyConfig::parse("30:-50:stop").unwrap();
+        assert_eq!((config.hold, config.threshold_db), (Duration::from_secs(30), -50.0));
+        assert_eq!(config.action, StandbyAction::StopOutput);
+        assert!(StandbyConfig::parse("soon").is_err());
+        assert!(StandbyConfig::parse("30:-50:off").is_err());
+        assert_eq!(
+            StandbyHook::gpio("/sys/class/gpio/gpio17/value:low"),
+            StandbyHook::Gpio { path: PathBuf::from("/sys/class/gpio/gpio17/value"), active_low: true }
+        );
+    }
+
+    #[test]
+    fn monitor_follows_the_signal_and_runs_hooks() {
+        let value = std::env::temp_dir().join(format!("audioserver-gpio-{}", std::process::id()));
+        let config = StandbyConfig {
+            hold: Duration::from_millis(200),
+            hooks: vec![StandbyHook::Gpio { path: value.clone(), active_low: false }],
+            ..StandbyConfig::default()
+        };
+        let detector = Arc::new(SignalDetector::new(config.threshold_db));
+        let hub = StandbyHub::default();
+        let events = hub.subscribe();
+        let running = Arc::new(AtomicBool::new(true));
+        let monitor = hub.start_monitor(detector.clone(), config, running.clone());
+        let next = || events.recv_timeout(Duration::from_secs(2)).unwrap();
+
+        assert_eq!(next(), PowerState::Active);
+        assert_eq!(std::fs::read_to_string(&value).unwrap(), "1");
+        assert_eq!(next(), PowerState::Standby);
+        assert!(detector.is_standby());
+        assert_eq!(std::fs::read_to_string(&value).unwrap(), "0");
+        // Last signal times are in whole milliseconds
+        std::thread::sleep(Duration::from_millis(2));
+        detector.observe(&[0.0, 0.5]);
+        assert_eq!(next(), PowerState::Active);
+        assert_eq!(hub.state(), Some(PowerState::Active));
+
+        running.store(false, Ordering::SeqCst);
+        monitor.join().unwrap();
+        assert_eq!(next(), PowerState::Standby);
+        assert_eq!(hub.state(), None);
+        std::fs::remove_file(&value).unwrap();
+    }
+}
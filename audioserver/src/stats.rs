@@ -0,0 +1,208 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::meter::AtomicF32;
+
+// Weight of the newest callback in the rolling load average
+const LOAD_SMOOTHING: f32 = 0.05;
+
+#[derive(Debug, Default)]
+pub struct CallbackStats {
+    callbacks: AtomicU64,
+    last_ns: AtomicU64,
+    max_ns: AtomicU64,
+    period_ns: AtomicU64,
+    deadline_misses: AtomicU64,
+    load: AtomicF32,
+}
+
+impl CallbackStats {
+    // Record one callback that took `elapsed` to process `frames` at `sample_rate`
+    pub fn record(&self, elapsed: Duration, frames: usize, sample_rate: u32) {
+        let elapsed_ns = elapsed.as_nanos() as u64;
+        let period_ns = frames as u64 * 1_000_000_000 / sample_rate.max(1) as u64;
+
+        self.callbacks.fetch_add(1, Ordering::Relaxed);
+        self.last_ns.store(elapsed_ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(elapsed_ns, Ordering::Relaxed);
+        self.period_ns.store(period_ns, Ordering::Relaxed);
+        if elapsed_ns > period_ns {
+            self.deadline_misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Only the callback thread writes the average, so load/store is enough
+        if period_ns > 0 {
+            let load = elapsed_ns as f32 / period_ns as f32;
+            let previous = self.load.load();
+            self.load.store(previous + LOAD_SMOOTHING * (load - previous));
+        }
+    }
+
+    fn report(&self) -> CallbackReport {
+        CallbackReport {
+            callbacks: self.callbacks.load(Ordering::Relaxed),
+            last_ms: self.last_ns.load(Ordering::Relaxed) as f32 / 1e6,
+            max_ms: self.max_ns.load(Ordering::Relaxed) as f32 / 1e6,
+            period_ms: self.period_ns.load(Ordering::Relaxed) as f32 / 1e6,
+            deadline_misses: self.deadline_misses.load(Ordering::Relaxed),
+            load_percent: self.load.load() * 100.0,
+        }
+    }
+}
+
+// Timing and transport health of the running engine, shared with both callbacks
+#[derive(Debug, Default)]
+pub struct EngineStats {
+    pub input: CallbackStats,
+    pub output: CallbackStats,
+    underruns: AtomicU64,
+    overruns: AtomicU64,
+}
+
+impl EngineStats {
+    // Output callback found the transport short of frames
+    pub fn record_underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Input callback dropped a block because the transport was full
+    pub fn record_overrun(&self) {
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> StatsReport {
+        let input = self.input.report();
+        let output = self.output.report();
+        StatsReport {
+            dsp_load_percent: input.load_percent.max(output.load_percent),
+            input,
+            output,
+            underruns: self.underruns.load(Ordering::Relaxed),
+            overruns: self.overruns.load(Ordering::Relaxed),
+        }
+    }
+
+    // Warn as soon as new xruns show up, and print a summary every `interval` if given
+    pub fn start_logger(
+        self: &Arc<Self>,
+        interval: Option<Duration>,
+        running: Arc<AtomicBool>,
+    ) -> JoinHandle<()> {
+        let stats = self.clone();
+        std::thread::spawn(move || {
+            let tick = Duration::from_millis(100);
+            let mut last_log = Instant::now();
+            let mut last_xruns = (0, 0);
+            while running.load(Ordering::SeqCst) {
+                std::thread::sleep(tick);
+                let report = stats.report();
+
+                let xruns = (report.underruns, report.overruns);
+                if xruns != last_xruns {
+                    eprintln!(
+                        "Transport xrun: {} underruns, {} overruns",
+                        report.underruns, report.overruns
+                    );
+                    last_xruns = xruns;
+                }
+
+                if interval.is_some_and(|interval| last_log.elapsed() >= interval) {
+                    println!("{}", report);
+                    last_log = Instant::now();
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallbackReport {
+    pub callbacks: u64,
+    pub last_ms: f32,
+    pub max_ms: f32,
+    // Duration of audio delivered per callback, the processing deadline
+    pub period_ms: f32,
+    pub deadline_misses: u64,
+    pub load_percent: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsReport {
+    pub input: CallbackReport,
+    pub output: CallbackReport,
+    pub underruns: u64,
+    pub overruns: u64,
+    // Rolling share of the buffer period spent in the busier callback. The callbacks run
+    // on their own threads, so their loads don't add up.
+    pub dsp_load_percent: f32,
+}
+
+impl std::fmt::Display for StatsReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "DSP load {:.1}% | input {:.2}/{:.2} ms (max {:.2}, {} late) | output {:.2}/{:.2} ms (max {:.2}, {} late) | {} underruns, {} overruns",
+            self.dsp_load_percent,
+            self.input.last_ms,
+            self.input.period_ms,
+            self.input.max_ms,
+            self.input.deadline_misses,
+            self.output.last_ms,
+            self.output.period_ms,
+            self.output.max_ms,
+            self.output.deadline_misses,
+            self.underruns,
+            self.overruns,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_callbacks_past_their_period() {
+        let stats = CallbackStats::default();
+        // 480 frames at 48 kHz is a 10 ms deadline
+        stats.record(Duration::from_millis(4), 480, 48000);
+        stats.record(Duration::from_millis(12), 480, 48000);
+        stats.record(Duration::from_millis(10), 480, 48000);
+        let report = stats.report();
+        assert_eq!((report.callbacks, report.deadline_misses), (3, 1));
+        assert_eq!((report.last_ms, report.max_ms, report.period_ms), (10.0, 12.0, 10.0));
+    }
+
+    #[test]
+    fn load_settles_on_the_share_of_the_period() {
+        let stats = CallbackStats::default();
+        for _ in 0..500 {
+            stats.record(Duration::from_millis(3), 480, 48000);
+        }
+        assert!((stats.report().load_percent - 30.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn dsp_load_is_the_busier_callback() {
+        let stats = EngineStats::default();
+        for _ in 0..500 {
+            stats.input.record(Duration::from_millis(7), 480, 48000);
+            stats.output.record(Duration::from_millis(6), 480, 48000);
+        }
+        let report = stats.report();
+        assert_eq!((report.input.deadline_misses, report.output.deadline_misses), (0, 0));
+        assert!((report.dsp_load_percent - 70.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn counts_xruns() {
+        let stats = EngineStats::default();
+        stats.record_underrun();
+        stats.record_underrun();
+        stats.record_overrun();
+        let report = stats.report();
+        assert_eq!((report.underruns, report.overruns), (2, 1));
+    }
+}
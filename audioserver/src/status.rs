@@ -0,0 +1,19 @@
+use crate::latency::LatencyReport;
+use crate::rt::RealtimeReport;
+use crate::standby::PowerState;
+use crate::stats::StatsReport;
+
+// Snapshot of the engine state for the status API and logs
+#[derive(Debug, Clone)]
+pub struct StatusReport {
+    pub running: bool,
+    pub source: String,
+    pub latency: Option<LatencyReport>,
+    pub stats: Option<StatsReport>,
+    pub realtime: Option<RealtimeReport>,
+    pub volume_db: f32,
+    // Low and high shelf boost of the loudness compensation, None when it is off
+    pub loudness_shelves_db: Option<(f32, f32)>,
+    // None while stopped or with auto standby off
+    pub power: Option<PowerState>,
+}
@@ -0,0 +1,244 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::renderer::Renderer;
+use super::services::SERVICES;
+use super::xml;
+use crate::http::{self, Url};
+
+// How often the event thread looks for changes, which also limits the event rate
+const CHECK_PERIOD: Duration = Duration::from_millis(200);
+// Subscriptions asking for longer, or for infinite, get this
+const MAX_TIMEOUT: Duration = Duration::from_secs(1800);
+const MIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Subscription {
+    sid: String,
+    // Index into SERVICES
+    service: usize,
+    // Tried in order until one takes the event
+    callbacks: Vec<Url>,
+    expires: Instant,
+    seq: u32,
+    // Last property set sent, the first event goes out because this is None
+    sent: Option<String>,
+}
+
+// GENA subscriptions of control points to the services' evented variables
+#[derive(Default)]
+pub struct Subscriptions {
+    subscriptions: Mutex<Vec<Subscription>>,
+    next_sid: AtomicU64,
+}
+
+// TIMEOUT: Second-1800 or Second-infinite
+fn timeout(header: Option<&str>) -> Duration {
+    header
+        .and_then(|value| value.trim().strip_prefix("Second-"))
+        .and_then(|seconds| seconds.parse().ok())
+        .map_or(MAX_TIMEOUT, Duration::from_secs)
+        .clamp(MIN_TIMEOUT, MAX_TIMEOUT)
+}
+
+impl Subscriptions {
+    // New subscription from a CALLBACK header, "<http://host/path>" one or more times.
+    // Returns the SID and granted timeout.
+    pub fn subscribe(&self, service: usize, callback: &str, requested: Option<&str>, uuid: &str) -> Option<(String, Duration)> {
+        let callbacks: Vec<Url> = callback
+            .split('<')
+            .filter_map(|part| part.split('>').next())
+            .filter_map(|url| Url::parse(url.trim()).ok())
+            .collect();
+        if callbacks.is_empty() {
+            return None;
+        }
+        let timeout = timeout(requested);
+        let sid = format!("uuid:{}-{}", uuid, self.next_sid.fetch_add(1, Ordering::Relaxed));
+        self.subscriptions.lock().unwrap().push(Subscription {
+            sid: sid.clone(),
+            service,
+            callbacks,
+            expires: Instant::now() + timeout,
+            seq: 0,
+            sent: None,
+        });
+        Some((sid, timeout))
+    }
+
+    // None when the SID is unknown or has expired
+    pub fn renew(&self, sid: &str, requested: Option<&str>) -> Option<Duration> {
+        let timeout = timeout(requested);
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let subscription = subscriptions.iter_mut().find(|subscription| subscription.sid == sid)?;
+        subscription.expires = Instant::now() + timeout;
+        Some(timeout)
+    }
+
+    pub fn unsubscribe(&self, sid: &str) -> bool {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let before = subscriptions.len();
+        subscriptions.retain(|subscription| subscription.sid != sid);
+        subscriptions.len() != before
+    }
+}
+
+fn property_set(values: &[(&'static str, String)]) -> String {
+    let mut body = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<e:propertyset xmlns:e=\"urn:schemas-upnp-org:event-1-0\">",
+    );
+    for (name, value) in values {
+        body.push_str(&format!("<e:property><{}>{}</{}></e:property>", name, xml::escape(value), name));
+    }
+    body.push_str("</e:propertyset>\n");
+    body
+}
+
+fn notify(sid: &str, seq: u32, callbacks: &[Url], body: &str) -> bool {
+    let seq = seq.to_string();
+    let headers = [
+        ("Content-Type", "text/xml; charset=\"utf-8\""),
+        ("NT", "upnp:event"),
+        ("NTS", "upnp:propchange"),
+        ("SID", sid),
+        ("SEQ", seq.as_str()),
+    ];
+    callbacks
+        .iter()
+        .any(|callback| http::request("NOTIFY", callback, &headers, body.as_bytes()).is_ok_and(|response| response.status == 200))
+}
+
+// Sends every subscriber the evented state of its service whenever it changes
+pub fn run(subscriptions: Arc<Subscriptions>, renderer: Arc<Renderer>, running: Arc<AtomicBool>) {
+    while running.load(Ordering::SeqCst) {
+        std::thread::sleep(CHECK_PERIOD);
+        let now = Instant::now();
+        // Sent outside the lock, control points can take their time to answer
+        let due: Vec<(String, u32, Vec<Url>, String)> = {
+            let mut list = subscriptions.subscriptions.lock().unwrap();
+            list.retain(|subscription| subscription.expires > now);
+            if list.is_empty() {
+                continue;
+            }
+            let bodies: Vec<Option<String>> = (0..SERVICES.len())
+                .map(|service| {
+                    list.iter()
+                        .any(|subscription| subscription.service == service)
+                        .then(|| property_set(&renderer.evented(SERVICES[service])))
+                })
+                .collect();
+            list.iter_mut()
+                .filter_map(|subscription| {
+                    let body = bodies[subscription.service].as_ref()?;
+                    if subscription.sent.as_ref() == Some(body) {
+                        return None;
+                    }
+                    subscription.sent = Some(body.clone());
+                    let seq = subscription.seq;
+                    // Wraps to 1, 0 is only the initial event
+                    subscription.seq = subscription.seq.checked_add(1).unwrap_or(1);
+                    Some((subscription.sid.clone(), seq, subscription.callbacks.clone(), body.clone()))
+                })
+                .collect()
+        };
+        for (sid, seq, callbacks, body) in due {
+            if !notify(&sid, seq, &callbacks, &body) {
+                eprintln!("UPnP: event {} not delivered", sid);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::Player;
+    use crate::volume::VolumeControl;
+    use tiny_http::{Response, Server};
+
+    #[test]
+    fn timeouts_are_clamped() {
+        assert_eq!(timeout(Some("Second-300")), Duration::from_secs(300));
+        assert_eq!(timeout(Some("Second-10")), MIN_TIMEOUT);
+        assert_eq!(timeout(Some("Second-86400")), MAX_TIMEOUT);
+        assert_eq!(timeout(Some("Second-infinite")), MAX_TIMEOUT);
+        assert_eq!(timeout(None), MAX_TIMEOUT);
+    }
+
+    #[test]
+    fn subscriptions_are_renewed_and_cancelled() {
+        let subscriptions = Subscriptions::default();
+        let callback = "<http://192.0.2.1:8080/one> <http://192.0.2.1:8080/two>";
+        let (sid, granted) = subscriptions.subscribe(0, callback, Some("Second-300"), "device").unwrap();
+        assert_eq!((sid.as_str(), granted), ("uuid:device-0", Duration::from_secs(300)));
+        let paths: Vec<String> = subscriptions.subscriptions.lock().unwrap()[0]
+            .callbacks
+            .iter()
+            .map(|url| url.path.clone())
+            .collect();
+        assert_eq!(paths, ["/one", "/two"]);
+        let (other, _) = subscriptions.subscribe(1, "<http://192.0.2.1/>", None, "device").unwrap();
+        assert_ne!(sid, other);
+        assert!(subscriptions.subscribe(0, "<mailto:someone>", None, "device").is_none());
+
+        assert_eq!(subscriptions.renew(&sid, Some("Second-infinite")), Some(MAX_TIMEOUT));
+        assert!(subscriptions.unsubscribe(&sid));
+        assert!(!subscriptions.unsubscribe(&sid));
+        assert_eq!(subscriptions.renew(&sid, None), None);
+        assert_eq!(subscriptions.subscriptions.lock().unwrap().len(), 1);
+    }
+
+    // Next NOTIFY the server gets, as (SID, SEQ, body)
+    fn notification(server: &Server) -> (String, String, String) {
+        let mut request = server.recv_timeout(Duration::from_secs(2)).unwrap().expect("no event");
+        assert_eq!(request.method().as_str(), "NOTIFY");
+        let header = |name: &str| {
+            request
+                .headers()
+                .iter()
+                .find(|header| header.field.as_str().as_str().eq_ignore_ascii_case(name))
+                .map(|header| header.value.as_str().to_string())
+                .unwrap_or_default()
+        };
+        let (sid, seq) = (header("SID"), header("SEQ"));
+        assert_eq!(header("NT"), "upnp:event");
+        assert_eq!(header("NTS"), "upnp:propchange");
+        let mut body = String::new();
+        request.as_reader().read_to_string(&mut body).unwrap();
+        request.respond(Response::empty(200)).unwrap();
+        (sid, seq, body)
+    }
+
+    #[test]
+    fn changes_are_sent_to_subscribers() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let callback = format!("<http://{}/event>", server.server_addr().to_ip().unwrap());
+        let subscriptions = Arc::new(Subscriptions::default());
+        // RenderingControl
+        let (sid, _) = subscriptions.subscribe(1, &callback, None, "device").unwrap();
+        let volume = VolumeControl::default();
+        let renderer = Arc::new(Renderer::new(Player::default(), volume.clone()));
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let (subscriptions, running) = (subscriptions.clone(), running.clone());
+            std::thread::spawn(move || run(subscriptions, renderer, running))
+        };
+
+        // The whole state goes out first, as LastChange
+        let (event_sid, seq, body) = notification(&server);
+        assert_eq!((event_sid.as_str(), seq.as_str()), (sid.as_str(), "0"));
+        let property = xml::child(xml::child(&body, "propertyset").unwrap(), "property").unwrap();
+        let last_change = xml::text(xml::child(property, "LastChange").unwrap());
+        assert!(last_change.contains("<Volume val=\"100\" channel=\"Master\"/>"), "{}", last_change);
+
+        volume.set_volume_db(-30.0);
+        let (_, seq, body) = notification(&server);
+        assert_eq!(seq, "1");
+        assert!(xml::unescape(&body).contains("<Volume val=\"50\" channel=\"Master\"/>"), "{}", body);
+
+        // Nothing changed, nothing is sent
+        assert!(server.recv_timeout(CHECK_PERIOD * 3).unwrap().is_none());
+        running.store(false, Ordering::SeqCst);
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,465 @@
+mod events;
+mod renderer;
+mod services;
+mod ssdp;
+mod xml;
+
+use anyhow::{anyhow, Result};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::player::Player;
+use crate::volume::VolumeControl;
+use events::Subscriptions;
+use renderer::{Fault, Renderer};
+use services::SERVICES;
+use ssdp::Ssdp;
+
+// How often the HTTP thread checks whether it should stop
+const POLL: Duration = Duration::from_millis(100);
+const XML: &str = "text/xml; charset=\"utf-8\"";
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+// UPnP AV MediaRenderer that control points push URLs to
+#[derive(Debug, Clone)]
+pub struct UpnpConfig {
+    // friendlyName in the control points' renderer lists
+    pub name: String,
+    // Device description, control and eventing
+    pub port: u16,
+}
+
+impl Default for UpnpConfig {
+    fn default() -> Self {
+        UpnpConfig {
+            name: "Virtual Crossover".to_string(),
+            port: 49494,
+        }
+    }
+}
+
+// Stable across restarts so control points remember the renderer, and
+// different for every name on the same machine
+fn device_uuid(name: &str) -> String {
+    let machine = std::fs::read_to_string("/etc/machine-id")
+        .or_else(|_| std::fs::read_to_string("/var/lib/dbus/machine-id"))
+        .unwrap_or_default();
+    // FNV-1a, twice with different offsets for 128 bits
+    let hash = |offset: u64| {
+        machine
+            .trim()
+            .bytes()
+            .chain([0])
+            .chain(name.bytes())
+            .fold(offset, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+    };
+    // Marked as a version 4 (random) UUID of the RFC 4122 variant
+    let high = (hash(0xcbf2_9ce4_8422_2325) & !0xf000) | 0x4000;
+    let low = (hash(0x8422_2325_cbf2_9ce4) & !(0xc << 60)) | (0x8 << 60);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+fn description(config: &UpnpConfig, uuid: &str) -> String {
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <root xmlns=\"urn:schemas-upnp-org:device-1-0\" xmlns:dlna=\"urn:schemas-dlna-org:device-1-0\">\
+         <specVersion><major>1</major><minor>0</minor></specVersion><device>\
+         <deviceType>urn:schemas-upnp-org:device:MediaRenderer:1</deviceType>\
+         <friendlyName>{}</friendlyName><manufacturer>audioserver</manufacturer>\
+         <modelName>Virtual Crossover</modelName><modelNumber>{}</modelNumber>\
+         <UDN>uuid:{}</UDN><dlna:X_DLNADOC>DMR-1.50</dlna:X_DLNADOC><serviceList>",
+        xml::escape(&config.name),
+        env!("CARGO_PKG_VERSION"),
+        uuid
+    );
+    for service in SERVICES {
+        xml.push_str(&format!(
+            "<service><serviceType>{}</serviceType><serviceId>{}</serviceId>\
+             <SCPDURL>/{name}/scpd.xml</SCPDURL><controlURL>/{name}/control</controlURL>\
+             <eventSubURL>/{name}/event</eventSubURL></service>",
+            service.service_type,
+            service.service_id,
+            name = service.name
+        ));
+    }
+    xml.push_str("</serviceList></device></root>\n");
+    xml
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name, value).expect("valid header")
+}
+
+fn request_header<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|header| header.value.as_str())
+}
+
+fn xml_response(status: u16, body: String) -> HttpResponse {
+    Response::from_string(body)
+        .with_status_code(status)
+        .with_header(header("Content-Type", XML))
+}
+
+fn envelope(body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>{}</s:Body></s:Envelope>\n",
+        body
+    )
+}
+
+fn fault(fault: Fault) -> HttpResponse {
+    xml_response(
+        500,
+        envelope(&format!(
+            "<s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail>\
+             <UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\"><errorCode>{}</errorCode>\
+             <errorDescription>{}</errorDescription></UPnPError></detail></s:Fault>",
+            fault.code, fault.description
+        )),
+    )
+}
+
+// Action name and arguments of a SOAP request
+fn parse_action(body: &str) -> Option<(String, Vec<(String, String)>)> {
+    let envelope = xml::child(body, "Envelope")?;
+    let body = xml::child(envelope, "Body")?;
+    let (action, content) = xml::children(body).into_iter().next()?;
+    let arguments = xml::children(content)
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), xml::text(value)))
+        .collect();
+    Some((action.to_string(), arguments))
+}
+
+// Renderer endpoints:
+//   GET         /description.xml
+//   GET         /<service>/scpd.xml
+//   POST        /<service>/control      SOAP actions
+//   SUBSCRIBE   /<service>/event        GENA, UNSUBSCRIBE to stop
+// for AVTransport, RenderingControl and ConnectionManager, plus SSDP on port 1900
+pub struct UpnpRenderer {
+    running: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl UpnpRenderer {
+    pub fn start(config: UpnpConfig, player: Player, volume: VolumeControl) -> Result<Self> {
+        let server = Server::http(("0.0.0.0", config.port)).map_err(|e| anyhow!("UPnP port {}: {}", config.port, e))?;
+        let uuid = device_uuid(&config.name);
+        let running = Arc::new(AtomicBool::new(true));
+        let renderer = Arc::new(Renderer::new(player, volume));
+        let subscriptions = Arc::new(Subscriptions::default());
+        let mut threads = Vec::new();
+
+        // Control points can still be pointed at the description without discovery
+        match Ssdp::new(&uuid, config.port) {
+            Ok(ssdp) => {
+                let running = running.clone();
+                threads.push(std::thread::spawn(move || ssdp.run(running)));
+            }
+            Err(e) => eprintln!("UPnP: not discoverable over SSDP: {}", e),
+        }
+        {
+            let (subscriptions, renderer, running) = (subscriptions.clone(), renderer.clone(), running.clone());
+            threads.push(std::thread::spawn(move || events::run(subscriptions, renderer, running)));
+        }
+        let http = Http {
+            description: description(&config, &uuid),
+            uuid,
+            renderer,
+            subscriptions,
+        };
+        let http_running = running.clone();
+        threads.push(std::thread::spawn(move || {
+            while http_running.load(Ordering::SeqCst) {
+                match server.recv_timeout(POLL) {
+                    Ok(Some(request)) => http.handle(request),
+                    Ok(None) => {}
+                    Err(e) => {
+                        eprintln!("UPnP: HTTP server stopped: {}", e);
+                        break;
+                    }
+                }
+            }
+        }));
+        println!("UPnP: renderer \"{}\" on port {}", config.name, config.port);
+        Ok(UpnpRenderer { running, threads })
+    }
+
+    // Also sends the SSDP goodbye
+    pub fn stop(self) {
+        self.running.store(false, Ordering::SeqCst);
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Http {
+    description: String,
+    uuid: String,
+    renderer: Arc<Renderer>,
+    subscriptions: Arc<Subscriptions>,
+}
+
+impl Http {
+    fn handle(&self, mut request: Request) {
+        let path = request.url().split('?').next().unwrap_or("").to_string();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let service = SERVICES
+            .iter()
+            .position(|service| segments.first() == Some(&service.name));
+        let response = match (request.method().as_str(), service, segments.as_slice()) {
+            ("GET" | "HEAD", _, ["description.xml"]) => xml_response(200, self.description.clone()),
+            ("GET" | "HEAD", Some(service), [_, "scpd.xml"]) => xml_response(200, services::scpd(SERVICES[service])),
+            ("POST", Some(service), [_, "control"]) => {
+                let mut body = String::new();
+                match request.as_reader().read_to_string(&mut body) {
+                    Ok(_) => self.control(service, &body),
+                    Err(_) => Response::from_string("Bad request").with_status_code(400),
+                }
+            }
+            ("SUBSCRIBE", Some(service), [_, "event"]) => self.subscribe(&request, service),
+            ("UNSUBSCRIBE", Some(_), [_, "event"]) => {
+                match request_header(&request, "SID").filter(|sid| self.subscriptions.unsubscribe(sid)) {
+                    Some(_) => Response::from_string(""),
+                    None => Response::from_string("").with_status_code(412),
+                }
+            }
+            _ => Response::from_string("Not found").with_status_code(404),
+        };
+        if let Err(e) = request.respond(response) {
+            eprintln!("UPnP: response failed: {}", e);
+        }
+    }
+
+    fn control(&self, service: usize, body: &str) -> HttpResponse {
+        let service = SERVICES[service];
+        let Some((action, arguments)) = parse_action(body) else {
+            return fault(Fault {
+                code: 401,
+                description: "Invalid Action",
+            });
+        };
+        match self.renderer.invoke(service, &action, &arguments) {
+            Ok(outputs) => {
+                let mut body = format!("<u:{}Response xmlns:u=\"{}\">", action, service.service_type);
+                for (name, value) in outputs {
+                    body.push_str(&format!("<{}>{}</{}>", name, xml::escape(&value), name));
+                }
+                body.push_str(&format!("</u:{}Response>", action));
+                xml_response(200, envelope(&body))
+            }
+            Err(error) => {
+                eprintln!("UPnP: {} failed: {} {}", action, error.code, error.description);
+                fault(error)
+            }
+        }
+    }
+
+    // A new subscription has CALLBACK and NT, a renewal only SID
+    fn subscribe(&self, request: &Request, service: usize) -> HttpResponse {
+        let timeout = request_header(request, "TIMEOUT");
+        let granted = match (request_header(request, "SID"), request_header(request, "CALLBACK")) {
+            (Some(sid), None) => self.subscriptions.renew(sid, timeout).map(|timeout| (sid.to_string(), timeout)),
+            (None, Some(callback)) if request_header(request, "NT") == Some("upnp:event") => {
+                self.subscriptions.subscribe(service, callback, timeout, &self.uuid)
+            }
+            // Both, or neither
+            (Some(_), Some(_)) => return Response::from_string("").with_status_code(400),
+            _ => None,
+        };
+        match granted {
+            Some((sid, timeout)) => Response::from_string("")
+                .with_header(header("SID", &sid))
+                .with_header(header("TIMEOUT", &format!("Second-{}", timeout.as_secs()))),
+            None => Response::from_string("").with_status_code(412),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{self, Url};
+    use crate::meter::AtomicF32;
+    use services::AV_TRANSPORT;
+    use std::io::Read;
+    use std::time::Instant;
+
+    #[test]
+    fn actions_are_read_from_soap_envelopes() {
+        let body = "<?xml version=\"1.0\"?>\n\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+            s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+            <u:SetAVTransportURI xmlns:u=\"urn:schemas-upnp-org:service:AVTransport:1\">\
+            <InstanceID>0</InstanceID><CurrentURI>http://192.0.2.1/a.flac?x=1&amp;y=2</CurrentURI>\
+            <CurrentURIMetaData>&lt;DIDL-Lite&gt;&lt;item&gt;&lt;dc:title&gt;A&lt;/dc:title&gt;\
+            &lt;/item&gt;&lt;/DIDL-Lite&gt;</CurrentURIMetaData>\
+            </u:SetAVTransportURI></s:Body></s:Envelope>";
+        let (action, arguments) = parse_action(body).unwrap();
+        assert_eq!(action, "SetAVTransportURI");
+        let arguments: Vec<(&str, &str)> =
+            arguments.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+        assert_eq!(
+            arguments,
+            [
+                ("InstanceID", "0"),
+                ("CurrentURI", "http://192.0.2.1/a.flac?x=1&y=2"),
+                ("CurrentURIMetaData", "<DIDL-Lite><item><dc:title>A</dc:title></item></DIDL-Lite>"),
+            ]
+        );
+
+        // Without arguments, and with whatever the prefix is
+        let (action, arguments) = parse_action(&envelope("<m:GetVolumeDBRange xmlns:m=\"x\"/>")).unwrap();
+        assert_eq!((action.as_str(), arguments.len()), ("GetVolumeDBRange", 0));
+        assert!(parse_action("<Body><Play/></Body>").is_none());
+        assert!(parse_action(&envelope("")).is_none());
+        assert!(parse_action("not xml").is_none());
+    }
+
+    #[test]
+    fn device_uuid_is_stable_per_name() {
+        let uuid = device_uuid("Living room");
+        assert_eq!(uuid, device_uuid("Living room"));
+        assert_ne!(uuid, device_uuid("Kitchen"));
+        let groups: Vec<usize> = uuid.split('-').map(str::len).collect();
+        assert_eq!(groups, [8, 4, 4, 4, 12]);
+        assert!(uuid.as_bytes()[14] == b'4' && b"89ab".contains(&uuid.as_bytes()[19]), "{}", uuid);
+    }
+
+    // Serves requests on a loopback port until `running` is cleared
+    fn serve(
+        handle: impl Fn(Request) + Send + 'static,
+        running: &Arc<AtomicBool>,
+    ) -> (std::net::SocketAddr, JoinHandle<()>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+        let running = running.clone();
+        let thread = std::thread::spawn(move || {
+            while running.load(Ordering::SeqCst) {
+                if let Ok(Some(request)) = server.recv_timeout(POLL) {
+                    handle(request);
+                }
+            }
+        });
+        (address, thread)
+    }
+
+    // Status and body of an AVTransport action
+    fn soap(address: std::net::SocketAddr, action: &str, arguments: &str) -> (u16, String) {
+        let url = Url::parse(&format!("http://{}/AVTransport/control", address)).unwrap();
+        let body = envelope(&format!(
+            "<u:{0} xmlns:u=\"{1}\"><InstanceID>0</InstanceID>{2}</u:{0}>",
+            action, AV_TRANSPORT.service_type, arguments
+        ));
+        let soap_action = format!("\"{}#{}\"", AV_TRANSPORT.service_type, action);
+        let headers = [("Content-Type", XML), ("SOAPAction", soap_action.as_str())];
+        let mut response = http::request("POST", &url, &headers, body.as_bytes()).unwrap();
+        let mut body = String::new();
+        response.read_to_string(&mut body).unwrap();
+        (response.status, body)
+    }
+
+    #[test]
+    fn a_pushed_url_plays() {
+        // 0.1 s of stereo 16 bit WAV, 0.25 on the left and -0.25 on the right
+        let mut wav = Cursor::new(Vec::new());
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::new(&mut wav, spec).unwrap();
+        for _ in 0..4800 {
+            writer.write_sample(8192i16).unwrap();
+            writer.write_sample(-8192i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        let wav = wav.into_inner();
+
+        let running = Arc::new(AtomicBool::new(true));
+        let (media, media_thread) = serve(
+            move |request| {
+                let response = Response::from_data(wav.clone()).with_header(header("Content-Type", "audio/wav"));
+                let _ = request.respond(response);
+            },
+            &running,
+        );
+        let player = Player::default();
+        let http = Http {
+            description: String::new(),
+            uuid: "device".to_string(),
+            renderer: Arc::new(Renderer::new(player.clone(), VolumeControl::default())),
+            subscriptions: Arc::new(Subscriptions::default()),
+        };
+        let (renderer, renderer_thread) = serve(move |request| http.handle(request), &running);
+
+        let uri = format!("http://{}/track.wav", media);
+        let metadata = "<DIDL-Lite><item><dc:title>Test &amp; tone</dc:title></item></DIDL-Lite>";
+        let arguments = format!(
+            "<CurrentURI>{}</CurrentURI><CurrentURIMetaData>{}</CurrentURIMetaData>",
+            xml::escape(&uri),
+            xml::escape(metadata)
+        );
+        let (status, body) = soap(renderer, "SetAVTransportURI", &arguments);
+        assert_eq!(status, 200, "{}", body);
+        assert!(body.contains("<u:SetAVTransportURIResponse"), "{}", body);
+        let queue = player.queue();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].path.to_str(), Some(uri.as_str()));
+        assert_eq!(queue[0].title.as_deref(), Some("Test & tone"));
+
+        let (status, body) = soap(renderer, "Play", "<Speed>1</Speed>");
+        assert_eq!(status, 200, "{}", body);
+        let (_, body) = soap(renderer, "GetMediaInfo", "");
+        assert!(body.contains(&format!("<CurrentURI>{}</CurrentURI>", xml::escape(&uri))), "{}", body);
+
+        // The output gets the file the renderer fetched
+        let playing = Arc::new(AtomicBool::new(true));
+        let (mut source, decoding) = player.start(48000, 2, Arc::new(AtomicF32::default()), playing.clone());
+        let started = Instant::now();
+        let mut output = vec![0.0; 2 * 480];
+        loop {
+            assert!(started.elapsed() < Duration::from_secs(2), "nothing played");
+            source.fill(&mut output);
+            if output[0] != 0.0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(output.chunks(2).all(|frame| frame == [0.25, -0.25]), "{:?}", &output[..8]);
+        let (_, body) = soap(renderer, "GetTransportInfo", "");
+        assert!(body.contains("<CurrentTransportState>PLAYING</CurrentTransportState>"), "{}", body);
+
+        // Unknown actions are SOAP faults
+        let (status, body) = soap(renderer, "Record", "");
+        assert_eq!(status, 500);
+        assert!(body.contains("<errorCode>401</errorCode>"), "{}", body);
+
+        playing.store(false, Ordering::SeqCst);
+        decoding.join().unwrap();
+        running.store(false, Ordering::SeqCst);
+        media_thread.join().unwrap();
+        renderer_thread.join().unwrap();
+    }
+}
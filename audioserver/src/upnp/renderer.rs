@@ -0,0 +1,706 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::services::{self, Service, SINK_PROTOCOLS};
+use super::xml;
+use crate::http::Url;
+use crate::player::{PlaybackState, Player, PlayerStatus, QueueEntry, RepeatMode};
+use crate::volume::VolumeControl;
+
+// RenderingControl's 0-100 covers the bottom 60 dB of the master volume, 0 mutes
+const VOLUME_RANGE_DB: f32 = 60.0;
+const MUTE_DB: f32 = -144.0;
+// VolumeDB is in 1/256 dB
+const VOLUME_DB_STEPS: f32 = 256.0;
+
+const DIDL_HEADER: &str = "<DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" \
+    xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">";
+
+// UPnP error returned in a SOAP fault
+#[derive(Debug, Clone, Copy)]
+pub struct Fault {
+    pub code: u16,
+    pub description: &'static str,
+}
+
+const INVALID_ACTION: Fault = Fault {
+    code: 401,
+    description: "Invalid Action",
+};
+const INVALID_ARGS: Fault = Fault {
+    code: 402,
+    description: "Invalid Args",
+};
+const TRANSITION_NOT_AVAILABLE: Fault = Fault {
+    code: 701,
+    description: "Transition not available",
+};
+const SEEK_MODE_NOT_SUPPORTED: Fault = Fault {
+    code: 710,
+    description: "Seek mode not supported",
+};
+const ILLEGAL_SEEK_TARGET: Fault = Fault {
+    code: 711,
+    description: "Illegal seek target",
+};
+const PLAY_MODE_NOT_SUPPORTED: Fault = Fault {
+    code: 712,
+    description: "Play mode not supported",
+};
+const RESOURCE_NOT_FOUND: Fault = Fault {
+    code: 716,
+    description: "Resource not found",
+};
+const INVALID_INSTANCE: Fault = Fault {
+    code: 718,
+    description: "Invalid InstanceID",
+};
+const INVALID_CONNECTION: Fault = Fault {
+    code: 706,
+    description: "Invalid connection reference",
+};
+
+pub type Arguments = Vec<(&'static str, String)>;
+
+// H:MM:SS, the form AVTransport uses for times
+fn format_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+// H+:MM:SS[.F+], also plain seconds
+fn parse_time(text: &str) -> Option<Duration> {
+    let mut seconds = 0.0;
+    for part in text.trim().split(':') {
+        // Fractions may also be written F0/F1, only the whole seconds count then
+        let part = match part.split_once('/') {
+            Some((whole, _)) => whole.split('.').next()?,
+            None => part,
+        };
+        let value: f64 = part.parse().ok()?;
+        seconds = seconds * 60.0 + value;
+    }
+    (seconds.is_finite() && seconds >= 0.0).then(|| Duration::from_secs_f64(seconds))
+}
+
+fn volume_percent(volume_db: f32) -> u16 {
+    (100.0 * (1.0 + volume_db / VOLUME_RANGE_DB)).round().clamp(0.0, 100.0) as u16
+}
+
+fn percent_volume_db(percent: u16) -> f32 {
+    if percent == 0 { MUTE_DB } else { -VOLUME_RANGE_DB * (1.0 - percent as f32 / 100.0) }
+}
+
+fn boolean(text: &str) -> Option<bool> {
+    match text.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" => Some(true),
+        "0" | "false" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+fn didl_title(didl: &str) -> Option<String> {
+    let item = xml::child(didl, "DIDL-Lite").and_then(|didl| xml::child(didl, "item"))?;
+    xml::child(item, "title").map(xml::text).filter(|title| !title.is_empty())
+}
+
+struct RendererState {
+    // DIDL-Lite the control point sent along with each URI, by queue entry
+    metadata: HashMap<u64, String>,
+    // Entry set up by SetNextAVTransportURI that has not started yet
+    next: Option<u64>,
+    // Volume to restore on unmute, Some while muted
+    unmuted_db: Option<f32>,
+    // Volume when the renderer started, the FactoryDefaults preset
+    default_db: f32,
+}
+
+// AVTransport, RenderingControl and ConnectionManager on top of the player and the master volume
+pub struct Renderer {
+    player: Player,
+    volume: VolumeControl,
+    state: Mutex<RendererState>,
+}
+
+impl Renderer {
+    pub fn new(player: Player, volume: VolumeControl) -> Self {
+        let default_db = volume.volume_db();
+        Renderer {
+            player,
+            volume,
+            state: Mutex::new(RendererState {
+                metadata: HashMap::new(),
+                next: None,
+                unmuted_db: None,
+                default_db,
+            }),
+        }
+    }
+
+    pub fn invoke(&self, service: &Service, action: &str, arguments: &[(String, String)]) -> Result<Arguments, Fault> {
+        let declared = service.action(action).ok_or(INVALID_ACTION)?;
+        let argument = |name: &str| {
+            arguments
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+                .ok_or(INVALID_ARGS)
+        };
+        for (name, _) in declared.inputs {
+            argument(name)?;
+        }
+        if declared.inputs.iter().any(|(name, _)| *name == "InstanceID") && argument("InstanceID")?.trim() != "0" {
+            return Err(INVALID_INSTANCE);
+        }
+        if declared.inputs.iter().any(|(name, _)| *name == "Channel") && argument("Channel")? != "Master" {
+            return Err(INVALID_ARGS);
+        }
+
+        match (service.name, action) {
+            ("AVTransport", "SetAVTransportURI") => {
+                self.set_uri(argument("CurrentURI")?, argument("CurrentURIMetaData")?)
+            }
+            ("AVTransport", "SetNextAVTransportURI") => {
+                self.set_next_uri(argument("NextURI")?, argument("NextURIMetaData")?)
+            }
+            ("AVTransport", "GetMediaInfo") => Ok(self.media_info()),
+            ("AVTransport", "GetTransportInfo") => Ok(vec![
+                ("CurrentTransportState", self.transport_state(&self.player.status()).to_string()),
+                ("CurrentTransportStatus", "OK".to_string()),
+                ("CurrentSpeed", "1".to_string()),
+            ]),
+            ("AVTransport", "GetPositionInfo") => Ok(self.position_info()),
+            ("AVTransport", "GetDeviceCapabilities") => Ok(vec![
+                ("PlayMedia", "NETWORK".to_string()),
+                ("RecMedia", "NOT_IMPLEMENTED".to_string()),
+                ("RecQualityModes", "NOT_IMPLEMENTED".to_string()),
+            ]),
+            ("AVTransport", "GetTransportSettings") => Ok(vec![
+                ("PlayMode", self.play_mode().to_string()),
+                ("RecQualityMode", "NOT_IMPLEMENTED".to_string()),
+            ]),
+            ("AVTransport", "GetCurrentTransportActions") => {
+                Ok(vec![("Actions", self.transport_actions(&self.player.status()).to_string())])
+            }
+            ("AVTransport", "Play") => {
+                if self.player.queue().is_empty() {
+                    return Err(TRANSITION_NOT_AVAILABLE);
+                }
+                self.player.play();
+                Ok(Vec::new())
+            }
+            ("AVTransport", "Pause") => {
+                match self.player.state() {
+                    PlaybackState::Stopped => return Err(TRANSITION_NOT_AVAILABLE),
+                    _ => self.player.pause(),
+                }
+                Ok(Vec::new())
+            }
+            ("AVTransport", "Stop") => {
+                self.player.stop();
+                Ok(Vec::new())
+            }
+            ("AVTransport", "Next") => {
+                self.player.next();
+                Ok(Vec::new())
+            }
+            ("AVTransport", "Previous") => {
+                self.player.previous();
+                Ok(Vec::new())
+            }
+            ("AVTransport", "Seek") => self.seek(argument("Unit")?, argument("Target")?),
+            ("AVTransport", "SetPlayMode") => {
+                let (repeat, shuffle) = match argument("NewPlayMode")? {
+                    "NORMAL" => (RepeatMode::Off, false),
+                    "SHUFFLE" => (RepeatMode::Off, true),
+                    "REPEAT_ONE" => (RepeatMode::One, false),
+                    "REPEAT_ALL" => (RepeatMode::All, false),
+                    _ => return Err(PLAY_MODE_NOT_SUPPORTED),
+                };
+                self.player.set_repeat(repeat);
+                self.player.set_shuffle(shuffle);
+                Ok(Vec::new())
+            }
+
+            ("RenderingControl", "ListPresets") => Ok(vec![("CurrentPresetNameList", "FactoryDefaults".to_string())]),
+            ("RenderingControl", "SelectPreset") => {
+                if argument("PresetName")? != "FactoryDefaults" {
+                    return Err(INVALID_ARGS);
+                }
+                let mut state = self.state.lock().unwrap();
+                state.unmuted_db = None;
+                self.volume.set_volume_db(state.default_db);
+                Ok(Vec::new())
+            }
+            ("RenderingControl", "GetMute") => {
+                Ok(vec![("CurrentMute", if self.muted() { "1" } else { "0" }.to_string())])
+            }
+            ("RenderingControl", "SetMute") => {
+                let mute = boolean(argument("DesiredMute")?).ok_or(INVALID_ARGS)?;
+                self.set_mute(mute);
+                Ok(Vec::new())
+            }
+            ("RenderingControl", "GetVolume") => {
+                Ok(vec![("CurrentVolume", volume_percent(self.volume_db()).to_string())])
+            }
+            ("RenderingControl", "SetVolume") => {
+                let percent: u16 = argument("DesiredVolume")?.trim().parse().map_err(|_| INVALID_ARGS)?;
+                if percent > 100 {
+                    return Err(INVALID_ARGS);
+                }
+                self.set_volume_db(percent_volume_db(percent));
+                Ok(Vec::new())
+            }
+            ("RenderingControl", "GetVolumeDB") => {
+                let steps = (self.volume_db().max(-VOLUME_RANGE_DB) * VOLUME_DB_STEPS).round() as i16;
+                Ok(vec![("CurrentVolume", steps.to_string())])
+            }
+            ("RenderingControl", "SetVolumeDB") => {
+                let steps: i16 = argument("DesiredVolume")?.trim().parse().map_err(|_| INVALID_ARGS)?;
+                let volume_db = steps as f32 / VOLUME_DB_STEPS;
+                self.set_volume_db(if volume_db <= -VOLUME_RANGE_DB { MUTE_DB } else { volume_db });
+                Ok(Vec::new())
+            }
+            ("RenderingControl", "GetVolumeDBRange") => Ok(vec![
+                ("MinValue", ((-VOLUME_RANGE_DB * VOLUME_DB_STEPS) as i16).to_string()),
+                ("MaxValue", "0".to_string()),
+            ]),
+
+            ("ConnectionManager", "GetProtocolInfo") => {
+                Ok(vec![("Source", String::new()), ("Sink", SINK_PROTOCOLS.join(","))])
+            }
+            ("ConnectionManager", "GetCurrentConnectionIDs") => Ok(vec![("ConnectionIDs", "0".to_string())]),
+            ("ConnectionManager", "GetCurrentConnectionInfo") => {
+                if argument("ConnectionID")?.trim() != "0" {
+                    return Err(INVALID_CONNECTION);
+                }
+                Ok(vec![
+                    ("RcsID", "0".to_string()),
+                    ("AVTransportID", "0".to_string()),
+                    ("ProtocolInfo", String::new()),
+                    ("PeerConnectionManager", String::new()),
+                    ("PeerConnectionID", "-1".to_string()),
+                    ("Direction", "Input".to_string()),
+                    ("Status", "OK".to_string()),
+                ])
+            }
+            _ => Err(INVALID_ACTION),
+        }
+    }
+
+    // Replaces the queue, playback carries on with the new URI if it was playing
+    fn set_uri(&self, uri: &str, metadata: &str) -> Result<Arguments, Fault> {
+        Url::parse(uri).map_err(|_| RESOURCE_NOT_FOUND)?;
+        let playing = self.player.state() == PlaybackState::Playing;
+        self.player.clear();
+        let id = self.player.enqueue_url(uri, didl_title(metadata));
+        {
+            let mut state = self.state.lock().unwrap();
+            state.metadata.clear();
+            state.metadata.insert(id, metadata.to_string());
+            state.next = None;
+        }
+        if playing {
+            self.player.play();
+        }
+        Ok(Vec::new())
+    }
+
+    // Queued after the current URI so the player moves on to it without a gap
+    fn set_next_uri(&self, uri: &str, metadata: &str) -> Result<Arguments, Fault> {
+        // Replaces an earlier next URI, unless the player has moved on to that already
+        let previous = self.state.lock().unwrap().next.take();
+        let started = [self.player.current(), self.player.now_playing()].map(|entry| entry.map(|entry| entry.id));
+        if let Some(previous) = previous
+            && !started.contains(&Some(previous))
+        {
+            self.player.remove(previous);
+        }
+        if uri.is_empty() {
+            return Ok(Vec::new());
+        }
+        Url::parse(uri).map_err(|_| RESOURCE_NOT_FOUND)?;
+        let id = self.player.enqueue_url(uri, didl_title(metadata));
+        let mut state = self.state.lock().unwrap();
+        state.metadata.insert(id, metadata.to_string());
+        state.next = Some(id);
+        Ok(Vec::new())
+    }
+
+    fn seek(&self, unit: &str, target: &str) -> Result<Arguments, Fault> {
+        let moved = match unit {
+            "REL_TIME" | "ABS_TIME" => {
+                let position = parse_time(target).ok_or(ILLEGAL_SEEK_TARGET)?;
+                self.player.seek(position)
+            }
+            "TRACK_NR" => {
+                let track: usize = target.trim().parse().map_err(|_| ILLEGAL_SEEK_TARGET)?;
+                let entry = track.checked_sub(1).and_then(|index| self.player.queue().get(index).cloned());
+                self.player.jump(entry.ok_or(ILLEGAL_SEEK_TARGET)?.id)
+            }
+            _ => return Err(SEEK_MODE_NOT_SUPPORTED),
+        };
+        if !moved {
+            return Err(TRANSITION_NOT_AVAILABLE);
+        }
+        Ok(Vec::new())
+    }
+
+    fn volume_db(&self) -> f32 {
+        let state = self.state.lock().unwrap();
+        match state.unmuted_db {
+            Some(volume_db) if self.volume.volume_db() <= MUTE_DB => volume_db,
+            _ => self.volume.volume_db(),
+        }
+    }
+
+    // A volume set while muted applies on unmute
+    fn set_volume_db(&self, volume_db: f32) {
+        let mut state = self.state.lock().unwrap();
+        if state.unmuted_db.is_some() && self.volume.volume_db() <= MUTE_DB {
+            state.unmuted_db = Some(volume_db);
+        } else {
+            state.unmuted_db = None;
+            self.volume.set_volume_db(volume_db);
+        }
+    }
+
+    // Only muted by SetMute, until something else changes the volume
+    fn muted(&self) -> bool {
+        self.state.lock().unwrap().unmuted_db.is_some() && self.volume.volume_db() <= MUTE_DB
+    }
+
+    fn set_mute(&self, mute: bool) {
+        let muted = self.muted();
+        let mut state = self.state.lock().unwrap();
+        if mute && !muted {
+            state.unmuted_db = Some(self.volume.volume_db());
+            self.volume.set_volume_db(MUTE_DB);
+        } else if !mute && let Some(volume_db) = state.unmuted_db.take() {
+            self.volume.set_volume_db(volume_db);
+        }
+    }
+
+    fn play_mode(&self) -> &'static str {
+        match (self.player.shuffle(), self.player.repeat()) {
+            (true, _) => "SHUFFLE",
+            (false, RepeatMode::One) => "REPEAT_ONE",
+            (false, RepeatMode::All) => "REPEAT_ALL",
+            (false, RepeatMode::Off) => "NORMAL",
+        }
+    }
+
+    fn transport_state(&self, status: &PlayerStatus) -> &'static str {
+        match status.state {
+            PlaybackState::Playing if status.buffering => "TRANSITIONING",
+            PlaybackState::Playing => "PLAYING",
+            PlaybackState::Paused => "PAUSED_PLAYBACK",
+            PlaybackState::Stopped if self.player.queue().is_empty() => "NO_MEDIA_PRESENT",
+            PlaybackState::Stopped => "STOPPED",
+        }
+    }
+
+    fn transport_actions(&self, status: &PlayerStatus) -> &'static str {
+        match self.transport_state(status) {
+            "PLAYING" | "TRANSITIONING" => "Pause,Stop,Seek,Next,Previous",
+            "PAUSED_PLAYBACK" => "Play,Stop,Seek,Next,Previous",
+            "STOPPED" => "Play,Next,Previous",
+            _ => "",
+        }
+    }
+
+    // The entry playing, or the one Play would start
+    fn current_entry(&self, status: &PlayerStatus) -> Option<QueueEntry> {
+        status.entry.clone().or_else(|| self.player.current()).or_else(|| self.player.queue().first().cloned())
+    }
+
+    // The DIDL-Lite sent with the entry, or one made from its tags
+    fn entry_metadata(&self, entry: &QueueEntry, status: &PlayerStatus) -> String {
+        if let Some(metadata) = self.state.lock().unwrap().metadata.get(&entry.id).filter(|metadata| !metadata.is_empty()) {
+            return metadata.clone();
+        }
+        let tags = status
+            .metadata
+            .as_ref()
+            .filter(|_| status.entry.as_ref().is_some_and(|playing| playing.id == entry.id));
+        let title = tags
+            .and_then(|tags| tags.title.clone())
+            .or_else(|| entry.title.clone())
+            .unwrap_or_else(|| entry.path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned()));
+        let mut didl = format!(
+            "{}<item id=\"{}\" parentID=\"0\" restricted=\"1\"><dc:title>{}</dc:title>",
+            DIDL_HEADER,
+            entry.id,
+            xml::escape(&title)
+        );
+        if let Some(artist) = tags.and_then(|tags| tags.artist.as_ref()) {
+            didl.push_str(&format!("<upnp:artist>{}</upnp:artist>", xml::escape(artist)));
+        }
+        if let Some(album) = tags.and_then(|tags| tags.album.as_ref()) {
+            didl.push_str(&format!("<upnp:album>{}</upnp:album>", xml::escape(album)));
+        }
+        didl.push_str("<upnp:class>object.item.audioItem.musicTrack</upnp:class></item></DIDL-Lite>");
+        didl
+    }
+
+    // URI and metadata of the entry set up with SetNextAVTransportURI, until it plays
+    fn next_entry(&self, current: Option<&QueueEntry>) -> (String, String) {
+        let mut state = self.state.lock().unwrap();
+        if state.next.is_some() && state.next == current.map(|entry| entry.id) {
+            state.next = None;
+        }
+        let queue = self.player.queue();
+        match state.next.and_then(|id| queue.iter().find(|entry| entry.id == id)) {
+            Some(entry) => (
+                entry.path.display().to_string(),
+                state.metadata.get(&entry.id).cloned().unwrap_or_default(),
+            ),
+            None => (String::new(), String::new()),
+        }
+    }
+
+    fn media_info(&self) -> Arguments {
+        let status = self.player.status();
+        let current = self.current_entry(&status);
+        let (next_uri, next_metadata) = self.next_entry(current.as_ref());
+        vec![
+            ("NrTracks", self.player.queue().len().to_string()),
+            ("MediaDuration", status.duration.map_or_else(|| "0:00:00".to_string(), format_time)),
+            ("CurrentURI", current.as_ref().map_or_else(String::new, |entry| entry.path.display().to_string())),
+            (
+                "CurrentURIMetaData",
+                current.as_ref().map_or_else(String::new, |entry| self.entry_metadata(entry, &status)),
+            ),
+            ("NextURI", next_uri),
+            ("NextURIMetaData", next_metadata),
+            ("PlayMedium", if current.is_some() { "NETWORK" } else { "NONE" }.to_string()),
+            ("RecordMedium", "NOT_IMPLEMENTED".to_string()),
+            ("WriteStatus", "NOT_IMPLEMENTED".to_string()),
+        ]
+    }
+
+    fn position_info(&self) -> Arguments {
+        let status = self.player.status();
+        let current = self.current_entry(&status);
+        let track = current
+            .as_ref()
+            .and_then(|entry| self.player.queue().iter().position(|queued| queued.id == entry.id))
+            .map_or(0, |index| index + 1);
+        let position = format_time(status.position);
+        vec![
+            ("Track", track.to_string()),
+            ("TrackDuration", status.duration.map_or_else(|| "0:00:00".to_string(), format_time)),
+            (
+                "TrackMetaData",
+                current.as_ref().map_or_else(String::new, |entry| self.entry_metadata(entry, &status)),
+            ),
+            ("TrackURI", current.as_ref().map_or_else(String::new, |entry| entry.path.display().to_string())),
+            ("RelTime", position.clone()),
+            ("AbsTime", position),
+            ("RelCount", i32::MAX.to_string()),
+            ("AbsCount", i32::MAX.to_string()),
+        ]
+    }
+
+    // Values of the service's evented variables. AVTransport and RenderingControl
+    // send their state as one LastChange document.
+    pub fn evented(&self, service: &Service) -> Arguments {
+        let values: Arguments = match service.name {
+            "AVTransport" => {
+                let status = self.player.status();
+                let media = self.media_info();
+                let position = self.position_info();
+                let find = |arguments: &Arguments, name: &str| {
+                    arguments
+                        .iter()
+                        .find(|(key, _)| *key == name)
+                        .map(|(_, value)| value.clone())
+                        .unwrap_or_default()
+                };
+                vec![
+                    ("TransportState", self.transport_state(&status).to_string()),
+                    ("TransportStatus", "OK".to_string()),
+                    ("TransportPlaySpeed", "1".to_string()),
+                    ("CurrentPlayMode", self.play_mode().to_string()),
+                    ("CurrentTransportActions", self.transport_actions(&status).to_string()),
+                    ("NumberOfTracks", find(&media, "NrTracks")),
+                    ("CurrentTrack", find(&position, "Track")),
+                    ("CurrentTrackDuration", find(&position, "TrackDuration")),
+                    ("CurrentMediaDuration", find(&media, "MediaDuration")),
+                    ("CurrentTrackURI", find(&position, "TrackURI")),
+                    ("CurrentTrackMetaData", find(&position, "TrackMetaData")),
+                    ("AVTransportURI", find(&media, "CurrentURI")),
+                    ("AVTransportURIMetaData", find(&media, "CurrentURIMetaData")),
+                    ("NextAVTransportURI", find(&media, "NextURI")),
+                    ("NextAVTransportURIMetaData", find(&media, "NextURIMetaData")),
+                    ("PlaybackStorageMedium", find(&media, "PlayMedium")),
+                ]
+            }
+            "RenderingControl" => {
+                let volume_db = self.volume_db();
+                vec![
+                    ("Volume", volume_percent(volume_db).to_string()),
+                    ("VolumeDB", ((volume_db.max(-VOLUME_RANGE_DB) * VOLUME_DB_STEPS).round() as i16).to_string()),
+                    ("Mute", if self.muted() { "1" } else { "0" }.to_string()),
+                    ("PresetNameList", "FactoryDefaults".to_string()),
+                ]
+            }
+            _ => {
+                return vec![
+                    ("SourceProtocolInfo", String::new()),
+                    ("SinkProtocolInfo", SINK_PROTOCOLS.join(",")),
+                    ("CurrentConnectionIDs", "0".to_string()),
+                ];
+            }
+        };
+
+        let namespace = if service.name == services::AV_TRANSPORT.name { "AVT" } else { "RCS" };
+        let mut last_change = format!(
+            "<Event xmlns=\"urn:schemas-upnp-org:metadata-1-0/{}/\"><InstanceID val=\"0\">",
+            namespace
+        );
+        for (name, value) in values {
+            let channel = if matches!(name, "Volume" | "VolumeDB" | "Mute") { " channel=\"Master\"" } else { "" };
+            last_change.push_str(&format!("<{} val=\"{}\"{}/>", name, xml::escape(&value), channel));
+        }
+        last_change.push_str("</InstanceID></Event>");
+        vec![("LastChange", last_change)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upnp::services::{AV_TRANSPORT, RENDERING_CONTROL};
+
+    fn call(
+        renderer: &Renderer,
+        service: &Service,
+        action: &str,
+        arguments: &[(&str, &str)],
+    ) -> Result<Arguments, Fault> {
+        let mut arguments: Vec<(String, String)> =
+            arguments.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        arguments.push(("InstanceID".to_string(), "0".to_string()));
+        arguments.push(("Channel".to_string(), "Master".to_string()));
+        renderer.invoke(service, action, &arguments)
+    }
+
+    fn volume(renderer: &Renderer) -> String {
+        let outputs = call(renderer, &RENDERING_CONTROL, "GetVolume", &[]).unwrap();
+        outputs[0].1.clone()
+    }
+
+    #[test]
+    fn volume_percent_covers_the_bottom_60_db() {
+        assert_eq!(volume_percent(0.0), 100);
+        assert_eq!(volume_percent(-30.0), 50);
+        assert_eq!(volume_percent(-60.0), 0);
+        assert_eq!(volume_percent(-90.0), 0);
+        assert_eq!(volume_percent(MUTE_DB), 0);
+        assert_eq!(percent_volume_db(100), 0.0);
+        assert_eq!(percent_volume_db(50), -30.0);
+        assert_eq!(percent_volume_db(0), MUTE_DB);
+        for percent in 0..=100 {
+            assert_eq!(volume_percent(percent_volume_db(percent)), percent);
+        }
+    }
+
+    #[test]
+    fn rendering_control_sets_the_master_volume() {
+        let control = VolumeControl::default();
+        let renderer = Renderer::new(Player::default(), control.clone());
+        call(&renderer, &RENDERING_CONTROL, "SetVolume", &[("DesiredVolume", "25")]).unwrap();
+        assert_eq!(control.volume_db(), -45.0);
+        assert_eq!(volume(&renderer), "25");
+        let outputs = call(&renderer, &RENDERING_CONTROL, "GetVolumeDB", &[]).unwrap();
+        assert_eq!(outputs, [("CurrentVolume", (-45 * 256).to_string())]);
+
+        // Other volume changes show up
+        control.set_volume_db(-6.0);
+        assert_eq!(volume(&renderer), "90");
+        call(&renderer, &RENDERING_CONTROL, "SetVolumeDB", &[("DesiredVolume", "-7680")]).unwrap();
+        assert_eq!(control.volume_db(), -30.0);
+        call(&renderer, &RENDERING_CONTROL, "SetVolumeDB", &[("DesiredVolume", "-32768")]).unwrap();
+        assert_eq!(control.volume_db(), MUTE_DB);
+        call(&renderer, &RENDERING_CONTROL, "SetVolume", &[("DesiredVolume", "0")]).unwrap();
+        assert_eq!(control.volume_db(), MUTE_DB);
+
+        let invalid = |volume: &str| call(&renderer, &RENDERING_CONTROL, "SetVolume", &[("DesiredVolume", volume)]);
+        assert_eq!(invalid("101").unwrap_err().code, 402);
+        assert_eq!(invalid("loud").unwrap_err().code, 402);
+        let mut arguments = vec![("InstanceID".to_string(), "0".to_string())];
+        arguments.push(("Channel".to_string(), "LF".to_string()));
+        assert_eq!(renderer.invoke(&RENDERING_CONTROL, "GetVolume", &arguments).unwrap_err().code, 402);
+    }
+
+    #[test]
+    fn mute_keeps_the_volume_to_restore() {
+        let control = VolumeControl::default();
+        let renderer = Renderer::new(Player::default(), control.clone());
+        call(&renderer, &RENDERING_CONTROL, "SetVolume", &[("DesiredVolume", "50")]).unwrap();
+        call(&renderer, &RENDERING_CONTROL, "SetMute", &[("DesiredMute", "1")]).unwrap();
+        assert_eq!(control.volume_db(), MUTE_DB);
+        assert_eq!(volume(&renderer), "50");
+        let outputs = call(&renderer, &RENDERING_CONTROL, "GetMute", &[]).unwrap();
+        assert_eq!(outputs, [("CurrentMute", "1".to_string())]);
+
+        // A volume set while muted is what unmuting goes back to
+        call(&renderer, &RENDERING_CONTROL, "SetVolume", &[("DesiredVolume", "75")]).unwrap();
+        assert_eq!(control.volume_db(), MUTE_DB);
+        call(&renderer, &RENDERING_CONTROL, "SetMute", &[("DesiredMute", "false")]).unwrap();
+        assert_eq!(control.volume_db(), -15.0);
+        assert!(!renderer.muted());
+        assert_eq!(call(&renderer, &RENDERING_CONTROL, "SetMute", &[("DesiredMute", "maybe")]).unwrap_err().code, 402);
+
+        call(&renderer, &RENDERING_CONTROL, "SelectPreset", &[("PresetName", "FactoryDefaults")]).unwrap();
+        assert_eq!(control.volume_db(), 0.0);
+    }
+
+    #[test]
+    fn titles_come_from_didl_lite() {
+        let didl = format!(
+            "{}<item id=\"1\" parentID=\"0\" restricted=\"1\"><dc:title>Rock &amp; Roll</dc:title>\
+             <upnp:class>object.item.audioItem.musicTrack</upnp:class></item></DIDL-Lite>",
+            DIDL_HEADER
+        );
+        assert_eq!(didl_title(&didl).as_deref(), Some("Rock & Roll"));
+        let cdata = "<DIDL-Lite><item><dc:title><![CDATA[A <b> side]]></dc:title></item></DIDL-Lite>";
+        assert_eq!(didl_title(cdata).as_deref(), Some("A <b> side"));
+        assert_eq!(didl_title("<DIDL-Lite><item><dc:title> </dc:title></item></DIDL-Lite>"), None);
+        assert_eq!(didl_title("<DIDL-Lite><container><dc:title>Album</dc:title></container></DIDL-Lite>"), None);
+        assert_eq!(didl_title(""), None);
+        assert_eq!(didl_title("NOT_IMPLEMENTED"), None);
+    }
+
+    #[test]
+    fn times_in_avtransport_form() {
+        assert_eq!(format_time(Duration::from_millis(3_723_900)), "1:02:03");
+        assert_eq!(parse_time("1:02:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_time("0:00:01.500"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_time("0:00:07.1/2"), Some(Duration::from_secs(7)));
+        assert_eq!(parse_time("42"), Some(Duration::from_secs(42)));
+        assert_eq!(parse_time("-1"), None);
+        assert_eq!(parse_time("soon"), None);
+    }
+
+    #[test]
+    fn transport_needs_media_to_play() {
+        let renderer = Renderer::new(Player::default(), VolumeControl::default());
+        let state = |renderer: &Renderer| call(renderer, &AV_TRANSPORT, "GetTransportInfo", &[]).unwrap()[0].1.clone();
+        assert_eq!(state(&renderer), "NO_MEDIA_PRESENT");
+        assert_eq!(call(&renderer, &AV_TRANSPORT, "Play", &[("Speed", "1")]).unwrap_err().code, 701);
+        let set = |uri: &str| {
+            let arguments = [("CurrentURI", uri), ("CurrentURIMetaData", "")];
+            call(&renderer, &AV_TRANSPORT, "SetAVTransportURI", &arguments)
+        };
+        assert_eq!(set("ftp://example/track.flac").unwrap_err().code, 716);
+        set("http://192.0.2.1/track.flac").unwrap();
+        assert_eq!(state(&renderer), "STOPPED");
+        assert_eq!(call(&renderer, &AV_TRANSPORT, "Rewind", &[]).unwrap_err().code, 401);
+        let mut arguments = vec![("InstanceID".to_string(), "1".to_string())];
+        arguments.push(("Speed".to_string(), "1".to_string()));
+        assert_eq!(renderer.invoke(&AV_TRANSPORT, "Play", &arguments).unwrap_err().code, 718);
+    }
+}
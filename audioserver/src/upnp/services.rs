@@ -0,0 +1,418 @@
+// The three services of a MediaRenderer as tables, from which the SCPD documents are written
+
+pub struct StateVariable {
+    pub name: &'static str,
+    pub data_type: &'static str,
+    pub evented: bool,
+    pub allowed: &'static [&'static str],
+    // minimum, maximum, step
+    pub range: Option<(i32, i32, i32)>,
+}
+
+pub struct Action {
+    pub name: &'static str,
+    // (name, related state variable), in and out
+    pub inputs: &'static [(&'static str, &'static str)],
+    pub outputs: &'static [(&'static str, &'static str)],
+}
+
+pub struct Service {
+    // AVTransport, also the path the service is served under
+    pub name: &'static str,
+    pub service_type: &'static str,
+    pub service_id: &'static str,
+    pub actions: &'static [Action],
+    pub variables: &'static [StateVariable],
+}
+
+impl Service {
+    pub fn action(&self, name: &str) -> Option<&Action> {
+        self.actions.iter().find(|action| action.name == name)
+    }
+}
+
+const fn variable(name: &'static str, data_type: &'static str) -> StateVariable {
+    StateVariable {
+        name,
+        data_type,
+        evented: false,
+        allowed: &[],
+        range: None,
+    }
+}
+
+const fn allowed(name: &'static str, allowed: &'static [&'static str]) -> StateVariable {
+    StateVariable {
+        name,
+        data_type: "string",
+        evented: false,
+        allowed,
+        range: None,
+    }
+}
+
+const fn evented(name: &'static str) -> StateVariable {
+    StateVariable {
+        name,
+        data_type: "string",
+        evented: true,
+        allowed: &[],
+        range: None,
+    }
+}
+
+pub const TRANSPORT_STATES: &[&str] = &["STOPPED", "PLAYING", "PAUSED_PLAYBACK", "TRANSITIONING", "NO_MEDIA_PRESENT"];
+pub const PLAY_MODES: &[&str] = &["NORMAL", "SHUFFLE", "REPEAT_ONE", "REPEAT_ALL"];
+
+pub const AV_TRANSPORT: Service = Service {
+    name: "AVTransport",
+    service_type: "urn:schemas-upnp-org:service:AVTransport:1",
+    service_id: "urn:upnp-org:serviceId:AVTransport",
+    actions: &[
+        Action {
+            name: "SetAVTransportURI",
+            inputs: &[
+                ("InstanceID", "A_ARG_TYPE_InstanceID"),
+                ("CurrentURI", "AVTransportURI"),
+                ("CurrentURIMetaData", "AVTransportURIMetaData"),
+            ],
+            outputs: &[],
+        },
+        Action {
+            name: "SetNextAVTransportURI",
+            inputs: &[
+                ("InstanceID", "A_ARG_TYPE_InstanceID"),
+                ("NextURI", "NextAVTransportURI"),
+                ("NextURIMetaData", "NextAVTransportURIMetaData"),
+            ],
+            outputs: &[],
+        },
+        Action {
+            name: "GetMediaInfo",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID")],
+            outputs: &[
+                ("NrTracks", "NumberOfTracks"),
+                ("MediaDuration", "CurrentMediaDuration"),
+                ("CurrentURI", "AVTransportURI"),
+                ("CurrentURIMetaData", "AVTransportURIMetaData"),
+                ("NextURI", "NextAVTransportURI"),
+                ("NextURIMetaData", "NextAVTransportURIMetaData"),
+                ("PlayMedium", "PlaybackStorageMedium"),
+                ("RecordMedium", "RecordStorageMedium"),
+                ("WriteStatus", "RecordMediumWriteStatus"),
+            ],
+        },
+        Action {
+            name: "GetTransportInfo",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID")],
+            outputs: &[
+                ("CurrentTransportState", "TransportState"),
+                ("CurrentTransportStatus", "TransportStatus"),
+                ("CurrentSpeed", "TransportPlaySpeed"),
+            ],
+        },
+        Action {
+            name: "GetPositionInfo",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID")],
+            outputs: &[
+                ("Track", "CurrentTrack"),
+                ("TrackDuration", "CurrentTrackDuration"),
+                ("TrackMetaData", "CurrentTrackMetaData"),
+                ("TrackURI", "CurrentTrackURI"),
+                ("RelTime", "RelativeTimePosition"),
+                ("AbsTime", "AbsoluteTimePosition"),
+                ("RelCount", "RelativeCounterPosition"),
+                ("AbsCount", "AbsoluteCounterPosition"),
+            ],
+        },
+        Action {
+            name: "GetDeviceCapabilities",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID")],
+            outputs: &[
+                ("PlayMedia", "PossiblePlaybackStorageMedia"),
+                ("RecMedia", "PossibleRecordStorageMedia"),
+                ("RecQualityModes", "PossibleRecordQualityModes"),
+            ],
+        },
+        Action {
+            name: "GetTransportSettings",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID")],
+            outputs: &[("PlayMode", "CurrentPlayMode"), ("RecQualityMode", "CurrentRecordQualityMode")],
+        },
+        Action {
+            name: "GetCurrentTransportActions",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID")],
+            outputs: &[("Actions", "CurrentTransportActions")],
+        },
+        Action {
+            name: "Stop",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID")],
+            outputs: &[],
+        },
+        Action {
+            name: "Play",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID"), ("Speed", "TransportPlaySpeed")],
+            outputs: &[],
+        },
+        Action {
+            name: "Pause",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID")],
+            outputs: &[],
+        },
+        Action {
+            name: "Seek",
+            inputs: &[
+                ("InstanceID", "A_ARG_TYPE_InstanceID"),
+                ("Unit", "A_ARG_TYPE_SeekMode"),
+                ("Target", "A_ARG_TYPE_SeekTarget"),
+            ],
+            outputs: &[],
+        },
+        Action {
+            name: "Next",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID")],
+            outputs: &[],
+        },
+        Action {
+            name: "Previous",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID")],
+            outputs: &[],
+        },
+        Action {
+            name: "SetPlayMode",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID"), ("NewPlayMode", "CurrentPlayMode")],
+            outputs: &[],
+        },
+    ],
+    variables: &[
+        allowed("TransportState", TRANSPORT_STATES),
+        allowed("TransportStatus", &["OK", "ERROR_OCCURRED"]),
+        allowed("PlaybackStorageMedium", &["NONE", "NETWORK"]),
+        allowed("RecordStorageMedium", &["NOT_IMPLEMENTED"]),
+        variable("PossiblePlaybackStorageMedia", "string"),
+        variable("PossibleRecordStorageMedia", "string"),
+        allowed("CurrentPlayMode", PLAY_MODES),
+        allowed("TransportPlaySpeed", &["1"]),
+        allowed("RecordMediumWriteStatus", &["NOT_IMPLEMENTED"]),
+        allowed("CurrentRecordQualityMode", &["NOT_IMPLEMENTED"]),
+        variable("PossibleRecordQualityModes", "string"),
+        variable("NumberOfTracks", "ui4"),
+        variable("CurrentTrack", "ui4"),
+        variable("CurrentTrackDuration", "string"),
+        variable("CurrentMediaDuration", "string"),
+        variable("CurrentTrackMetaData", "string"),
+        variable("CurrentTrackURI", "string"),
+        variable("AVTransportURI", "string"),
+        variable("AVTransportURIMetaData", "string"),
+        variable("NextAVTransportURI", "string"),
+        variable("NextAVTransportURIMetaData", "string"),
+        variable("RelativeTimePosition", "string"),
+        variable("AbsoluteTimePosition", "string"),
+        variable("RelativeCounterPosition", "i4"),
+        variable("AbsoluteCounterPosition", "i4"),
+        variable("CurrentTransportActions", "string"),
+        evented("LastChange"),
+        allowed("A_ARG_TYPE_SeekMode", &["TRACK_NR", "REL_TIME", "ABS_TIME"]),
+        variable("A_ARG_TYPE_SeekTarget", "string"),
+        variable("A_ARG_TYPE_InstanceID", "ui4"),
+    ],
+};
+
+pub const RENDERING_CONTROL: Service = Service {
+    name: "RenderingControl",
+    service_type: "urn:schemas-upnp-org:service:RenderingControl:1",
+    service_id: "urn:upnp-org:serviceId:RenderingControl",
+    actions: &[
+        Action {
+            name: "ListPresets",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID")],
+            outputs: &[("CurrentPresetNameList", "PresetNameList")],
+        },
+        Action {
+            name: "SelectPreset",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID"), ("PresetName", "A_ARG_TYPE_PresetName")],
+            outputs: &[],
+        },
+        Action {
+            name: "GetMute",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID"), ("Channel", "A_ARG_TYPE_Channel")],
+            outputs: &[("CurrentMute", "Mute")],
+        },
+        Action {
+            name: "SetMute",
+            inputs: &[
+                ("InstanceID", "A_ARG_TYPE_InstanceID"),
+                ("Channel", "A_ARG_TYPE_Channel"),
+                ("DesiredMute", "Mute"),
+            ],
+            outputs: &[],
+        },
+        Action {
+            name: "GetVolume",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID"), ("Channel", "A_ARG_TYPE_Channel")],
+            outputs: &[("CurrentVolume", "Volume")],
+        },
+        Action {
+            name: "SetVolume",
+            inputs: &[
+                ("InstanceID", "A_ARG_TYPE_InstanceID"),
+                ("Channel", "A_ARG_TYPE_Channel"),
+                ("DesiredVolume", "Volume"),
+            ],
+            outputs: &[],
+        },
+        Action {
+            name: "GetVolumeDB",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID"), ("Channel", "A_ARG_TYPE_Channel")],
+            outputs: &[("CurrentVolume", "VolumeDB")],
+        },
+        Action {
+            name: "SetVolumeDB",
+            inputs: &[
+                ("InstanceID", "A_ARG_TYPE_InstanceID"),
+                ("Channel", "A_ARG_TYPE_Channel"),
+                ("DesiredVolume", "VolumeDB"),
+            ],
+            outputs: &[],
+        },
+        Action {
+            name: "GetVolumeDBRange",
+            inputs: &[("InstanceID", "A_ARG_TYPE_InstanceID"), ("Channel", "A_ARG_TYPE_Channel")],
+            outputs: &[("MinValue", "VolumeDB"), ("MaxValue", "VolumeDB")],
+        },
+    ],
+    variables: &[
+        variable("PresetNameList", "string"),
+        evented("LastChange"),
+        variable("Mute", "boolean"),
+        StateVariable {
+            name: "Volume",
+            data_type: "ui2",
+            evented: false,
+            allowed: &[],
+            range: Some((0, 100, 1)),
+        },
+        // 1/256 dB steps
+        StateVariable {
+            name: "VolumeDB",
+            data_type: "i2",
+            evented: false,
+            allowed: &[],
+            range: Some((-15360, 0, 1)),
+        },
+        allowed("A_ARG_TYPE_Channel", &["Master"]),
+        variable("A_ARG_TYPE_InstanceID", "ui4"),
+        allowed("A_ARG_TYPE_PresetName", &["FactoryDefaults"]),
+    ],
+};
+
+pub const CONNECTION_MANAGER: Service = Service {
+    name: "ConnectionManager",
+    service_type: "urn:schemas-upnp-org:service:ConnectionManager:1",
+    service_id: "urn:upnp-org:serviceId:ConnectionManager",
+    actions: &[
+        Action {
+            name: "GetProtocolInfo",
+            inputs: &[],
+            outputs: &[("Source", "SourceProtocolInfo"), ("Sink", "SinkProtocolInfo")],
+        },
+        Action {
+            name: "GetCurrentConnectionIDs",
+            inputs: &[],
+            outputs: &[("ConnectionIDs", "CurrentConnectionIDs")],
+        },
+        Action {
+            name: "GetCurrentConnectionInfo",
+            inputs: &[("ConnectionID", "A_ARG_TYPE_ConnectionID")],
+            outputs: &[
+                ("RcsID", "A_ARG_TYPE_RcsID"),
+                ("AVTransportID", "A_ARG_TYPE_AVTransportID"),
+                ("ProtocolInfo", "A_ARG_TYPE_ProtocolInfo"),
+                ("PeerConnectionManager", "A_ARG_TYPE_ConnectionManager"),
+                ("PeerConnectionID", "A_ARG_TYPE_ConnectionID"),
+                ("Direction", "A_ARG_TYPE_Direction"),
+                ("Status", "A_ARG_TYPE_ConnectionStatus"),
+            ],
+        },
+    ],
+    variables: &[
+        evented("SourceProtocolInfo"),
+        evented("SinkProtocolInfo"),
+        evented("CurrentConnectionIDs"),
+        allowed("A_ARG_TYPE_ConnectionStatus", &["OK", "ContentFormatMismatch", "InsufficientBandwidth", "UnreliableChannel", "Unknown"]),
+        variable("A_ARG_TYPE_ConnectionManager", "string"),
+        allowed("A_ARG_TYPE_Direction", &["Input", "Output"]),
+        variable("A_ARG_TYPE_ProtocolInfo", "string"),
+        variable("A_ARG_TYPE_ConnectionID", "i4"),
+        variable("A_ARG_TYPE_AVTransportID", "i4"),
+        variable("A_ARG_TYPE_RcsID", "i4"),
+    ],
+};
+
+pub const SERVICES: [&Service; 3] = [&AV_TRANSPORT, &RENDERING_CONTROL, &CONNECTION_MANAGER];
+
+// Formats the decoder plays, offered to control points over plain HTTP
+pub const SINK_PROTOCOLS: &[&str] = &[
+    "http-get:*:audio/mpeg:*",
+    "http-get:*:audio/mp3:*",
+    "http-get:*:audio/flac:*",
+    "http-get:*:audio/x-flac:*",
+    "http-get:*:audio/wav:*",
+    "http-get:*:audio/x-wav:*",
+    "http-get:*:audio/L16:*",
+    "http-get:*:audio/ogg:*",
+    "http-get:*:application/ogg:*",
+    "http-get:*:audio/mp4:*",
+    "http-get:*:audio/x-m4a:*",
+    "http-get:*:audio/aac:*",
+];
+
+// Service Control Protocol Description, served at /<service>/scpd.xml
+pub fn scpd(service: &Service) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <scpd xmlns=\"urn:schemas-upnp-org:service-1-0\">\
+         <specVersion><major>1</major><minor>0</minor></specVersion><actionList>",
+    );
+    for action in service.actions {
+        xml.push_str(&format!("<action><name>{}</name><argumentList>", action.name));
+        let arguments = action
+            .inputs
+            .iter()
+            .map(|argument| (argument, "in"))
+            .chain(action.outputs.iter().map(|argument| (argument, "out")));
+        for ((name, related), direction) in arguments {
+            xml.push_str(&format!(
+                "<argument><name>{}</name><direction>{}</direction>\
+                 <relatedStateVariable>{}</relatedStateVariable></argument>",
+                name, direction, related
+            ));
+        }
+        xml.push_str("</argumentList></action>");
+    }
+    xml.push_str("</actionList><serviceStateTable>");
+    for variable in service.variables {
+        xml.push_str(&format!(
+            "<stateVariable sendEvents=\"{}\"><name>{}</name><dataType>{}</dataType>",
+            if variable.evented { "yes" } else { "no" },
+            variable.name,
+            variable.data_type
+        ));
+        if !variable.allowed.is_empty() {
+            xml.push_str("<allowedValueList>");
+            for value in variable.allowed {
+                xml.push_str(&format!("<allowedValue>{}</allowedValue>", value));
+            }
+            xml.push_str("</allowedValueList>");
+        }
+        if let Some((minimum, maximum, step)) = variable.range {
+            xml.push_str(&format!(
+                "<allowedValueRange><minimum>{}</minimum><maximum>{}</maximum><step>{}</step></allowedValueRange>",
+                minimum, maximum, step
+            ));
+        }
+        xml.push_str("</stateVariable>");
+    }
+    xml.push_str("</serviceStateTable></scpd>\n");
+    xml
+}
@@ -0,0 +1,267 @@
+use anyhow::Result;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::services::SERVICES;
+
+const MULTICAST: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const PORT: u16 = 1900;
+const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:MediaRenderer:1";
+// CACHE-CONTROL max-age, re-announced well within it
+const MAX_AGE: u64 = 1800;
+const ANNOUNCE_PERIOD: Duration = Duration::from_secs(600);
+// Read timeout, how often `running` is checked
+const POLL: Duration = Duration::from_millis(200);
+
+// What the device answers to and announces, as (NT or ST, USN)
+fn targets(uuid: &str) -> Vec<(String, String)> {
+    let udn = format!("uuid:{}", uuid);
+    let mut targets = vec![
+        ("upnp:rootdevice".to_string(), format!("{}::upnp:rootdevice", udn)),
+        (udn.clone(), udn.clone()),
+        (DEVICE_TYPE.to_string(), format!("{}::{}", udn, DEVICE_TYPE)),
+    ];
+    for service in SERVICES {
+        targets.push((service.service_type.to_string(), format!("{}::{}", udn, service.service_type)));
+    }
+    targets
+}
+
+// 0.0.0.0:1900 shared with other UPnP stacks on the host, e.g. minidlna
+#[cfg(target_os = "linux")]
+fn bind() -> Result<UdpSocket> {
+    use std::os::fd::FromRawFd;
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // Owned from here so the descriptor is closed on errors
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let enable: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let address = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: PORT.to_be(),
+        sin_addr: libc::in_addr { s_addr: 0 },
+        sin_zero: [0; 8],
+    };
+    let result = unsafe {
+        libc::bind(
+            fd,
+            &address as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind() -> Result<UdpSocket> {
+    Ok(UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT))?)
+}
+
+// The address this host is reached at from `peer`, for LOCATION
+fn local_address(peer: SocketAddr) -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(peer).ok()?;
+    socket.local_addr().ok().map(|address| address.ip())
+}
+
+pub struct Ssdp {
+    socket: UdpSocket,
+    uuid: String,
+    http_port: u16,
+    server: String,
+}
+
+impl Ssdp {
+    pub fn new(uuid: &str, http_port: u16) -> Result<Self> {
+        let socket = bind()?;
+        socket.join_multicast_v4(&MULTICAST, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_read_timeout(Some(POLL))?;
+        Ok(Ssdp {
+            socket,
+            uuid: uuid.to_string(),
+            http_port,
+            server: format!("{}/1.0 UPnP/1.0 audioserver/{}", std::env::consts::OS, env!("CARGO_PKG_VERSION")),
+        })
+    }
+
+    fn location(&self, peer: SocketAddr) -> Option<String> {
+        let address = local_address(peer)?;
+        Some(format!("http://{}:{}/description.xml", address, self.http_port))
+    }
+
+    fn announce(&self, alive: bool) {
+        let destination = SocketAddr::from((MULTICAST, PORT));
+        let Some(location) = self.location(destination) else {
+            return;
+        };
+        for (nt, usn) in targets(&self.uuid) {
+            let message = if alive {
+                format!(
+                    "NOTIFY * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nCACHE-CONTROL: max-age={}\r\n\
+                     LOCATION: {}\r\nNT: {}\r\nNTS: ssdp:alive\r\nSERVER: {}\r\nUSN: {}\r\n\r\n",
+                    MAX_AGE, location, nt, self.server, usn
+                )
+            } else {
+                format!(
+                    "NOTIFY * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nNT: {}\r\nNTS: ssdp:byebye\r\nUSN: {}\r\n\r\n",
+                    nt, usn
+                )
+            };
+            if let Err(e) = self.socket.send_to(message.as_bytes(), destination) {
+                eprintln!("UPnP: SSDP announcement failed: {}", e);
+                return;
+            }
+        }
+    }
+
+    fn answer(&self, request: &str, peer: SocketAddr) {
+        let mut lines = request.lines();
+        if !lines.next().is_some_and(|line| line.starts_with("M-SEARCH")) {
+            return;
+        }
+        let header = |name: &str| {
+            request
+                .lines()
+                .skip(1)
+                .filter_map(|line| line.split_once(':'))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim().to_string())
+        };
+        if header("MAN").as_deref() != Some("\"ssdp:discover\"") {
+            return;
+        }
+        let Some(st) = header("ST") else {
+            return;
+        };
+        let Some(location) = self.location(peer) else {
+            return;
+        };
+        for (target, usn) in targets(&self.uuid) {
+            if st != "ssdp:all" && st != target {
+                continue;
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age={}\r\nEXT:\r\nLOCATION: {}\r\n\
+                 SERVER: {}\r\nST: {}\r\nUSN: {}\r\n\r\n",
+                MAX_AGE, location, self.server, target, usn
+            );
+            if let Err(e) = self.socket.send_to(response.as_bytes(), peer) {
+                eprintln!("UPnP: SSDP response to {} failed: {}", peer, e);
+                return;
+            }
+        }
+    }
+
+    // Answer searches and announce the device until `running` is cleared, then say goodbye
+    pub fn run(self, running: Arc<AtomicBool>) {
+        self.announce(true);
+        let mut announced = Instant::now();
+        let mut buffer = [0u8; 2048];
+        while running.load(Ordering::SeqCst) {
+            if announced.elapsed() >= ANNOUNCE_PERIOD {
+                self.announce(true);
+                announced = Instant::now();
+            }
+            match self.socket.recv_from(&mut buffer) {
+                Ok((length, peer)) => self.answer(&String::from_utf8_lossy(&buffer[..length]), peer),
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {}
+                Err(e) => {
+                    eprintln!("UPnP: SSDP: {}", e);
+                    std::thread::sleep(POLL);
+                }
+            }
+        }
+        self.announce(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers from a loopback socket instead of port 1900
+    fn ssdp() -> Ssdp {
+        Ssdp {
+            socket: UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+            uuid: "device".to_string(),
+            http_port: 49494,
+            server: "test".to_string(),
+        }
+    }
+
+    // Responses to one search, as their header lines
+    fn search(ssdp: &Ssdp, request: &str) -> Vec<Vec<String>> {
+        let peer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        peer.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        ssdp.answer(request, peer.local_addr().unwrap());
+        let mut responses = Vec::new();
+        let mut buffer = [0u8; 2048];
+        while let Ok(length) = peer.recv(&mut buffer) {
+            responses.push(String::from_utf8_lossy(&buffer[..length]).lines().map(str::to_string).collect());
+        }
+        responses
+    }
+
+    fn request(st: &str) -> String {
+        format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: {}\r\n\r\n",
+            st
+        )
+    }
+
+    #[test]
+    fn searches_for_the_renderer_are_answered() {
+        let ssdp = ssdp();
+        let responses = search(&ssdp, &request(DEVICE_TYPE));
+        assert_eq!(responses.len(), 1);
+        let response = &responses[0];
+        assert_eq!(response[0], "HTTP/1.1 200 OK");
+        assert!(response.contains(&"CACHE-CONTROL: max-age=1800".to_string()), "{:?}", response);
+        assert!(response.contains(&"LOCATION: http://127.0.0.1:49494/description.xml".to_string()));
+        assert!(response.contains(&format!("ST: {}", DEVICE_TYPE)));
+        assert!(response.contains(&format!("USN: uuid:device::{}", DEVICE_TYPE)));
+        assert!(response.contains(&"EXT:".to_string()));
+
+        let responses = search(&ssdp, &request("uuid:device"));
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].contains(&"USN: uuid:device".to_string()));
+        let responses = search(&ssdp, &request("urn:schemas-upnp-org:service:RenderingControl:1"));
+        assert_eq!(responses.len(), 1);
+
+        // The root device, the UDN, the device type and every service
+        let responses = search(&ssdp, &request("ssdp:all"));
+        assert_eq!(responses.len(), 3 + SERVICES.len());
+    }
+
+    #[test]
+    fn other_messages_are_ignored() {
+        let ssdp = ssdp();
+        assert!(search(&ssdp, &request("urn:schemas-upnp-org:device:MediaServer:1")).is_empty());
+        assert!(search(&ssdp, &request("uuid:other")).is_empty());
+        let without_man = "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nST: ssdp:all\r\n\r\n";
+        assert!(search(&ssdp, without_man).is_empty());
+        let notify =
+            "NOTIFY * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nNT: upnp:rootdevice\r\nNTS: ssdp:alive\r\n\r\n";
+        assert!(search(&ssdp, notify).is_empty());
+    }
+}
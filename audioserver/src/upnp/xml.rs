@@ -0,0 +1,132 @@
+// Just enough XML for SOAP requests and DIDL-Lite metadata: no namespaces
+// beyond dropping prefixes, no validation
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('&') {
+        unescaped.push_str(&rest[..at]);
+        rest = &rest[at..];
+        let Some(end) = rest.find(';').filter(|&end| end <= 10) else {
+            unescaped.push('&');
+            rest = &rest[1..];
+            continue;
+        };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => entity.strip_prefix('#').and_then(|n| n.parse().ok()).and_then(char::from_u32),
+            },
+        };
+        match decoded {
+            Some(c) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+// "u:Play" -> "Play"
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+// Top-level elements of a fragment as (local name, raw content)
+pub fn children(xml: &str) -> Vec<(&str, &str)> {
+    let mut children = Vec::new();
+    let mut depth = 0usize;
+    let mut open: Option<(&str, usize)> = None;
+    let mut at = 0;
+    while let Some(start) = xml[at..].find('<').map(|offset| at + offset) {
+        let rest = &xml[start..];
+        let skip_to = |marker: &str| start + rest.find(marker).map_or(rest.len(), |end| end + marker.len());
+        if rest.starts_with("<!--") {
+            at = skip_to("-->");
+            continue;
+        }
+        if rest.starts_with("<![CDATA[") {
+            at = skip_to("]]>");
+            continue;
+        }
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let tag = &rest[1..end];
+        at = start + end + 1;
+        if tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+        if tag.starts_with('/') {
+            depth = depth.saturating_sub(1);
+            if depth == 0
+                && let Some((name, content)) = open.take()
+            {
+                children.push((name, &xml[content..start]));
+            }
+            continue;
+        }
+        let name = local_name(tag.split([' ', '\t', '\r', '\n', '/']).next().unwrap_or(""));
+        if tag.ends_with('/') {
+            if depth == 0 {
+                children.push((name, ""));
+            }
+        } else {
+            if depth == 0 {
+                open = Some((name, at));
+            }
+            depth += 1;
+        }
+    }
+    children
+}
+
+// First top-level element called `name`
+pub fn child<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    children(xml)
+        .into_iter()
+        .find(|(child, _)| *child == name)
+        .map(|(_, content)| content)
+}
+
+// Text content of an element, CDATA sections as they are
+pub fn text(content: &str) -> String {
+    let mut text = String::new();
+    let mut rest = content;
+    while let Some(at) = rest.find("<![CDATA[") {
+        text.push_str(&unescape(&rest[..at]));
+        rest = &rest[at + 9..];
+        let end = rest.find("]]>").unwrap_or(rest.len());
+        text.push_str(&rest[..end]);
+        rest = &rest[(end + 3).min(rest.len())..];
+    }
+    text.push_str(&unescape(rest));
+    text.trim().to_string()
+}
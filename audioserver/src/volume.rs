@@ -0,0 +1,366 @@
+use anyhow::{anyhow, bail, Result};
+use std::sync::atomic::{fence, AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::crossover::FilterSpec;
+use crate::dsp::biquad::{BiquadBank, BiquadCoefficients};
+use crate::meter::AtomicF32;
+
+// ISO 226:2003 equal-loudness contour parameters
+const ISO_FREQUENCIES: [f32; 29] = [
+    20.0, 25.0, 31.5, 40.0, 50.0, 63.0, 80.0, 100.0, 125.0, 160.0, 200.0, 250.0, 315.0, 400.0, 500.0, 630.0, 800.0,
+    1000.0, 1250.0, 1600.0, 2000.0, 2500.0, 3150.0, 4000.0, 5000.0, 6300.0, 8000.0, 10000.0, 12500.0,
+];
+const ISO_AF: [f32; 29] = [
+    0.532, 0.506, 0.480, 0.455, 0.432, 0.409, 0.387, 0.367, 0.349, 0.330, 0.315, 0.301, 0.288, 0.276, 0.267, 0.259,
+    0.253, 0.250, 0.246, 0.244, 0.243, 0.243, 0.243, 0.242, 0.242, 0.245, 0.254, 0.271, 0.301,
+];
+const ISO_LU: [f32; 29] = [
+    -31.6, -27.2, -23.0, -19.1, -15.9, -13.0, -10.3, -8.1, -6.2, -4.5, -3.1, -2.0, -1.1, -0.4, 0.0, 0.3, 0.5, 0.0,
+    -2.7, -4.1, -1.0, 1.7, 2.5, 1.2, -2.1, -7.1, -11.2, -10.7, -3.1,
+];
+const ISO_TF: [f32; 29] = [
+    78.5, 68.7, 59.5, 51.1, 44.0, 37.5, 31.5, 26.5, 22.1, 17.9, 14.4, 11.4, 8.6, 6.2, 4.4, 3.0, 2.2, 2.4, 3.5, 1.7,
+    -1.3, -4.2, -6.0, -5.4, -1.5, 6.0, 12.6, 13.9, 12.3,
+];
+
+// Range the contours are defined for
+const MIN_PHON: f32 = 20.0;
+const MAX_PHON: f32 = 90.0;
+
+// Corners of the compensation shelves and the frequencies their gain is taken from
+const LOW_SHELF_HZ: f32 = 120.0;
+const LOW_REFERENCE_HZ: f32 = 40.0;
+const HIGH_SHELF_HZ: f32 = 8000.0;
+const HIGH_REFERENCE_HZ: f32 = 12500.0;
+const SHELF_Q: f32 = 0.5;
+
+// Sound pressure level in dB needed at ISO_FREQUENCIES[idx] to sound as loud as `phon`
+fn contour_spl(idx: usize, phon: f32) -> f32 {
+    let (af, lu, tf) = (ISO_AF[idx], ISO_LU[idx], ISO_TF[idx]);
+    let a = 4.47e-3 * (10f32.powf(0.025 * phon) - 1.15) + (0.4 * 10f32.powf((tf + lu) / 10.0 - 9.0)).powf(af);
+    10.0 / af * a.log10() - lu + 94.0
+}
+
+// Extra level `frequency` needs at `listening` phon, relative to 1 kHz, compared to `reference` phon
+pub fn loudness_compensation_db(frequency: f32, listening: f32, reference: f32) -> f32 {
+    let listening = listening.clamp(MIN_PHON, MAX_PHON);
+    let reference = reference.clamp(MIN_PHON, MAX_PHON);
+    let idx = ISO_FREQUENCIES
+        .iter()
+        .position(|&f| f >= frequency)
+        .unwrap_or(ISO_FREQUENCIES.len() - 1);
+    let khz = ISO_FREQUENCIES.iter().position(|&f| f == 1000.0).unwrap_or(17);
+    let relative = |phon: f32| contour_spl(idx, phon) - contour_spl(khz, phon);
+    relative(listening) - relative(reference)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoudnessConfig {
+    // Loudness in phon the system plays at with the volume at `reference_volume_db`,
+    // usually the level the music was mixed at
+    pub reference_phon: f32,
+    pub reference_volume_db: f32,
+    // Largest boost the shelves apply, whatever the contours ask for
+    pub max_boost_db: f32,
+}
+
+impl LoudnessConfig {
+    // "<reference phon>[:<reference volume dB>[:<max boost dB>]]", as in AUDIOSERVER_LOUDNESS
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut config = LoudnessConfig::default();
+        let fields = [&mut config.reference_phon, &mut config.reference_volume_db, &mut config.max_boost_db];
+        let values: Vec<&str> = text.trim().split(':').collect();
+        if values.len() > fields.len() {
+            bail!("Expected <reference phon>[:<reference volume dB>[:<max boost dB>]], got {}", text);
+        }
+        for (field, value) in fields.into_iter().zip(values) {
+            *field = value
+                .trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|value| value.is_finite())
+                .ok_or_else(|| anyhow!("Bad loudness setting {} in {}", value, text))?;
+        }
+        Ok(config)
+    }
+}
+
+impl Default for LoudnessConfig {
+    fn default() -> Self {
+        LoudnessConfig {
+            reference_phon: 83.0,
+            reference_volume_db: 0.0,
+            max_boost_db: 15.0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct VolumeShared {
+    volume_db: AtomicF32,
+    loudness: AtomicBool,
+    reference_phon: AtomicF32,
+    reference_volume_db: AtomicF32,
+    max_boost_db: AtomicF32,
+    // Rate of the running stage, the shelves are designed for it
+    sample_rate: AtomicU32,
+    // What the audio thread applies, computed by `publish`
+    gain: AtomicF32,
+    shelves_active: AtomicBool,
+    // Low and high shelf as b0, b1, b2, a1, a2
+    shelves: [[AtomicF32; 5]; 2],
+    // Odd while `publish` writes the values above, so the audio thread notices
+    // changes with one load and never applies a half written set
+    generation: AtomicU64,
+    // Serializes the control threads, the audio thread never takes it
+    writer: Mutex<()>,
+}
+
+// Volume setting shared between the control side and the running pipeline
+#[derive(Debug, Clone)]
+pub struct VolumeControl {
+    shared: Arc<VolumeShared>,
+}
+
+impl Default for VolumeControl {
+    fn default() -> Self {
+        let control = VolumeControl {
+            shared: Arc::new(VolumeShared::default()),
+        };
+        control.set_loudness(None);
+        control
+    }
+}
+
+impl VolumeControl {
+    // 0 dB is unity gain
+    pub fn set_volume_db(&self, volume_db: f32) {
+        let _writer = self.shared.writer.lock().unwrap();
+        self.shared.volume_db.store(volume_db.min(0.0));
+        self.publish();
+    }
+
+    pub fn volume_db(&self) -> f32 {
+        self.shared.volume_db.load()
+    }
+
+    // None for plain gain, Some for equal-loudness compensation that follows the volume
+    pub fn set_loudness(&self, config: Option<LoudnessConfig>) {
+        let settings = config.unwrap_or_default();
+        let _writer = self.shared.writer.lock().unwrap();
+        self.shared.loudness.store(config.is_some(), Ordering::Relaxed);
+        self.shared.reference_phon.store(settings.reference_phon);
+        self.shared.reference_volume_db.store(settings.reference_volume_db);
+        self.shared.max_boost_db.store(settings.max_boost_db);
+        self.publish();
+    }
+
+    pub fn loudness(&self) -> Option<LoudnessConfig> {
+        self.shared.loudness.load(Ordering::Relaxed).then(|| LoudnessConfig {
+            reference_phon: self.shared.reference_phon.load(),
+            reference_volume_db: self.shared.reference_volume_db.load(),
+            max_boost_db: self.shared.max_boost_db.load(),
+        })
+    }
+
+    // Called by a new stage before it starts, so the shelves match its rate
+    fn set_sample_rate(&self, sample_rate: u32) {
+        let _writer = self.shared.writer.lock().unwrap();
+        self.shared.sample_rate.store(sample_rate, Ordering::Relaxed);
+        self.publish();
+    }
+
+    // Computes gain and shelf coefficients for the audio thread. Callers hold `writer`.
+    fn publish(&self) {
+        let gain = 10f32.powf(self.volume_db() / 20.0);
+        let (low_db, high_db) = self.shelf_gains_db();
+        let sample_rate = self.shared.sample_rate.load(Ordering::Relaxed);
+        let mut cascade = [BiquadCoefficients::IDENTITY; 2];
+        if sample_rate > 0 {
+            let specs = [
+                FilterSpec::LowShelf { frequency: LOW_SHELF_HZ, q: SHELF_Q, gain_db: low_db },
+                FilterSpec::HighShelf { frequency: HIGH_SHELF_HZ, q: SHELF_Q, gain_db: high_db },
+            ];
+            for (section, spec) in cascade.iter_mut().zip(specs) {
+                if let Some(&first) = spec.sections(sample_rate).unwrap_or_default().first() {
+                    *section = first;
+                }
+            }
+        }
+
+        let shared = &self.shared;
+        shared.generation.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        shared.gain.store(gain);
+        shared.shelves_active.store(low_db > 0.0 || high_db > 0.0, Ordering::Relaxed);
+        for (values, c) in shared.shelves.iter().zip(cascade) {
+            for (value, coefficient) in values.iter().zip([c.b0, c.b1, c.b2, c.a1, c.a2]) {
+                value.store(coefficient);
+            }
+        }
+        shared.generation.fetch_add(1, Ordering::Release);
+    }
+
+    // Low and high shelf gains for the current setting
+    pub fn shelf_gains_db(&self) -> (f32, f32) {
+        let Some(config) = self.loudness() else {
+            return (0.0, 0.0);
+        };
+        let listening = config.reference_phon + self.volume_db() - config.reference_volume_db;
+        if listening >= config.reference_phon {
+            return (0.0, 0.0);
+        }
+        let gain = |frequency| {
+            loudness_compensation_db(frequency, listening, config.reference_phon).clamp(0.0, config.max_boost_db)
+        };
+        (gain(LOW_REFERENCE_HZ), gain(HIGH_REFERENCE_HZ))
+    }
+}
+
+// Realtime side: gain with a ramp per block, plus the loudness shelves. It only
+// copies what the control side computed, without locks or allocation.
+pub struct VolumeStage {
+    control: VolumeControl,
+    channels: usize,
+    generation: u64,
+    gain: f32,
+    target_gain: f32,
+    shelves: BiquadBank,
+    shelves_active: bool,
+}
+
+impl VolumeStage {
+    pub fn new(control: VolumeControl, channels: usize, sample_rate: u32) -> Self {
+        control.set_sample_rate(sample_rate);
+        let mut stage = VolumeStage {
+            control,
+            channels,
+            generation: u64::MAX,
+            gain: 1.0,
+            target_gain: 1.0,
+            shelves: BiquadBank::new(&vec![vec![BiquadCoefficients::IDENTITY; 2]; channels]),
+            shelves_active: false,
+        };
+        stage.update();
+        stage.gain = stage.target_gain;
+        stage
+    }
+
+    // Takes over published settings. While a control thread is writing them the
+    // old ones stay and the next block tries again.
+    fn update(&mut self) {
+        let shared = &self.control.shared;
+        let generation = shared.generation.load(Ordering::Acquire);
+        if generation == self.generation || generation % 2 == 1 {
+            return;
+        }
+        let gain = shared.gain.load();
+        let shelves_active = shared.shelves_active.load(Ordering::Relaxed);
+        let mut cascade = [BiquadCoefficients::IDENTITY; 2];
+        for (section, values) in cascade.iter_mut().zip(&shared.shelves) {
+            let [b0, b1, b2, a1, a2] = [0, 1, 2, 3, 4].map(|idx| values[idx].load());
+            *section = BiquadCoefficients { b0, b1, b2, a1, a2 };
+        }
+        fence(Ordering::Acquire);
+        if shared.generation.load(Ordering::Relaxed) != generation {
+            return;
+        }
+
+        self.generation = generation;
+        self.target_gain = gain;
+        self.shelves_active = shelves_active;
+        for channel in 0..self.channels {
+            self.shelves.set_cascade(channel, &cascade);
+        }
+    }
+
+    pub fn process(&mut self, data: &mut [f32]) {
+        if self.channels == 0 {
+            return;
+        }
+        self.update();
+
+        if self.shelves_active {
+            self.shelves.process_interleaved(data);
+        }
+
+        let frames = data.len() / self.channels;
+        if self.gain == self.target_gain {
+            if self.gain != 1.0 {
+                for sample in data.iter_mut() {
+                    *sample *= self.gain;
+                }
+            }
+            return;
+        }
+        // Ramp over the block to avoid zipper noise
+        let step = (self.target_gain - self.gain) / frames.max(1) as f32;
+        for frame in data.chunks_mut(self.channels) {
+            self.gain += step;
+            for sample in frame.iter_mut() {
+                *sample *= self.gain;
+            }
+        }
+        self.gain = self.target_gain;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loudness_from_text() {
+        let config = LoudnessConfig::parse("80:-10").unwrap();
+        assert_eq!((config.reference_phon, config.reference_volume_db, config.max_boost_db), (80.0, -10.0, 15.0));
+        assert!(LoudnessConfig::parse("80:-10:12:3").is_err());
+        assert!(LoudnessConfig::parse("loud").is_err());
+    }
+
+    fn level(stage: &mut VolumeStage) -> f32 {
+        let mut block = [1.0; 64];
+        stage.process(&mut block);
+        block[63]
+    }
+
+    #[test]
+    fn stage_follows_the_volume() {
+        let control = VolumeControl::default();
+        control.set_volume_db(-6.0);
+        let mut stage = VolumeStage::new(control.clone(), 2, 48000);
+        assert!((level(&mut stage) - 0.501).abs() < 1e-3);
+        control.set_volume_db(-20.0);
+        assert!((level(&mut stage) - 0.1).abs() < 1e-4);
+    }
+
+    #[test]
+    fn stage_keeps_its_settings_while_they_are_written() {
+        let control = VolumeControl::default();
+        let mut stage = VolumeStage::new(control.clone(), 1, 48000);
+        control.set_volume_db(-20.0);
+        // A writer in the middle of publishing
+        control.shared.generation.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(level(&mut stage), 1.0);
+        control.shared.generation.fetch_add(1, Ordering::Release);
+        assert!((level(&mut stage) - 0.1).abs() < 1e-4);
+    }
+
+    #[test]
+    fn loudness_shelves_are_designed_for_the_stage_rate() {
+        let control = VolumeControl::default();
+        control.set_loudness(Some(LoudnessConfig::default()));
+        control.set_volume_db(-30.0);
+        let mut stage = VolumeStage::new(control.clone(), 1, 48000);
+        assert!(stage.shelves_active);
+        let (low_db, _) = control.shelf_gains_db();
+        let low_shelf = FilterSpec::LowShelf { frequency: LOW_SHELF_HZ, q: SHELF_Q, gain_db: low_db };
+        let expected = low_shelf.sections(48000).unwrap()[0];
+        assert_eq!(control.shared.shelves[0][0].load(), expected.b0);
+        assert_eq!(control.shared.shelves[0][4].load(), expected.a2);
+        level(&mut stage);
+        control.set_loudness(None);
+        level(&mut stage);
+        assert!(!stage.shelves_active);
+    }
+}
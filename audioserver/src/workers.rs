@@ -0,0 +1,325 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use crate::crossover::{BandGroup, BandProcessor};
+use crate::rt::RealtimeState;
+
+// Largest block handed to the bands at once, longer callbacks are split
+pub const MAX_BLOCK_FRAMES: usize = 8192;
+
+// Spins before a waiting thread sleeps: workers park, the callback thread waits on a futex
+const SPIN_LIMIT: u32 = 2000;
+
+// Buffers shared between the callback thread and the workers.
+// Inputs are written only while no block is in flight; each band output has a
+// single owner (the thread its band is assigned to) while a block is in flight.
+struct PoolShared {
+    inputs: Vec<UnsafeCell<Vec<f32>>>,
+    outputs: Vec<UnsafeCell<Vec<f32>>>,
+    frames: AtomicUsize,
+    generation: AtomicU64,
+    // Workers still running the current block, a futex word on Linux
+    pending: AtomicU32,
+    // Set while the callback thread sleeps on `pending`, so the last worker knows to wake it
+    sleeping: AtomicBool,
+    stop: AtomicBool,
+}
+
+// Safety: access to the cells follows the block protocol described above
+unsafe impl Sync for PoolShared {}
+
+struct Worker {
+    thread: JoinHandle<()>,
+}
+
+// Runs every band of the crossover once per block, spread over a fixed set of
+// pre-spawned threads. Band i goes to thread i % (workers + 1), thread 0 being
+// the caller, and outputs are mixed in band order, so results don't depend on
+// the number of workers. Each thread runs its bands as one BandGroup.
+pub struct BandPool {
+    shared: Arc<PoolShared>,
+    local: BandGroup,
+    routes: Vec<(usize, usize)>,
+    workers: Vec<Worker>,
+}
+
+impl BandPool {
+    pub fn new(
+        bands: Vec<BandProcessor>,
+        input_channels: usize,
+        worker_threads: usize,
+        realtime: &Arc<RealtimeState>,
+    ) -> Self {
+        let routes = bands
+            .iter()
+            .map(|band| (band.input_channel, band.output_channel))
+            .collect();
+        let shared = Arc::new(PoolShared {
+            inputs: (0..input_channels)
+                .map(|_| UnsafeCell::new(vec![0.0; MAX_BLOCK_FRAMES]))
+                .collect(),
+            outputs: (0..bands.len())
+                .map(|_| UnsafeCell::new(vec![0.0; MAX_BLOCK_FRAMES]))
+                .collect(),
+            frames: AtomicUsize::new(0),
+            generation: AtomicU64::new(0),
+            pending: AtomicU32::new(0),
+            sleeping: AtomicBool::new(false),
+            stop: AtomicBool::new(false),
+        });
+
+        // No point in threads that would have no band to run
+        let worker_threads = worker_threads.min(bands.len().saturating_sub(1));
+        let mut assigned: Vec<Vec<(usize, BandProcessor)>> = (0..=worker_threads).map(|_| Vec::new()).collect();
+        for (idx, band) in bands.into_iter().enumerate() {
+            assigned[idx % (worker_threads + 1)].push((idx, band));
+        }
+        let local = BandGroup::new(assigned.remove(0));
+
+        let workers = assigned
+            .into_iter()
+            .enumerate()
+            .map(|(idx, bands)| {
+                let shared = shared.clone();
+                let realtime = realtime.clone();
+                Worker {
+                    thread: std::thread::spawn(move || {
+                        realtime.apply_to_current_thread(&format!("band worker {}", idx + 1), idx + 2);
+                        worker_loop(shared, BandGroup::new(bands))
+                    }),
+                }
+            })
+            .collect();
+
+        BandPool {
+            shared,
+            local,
+            routes,
+            workers,
+        }
+    }
+
+    // Run all bands on an interleaved block and mix them into `output`
+    pub fn process(&mut self, input: &[f32], input_channels: usize, output: &mut [f32], output_channels: usize) {
+        output.fill(0.0);
+        if input_channels == 0 || output_channels == 0 {
+            return;
+        }
+        let frames = (input.len() / input_channels).min(output.len() / output_channels);
+
+        let mut start = 0;
+        while start < frames {
+            let len = (frames - start).min(MAX_BLOCK_FRAMES);
+            let block_in = &input[start * input_channels..(start + len) * input_channels];
+            let block_out = &mut output[start * output_channels..(start + len) * output_channels];
+            self.process_block(block_in, input_channels, block_out, output_channels, len);
+            start += len;
+        }
+    }
+
+    fn process_block(&mut self, input: &[f32], input_channels: usize, output: &mut [f32], output_channels: usize, frames: usize) {
+        // Safety: no block is in flight, the callback thread owns every buffer
+        for (ch, cell) in self.shared.inputs.iter().enumerate() {
+            let planar = unsafe { &mut *cell.get() };
+            for (frame, sample) in planar[..frames].iter_mut().enumerate() {
+                *sample = input.get(frame * input_channels + ch).copied().unwrap_or(0.0);
+            }
+        }
+
+        if !self.workers.is_empty() {
+            self.shared.frames.store(frames, Ordering::Relaxed);
+            self.shared.pending.store(self.workers.len() as u32, Ordering::Relaxed);
+            self.shared.generation.fetch_add(1, Ordering::Release);
+            for worker in &self.workers {
+                worker.thread.thread().unpark();
+            }
+        }
+
+        run_bands(&self.shared, &mut self.local, frames);
+
+        // Usually the workers finish while the callback thread runs its own bands
+        let mut spins = 0u32;
+        while self.shared.pending.load(Ordering::Acquire) != 0 && spins < SPIN_LIMIT {
+            std::hint::spin_loop();
+            spins += 1;
+        }
+        if spins == SPIN_LIMIT {
+            self.shared.sleeping.store(true, Ordering::SeqCst);
+            loop {
+                let pending = self.shared.pending.load(Ordering::SeqCst);
+                if pending == 0 {
+                    break;
+                }
+                futex_wait(&self.shared.pending, pending);
+            }
+            self.shared.sleeping.store(false, Ordering::Relaxed);
+        }
+
+        // Safety: all workers are done with this block
+        for (idx, &(_, output_channel)) in self.routes.iter().enumerate() {
+            if output_channel >= output_channels {
+                continue;
+            }
+            let band = unsafe { &*self.shared.outputs[idx].get() };
+            for (frame, sample) in band[..frames].iter().enumerate() {
+                output[frame * output_channels + output_channel] += sample;
+            }
+        }
+    }
+
+    // Output of one band from the last block, for analyzer taps
+    pub fn band_output(&self, band: usize, frames: usize) -> Option<&[f32]> {
+        // Safety: only called between blocks from the callback thread
+        self.shared
+            .outputs
+            .get(band)
+            .map(|cell| unsafe { &(&*cell.get())[..frames.min(MAX_BLOCK_FRAMES)] })
+    }
+}
+
+fn run_bands(shared: &PoolShared, bands: &mut BandGroup, frames: usize) {
+    // Safety: inputs are read-only and the outputs of this group's bands belong to
+    // this thread during the block; the group never holds two slices of one band
+    bands.process(
+        frames,
+        |channel| shared.inputs.get(channel).map(|cell| unsafe { &(&*cell.get())[..frames] }),
+        |idx| unsafe { &mut (&mut *shared.outputs[idx].get())[..frames] },
+    );
+}
+
+fn worker_loop(shared: Arc<PoolShared>, mut bands: BandGroup) {
+    let mut seen = 0u64;
+    loop {
+        let mut spins = 0u32;
+        loop {
+            if shared.stop.load(Ordering::Acquire) {
+                return;
+            }
+            let generation = shared.generation.load(Ordering::Acquire);
+            if generation != seen {
+                seen = generation;
+                break;
+            }
+            if spins < SPIN_LIMIT {
+                std::hint::spin_loop();
+                spins += 1;
+            } else {
+                std::thread::park();
+            }
+        }
+
+        let frames = shared.frames.load(Ordering::Relaxed);
+        run_bands(&shared, &mut bands, frames);
+        // Pairs with the store of `sleeping` and load of `pending` on the callback
+        // thread: either it sees zero or this sees it sleeping
+        if shared.pending.fetch_sub(1, Ordering::SeqCst) == 1 && shared.sleeping.load(Ordering::SeqCst) {
+            futex_wake(&shared.pending);
+        }
+    }
+}
+
+// Sleeps unless `word` changed from `expected`, may wake spuriously
+#[cfg(target_os = "linux")]
+fn futex_wait(word: &AtomicU32, expected: u32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+            expected,
+            std::ptr::null::<libc::timespec>(),
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn futex_wake(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG, 1);
+    }
+}
+
+// Without futexes the callback thread yields until the workers are done
+#[cfg(not(target_os = "linux"))]
+fn futex_wait(_word: &AtomicU32, _expected: u32) {
+    std::thread::yield_now();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn futex_wake(_word: &AtomicU32) {}
+
+impl Drop for BandPool {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Release);
+        for worker in self.workers.drain(..) {
+            worker.thread.thread().unpark();
+            let _ = worker.thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crossover::{Band, FilterSpec, PassKind};
+    use crate::protection::{DriverModel, DynamicBass, ExcursionLimiter};
+    use crate::rt::RealtimeConfig;
+
+    // Bands 1, 2, 4 and 5 run a long FIR, so with two workers the callback thread
+    // is done with its own bands first and has to wait for them. Every band has its
+    // own cascade and the even ones are limited, so the lanes of each thread's
+    // biquad banks change with the worker count.
+    fn bands() -> Vec<BandProcessor> {
+        let driver = DriverModel {
+            fs_hz: 40.0,
+            qts: 0.7,
+            xmax_mm: 2.0,
+            excursion_at_full_scale_mm: 10.0,
+        };
+        (0..6)
+            .map(|idx| {
+                let mut band = Band::new(&format!("band {}", idx), idx % 2, idx % 3);
+                band.gain_db = -(idx as f32);
+                band.filters.push(FilterSpec::LinkwitzRiley {
+                    kind: if idx < 3 { PassKind::LowPass } else { PassKind::HighPass },
+                    frequency: 200.0 * (idx + 1) as f32,
+                    order: 2 * (idx as u32 % 2 + 1),
+                });
+                if idx % 3 != 0 {
+                    band.fir = Some((0..2048).map(|tap| 1.0 / (tap + idx) as f32 / 8.0).collect());
+                }
+                if idx % 2 == 0 {
+                    band.excursion_limiter = Some(ExcursionLimiter::new(driver));
+                }
+                if idx == 3 {
+                    band.dynamic_bass = Some(DynamicBass::default());
+                }
+                BandProcessor::new(&band, 48000).unwrap()
+            })
+            .collect()
+    }
+
+    fn run(worker_threads: usize) -> Vec<f32> {
+        let realtime = Arc::new(RealtimeState::new(RealtimeConfig::default()));
+        let mut pool = BandPool::new(bands(), 2, worker_threads, &realtime);
+        assert_eq!(pool.workers.len(), worker_threads);
+        let input: Vec<f32> = (0..2 * 3000).map(|n| ((n * 7919) % 1000) as f32 / 500.0 - 1.0).collect();
+        let mut result = Vec::new();
+        for block in input.chunks(2 * 512) {
+            let mut output = vec![0.0; block.len() / 2 * 3];
+            pool.process(block, 2, &mut output, 3);
+            result.extend(output);
+        }
+        result
+    }
+
+    #[test]
+    fn workers_give_the_same_output_as_one_thread() {
+        let single = run(0);
+        assert!(single.iter().any(|&x| x != 0.0));
+        assert_eq!(run(2), single);
+        assert_eq!(run(5), single);
+    }
+}
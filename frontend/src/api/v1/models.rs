@@ -36,4 +36,87 @@ pub struct User {
     pub id: String,
     pub karma: i32,
     pub about: Option<String>,
-}
\ No newline at end of file
+}
+
+// Audioserver control API, /api/v1/player and /api/v1/queue
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct QueueEntry {
+    pub id: u64,
+    pub path: String,
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct TrackMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub track_total: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub date: Option<String>,
+    pub genre: Option<String>,
+    // Seconds
+    pub duration: Option<f64>,
+    // URL of the embedded cover art
+    pub cover: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct PlayerStatus {
+    // "stopped", "playing" or "paused"
+    pub state: String,
+    pub entry: Option<QueueEntry>,
+    pub metadata: Option<TrackMetadata>,
+    // Seconds
+    pub position: f64,
+    pub duration: Option<f64>,
+    // ReplayGain applied to the track, None with normalization off
+    pub gain_db: Option<f32>,
+    pub buffered: f64,
+    pub buffering: bool,
+}
+
+// Audioserver music library, /api/v1/library
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct LibraryTrack {
+    pub id: i64,
+    pub path: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub genre: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub date: Option<String>,
+    // Seconds
+    pub duration: Option<f64>,
+    // URL of the embedded cover art
+    pub cover: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct LibraryArtist {
+    pub name: String,
+    pub albums: u32,
+    pub tracks: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct LibraryAlbum {
+    pub title: String,
+    pub artist: Option<String>,
+    pub date: Option<String>,
+    pub tracks: u32,
+    pub cover: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct LibraryGenre {
+    pub name: String,
+    pub tracks: u32,
+}